@@ -2,10 +2,14 @@ extern crate cbindgen;
 
 use std::env;
 use std::path::PathBuf;
-use cbindgen::Config;
+use std::process::Command;
+use cbindgen::{Config, Language};
 
 
 fn main() {
+    // Plugins need to be built by the same compiler as the host
+    println!("cargo:rustc-env=RUSTC_VERSION={}", rustc_version());
+
     let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
 
     let package_name = env::var("CARGO_PKG_NAME").unwrap();
@@ -22,6 +26,28 @@ fn main() {
     cbindgen::generate_with_config(&crate_dir, config)
         .unwrap()
         .write_to_file(&output_file);
+
+    // Plugins written in C need a plain C header
+    let c_header = target_dir()
+        .join(format!("{}.h", package_name))
+        .display()
+        .to_string();
+
+    let c_config = Config {
+        language: Language::C,
+        ..Default::default()
+    };
+
+    cbindgen::generate_with_config(&crate_dir, c_config)
+        .unwrap()
+        .write_to_file(&c_header);
+}
+
+fn rustc_version() -> String {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let output = Command::new(rustc).arg("--version").output().unwrap();
+
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
 }
 
 /// Find the location of the `target/` directory. Note that this may be
@@ -0,0 +1,1102 @@
+//! The ABI-stable interface between the host and a plugin.
+//!
+//! Trait objects don't have a stable layout, so passing a `Box<Plugin>`
+//! between two separately compiled libraries means trusting that both sides
+//! agree on where each method lives in the vtable. Instead, plugins hand us a
+//! `#[repr(C)]` table of `extern "C"` function pointers, generated from the
+//! `Plugin` trait by [`PluginVTable::new()`].
+//!
+//! [`PluginVTable::new()`]: struct.PluginVTable.html#method.new
+
+use std::fmt::{self, Debug, Formatter};
+use std::marker::PhantomData;
+use std::ops::BitOr;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use futures::{future, Async, Future, Poll};
+use futures::executor::{self, Notify, NotifyHandle, Spawn};
+use futures::task::{self, Task};
+use std::slice;
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use libc::c_void;
+use reqwest::Url;
+
+use errors::*;
+use context::PluginContext;
+use dependencies::Dependency;
+use plugins::{HookFuture, HookResult, Plugin, PluginConfig};
+use validate::ValidationWarning;
+use {Request, Response};
+
+
+/// The version of the plugin interface. This is bumped whenever the
+/// `PluginVTable`, or the types passed to plugins, change.
+pub const PLUGIN_API_VERSION: u32 = 11;
+
+/// The compiler this library was built with. `Request` and `Response` are
+/// passed to plugins by pointer, so their layout must match on both sides.
+pub const RUSTC_VERSION: &str = env!("RUSTC_VERSION");
+
+/// Which version of the plugin interface a library was built for.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct AbiVersion {
+    pub api_version: u32,
+    pub rustc_version: RawStr,
+}
+
+impl AbiVersion {
+    /// The version this library was compiled with.
+    pub fn current() -> AbiVersion {
+        AbiVersion {
+            api_version: PLUGIN_API_VERSION,
+            rustc_version: RawStr::new(RUSTC_VERSION),
+        }
+    }
+
+    /// Make sure a plugin built for `self` can be used by this library.
+    pub(crate) fn check_compatible(&self) -> Result<()> {
+        if self.api_version != PLUGIN_API_VERSION {
+            let reason = format!(
+                "it uses version {} of the plugin API but we need version {}",
+                self.api_version, PLUGIN_API_VERSION
+            );
+            bail!(ErrorKind::IncompatiblePlugin(reason));
+        }
+
+        let rustc = unsafe { self.rustc_version.as_str() };
+        if rustc != RUSTC_VERSION {
+            let reason = format!(
+                "it was built with {} but we were built with {}",
+                rustc, RUSTC_VERSION
+            );
+            bail!(ErrorKind::IncompatiblePlugin(reason));
+        }
+
+        Ok(())
+    }
+}
+
+/// A borrowed string which can be passed across the FFI boundary.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct RawStr {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl RawStr {
+    pub fn new(s: &str) -> RawStr {
+        RawStr {
+            ptr: s.as_ptr(),
+            len: s.len(),
+        }
+    }
+
+    /// Get the string back.
+    ///
+    /// # Safety
+    ///
+    /// The string must still be alive and contain valid UTF-8.
+    pub unsafe fn as_str<'a>(&self) -> &'a str {
+        if self.ptr.is_null() {
+            return "";
+        }
+
+        str::from_utf8_unchecked(slice::from_raw_parts(self.ptr, self.len))
+    }
+}
+
+/// Flags describing what a plugin does, so the host can show it to the user.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Capabilities {
+    pub bits: u32,
+}
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities { bits: 0 };
+    /// The plugin changes requests before they are sent.
+    pub const MODIFIES_REQUESTS: Capabilities = Capabilities { bits: 1 << 0 };
+    /// The plugin changes responses before they are shown to the user.
+    pub const MODIFIES_RESPONSES: Capabilities = Capabilities { bits: 1 << 1 };
+    /// The plugin adds its own checks when a request is validated.
+    pub const VALIDATES_REQUESTS: Capabilities = Capabilities { bits: 1 << 2 };
+    /// The plugin wants to know when a quota is exceeded.
+    pub const OBSERVES_QUOTAS: Capabilities = Capabilities { bits: 1 << 3 };
+
+    /// Are all the flags in `other` set?
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.bits & other.bits == other.bits
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities {
+            bits: self.bits | other.bits,
+        }
+    }
+}
+
+/// Information about a loaded plugin, for showing in a UI.
+///
+/// The strings are borrowed from the plugin, so they are only valid until
+/// it is unloaded.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct PluginInfo {
+    pub name: RawStr,
+    pub version: RawStr,
+    pub author: RawStr,
+    pub description: RawStr,
+    pub capabilities: Capabilities,
+    pub priority: i32,
+    pub poisoned: bool,
+}
+
+/// What happened when the host polled a plugin's `RawFuture`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub enum PollStatus {
+    /// The future isn't ready yet. The plugin will call the `Waker` when it
+    /// should be polled again.
+    Pending = 0,
+    /// The future finished and its outcome was written to `output`.
+    Ready = 1,
+    /// The future panicked.
+    Panicked = 2,
+}
+
+/// Lets a plugin tell the host that an async hook can make progress.
+///
+/// Each `Waker` owns a reference to `data`, which must be given back by
+/// calling `release` exactly once.
+#[derive(Debug)]
+#[repr(C)]
+pub struct Waker {
+    pub data: *const c_void,
+    pub wake: unsafe extern "C" fn(data: *const c_void),
+    pub release: unsafe extern "C" fn(data: *const c_void),
+}
+
+impl Waker {
+    /// A `Waker` which notifies the task currently being run by the host.
+    fn current() -> Waker {
+        unsafe extern "C" fn wake(data: *const c_void) {
+            (*(data as *const Task)).notify();
+        }
+
+        unsafe extern "C" fn release(data: *const c_void) {
+            drop(Arc::from_raw(data as *const Task));
+        }
+
+        Waker {
+            data: Arc::into_raw(Arc::new(task::current())) as *const c_void,
+            wake,
+            release,
+        }
+    }
+}
+
+/// The future returned by an async hook, as a pair of functions the host can
+/// call instead of a trait object.
+///
+/// The plugin drives the future with its own executor, so it never depends
+/// on the host's task system or the layout of its trait objects.
+#[derive(Debug)]
+#[repr(C)]
+pub struct RawFuture {
+    pub state: *mut c_void,
+    /// Poll the future. Once it is ready, its outcome is written to
+    /// `output`, an `Option<Result<T, String>>` where `T` depends on the
+    /// hook and errors have been turned into messages.
+    pub poll: Option<
+        unsafe extern "C" fn(state: *mut c_void, waker: Waker, output: *mut c_void) -> PollStatus,
+    >,
+    /// Destroy the future, returning `false` if it panicked.
+    pub destroy: Option<unsafe extern "C" fn(state: *mut c_void) -> bool>,
+}
+
+impl RawFuture {
+    fn new<T>(future: HookFuture<'static, T>) -> RawFuture {
+        let spawned = Box::new(executor::spawn(future));
+
+        RawFuture {
+            state: Box::into_raw(spawned) as *mut c_void,
+            poll: Some(poll_future::<T>),
+            destroy: Some(destroy_future::<T>),
+        }
+    }
+}
+
+impl Default for RawFuture {
+    fn default() -> RawFuture {
+        RawFuture {
+            state: ptr::null_mut(),
+            poll: None,
+            destroy: None,
+        }
+    }
+}
+
+/// The plugin's side of a `Waker`.
+struct Wake(Waker);
+
+// The host's wake and release functions can be called from any thread.
+unsafe impl Send for Wake {}
+unsafe impl Sync for Wake {}
+
+impl Notify for Wake {
+    fn notify(&self, _id: usize) {
+        unsafe { (self.0.wake)(self.0.data) }
+    }
+}
+
+impl Drop for Wake {
+    fn drop(&mut self) {
+        unsafe { (self.0.release)(self.0.data) }
+    }
+}
+
+type SpawnedHook<T> = Spawn<HookFuture<'static, T>>;
+
+unsafe extern "C" fn poll_future<T>(
+    state: *mut c_void,
+    waker: Waker,
+    output: *mut c_void,
+) -> PollStatus {
+    let notify = NotifyHandle::from(Arc::new(Wake(waker)));
+    let future = &mut *(state as *mut SpawnedHook<T>);
+    let output = &mut *(output as *mut Option<::std::result::Result<T, String>>);
+
+    match panic::catch_unwind(AssertUnwindSafe(|| future.poll_future_notify(&notify, 0))) {
+        Ok(Ok(Async::NotReady)) => PollStatus::Pending,
+        Ok(Ok(Async::Ready(value))) => {
+            *output = Some(Ok(value));
+            PollStatus::Ready
+        }
+        Ok(Err(e)) => {
+            *output = Some(Err(describe(&e)));
+            PollStatus::Ready
+        }
+        Err(_) => PollStatus::Panicked,
+    }
+}
+
+unsafe extern "C" fn destroy_future<T>(state: *mut c_void) -> bool {
+    guard(|| drop(Box::from_raw(state as *mut SpawnedHook<T>)))
+}
+
+/// The host's side of a `RawFuture`, which poisons the plugin if it panics.
+struct PluginFuture<'a, T> {
+    raw: RawFuture,
+    plugin: &'a PluginHandle,
+    hook: &'static str,
+    output: PhantomData<T>,
+}
+
+impl<'a, T> Future for PluginFuture<'a, T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<T, Error> {
+        let poll = match self.raw.poll {
+            Some(poll) => poll,
+            None => return Err(self.plugin.poison(self.hook)),
+        };
+
+        let mut output: Option<::std::result::Result<T, String>> = None;
+        let status = {
+            let sink = &mut output as *mut Option<_> as *mut c_void;
+            unsafe { poll(self.raw.state, Waker::current(), sink) }
+        };
+
+        match (status, output) {
+            (PollStatus::Pending, _) => Ok(Async::NotReady),
+            (PollStatus::Ready, Some(Ok(value))) => Ok(Async::Ready(value)),
+            (PollStatus::Ready, Some(Err(message))) => {
+                let name = self.plugin.name();
+                bail!("{}'s {} hook failed, {}", name, self.hook, message)
+            }
+            _ => Err(self.plugin.poison(self.hook)),
+        }
+    }
+}
+
+impl<'a, T> Drop for PluginFuture<'a, T> {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.raw.destroy {
+            let ok = unsafe { destroy(self.raw.state) };
+            if !ok {
+                let name = self.plugin.name();
+                warn!("A future returned by {:?} panicked while being destroyed", name);
+            }
+        }
+    }
+}
+
+/// Called by a plugin's `validate` hook for each warning it finds.
+pub type AddWarning =
+    unsafe extern "C" fn(warnings: *mut c_void, code: RawStr, message: RawStr);
+
+/// Called by a plugin's `dependencies` function for each plugin it needs.
+pub type AddDependency =
+    unsafe extern "C" fn(dependencies: *mut c_void, name: RawStr, version: RawStr);
+
+/// Every hook a plugin provides, as plain `extern "C"` functions which take a
+/// pointer to the plugin object.
+///
+/// Panics can't unwind across an `extern "C"` function, so every function
+/// catches them on the plugin's side and returns `false` instead. Functions
+/// which produce a value write it to an out-parameter.
+#[repr(C)]
+pub struct PluginVTable {
+    pub instance: *mut c_void,
+    pub name: unsafe extern "C" fn(instance: *const c_void, name: *mut RawStr) -> bool,
+    pub version: unsafe extern "C" fn(instance: *const c_void, version: *mut RawStr) -> bool,
+    pub author: unsafe extern "C" fn(instance: *const c_void, author: *mut RawStr) -> bool,
+    pub description:
+        unsafe extern "C" fn(instance: *const c_void, description: *mut RawStr) -> bool,
+    pub capabilities:
+        unsafe extern "C" fn(instance: *const c_void, capabilities: *mut Capabilities) -> bool,
+    pub priority: unsafe extern "C" fn(instance: *const c_void, priority: *mut i32) -> bool,
+    pub dependencies: unsafe extern "C" fn(
+        instance: *const c_void,
+        dependencies: *mut c_void,
+        add_dependency: AddDependency,
+    ) -> bool,
+    pub on_plugin_load:
+        unsafe extern "C" fn(instance: *const c_void, ctx: *const PluginContext) -> bool,
+    pub on_plugin_unload:
+        unsafe extern "C" fn(instance: *const c_void, ctx: *const PluginContext) -> bool,
+    pub on_configure: unsafe extern "C" fn(
+        instance: *const c_void,
+        ctx: *const PluginContext,
+        config: *const PluginConfig,
+    ) -> bool,
+    pub pre_send: unsafe extern "C" fn(
+        instance: *const c_void,
+        ctx: *const PluginContext,
+        request: *mut Request,
+        result: *mut HookResult,
+    ) -> bool,
+    pub post_receive: unsafe extern "C" fn(
+        instance: *const c_void,
+        ctx: *const PluginContext,
+        response: *mut Response,
+    ) -> bool,
+    /// Takes the request out of `request` and writes the hook's future to
+    /// `future`. The future resolves to a `(Request, HookResult)`, and
+    /// borrows the plugin and the context so it must be destroyed before
+    /// either of them.
+    pub pre_send_async: unsafe extern "C" fn(
+        instance: *const c_void,
+        ctx: *const PluginContext,
+        request: *mut Option<Request>,
+        future: *mut RawFuture,
+    ) -> bool,
+    /// Like `pre_send_async`, except the future resolves to a `Response`.
+    pub post_receive_async: unsafe extern "C" fn(
+        instance: *const c_void,
+        ctx: *const PluginContext,
+        response: *mut Option<Response>,
+        future: *mut RawFuture,
+    ) -> bool,
+    pub on_error: unsafe extern "C" fn(
+        instance: *const c_void,
+        ctx: *const PluginContext,
+        error: *const Error,
+        request: *const Request,
+    ) -> bool,
+    pub on_redirect: unsafe extern "C" fn(
+        instance: *const c_void,
+        ctx: *const PluginContext,
+        url: *const Url,
+        request: *mut Request,
+    ) -> bool,
+    pub on_retry: unsafe extern "C" fn(
+        instance: *const c_void,
+        ctx: *const PluginContext,
+        attempt: u32,
+        request: *mut Request,
+    ) -> bool,
+    pub on_quota_exceeded: unsafe extern "C" fn(
+        instance: *const c_void,
+        ctx: *const PluginContext,
+        environment: RawStr,
+        request: *const Request,
+    ) -> bool,
+    pub validate: unsafe extern "C" fn(
+        instance: *const c_void,
+        ctx: *const PluginContext,
+        request: *const Request,
+        warnings: *mut c_void,
+        add_warning: AddWarning,
+    ) -> bool,
+    /// Destroy the plugin object, using the plugin's own allocator.
+    pub destroy: unsafe extern "C" fn(instance: *mut c_void) -> bool,
+}
+
+impl PluginVTable {
+    /// Wrap up a plugin so it can be passed to the host.
+    pub fn new<P: Plugin>(plugin: P) -> PluginVTable {
+        let instance = Box::into_raw(Box::new(plugin)) as *mut c_void;
+
+        PluginVTable {
+            instance,
+            name: name::<P>,
+            version: version::<P>,
+            author: author::<P>,
+            description: description::<P>,
+            capabilities: capabilities::<P>,
+            priority: priority::<P>,
+            dependencies: dependencies::<P>,
+            on_plugin_load: on_plugin_load::<P>,
+            on_plugin_unload: on_plugin_unload::<P>,
+            on_configure: on_configure::<P>,
+            pre_send: pre_send::<P>,
+            post_receive: post_receive::<P>,
+            pre_send_async: pre_send_async::<P>,
+            post_receive_async: post_receive_async::<P>,
+            on_error: on_error::<P>,
+            on_redirect: on_redirect::<P>,
+            on_retry: on_retry::<P>,
+            on_quota_exceeded: on_quota_exceeded::<P>,
+            validate: validate::<P>,
+            destroy: destroy::<P>,
+        }
+    }
+}
+
+impl Debug for PluginVTable {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("PluginVTable")
+            .field("instance", &self.instance)
+            .finish()
+    }
+}
+
+unsafe fn instance<'a, P: Plugin>(instance: *const c_void) -> &'a P {
+    &*(instance as *const P)
+}
+
+/// Run a hook, returning `false` if it panicked.
+fn guard<F: FnOnce()>(hook: F) -> bool {
+    panic::catch_unwind(AssertUnwindSafe(hook)).is_ok()
+}
+
+unsafe extern "C" fn name<P: Plugin>(plugin: *const c_void, name: *mut RawStr) -> bool {
+    guard(|| *name = RawStr::new(instance::<P>(plugin).name()))
+}
+
+unsafe extern "C" fn version<P: Plugin>(plugin: *const c_void, version: *mut RawStr) -> bool {
+    guard(|| *version = RawStr::new(instance::<P>(plugin).version()))
+}
+
+unsafe extern "C" fn author<P: Plugin>(plugin: *const c_void, author: *mut RawStr) -> bool {
+    guard(|| *author = RawStr::new(instance::<P>(plugin).author()))
+}
+
+unsafe extern "C" fn description<P: Plugin>(
+    plugin: *const c_void,
+    description: *mut RawStr,
+) -> bool {
+    guard(|| *description = RawStr::new(instance::<P>(plugin).description()))
+}
+
+unsafe extern "C" fn capabilities<P: Plugin>(
+    plugin: *const c_void,
+    capabilities: *mut Capabilities,
+) -> bool {
+    guard(|| *capabilities = instance::<P>(plugin).capabilities())
+}
+
+unsafe extern "C" fn priority<P: Plugin>(plugin: *const c_void, priority: *mut i32) -> bool {
+    guard(|| *priority = instance::<P>(plugin).priority())
+}
+
+unsafe extern "C" fn dependencies<P: Plugin>(
+    plugin: *const c_void,
+    dependencies: *mut c_void,
+    add_dependency: AddDependency,
+) -> bool {
+    guard(|| {
+        for dependency in instance::<P>(plugin).dependencies() {
+            add_dependency(
+                dependencies,
+                RawStr::new(&dependency.name),
+                RawStr::new(&dependency.version),
+            );
+        }
+    })
+}
+
+unsafe extern "C" fn on_plugin_load<P: Plugin>(
+    plugin: *const c_void,
+    ctx: *const PluginContext,
+) -> bool {
+    guard(|| instance::<P>(plugin).on_plugin_load(&*ctx))
+}
+
+unsafe extern "C" fn on_plugin_unload<P: Plugin>(
+    plugin: *const c_void,
+    ctx: *const PluginContext,
+) -> bool {
+    guard(|| instance::<P>(plugin).on_plugin_unload(&*ctx))
+}
+
+unsafe extern "C" fn on_configure<P: Plugin>(
+    plugin: *const c_void,
+    ctx: *const PluginContext,
+    config: *const PluginConfig,
+) -> bool {
+    guard(|| instance::<P>(plugin).on_configure(&*ctx, &*config))
+}
+
+unsafe extern "C" fn pre_send<P: Plugin>(
+    plugin: *const c_void,
+    ctx: *const PluginContext,
+    request: *mut Request,
+    result: *mut HookResult,
+) -> bool {
+    guard(|| *result = instance::<P>(plugin).pre_send(&*ctx, &mut *request))
+}
+
+unsafe extern "C" fn post_receive<P: Plugin>(
+    plugin: *const c_void,
+    ctx: *const PluginContext,
+    response: *mut Response,
+) -> bool {
+    guard(|| instance::<P>(plugin).post_receive(&*ctx, &mut *response))
+}
+
+unsafe extern "C" fn pre_send_async<P: Plugin>(
+    plugin: *const c_void,
+    ctx: *const PluginContext,
+    request: *mut Option<Request>,
+    future: *mut RawFuture,
+) -> bool {
+    guard(|| {
+        if let Some(request) = (*request).take() {
+            *future = RawFuture::new(instance::<P>(plugin).pre_send_async(&*ctx, request));
+        }
+    })
+}
+
+unsafe extern "C" fn post_receive_async<P: Plugin>(
+    plugin: *const c_void,
+    ctx: *const PluginContext,
+    response: *mut Option<Response>,
+    future: *mut RawFuture,
+) -> bool {
+    guard(|| {
+        if let Some(response) = (*response).take() {
+            *future = RawFuture::new(instance::<P>(plugin).post_receive_async(&*ctx, response));
+        }
+    })
+}
+
+unsafe extern "C" fn on_error<P: Plugin>(
+    plugin: *const c_void,
+    ctx: *const PluginContext,
+    error: *const Error,
+    request: *const Request,
+) -> bool {
+    guard(|| instance::<P>(plugin).on_error(&*ctx, &*error, &*request))
+}
+
+unsafe extern "C" fn on_redirect<P: Plugin>(
+    plugin: *const c_void,
+    ctx: *const PluginContext,
+    url: *const Url,
+    request: *mut Request,
+) -> bool {
+    guard(|| instance::<P>(plugin).on_redirect(&*ctx, &*url, &mut *request))
+}
+
+unsafe extern "C" fn on_retry<P: Plugin>(
+    plugin: *const c_void,
+    ctx: *const PluginContext,
+    attempt: u32,
+    request: *mut Request,
+) -> bool {
+    guard(|| instance::<P>(plugin).on_retry(&*ctx, attempt, &mut *request))
+}
+
+unsafe extern "C" fn on_quota_exceeded<P: Plugin>(
+    plugin: *const c_void,
+    ctx: *const PluginContext,
+    environment: RawStr,
+    request: *const Request,
+) -> bool {
+    guard(|| instance::<P>(plugin).on_quota_exceeded(&*ctx, environment.as_str(), &*request))
+}
+
+unsafe extern "C" fn validate<P: Plugin>(
+    plugin: *const c_void,
+    ctx: *const PluginContext,
+    request: *const Request,
+    warnings: *mut c_void,
+    add_warning: AddWarning,
+) -> bool {
+    guard(|| {
+        for warning in instance::<P>(plugin).validate(&*ctx, &*request) {
+            add_warning(warnings, RawStr::new(&warning.code), RawStr::new(&warning.message));
+        }
+    })
+}
+
+unsafe extern "C" fn destroy<P: Plugin>(plugin: *mut c_void) -> bool {
+    guard(|| drop(Box::from_raw(plugin as *mut P)))
+}
+
+/// The host's side of a loaded plugin.
+///
+/// Strings borrowed from the plugin point into its library, so the library
+/// must outlive the handle.
+///
+/// A plugin which panics is marked as poisoned and its hooks won't be run
+/// again. If it panics while describing itself, the host falls back to an
+/// empty string, no capabilities, a priority of `0` or no dependencies.
+pub struct PluginHandle {
+    vtable: PluginVTable,
+    poisoned: AtomicBool,
+}
+
+// The `Plugin` trait requires `Send + Sync`, so the shims are too.
+unsafe impl Send for PluginHandle {}
+unsafe impl Sync for PluginHandle {}
+
+impl PluginHandle {
+    pub(crate) fn new(vtable: PluginVTable) -> PluginHandle {
+        PluginHandle {
+            vtable,
+            poisoned: AtomicBool::new(false),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.metadata("name", self.vtable.name)
+    }
+
+    pub fn version(&self) -> &str {
+        self.metadata("version", self.vtable.version)
+    }
+
+    pub fn author(&self) -> &str {
+        self.metadata("author", self.vtable.author)
+    }
+
+    pub fn description(&self) -> &str {
+        self.metadata("description", self.vtable.description)
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        let mut capabilities = Capabilities::NONE;
+        let ok = unsafe { (self.vtable.capabilities)(self.vtable.instance, &mut capabilities) };
+        self.fallback("capabilities", ok, capabilities, Capabilities::NONE)
+    }
+
+    pub fn priority(&self) -> i32 {
+        let mut priority = 0;
+        let ok = unsafe { (self.vtable.priority)(self.vtable.instance, &mut priority) };
+        self.fallback("priority", ok, priority, 0)
+    }
+
+    /// Ask the plugin for one of the strings describing it.
+    fn metadata(
+        &self,
+        function: &'static str,
+        get: unsafe extern "C" fn(*const c_void, *mut RawStr) -> bool,
+    ) -> &str {
+        let mut value = RawStr::new("");
+        let ok = unsafe { get(self.vtable.instance, &mut value) };
+        let value = self.fallback(function, ok, value, RawStr::new(""));
+        unsafe { value.as_str() }
+    }
+
+    /// Use `default` instead of `value` if the plugin panicked while
+    /// producing it.
+    ///
+    /// This can't use `poison()`, because the plugin's name may be what
+    /// panicked.
+    fn fallback<T>(&self, function: &'static str, ok: bool, value: T, default: T) -> T {
+        if ok {
+            value
+        } else {
+            warn!("A plugin panicked in its {}() function", function);
+            self.poisoned.store(true, Ordering::SeqCst);
+            default
+        }
+    }
+
+    /// The other plugins this one needs.
+    pub fn dependencies(&self) -> Vec<Dependency> {
+        unsafe extern "C" fn add_dependency(
+            dependencies: *mut c_void,
+            name: RawStr,
+            version: RawStr,
+        ) {
+            let dependencies = &mut *(dependencies as *mut Vec<Dependency>);
+            dependencies.push(Dependency::new(name.as_str(), version.as_str()));
+        }
+
+        let mut dependencies = Vec::new();
+        let ok = {
+            let sink = &mut dependencies as *mut Vec<Dependency> as *mut c_void;
+            unsafe { (self.vtable.dependencies)(self.vtable.instance, sink, add_dependency) }
+        };
+
+        // Half a list of dependencies is worse than none at all
+        self.fallback("dependencies", ok, dependencies, Vec::new())
+    }
+
+    /// Everything the plugin says about itself.
+    pub fn info(&self) -> PluginInfo {
+        PluginInfo {
+            name: RawStr::new(self.name()),
+            version: RawStr::new(self.version()),
+            author: RawStr::new(self.author()),
+            description: RawStr::new(self.description()),
+            capabilities: self.capabilities(),
+            priority: self.priority(),
+            poisoned: self.is_poisoned(),
+        }
+    }
+
+    /// Has this plugin panicked?
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn on_plugin_load(&self, ctx: &PluginContext) -> Result<()> {
+        let ok = unsafe { (self.vtable.on_plugin_load)(self.vtable.instance, ctx) };
+        self.check("on_plugin_load", ok)
+    }
+
+    pub(crate) fn on_plugin_unload(&self, ctx: &PluginContext) -> Result<()> {
+        let ok = unsafe { (self.vtable.on_plugin_unload)(self.vtable.instance, ctx) };
+        self.check("on_plugin_unload", ok)
+    }
+
+    pub(crate) fn on_configure(&self, ctx: &PluginContext, config: &PluginConfig) -> Result<()> {
+        let ok = unsafe { (self.vtable.on_configure)(self.vtable.instance, ctx, config) };
+        self.check("on_configure", ok)
+    }
+
+    pub(crate) fn pre_send(
+        &self,
+        ctx: &PluginContext,
+        request: &mut Request,
+    ) -> Result<HookResult> {
+        let mut result = HookResult::Continue;
+        let ok = unsafe { (self.vtable.pre_send)(self.vtable.instance, ctx, request, &mut result) };
+        self.check("pre_send", ok).map(|_| result)
+    }
+
+    pub(crate) fn post_receive(&self, ctx: &PluginContext, response: &mut Response) -> Result<()> {
+        let ok = unsafe { (self.vtable.post_receive)(self.vtable.instance, ctx, response) };
+        self.check("post_receive", ok)
+    }
+
+    pub(crate) fn pre_send_async<'a>(
+        &'a self,
+        ctx: &'a PluginContext,
+        request: Request,
+    ) -> HookFuture<'a, (Request, HookResult)> {
+        let mut request = Some(request);
+        let mut future = RawFuture::default();
+        let ok = unsafe {
+            (self.vtable.pre_send_async)(self.vtable.instance, ctx, &mut request, &mut future)
+        };
+        self.check_future("pre_send_async", ok, future)
+    }
+
+    pub(crate) fn post_receive_async<'a>(
+        &'a self,
+        ctx: &'a PluginContext,
+        response: Response,
+    ) -> HookFuture<'a, Response> {
+        let mut response = Some(response);
+        let mut future = RawFuture::default();
+        let ok = unsafe {
+            (self.vtable.post_receive_async)(self.vtable.instance, ctx, &mut response, &mut future)
+        };
+        self.check_future("post_receive_async", ok, future)
+    }
+
+    fn check(&self, hook: &'static str, ok: bool) -> Result<()> {
+        if ok {
+            Ok(())
+        } else {
+            Err(self.poison(hook))
+        }
+    }
+
+    /// Make sure an async hook started properly, and poison the plugin if
+    /// its future panics.
+    fn check_future<'a, T: 'a>(
+        &'a self,
+        hook: &'static str,
+        ok: bool,
+        future: RawFuture,
+    ) -> HookFuture<'a, T> {
+        let future = PluginFuture {
+            raw: future,
+            plugin: self,
+            hook,
+            output: PhantomData,
+        };
+
+        if let Err(e) = self.check(hook, ok) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(future)
+    }
+
+    fn poison(&self, hook: &'static str) -> Error {
+        self.poisoned.store(true, Ordering::SeqCst);
+        ErrorKind::PluginPanicked(self.name().to_string(), hook).into()
+    }
+
+    pub(crate) fn on_error(
+        &self,
+        ctx: &PluginContext,
+        error: &Error,
+        request: &Request,
+    ) -> Result<()> {
+        let ok = unsafe { (self.vtable.on_error)(self.vtable.instance, ctx, error, request) };
+        self.check("on_error", ok)
+    }
+
+    pub(crate) fn on_redirect(
+        &self,
+        ctx: &PluginContext,
+        url: &Url,
+        request: &mut Request,
+    ) -> Result<()> {
+        let ok = unsafe { (self.vtable.on_redirect)(self.vtable.instance, ctx, url, request) };
+        self.check("on_redirect", ok)
+    }
+
+    pub(crate) fn on_retry(
+        &self,
+        ctx: &PluginContext,
+        attempt: u32,
+        request: &mut Request,
+    ) -> Result<()> {
+        let ok = unsafe { (self.vtable.on_retry)(self.vtable.instance, ctx, attempt, request) };
+        self.check("on_retry", ok)
+    }
+
+    pub(crate) fn on_quota_exceeded(
+        &self,
+        ctx: &PluginContext,
+        environment: &str,
+        request: &Request,
+    ) -> Result<()> {
+        let environment = RawStr::new(environment);
+        let ok = unsafe {
+            (self.vtable.on_quota_exceeded)(self.vtable.instance, ctx, environment, request)
+        };
+        self.check("on_quota_exceeded", ok)
+    }
+
+    pub(crate) fn validate(
+        &self,
+        ctx: &PluginContext,
+        request: &Request,
+    ) -> Result<Vec<ValidationWarning>> {
+        unsafe extern "C" fn add_warning(warnings: *mut c_void, code: RawStr, message: RawStr) {
+            let warnings = &mut *(warnings as *mut Vec<ValidationWarning>);
+            warnings.push(ValidationWarning::new(code.as_str(), message.as_str()));
+        }
+
+        let mut warnings = Vec::new();
+        let ok = {
+            let sink = &mut warnings as *mut Vec<ValidationWarning> as *mut c_void;
+            unsafe { (self.vtable.validate)(self.vtable.instance, ctx, request, sink, add_warning) }
+        };
+
+        self.check("validate", ok).map(|_| warnings)
+    }
+}
+
+impl Drop for PluginHandle {
+    fn drop(&mut self) {
+        // The plugin may be half destroyed, so all we can do is leak the rest
+        let ok = unsafe { (self.vtable.destroy)(self.vtable.instance) };
+        if !ok {
+            warn!("A plugin panicked while it was being destroyed");
+        }
+    }
+}
+
+impl Debug for PluginHandle {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("PluginHandle")
+            .field("name", &self.name())
+            .field("version", &self.version())
+            .field("poisoned", &self.is_poisoned())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use cookie::CookieJar;
+    use futures::future::poll_fn;
+    use reqwest::{Method, StatusCode};
+    use reqwest::header::Headers;
+    use Timing;
+
+    /// A plugin which panics whenever it's asked anything.
+    struct Broken;
+
+    impl Plugin for Broken {
+        fn name(&self) -> &'static str {
+            panic!("name")
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            panic!("capabilities")
+        }
+
+        fn priority(&self) -> i32 {
+            panic!("priority")
+        }
+
+        fn dependencies(&self) -> Vec<Dependency> {
+            panic!("dependencies")
+        }
+
+        fn on_quota_exceeded(&self, _ctx: &PluginContext, _environment: &str, _request: &Request) {
+            panic!("on_quota_exceeded")
+        }
+
+        fn validate(&self, _ctx: &PluginContext, _request: &Request) -> Vec<ValidationWarning> {
+            panic!("validate")
+        }
+    }
+
+    impl Drop for Broken {
+        fn drop(&mut self) {
+            panic!("destroy")
+        }
+    }
+
+    fn request() -> Request {
+        Request::new(Url::parse("http://localhost/").unwrap(), Method::Get)
+    }
+
+    fn response() -> Response {
+        Response {
+            headers: Headers::new(),
+            body: Vec::new(),
+            status: StatusCode::Ok,
+            cookies: CookieJar::new(),
+            redirects: Vec::new(),
+            timing: Timing {
+                first_byte_ms: 0.0,
+                total_ms: 0.0,
+            },
+        }
+    }
+
+    #[test]
+    fn metadata_falls_back_when_the_plugin_panics() {
+        let handle = PluginHandle::new(PluginVTable::new(Broken));
+
+        assert_eq!(handle.name(), "");
+        assert!(handle.is_poisoned());
+        assert_eq!(handle.capabilities(), Capabilities::NONE);
+        assert_eq!(handle.priority(), 0);
+        assert!(handle.dependencies().is_empty());
+    }
+
+    #[test]
+    fn hooks_without_a_result_still_poison_the_plugin() {
+        let ctx = PluginContext::new();
+        let req = request();
+
+        let handle = PluginHandle::new(PluginVTable::new(Broken));
+        assert!(handle.on_quota_exceeded(&ctx, "prod", &req).is_err());
+        assert!(handle.is_poisoned());
+
+        let handle = PluginHandle::new(PluginVTable::new(Broken));
+        assert!(handle.validate(&ctx, &req).is_err());
+        assert!(handle.is_poisoned());
+    }
+
+    #[test]
+    fn a_panicking_destructor_is_contained() {
+        drop(PluginHandle::new(PluginVTable::new(Broken)));
+    }
+
+    /// A plugin whose async hook isn't ready the first time it's polled.
+    struct Slow;
+
+    impl Plugin for Slow {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        fn pre_send_async<'a>(
+            &'a self,
+            _ctx: &'a PluginContext,
+            request: Request,
+        ) -> HookFuture<'a, (Request, HookResult)> {
+            let polled = Cell::new(false);
+            let mut request = Some(request);
+
+            Box::new(poll_fn(move || {
+                if polled.replace(true) {
+                    Ok(Async::Ready((request.take().unwrap(), HookResult::Continue)))
+                } else {
+                    task::current().notify();
+                    Ok(Async::NotReady)
+                }
+            }))
+        }
+
+        fn post_receive_async<'a>(
+            &'a self,
+            _ctx: &'a PluginContext,
+            _response: Response,
+        ) -> HookFuture<'a, Response> {
+            Box::new(poll_fn(|| -> Poll<Response, Error> { panic!("post_receive_async") }))
+        }
+    }
+
+    #[test]
+    fn async_hooks_are_driven_through_the_raw_future() {
+        let ctx = PluginContext::new();
+        let handle = PluginHandle::new(PluginVTable::new(Slow));
+
+        let (req, result) = handle.pre_send_async(&ctx, request()).wait().unwrap();
+
+        assert_eq!(req.destination.as_str(), "http://localhost/");
+        match result {
+            HookResult::Continue => {}
+            other => panic!("Expected the request to continue, got {:?}", other),
+        }
+        assert!(!handle.is_poisoned());
+    }
+
+    #[test]
+    fn a_panicking_future_poisons_the_plugin() {
+        let ctx = PluginContext::new();
+        let handle = PluginHandle::new(PluginVTable::new(Slow));
+
+        assert!(handle.post_receive_async(&ctx, response()).wait().is_err());
+        assert!(handle.is_poisoned());
+    }
+}
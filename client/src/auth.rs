@@ -0,0 +1,97 @@
+//! Adding credentials to requests.
+
+use std::fmt::{self, Debug, Formatter};
+use base64;
+
+use errors::*;
+use Request;
+
+
+/// Something which knows how to add credentials to a request.
+///
+/// This is called immediately before each attempt at sending the request
+/// (including retries), so schemes which sign the request (e.g. AWS SigV4)
+/// can include an up-to-date timestamp. Plugins can attach their own
+/// authenticator to a request from their `pre_send()` hook.
+pub trait Authenticator: Send + Sync {
+    /// The name of the authentication scheme (e.g. `"Basic"`).
+    fn scheme(&self) -> &str;
+    /// Add credentials to the request.
+    fn authenticate(&self, request: &mut Request) -> Result<()>;
+}
+
+impl Debug for Authenticator {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Authenticator({})", self.scheme())
+    }
+}
+
+/// HTTP Basic authentication.
+#[derive(Clone, PartialEq)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl BasicAuth {
+    pub fn new<U: Into<String>, P: Into<String>>(username: U, password: P) -> BasicAuth {
+        BasicAuth {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// The value of the `Authorization` header.
+    pub fn header_value(&self) -> String {
+        let credentials = format!("{}:{}", self.username, self.password);
+        format!("Basic {}", base64::encode(credentials.as_bytes()))
+    }
+}
+
+impl Authenticator for BasicAuth {
+    fn scheme(&self) -> &str {
+        "Basic"
+    }
+
+    fn authenticate(&self, request: &mut Request) -> Result<()> {
+        request.headers.set_raw("Authorization", self.header_value());
+        Ok(())
+    }
+}
+
+impl Debug for BasicAuth {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Bearer token authentication, as used by OAuth 2.0.
+#[derive(Clone, PartialEq)]
+pub struct BearerToken(pub String);
+
+impl BearerToken {
+    /// The value of the `Authorization` header.
+    pub fn header_value(&self) -> String {
+        format!("Bearer {}", self.0)
+    }
+}
+
+impl Authenticator for BearerToken {
+    fn scheme(&self) -> &str {
+        "Bearer"
+    }
+
+    fn authenticate(&self, request: &mut Request) -> Result<()> {
+        request.headers.set_raw("Authorization", self.header_value());
+        Ok(())
+    }
+}
+
+impl Debug for BearerToken {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "BearerToken(<redacted>)")
+    }
+}
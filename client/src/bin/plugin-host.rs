@@ -0,0 +1,23 @@
+//! Runs a single plugin on behalf of a client which loaded it with
+//! `PluginManager::load_plugin_sandboxed()`, talking to it over
+//! stdin/stdout.
+
+extern crate client;
+
+use std::io::{self, Write};
+use std::process;
+
+
+fn main() {
+    if let Err(e) = client::sandbox::serve() {
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+
+        writeln!(stderr, "Error: {}", e).ok();
+        for cause in e.iter().skip(1) {
+            writeln!(stderr, "\tCaused by: {}", cause).ok();
+        }
+
+        process::exit(1);
+    }
+}
@@ -0,0 +1,266 @@
+//! Plugins written in C, or any other language which can export C functions.
+//!
+//! Instead of `_plugin_create()`, a C plugin exports a `__plugin_create_c()`
+//! function which returns a `CPluginVTable`. Requests and responses are
+//! passed as opaque pointers which the plugin can inspect and modify using
+//! the normal FFI functions (`request_set_header()`, `response_body_ptr()`,
+//! etc.) declared in the generated `client.h`, so unlike Rust plugins they
+//! don't need to be built with the same compiler as the host.
+//!
+//! The `PluginContext` is a Rust type, so it isn't passed to C plugins.
+//!
+//! ```c
+//! #include "client.h"
+//!
+//! static int pre_send(void *user_data, Request *request) {
+//!     request_set_header(request, "X-Written-In", "C");
+//!     return 0;
+//! }
+//!
+//! CPluginVTable __plugin_create_c(void) {
+//!     CPluginVTable vtable = {0};
+//!     vtable.api_version = 1;
+//!     vtable.name = "C Example";
+//!     vtable.pre_send = pre_send;
+//!     return vtable;
+//! }
+//! ```
+
+use std::ffi::{CStr, CString};
+use libc::{c_char, c_int, c_void};
+
+use abi::Capabilities;
+use context::PluginContext;
+use errors::*;
+use plugins::{HookResult, Plugin, PluginConfig};
+use {Request, Response};
+
+
+/// The version of the C plugin interface. This is bumped whenever the
+/// `CPluginVTable` changes.
+pub const C_PLUGIN_API_VERSION: u32 = 1;
+
+/// Everything a C plugin provides. Any of the callbacks may be null.
+///
+/// The strings must be null-terminated UTF-8. They are copied when the
+/// plugin is loaded, so they only need to last until `__plugin_create_c()`
+/// returns.
+#[repr(C)]
+pub struct CPluginVTable {
+    /// Must be `C_PLUGIN_API_VERSION`.
+    pub api_version: u32,
+    /// Passed to every callback.
+    pub user_data: *mut c_void,
+    pub name: *const c_char,
+    /// May be null.
+    pub version: *const c_char,
+    /// May be null.
+    pub description: *const c_char,
+    /// Plugins with a higher priority have their hooks run first.
+    pub priority: i32,
+    pub on_plugin_load: Option<unsafe extern "C" fn(user_data: *mut c_void)>,
+    pub on_plugin_unload: Option<unsafe extern "C" fn(user_data: *mut c_void)>,
+    /// Called once for each of the plugin's settings.
+    pub on_configure: Option<
+        unsafe extern "C" fn(user_data: *mut c_void, key: *const c_char, value: *const c_char),
+    >,
+    /// Return `0` to carry on, or anything else to stop the request from
+    /// being sent.
+    pub pre_send:
+        Option<unsafe extern "C" fn(user_data: *mut c_void, request: *mut Request) -> c_int>,
+    pub post_receive:
+        Option<unsafe extern "C" fn(user_data: *mut c_void, response: *mut Response)>,
+    /// Free `user_data`.
+    pub destroy: Option<unsafe extern "C" fn(user_data: *mut c_void)>,
+}
+
+/// Adapts a `CPluginVTable` to the `Plugin` trait, so C plugins can be
+/// treated exactly the same as Rust ones.
+pub(crate) struct CPlugin {
+    vtable: CPluginVTable,
+    name: String,
+    version: String,
+    description: String,
+}
+
+// C plugins are responsible for their own synchronisation, just like Rust
+// plugins have to be `Send + Sync`.
+unsafe impl Send for CPlugin {}
+unsafe impl Sync for CPlugin {}
+
+impl CPlugin {
+    /// Check the vtable is something we can use, copying the plugin's
+    /// strings so they don't point into its library.
+    ///
+    /// # Safety
+    ///
+    /// The strings in the vtable must be valid until this returns.
+    pub(crate) unsafe fn new(vtable: CPluginVTable) -> Result<CPlugin> {
+        if vtable.api_version != C_PLUGIN_API_VERSION {
+            let reason = format!(
+                "it uses version {} of the C plugin API but we need version {}",
+                vtable.api_version, C_PLUGIN_API_VERSION
+            );
+            bail!(ErrorKind::IncompatiblePlugin(reason));
+        }
+
+        // From here on the plugin's destroy() callback gets called if
+        // something goes wrong
+        let mut plugin = CPlugin {
+            vtable,
+            name: String::new(),
+            version: String::from("unknown"),
+            description: String::new(),
+        };
+
+        plugin.name = copy_str(plugin.vtable.name, "name")?
+            .ok_or_else(|| Error::from("C plugins must have a name"))?;
+        if let Some(version) = copy_str(plugin.vtable.version, "version")? {
+            plugin.version = version;
+        }
+        if let Some(description) = copy_str(plugin.vtable.description, "description")? {
+            plugin.description = description;
+        }
+
+        Ok(plugin)
+    }
+}
+
+unsafe fn copy_str(raw: *const c_char, field: &str) -> Result<Option<String>> {
+    if raw.is_null() {
+        return Ok(None);
+    }
+
+    CStr::from_ptr(raw)
+        .to_str()
+        .map(|s| Some(s.to_string()))
+        .chain_err(|| format!("The plugin's {} isn't valid UTF-8", field))
+}
+
+impl Plugin for CPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn version(&self) -> &str {
+        &self.version
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let mut capabilities = Capabilities::NONE;
+
+        if self.vtable.pre_send.is_some() {
+            capabilities = capabilities | Capabilities::MODIFIES_REQUESTS;
+        }
+        if self.vtable.post_receive.is_some() {
+            capabilities = capabilities | Capabilities::MODIFIES_RESPONSES;
+        }
+
+        capabilities
+    }
+
+    fn priority(&self) -> i32 {
+        self.vtable.priority
+    }
+
+    fn on_plugin_load(&self, _ctx: &PluginContext) {
+        if let Some(on_plugin_load) = self.vtable.on_plugin_load {
+            unsafe { on_plugin_load(self.vtable.user_data) }
+        }
+    }
+
+    fn on_plugin_unload(&self, _ctx: &PluginContext) {
+        if let Some(on_plugin_unload) = self.vtable.on_plugin_unload {
+            unsafe { on_plugin_unload(self.vtable.user_data) }
+        }
+    }
+
+    fn on_configure(&self, _ctx: &PluginContext, config: &PluginConfig) {
+        let on_configure = match self.vtable.on_configure {
+            Some(f) => f,
+            None => return,
+        };
+
+        for (key, value) in config.values() {
+            match (CString::new(key), CString::new(value)) {
+                (Ok(k), Ok(v)) => unsafe {
+                    on_configure(self.vtable.user_data, k.as_ptr(), v.as_ptr());
+                },
+                _ => warn!("Skipping the {:?} setting because it contains a null byte", key),
+            }
+        }
+    }
+
+    fn pre_send(&self, _ctx: &PluginContext, request: &mut Request) -> HookResult {
+        let pre_send = match self.vtable.pre_send {
+            Some(f) => f,
+            None => return HookResult::Continue,
+        };
+
+        match unsafe { pre_send(self.vtable.user_data, request) } {
+            0 => HookResult::Continue,
+            code => HookResult::Abort(format!("{}'s pre_send() returned {}", self.name, code)),
+        }
+    }
+
+    fn post_receive(&self, _ctx: &PluginContext, response: &mut Response) {
+        if let Some(post_receive) = self.vtable.post_receive {
+            unsafe { post_receive(self.vtable.user_data, response) }
+        }
+    }
+}
+
+impl Drop for CPlugin {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.vtable.destroy {
+            unsafe { destroy(self.vtable.user_data) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    fn vtable(name: *const c_char, version: *const c_char) -> CPluginVTable {
+        CPluginVTable {
+            api_version: C_PLUGIN_API_VERSION,
+            user_data: ptr::null_mut(),
+            name,
+            version,
+            description: ptr::null(),
+            priority: 0,
+            on_plugin_load: None,
+            on_plugin_unload: None,
+            on_configure: None,
+            pre_send: None,
+            post_receive: None,
+            destroy: None,
+        }
+    }
+
+    #[test]
+    fn strings_are_copied_when_the_plugin_is_created() {
+        let name = CString::new("C Example").unwrap();
+        let version = CString::new("1.2.3").unwrap();
+
+        let plugin = unsafe { CPlugin::new(vtable(name.as_ptr(), version.as_ptr())).unwrap() };
+        drop(name);
+        drop(version);
+
+        assert_eq!(plugin.name(), "C Example");
+        assert_eq!(plugin.version(), "1.2.3");
+        assert_eq!(plugin.description(), "");
+    }
+
+    #[test]
+    fn plugins_need_a_name() {
+        let result = unsafe { CPlugin::new(vtable(ptr::null(), ptr::null())) };
+        assert!(result.is_err());
+    }
+}
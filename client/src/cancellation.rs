@@ -0,0 +1,173 @@
+//! A cancellation token which can be shared between Rust and C.
+//!
+//! Long-running Rust code can cheaply poll
+//! [`CancellationToken::is_cancelled()`] while the C side calls
+//! [`cancel_token_cancel()`] from a UI thread or even a signal handler (it
+//! only touches an atomic flag).
+//!
+//! [`CancellationToken::is_cancelled()`]: struct.CancellationToken.html#method.is_cancelled
+//! [`cancel_token_cancel()`]: fn.cancel_token_cancel.html
+
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use libc::{c_char, c_int, size_t};
+
+use errors::*;
+use ffi::{c_str_to_str, catch_panic, copy_to_buffer, update_last_error};
+
+
+/// A flag which can be used to ask an operation to stop, optionally with a
+/// reason why. Cloning a token gives you another handle to the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    reason: Mutex<Option<String>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Has cancellation been requested?
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Request cancellation.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Request cancellation, recording why. Only the first reason is kept.
+    pub fn cancel_with_reason<S: Into<String>>(&self, reason: S) {
+        if let Ok(mut r) = self.inner.reason.lock() {
+            if r.is_none() {
+                *r = Some(reason.into());
+            }
+        }
+
+        self.cancel();
+    }
+
+    /// The reason given for cancelling, if there was one.
+    pub fn reason(&self) -> Option<String> {
+        self.inner.reason.lock().ok().and_then(|r| r.clone())
+    }
+
+    /// Return an `ErrorKind::Cancelled` error if cancellation was requested,
+    /// making it easy to bail out with `?`.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            let reason = self.reason()
+                .unwrap_or_else(|| String::from("no reason given"));
+            Err(ErrorKind::Cancelled(reason).into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Create a new `CancellationToken`. Make sure to destroy it with
+/// [`cancel_token_destroy()`] afterwards.
+///
+/// [`cancel_token_destroy()`]: fn.cancel_token_destroy.html
+#[no_mangle]
+pub extern "C" fn cancel_token_new() -> *mut CancellationToken {
+    catch_panic(ptr::null_mut(), || {
+        Box::into_raw(Box::new(CancellationToken::new()))
+    })
+}
+
+/// Destroy a `CancellationToken` once you are done with it.
+#[no_mangle]
+pub unsafe extern "C" fn cancel_token_destroy(token: *mut CancellationToken) {
+    catch_panic((), || {
+        if !token.is_null() {
+            drop(Box::from_raw(token));
+        }
+    })
+}
+
+/// Request cancellation.
+///
+/// This only sets an atomic flag, so it is safe to call from a signal handler.
+#[no_mangle]
+pub unsafe extern "C" fn cancel_token_cancel(token: *const CancellationToken) {
+    catch_panic((), || {
+        if !token.is_null() {
+            (&*token).cancel();
+        }
+    })
+}
+
+/// Request cancellation, recording a reason which will be used in the
+/// resulting error message.
+///
+/// Unlike [`cancel_token_cancel()`], this allocates and **isn't** safe to call
+/// from a signal handler.
+///
+/// [`cancel_token_cancel()`]: fn.cancel_token_cancel.html
+#[no_mangle]
+pub unsafe extern "C" fn cancel_token_cancel_with_reason(
+    token: *const CancellationToken,
+    reason: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if token.is_null() {
+            update_last_error(Error::from(
+                "Null pointer passed to cancel_token_cancel_with_reason()",
+            ));
+            return -1;
+        }
+
+        match c_str_to_str(reason, "reason") {
+            Some(reason) => {
+                (&*token).cancel_with_reason(reason);
+                0
+            }
+            None => -1,
+        }
+    })
+}
+
+/// Check whether cancellation has been requested, returning `1` if it has,
+/// `0` if it hasn't, and `-1` if passed a null pointer.
+#[no_mangle]
+pub unsafe extern "C" fn cancel_token_is_cancelled(token: *const CancellationToken) -> c_int {
+    catch_panic(-1, || {
+        if token.is_null() {
+            update_last_error(Error::from("Null pointer passed to cancel_token_is_cancelled()"));
+            return -1;
+        }
+
+        (&*token).is_cancelled() as c_int
+    })
+}
+
+/// Write the cancellation reason into a buffer, returning the number of bytes
+/// written (`0` if no reason was given) or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn cancel_token_reason(
+    token: *const CancellationToken,
+    buffer: *mut c_char,
+    length: size_t,
+) -> c_int {
+    catch_panic(-1, || {
+        if token.is_null() {
+            update_last_error(Error::from("Null pointer passed to cancel_token_reason()"));
+            return -1;
+        }
+
+        match (&*token).reason() {
+            Some(reason) => copy_to_buffer(reason.as_bytes(), buffer, length),
+            None => 0,
+        }
+    })
+}
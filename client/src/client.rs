@@ -0,0 +1,808 @@
+use std::borrow::Cow;
+use std::fmt::{self, Debug, Formatter};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use futures::Future;
+use reqwest::{self, StatusCode, Url};
+use reqwest::header::{ContentLength, Location};
+use threadpool::ThreadPool;
+
+use auth::Authenticator;
+use errors::*;
+use history;
+use mock::MockTransport;
+use rate_limit::RateLimiter;
+use recorder::Recorder;
+use redirect::{self, RedirectPolicy};
+use transport::{ClientBuilder, TransportConfig};
+use {CancellationToken, HookResult, PluginManager, Request, RequestOptions, Response, Timing};
+
+
+/// The number of background threads used for asynchronous requests.
+pub const WORKER_THREADS: usize = 4;
+
+/// The number of background threads used for cancellable requests.
+pub const CANCELLABLE_THREADS: usize = 4;
+
+/// How often to check whether a cancellable request has been cancelled.
+const CANCEL_POLL_MS: u64 = 50;
+
+lazy_static! {
+    static ref WORKERS: Mutex<ThreadPool> =
+        Mutex::new(ThreadPool::with_name(String::from("http-client"), WORKER_THREADS));
+    static ref CANCELLABLE_WORKERS: Mutex<ThreadPool> = Mutex::new(ThreadPool::with_name(
+        String::from("http-client-cancellable"),
+        CANCELLABLE_THREADS,
+    ));
+}
+
+/// A long-lived HTTP client.
+///
+/// Creating a client sets up a TLS context and connection pool, so reusing
+/// the same `HttpClient` for many requests is a lot cheaper than calling
+/// [`send_request()`] each time.
+///
+/// Cloning a client is cheap and the clone will share the same connection
+/// pool.
+///
+/// [`send_request()`]: fn.send_request.html
+#[derive(Clone)]
+pub struct HttpClient {
+    inner: reqwest::Client,
+    options: RequestOptions,
+    transport: TransportConfig,
+    redirect_policy: RedirectPolicy,
+    limiter: Arc<RateLimiter>,
+    authenticator: Option<Arc<Authenticator>>,
+    recorder: Option<Recorder>,
+    mock: Option<MockTransport>,
+}
+
+/// Details about how a response was received, which get copied into the
+/// `Response`.
+#[derive(Debug, Clone)]
+struct Transfer {
+    redirects: Vec<Url>,
+    started: Instant,
+    first_byte: Duration,
+}
+
+impl Transfer {
+    /// Copy the details into a `Response`. This should be called after the
+    /// body has been read so the total time is correct.
+    fn apply(self, response: &mut Response) {
+        response.redirects = self.redirects;
+        response.timing = Timing {
+            first_byte_ms: millis(self.first_byte),
+            total_ms: millis(self.started.elapsed()),
+        };
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+impl HttpClient {
+    pub fn new() -> Result<HttpClient> {
+        HttpClient::with_options(RequestOptions::default())
+    }
+
+    /// Create a client which uses the provided options for any requests which
+    /// don't override them.
+    pub fn with_options(options: RequestOptions) -> Result<HttpClient> {
+        HttpClient::with_transport(options, TransportConfig::default())
+    }
+
+    /// Create a client which connects using a particular proxy or TLS setup.
+    pub fn with_transport(
+        options: RequestOptions,
+        transport: TransportConfig,
+    ) -> Result<HttpClient> {
+        let inner = build_client(&options, &transport)?;
+        Ok(HttpClient {
+            inner,
+            options,
+            transport,
+            redirect_policy: RedirectPolicy::default(),
+            limiter: Arc::new(RateLimiter::new()),
+            authenticator: None,
+            recorder: None,
+            mock: None,
+        })
+    }
+
+    /// Get a builder for configuring things like proxies and certificates.
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    pub fn options(&self) -> RequestOptions {
+        self.options
+    }
+
+    /// Change the client's default options.
+    pub fn set_options(&mut self, options: RequestOptions) -> Result<()> {
+        if options.timeout != self.options.timeout {
+            self.inner = build_client(&options, &self.transport)?;
+        }
+
+        self.options = options;
+        Ok(())
+    }
+
+    pub fn redirect_policy(&self) -> &RedirectPolicy {
+        &self.redirect_policy
+    }
+
+    /// Change how redirects are handled for requests which don't have their
+    /// own redirect policy.
+    pub fn set_redirect_policy(&mut self, policy: RedirectPolicy) {
+        self.redirect_policy = policy;
+    }
+
+    /// Add credentials to every request which doesn't have its own
+    /// authenticator.
+    pub fn set_authenticator(&mut self, authenticator: Option<Arc<Authenticator>>) {
+        self.authenticator = authenticator;
+    }
+
+    /// Record every request sent by this client (and its clones) so they can
+    /// be exported as a HAR file.
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.recorder = recorder;
+    }
+
+    pub fn mock(&self) -> Option<&MockTransport> {
+        self.mock.as_ref()
+    }
+
+    /// Answer requests using a `MockTransport` instead of the network, or go
+    /// back to using the network with `None`.
+    pub fn set_mock(&mut self, mock: Option<MockTransport>) {
+        self.mock = mock;
+    }
+
+    /// The rate limiter shared by this client and all its clones.
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+
+    pub fn transport(&self) -> &TransportConfig {
+        &self.transport
+    }
+
+    /// Change the proxy and TLS settings used when connecting to a server.
+    ///
+    /// Existing connections are dropped.
+    pub fn set_transport(&mut self, transport: TransportConfig) -> Result<()> {
+        self.inner = build_client(&self.options, &transport)?;
+        self.transport = transport;
+        Ok(())
+    }
+
+    /// Send a request, reusing an existing connection if possible.
+    pub fn send(&self, req: &Request) -> Result<Response> {
+        self.dispatch(req, None, None)
+    }
+
+    /// Send a request, firing the plugins' hooks at each step along the way
+    /// (`pre_send()`, `on_redirect()`, `on_retry()`, then `post_receive()` or
+    /// `on_error()`).
+    ///
+    /// If a plugin aborts the request an `ErrorKind::Aborted` error is
+    /// returned, and if a plugin answers the request itself its response is
+    /// passed to the `post_receive()` hooks instead of sending the request.
+    /// Plugins which panic are logged and skipped, but don't stop the request
+    /// from being sent.
+    ///
+    /// Anything the plugins allocated from their context's scratch space is
+    /// freed once the request is finished.
+    pub fn send_with_plugins(
+        &self,
+        req: &Request,
+        plugins: &mut PluginManager,
+    ) -> Result<Response> {
+        let result = self.send_through_plugins(req.clone(), plugins);
+        plugins.context_mut().clear_scratch();
+        result
+    }
+
+    fn send_through_plugins(
+        &self,
+        mut req: Request,
+        plugins: &mut PluginManager,
+    ) -> Result<Response> {
+        match plugins.pre_send(&mut req).unwrap_or(HookResult::Continue) {
+            HookResult::Continue => {}
+            HookResult::Abort(reason) => bail!(ErrorKind::Aborted(reason)),
+            HookResult::Respond(mut response) => {
+                plugins.post_receive_for(&req, &mut response).ok();
+                return Ok(response);
+            }
+        }
+
+        match self.dispatch(&req, None, Some(plugins)) {
+            Ok(mut response) => {
+                plugins.post_receive_for(&req, &mut response).ok();
+                Ok(response)
+            }
+            Err(e) => {
+                plugins.error(&e, &req);
+                Err(e)
+            }
+        }
+    }
+
+    /// Send a request which can be aborted part way through by cancelling
+    /// the `CancellationToken`, in which case an `ErrorKind::Cancelled` error
+    /// is returned.
+    ///
+    /// The request is sent on one of a fixed number of background threads
+    /// (see [`CANCELLABLE_THREADS`]) so this can return as soon as
+    /// cancellation is requested. The worker checks the token before
+    /// starting and between each chunk of the response body, so a cancelled
+    /// download is abandoned (and its connection closed) instead of read to
+    /// completion. The transport can't be interrupted while it waits for the
+    /// response headers, so until they arrive (or the timeout expires) a
+    /// cancelled request still occupies its thread.
+    ///
+    /// [`CANCELLABLE_THREADS`]: constant.CANCELLABLE_THREADS.html
+    pub fn send_cancellable(&self, req: &Request, token: &CancellationToken) -> Result<Response> {
+        token.check()?;
+
+        let (tx, rx) = mpsc::channel();
+        let client = self.clone();
+        let req = req.clone();
+        let worker_token = token.clone();
+
+        CANCELLABLE_WORKERS
+            .lock()
+            .map_err(|_| Error::from("The background thread pool is poisoned"))?
+            .execute(move || {
+                // It may have been cancelled while waiting for a free thread
+                let outcome = worker_token
+                    .check()
+                    .and_then(|_| client.dispatch(&req, Some(&worker_token), None));
+                let _ = tx.send(outcome);
+            });
+
+        loop {
+            match rx.recv_timeout(Duration::from_millis(CANCEL_POLL_MS)) {
+                Ok(outcome) => return outcome,
+                Err(RecvTimeoutError::Timeout) => token.check()?,
+                Err(RecvTimeoutError::Disconnected) => {
+                    bail!("The thread sending the request stopped unexpectedly")
+                }
+            }
+        }
+    }
+
+    /// Send a request, passing the response body to `on_chunk` piece by
+    /// piece as it arrives instead of keeping it in memory.
+    ///
+    /// The returned `Response` contains the status code and headers, but its
+    /// `body` will be empty.
+    pub fn send_streaming<F>(&self, req: &Request, mut on_chunk: F) -> Result<Response>
+    where
+        F: FnMut(&[u8]),
+    {
+        self.try_send_streaming(req, |chunk| {
+            on_chunk(chunk);
+            Ok(())
+        })
+    }
+
+    /// Like [`send_streaming()`], except the callback can return an error to
+    /// stop receiving the body.
+    ///
+    /// [`send_streaming()`]: #method.send_streaming
+    pub fn try_send_streaming<F>(&self, req: &Request, on_chunk: F) -> Result<Response>
+    where
+        F: FnMut(&[u8]) -> Result<()>,
+    {
+        let outcome = match self.mock {
+            Some(ref mock) => self.send_mocked(mock, req, None).and_then(|mut response| {
+                let mut on_chunk = on_chunk;
+                on_chunk(&response.body)?;
+                response.body.clear();
+                Ok(response)
+            }),
+            None => self.execute(req, None, None, |original, transfer| {
+                let decompress = req.wants_decompression();
+                let mut response = Response::stream_reqwest(original, None, decompress, on_chunk)?;
+                transfer.apply(&mut response);
+                Ok(response)
+            }),
+        };
+
+        self.remember(req, &outcome);
+        outcome
+    }
+
+    /// Download the response body straight to a file, calling `progress`
+    /// with the number of bytes received so far and the total size (if
+    /// known). Returning an error from `progress` stops the download.
+    ///
+    /// If the file already exists, we ask the server to only send the rest of
+    /// it using a `Range` header. Servers which don't support ranges send the
+    /// whole body, in which case the file is overwritten.
+    ///
+    /// Returns the size of the downloaded file.
+    pub fn download_to_file<P, F>(&self, req: &Request, path: P, mut progress: F) -> Result<u64>
+    where
+        P: AsRef<Path>,
+        F: FnMut(u64, Option<u64>) -> Result<()>,
+    {
+        let path = path.as_ref();
+        let existing = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+
+        if self.mock.is_some() {
+            bail!("Downloading to a file isn't supported by the mock transport");
+        }
+
+        // Partial downloads can only be stitched together if we save the body
+        // exactly as it was sent
+        let mut req = req.clone();
+        req.decompress = false;
+        // and we don't want to save an error page
+        req.options.strict = Some(true);
+        if existing > 0 {
+            debug!("Resuming the download of {} from byte {}", path.display(), existing);
+            req.headers.set_raw("Range", format!("bytes={}-", existing));
+        }
+
+        let outcome = self.execute(&req, None, None, |original, _| {
+            let resuming = original.status() == StatusCode::PartialContent;
+            let offset = if resuming { existing } else { 0 };
+            let total = original
+                .headers()
+                .get::<ContentLength>()
+                .map(|length| length.0 + offset);
+
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(resuming)
+                .truncate(!resuming)
+                .open(path)
+                .chain_err(|| format!("Unable to open {}", path.display()))?;
+
+            let mut received = offset;
+            progress(received, total)?;
+
+            Response::stream_reqwest(original, None, false, |chunk| {
+                file.write_all(chunk)
+                    .chain_err(|| format!("Unable to write to {}", path.display()))?;
+                received += chunk.len() as u64;
+                progress(received, total)
+            })?;
+
+            Ok(received)
+        });
+
+        match outcome {
+            // We asked for bytes past the end of the file, so it must
+            // already be complete
+            Err(ref e) if existing > 0 && status_of(e) == Some(StatusCode::RangeNotSatisfiable) => {
+                Ok(existing)
+            }
+            other => other,
+        }
+    }
+
+    fn dispatch(
+        &self,
+        req: &Request,
+        token: Option<&CancellationToken>,
+        plugins: Option<&PluginManager>,
+    ) -> Result<Response> {
+        let outcome = match self.mock {
+            Some(ref mock) => self.send_mocked(mock, req, token),
+            None => self.execute(req, token, plugins, |original, transfer| {
+                let decompress = req.wants_decompression();
+                let mut response = Response::from_reqwest(original, token, decompress)?;
+                transfer.apply(&mut response);
+                Ok(response)
+            }),
+        };
+
+        self.remember(req, &outcome);
+        outcome
+    }
+
+    /// Send a request on a background thread, invoking the callback with the
+    /// result once it completes.
+    pub fn send_async<F>(&self, req: Request, callback: F) -> Result<()>
+    where
+        F: FnOnce(Result<Response>) + Send + 'static,
+    {
+        let client = self.clone();
+        let workers = WORKERS
+            .lock()
+            .map_err(|_| Error::from("The background thread pool is poisoned"))?;
+
+        workers.execute(move || {
+            let outcome = client.send(&req);
+            callback(outcome);
+        });
+
+        Ok(())
+    }
+
+    /// Send a request on a background thread like [`send_async()`], firing
+    /// the plugins' hooks along the way. The `pre_send_async()` and
+    /// `post_receive_async()` hooks are used instead of their blocking
+    /// versions.
+    ///
+    /// Several requests may be using the plugins at once, so their scratch
+    /// space isn't cleared afterwards. Use `PluginContext::clear_scratch()`
+    /// once there are no requests in flight.
+    ///
+    /// [`send_async()`]: #method.send_async
+    pub fn send_async_with_plugins<P, F>(&self, req: Request, plugins: P, callback: F) -> Result<()>
+    where
+        P: Deref<Target = PluginManager> + Send + 'static,
+        F: FnOnce(Result<Response>) + Send + 'static,
+    {
+        let client = self.clone();
+        let workers = WORKERS
+            .lock()
+            .map_err(|_| Error::from("The background thread pool is poisoned"))?;
+
+        workers.execute(move || {
+            let outcome = client.send_through_plugins_async(req, &plugins);
+            callback(outcome);
+        });
+
+        Ok(())
+    }
+
+    /// The asynchronous version of `send_through_plugins()`, which blocks
+    /// until the plugins' futures have finished.
+    ///
+    /// A hook which fails takes the request (or response) with it, so the
+    /// copy made beforehand is used instead, just like the blocking hooks
+    /// carry on after a failure.
+    fn send_through_plugins_async(
+        &self,
+        req: Request,
+        plugins: &PluginManager,
+    ) -> Result<Response> {
+        let (req, result) = match plugins.pre_send_async(req.clone()).wait() {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                warn!("{}", e);
+                (req, HookResult::Continue)
+            }
+        };
+
+        let response = match result {
+            HookResult::Continue => match self.dispatch(&req, None, Some(plugins)) {
+                Ok(response) => response,
+                Err(e) => {
+                    plugins.error(&e, &req);
+                    return Err(e);
+                }
+            },
+            HookResult::Abort(reason) => bail!(ErrorKind::Aborted(reason)),
+            HookResult::Respond(response) => response,
+        };
+
+        match plugins.post_receive_async(&req, response.clone()).wait() {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                warn!("{}", e);
+                Ok(response)
+            }
+        }
+    }
+
+    /// Send the request (retrying if necessary) and hand the response to
+    /// `receive` once its headers have arrived.
+    ///
+    /// Retries only happen if something goes wrong before the response
+    /// headers arrive, and only for idempotent methods unless the options say
+    /// otherwise. Errors encountered while receiving the body are returned
+    /// immediately.
+    fn execute<T, F>(
+        &self,
+        req: &Request,
+        token: Option<&CancellationToken>,
+        plugins: Option<&PluginManager>,
+        receive: F,
+    ) -> Result<T>
+    where
+        F: FnOnce(reqwest::Response, Transfer) -> Result<T>,
+    {
+        info!("Sending a {} request to {}", req.method, req.destination);
+        if req.body.is_some() && !req.method_allows_body() {
+            warn!("A {} request usually shouldn't have a body", req.method);
+        }
+        if log_enabled!(::log::LogLevel::Debug) {
+            debug!("Sending {} Headers", req.headers.len());
+            for header in req.headers.iter() {
+                if header.name().eq_ignore_ascii_case("Authorization") {
+                    debug!("\t{}: <redacted>", header.name());
+                } else {
+                    debug!("\t{}: {}", header.name(), header.value_string());
+                }
+            }
+            for cookie in req.cookies.iter() {
+                debug!("\t{} = {}", cookie.name(), cookie.value());
+            }
+        }
+
+        let options = req.options.or(self.options);
+
+        // Requests with their own timeouts need a transport configured with
+        // those timeouts
+        let temporary;
+        let client = if options.timeout == self.options.timeout {
+            &self.inner
+        } else {
+            temporary = build_client(&options, &self.transport)?;
+            &temporary
+        };
+
+        let redirect_policy = req.redirect_policy
+            .as_ref()
+            .unwrap_or(&self.redirect_policy);
+        let mut attempt = 0;
+        // Plugins may change the request before it is retried
+        let mut current = Cow::Borrowed(req);
+
+        loop {
+            let permit = self.limiter.acquire(token)?;
+            let retries = options.retries_for(&current.method);
+
+            let error = {
+                let req = self.authenticate(&current)?;
+
+                match transmit(client, &self.transport, &req, redirect_policy, plugins) {
+                    Ok((response, transfer)) => {
+                        let status = response.status();
+
+                        if !status.is_server_error() || attempt >= retries {
+                            if options.strict() {
+                                check_status(status)?;
+                            }
+                            return receive(response, transfer);
+                        }
+
+                        Error::from(format!("The server responded with {}", status))
+                    }
+                    Err(e) => {
+                        if attempt >= retries || !is_retryable(&e) {
+                            return Err(e);
+                        }
+                        e
+                    }
+                }
+            };
+
+            // Don't count as being in flight while we back off
+            drop(permit);
+
+            let delay = options.backoff(attempt);
+            warn!("Attempt {} failed ({}), retrying in {:?}", attempt + 1, error, delay);
+            thread::sleep(delay);
+
+            if let Some(token) = token {
+                token.check()?;
+            }
+            attempt += 1;
+
+            if let Some(plugins) = plugins {
+                plugins.retry(attempt, current.to_mut());
+            }
+        }
+    }
+}
+
+impl HttpClient {
+    /// Add the request to the history, and the recorder if we have one.
+    fn remember(&self, req: &Request, outcome: &Result<Response>) {
+        history::record(req, outcome);
+
+        if let Some(ref recorder) = self.recorder {
+            recorder.record(req, outcome);
+        }
+    }
+
+    /// Answer the request using the mock transport instead of the network.
+    ///
+    /// Requests still go through the rate limiter and authenticator so they
+    /// look the same as they would on the wire, but they are never retried or
+    /// redirected.
+    fn send_mocked(
+        &self,
+        mock: &MockTransport,
+        req: &Request,
+        token: Option<&CancellationToken>,
+    ) -> Result<Response> {
+        info!("Sending a {} request to {} (mocked)", req.method, req.destination);
+
+        let _permit = self.limiter.acquire(token)?;
+        let req = self.authenticate(req)?;
+        let response = mock.respond(&req)?;
+
+        if req.options.or(self.options).strict() {
+            check_status(response.status)?;
+        }
+
+        Ok(response)
+    }
+
+    /// Give the request's authenticator (or ours) a chance to add
+    /// credentials.
+    fn authenticate<'a>(&self, req: &'a Request) -> Result<Cow<'a, Request>> {
+        let authenticator = match req.authenticator.as_ref().or(self.authenticator.as_ref()) {
+            Some(a) => a,
+            None => return Ok(Cow::Borrowed(req)),
+        };
+
+        debug!("Adding {} credentials", authenticator.scheme());
+        let mut authenticated = req.clone();
+        authenticator
+            .authenticate(&mut authenticated)
+            .chain_err(|| "Unable to authenticate the request")?;
+
+        Ok(Cow::Owned(authenticated))
+    }
+}
+
+impl Debug for HttpClient {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("HttpClient")
+            .field("options", &self.options)
+            .field("transport", &self.transport)
+            .field("redirect_policy", &self.redirect_policy)
+            .field("limiter", &self.limiter)
+            .field("authenticator", &self.authenticator)
+            .field("recorder", &self.recorder)
+            .field("mock", &self.mock)
+            .finish()
+    }
+}
+
+fn build_client(options: &RequestOptions, transport: &TransportConfig) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+
+    if let Some(timeout) = options.timeout {
+        builder.timeout(timeout);
+    }
+    // We follow redirects ourselves so we can record where we went, and
+    // decompress bodies ourselves so plugins can ask for the raw bytes
+    builder.redirect(reqwest::RedirectPolicy::none());
+    builder.gzip(false);
+    transport.apply(&mut builder)?;
+
+    builder
+        .build()
+        .chain_err(|| "The native TLS backend couldn't be initialized")
+}
+
+/// Send the request and follow any redirects, returning the response as soon
+/// as its headers arrive.
+fn transmit(
+    client: &reqwest::Client,
+    transport: &TransportConfig,
+    req: &Request,
+    redirect_policy: &RedirectPolicy,
+    plugins: Option<&PluginManager>,
+) -> Result<(reqwest::Response, Transfer)> {
+    let started = Instant::now();
+    let mut redirects = Vec::new();
+    let mut current = req.clone();
+
+    loop {
+        let response = client
+            .execute(transport.pin(&current)?.to_reqwest())
+            .chain_err(|| "The request failed")?;
+
+        let status = response.status();
+        let location = response.headers().get::<Location>();
+        let next = match redirect::target(&current.destination, status, location) {
+            Some(ref url) if redirect_policy.should_follow(url, &redirects)? => url.clone(),
+            other => {
+                if let Some(url) = other {
+                    debug!("Not following the redirect to {}", url);
+                }
+
+                let transfer = Transfer {
+                    redirects,
+                    started,
+                    first_byte: started.elapsed(),
+                };
+                return Ok((response, transfer));
+            }
+        };
+
+        debug!("Following a {} redirect to {}", status, next);
+        current = redirect::follow(&current, status, next.clone());
+        if let Some(plugins) = plugins {
+            plugins.redirect(&next, &mut current);
+        }
+        redirects.push(next);
+    }
+}
+
+/// Get the status code of the response which caused an error, if there was
+/// one.
+pub(crate) fn status_of(e: &Error) -> Option<StatusCode> {
+    match *e.kind() {
+        ErrorKind::HttpStatus(status) => Some(status),
+        ErrorKind::Reqwest(ref inner) => inner.status(),
+        _ => None,
+    }
+}
+
+/// Turn a `4xx` or `5xx` status code into an error, for when the options say
+/// to be strict.
+fn check_status(status: StatusCode) -> Result<()> {
+    if status.is_client_error() || status.is_server_error() {
+        Err(ErrorKind::HttpStatus(status).into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Could trying again possibly give a different result?
+///
+/// Only problems with the connection itself are worth retrying. Anything else
+/// (an invalid URL, a TLS failure, too many redirects, ...) would just fail
+/// the same way again.
+fn is_retryable(e: &Error) -> bool {
+    match ErrorCategory::of(e) {
+        ErrorCategory::Network | ErrorCategory::Timeout => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use reqwest::Method;
+
+    #[test]
+    fn only_connection_problems_are_retried() {
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        assert!(is_retryable(&Error::with_chain(reset, "The request failed")));
+
+        let timed_out = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        assert!(is_retryable(&Error::with_chain(timed_out, "The request failed")));
+
+        let gave_up = Error::from("Gave up after following 10 redirects");
+        assert!(!is_retryable(&gave_up));
+        let invalid_url = Error::from(ErrorKind::InvalidUrl(String::from("http://")));
+        assert!(!is_retryable(&invalid_url));
+        let cancelled = Error::from(ErrorKind::Cancelled(String::from("testing")));
+        assert!(!is_retryable(&cancelled.chain_err(|| "The request failed")));
+    }
+
+    #[test]
+    fn mocked_failures_carry_their_status_in_strict_mode() {
+        let url = Url::parse("http://example.com/missing").unwrap();
+        let mock = MockTransport::new();
+        mock.expect(Method::Get, url.clone(), StatusCode::NotFound, "Not Found");
+        let mut client = HttpClient::new().unwrap();
+        client.set_mock(Some(mock));
+
+        let mut req = Request::new(url, Method::Get);
+        assert_eq!(client.send(&req).unwrap().status, StatusCode::NotFound);
+
+        req.options.strict = Some(true);
+        let err = client.send(&req).unwrap_err();
+
+        assert_eq!(status_of(&err), Some(StatusCode::NotFound));
+        assert_eq!(ErrorCategory::of(&err), ErrorCategory::HttpStatus);
+    }
+}
@@ -0,0 +1,198 @@
+//! State which is shared between plugins.
+
+use std::collections::HashMap;
+use std::slice;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::{Mutex, RwLock};
+use log::LogLevel;
+
+
+/// Passed to every plugin hook so plugins can cooperate without resorting to
+/// global variables. For example, an authentication plugin can store a token
+/// in `pre_send()` which a request signing plugin then reads.
+///
+/// Each `PluginManager` has its own context, which lives for as long as the
+/// manager does.
+pub struct PluginContext {
+    values: RwLock<HashMap<String, String>>,
+    logger: Logger,
+    scratch: Scratch,
+}
+
+impl PluginContext {
+    pub fn new() -> PluginContext {
+        PluginContext {
+            values: RwLock::new(HashMap::new()),
+            logger: Logger::host(),
+            scratch: Scratch::default(),
+        }
+    }
+
+    /// Get a value stored by another plugin (or an earlier hook).
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.values
+            .read()
+            .ok()
+            .and_then(|values| values.get(key).cloned())
+    }
+
+    /// Store a value, returning the old one (if any).
+    pub fn set<K: Into<String>, V: Into<String>>(&self, key: K, value: V) -> Option<String> {
+        match self.values.write() {
+            Ok(mut values) => values.insert(key.into(), value.into()),
+            Err(_) => None,
+        }
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        match self.values.write() {
+            Ok(mut values) => values.remove(key),
+            Err(_) => None,
+        }
+    }
+
+    /// A handle to the host's logger.
+    pub fn logger(&self) -> &Logger {
+        &self.logger
+    }
+
+    /// Memory which is only needed until the current request is finished.
+    pub fn scratch(&self) -> &Scratch {
+        &self.scratch
+    }
+
+    /// Free everything allocated from the scratch space. This is done
+    /// automatically after each request sent with
+    /// `HttpClient::send_with_plugins()`.
+    pub fn clear_scratch(&mut self) {
+        self.scratch.clear();
+    }
+}
+
+impl Default for PluginContext {
+    fn default() -> PluginContext {
+        PluginContext::new()
+    }
+}
+
+impl Debug for PluginContext {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let keys: Vec<String> = self.values
+            .read()
+            .map(|values| values.keys().cloned().collect())
+            .unwrap_or_default();
+
+        f.debug_struct("PluginContext")
+            .field("keys", &keys)
+            .field("scratch", &self.scratch)
+            .finish()
+    }
+}
+
+/// Writes messages to the host's log.
+///
+/// A plugin gets its own copy of the `log` crate, so unless it sets up a
+/// logger of its own, anything it logs with `info!()` and friends is thrown
+/// away. The `Logger` holds a pointer to a function in the host, so its
+/// messages end up wherever the host's log messages go.
+#[derive(Copy, Clone)]
+pub struct Logger {
+    log: fn(LogLevel, &str),
+}
+
+impl Logger {
+    fn host() -> Logger {
+        fn log(level: LogLevel, message: &str) {
+            log!(target: "plugin", level, "{}", message);
+        }
+
+        Logger { log }
+    }
+
+    pub fn log(&self, level: LogLevel, message: &str) {
+        (self.log)(level, message)
+    }
+
+    pub fn error(&self, message: &str) {
+        self.log(LogLevel::Error, message)
+    }
+
+    pub fn warn(&self, message: &str) {
+        self.log(LogLevel::Warn, message)
+    }
+
+    pub fn info(&self, message: &str) {
+        self.log(LogLevel::Info, message)
+    }
+
+    pub fn debug(&self, message: &str) {
+        self.log(LogLevel::Debug, message)
+    }
+
+    pub fn trace(&self, message: &str) {
+        self.log(LogLevel::Trace, message)
+    }
+}
+
+impl Debug for Logger {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Logger").finish()
+    }
+}
+
+/// An arena for temporary buffers, so plugins don't need to keep their own
+/// per-request allocations around.
+#[derive(Default)]
+pub struct Scratch {
+    buffers: Mutex<Vec<Box<[u8]>>>,
+}
+
+impl Scratch {
+    /// Allocate a zeroed buffer which stays valid until the scratch space is
+    /// cleared.
+    #[cfg_attr(feature = "cargo-clippy", allow(mut_from_ref))]
+    pub fn alloc(&self, len: usize) -> &mut [u8] {
+        let mut buffers = self.buffers
+            .lock()
+            .expect("The scratch space's lock is poisoned");
+
+        let mut buffer = vec![0; len].into_boxed_slice();
+        let ptr = buffer.as_mut_ptr();
+        buffers.push(buffer);
+
+        // Each buffer is only handed out once, and its contents don't move
+        // when the Vec grows. They're only freed by clear() or when the
+        // arena is dropped, both of which need unique access.
+        unsafe { slice::from_raw_parts_mut(ptr, len) }
+    }
+
+    /// Copy some bytes into the scratch space.
+    pub fn copy(&self, data: &[u8]) -> &[u8] {
+        let buffer = self.alloc(data.len());
+        buffer.copy_from_slice(data);
+        buffer
+    }
+
+    /// The total number of bytes allocated.
+    pub fn allocated(&self) -> usize {
+        self.buffers
+            .lock()
+            .map(|buffers| buffers.iter().map(|b| b.len()).sum())
+            .unwrap_or(0)
+    }
+
+    fn clear(&mut self) {
+        match self.buffers.get_mut() {
+            Ok(buffers) => buffers.clear(),
+            Err(poisoned) => poisoned.into_inner().clear(),
+        }
+    }
+}
+
+impl Debug for Scratch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Scratch")
+            .field("allocated", &self.allocated())
+            .finish()
+    }
+}
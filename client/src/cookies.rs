@@ -0,0 +1,176 @@
+//! Saving and loading cookies so a session can outlive the program.
+//!
+//! Cookie jars are stored as plain text, with one cookie per line written
+//! the same way it would appear in a `Set-Cookie` header.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::ptr;
+use cookie::{Cookie, CookieJar};
+use libc::{c_char, c_int};
+use reqwest::header::{Headers, SetCookie};
+
+use errors::*;
+use ffi::{c_str_to_str, catch_panic, update_last_error};
+use {Request, Response};
+
+
+/// Collect the cookies set by a response.
+pub(crate) fn from_headers(headers: &Headers) -> CookieJar {
+    let mut jar = CookieJar::new();
+
+    if let Some(&SetCookie(ref values)) = headers.get::<SetCookie>() {
+        for value in values {
+            match Cookie::parse(value.clone()) {
+                Ok(cookie) => jar.add(cookie),
+                Err(e) => warn!("Ignoring an invalid cookie ({}), {:?}", e, value),
+            }
+        }
+    }
+
+    jar
+}
+
+/// Save every cookie in the jar to a file.
+pub fn save_cookies<P: AsRef<Path>>(jar: &CookieJar, path: P) -> Result<()> {
+    let path = path.as_ref();
+    debug!("Saving cookies to {}", path.display());
+
+    let mut f = File::create(path).chain_err(|| "Unable to create the cookie file")?;
+
+    for cookie in jar.iter() {
+        writeln!(f, "{}", cookie).chain_err(|| "Unable to save the cookie jar")?;
+    }
+
+    Ok(())
+}
+
+/// Load a cookie jar previously written by [`save_cookies()`].
+///
+/// [`save_cookies()`]: fn.save_cookies.html
+pub fn load_cookies<P: AsRef<Path>>(path: P) -> Result<CookieJar> {
+    let path = path.as_ref();
+    debug!("Loading cookies from {}", path.display());
+
+    let f = File::open(path).chain_err(|| "Unable to open the cookie file")?;
+    let mut jar = CookieJar::new();
+
+    for line in BufReader::new(f).lines() {
+        let line = line.chain_err(|| "Unable to read the cookie file")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let cookie = Cookie::parse(line.clone())
+            .chain_err(|| format!("Invalid cookie, {:?}", line))?;
+        jar.add_original(cookie);
+    }
+
+    Ok(jar)
+}
+
+/// Create an empty cookie jar.
+#[no_mangle]
+pub extern "C" fn cookiejar_new() -> *mut CookieJar {
+    catch_panic(ptr::null_mut(), || {
+        Box::into_raw(Box::new(CookieJar::new()))
+    })
+}
+
+/// Destroy a cookie jar once you are done with it.
+#[no_mangle]
+pub unsafe extern "C" fn cookiejar_destroy(jar: *mut CookieJar) {
+    catch_panic((), || {
+        if !jar.is_null() {
+            drop(Box::from_raw(jar));
+        }
+    })
+}
+
+/// Load a cookie jar from disk, returning a null pointer if it couldn't be
+/// loaded.
+#[no_mangle]
+pub unsafe extern "C" fn cookiejar_load(path: *const c_char) -> *mut CookieJar {
+    catch_panic(ptr::null_mut(), || {
+        let path = match c_str_to_str(path, "cookie file path") {
+            Some(p) => p,
+            None => return ptr::null_mut(),
+        };
+
+        match load_cookies(path) {
+            Ok(jar) => Box::into_raw(Box::new(jar)),
+            Err(e) => {
+                update_last_error(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Save a cookie jar to disk.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn cookiejar_save(jar: *const CookieJar, path: *const c_char) -> c_int {
+    catch_panic(-1, || {
+        if jar.is_null() {
+            update_last_error(Error::from("Null pointer passed to cookiejar_save()"));
+            return -1;
+        }
+
+        let path = match c_str_to_str(path, "cookie file path") {
+            Some(p) => p,
+            None => return -1,
+        };
+
+        match save_cookies(&*jar, path) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Remember any cookies set by a response, replacing existing cookies with
+/// the same name.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn cookiejar_update(jar: *mut CookieJar, res: *const Response) -> c_int {
+    catch_panic(-1, || {
+        if jar.is_null() || res.is_null() {
+            update_last_error(Error::from("Null pointer passed to cookiejar_update()"));
+            return -1;
+        }
+
+        let jar = &mut *jar;
+        for cookie in (&*res).cookies.iter() {
+            jar.add(cookie.clone());
+        }
+
+        0
+    })
+}
+
+/// Attach every cookie in the jar to a request.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn request_use_cookiejar(req: *mut Request, jar: *const CookieJar) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() || jar.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_use_cookiejar()"));
+            return -1;
+        }
+
+        let req = &mut *req;
+        for cookie in (&*jar).iter() {
+            req.cookies.add(cookie.clone());
+        }
+
+        0
+    })
+}
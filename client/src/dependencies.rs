@@ -0,0 +1,453 @@
+//! Plugins which depend on other plugins.
+//!
+//! When several plugins are loaded at once (e.g. with
+//! `PluginManager::load_dir()`) they're started in an order where each
+//! plugin's dependencies come before it. A plugin whose dependencies are
+//! missing, the wrong version, or part of a cycle isn't loaded at all, and
+//! a plugin can't be unloaded while other plugins still depend on it.
+
+use std::fmt::{self, Display, Formatter};
+use semver::{Version, VersionReq};
+
+use abi::PluginHandle;
+use errors::*;
+
+
+/// Another plugin which must be loaded before this one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Dependency {
+    /// The other plugin's name.
+    pub name: String,
+    /// A semver requirement the other plugin's version must satisfy (e.g.
+    /// `">=1.2"` or `"^0.3"`), or `"*"` for any version.
+    pub version: String,
+}
+
+impl Dependency {
+    pub fn new<N: Into<String>, V: Into<String>>(name: N, version: V) -> Dependency {
+        Dependency {
+            name: name.into(),
+            version: version.into(),
+        }
+    }
+
+    /// Depend on any version of a plugin.
+    pub fn any<N: Into<String>>(name: N) -> Dependency {
+        Dependency::new(name, "*")
+    }
+
+    /// Can `plugin` be used to satisfy this dependency?
+    fn is_satisfied_by(&self, plugin: &PluginHandle) -> Result<bool> {
+        let requirement = VersionReq::parse(&self.version).chain_err(|| {
+            format!("\"{}\" isn't a valid version requirement for {}", self.version, self.name)
+        })?;
+
+        Ok(match Version::parse(plugin.version()) {
+            Ok(version) => requirement.matches(&version),
+            // Plugins without a proper version only satisfy "*"
+            Err(_) => requirement == VersionReq::any(),
+        })
+    }
+}
+
+impl Display for Dependency {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.version)
+    }
+}
+
+/// Make sure everything `plugin` depends on has been loaded.
+pub(crate) fn check_loaded(plugin: &PluginHandle, loaded: &[&PluginHandle]) -> Result<()> {
+    for dependency in plugin.dependencies() {
+        let found = loaded.iter().find(|p| p.name() == dependency.name);
+
+        let satisfied = match found {
+            Some(other) => dependency.is_satisfied_by(other)?,
+            None => false,
+        };
+
+        if !satisfied {
+            let chain = format!("{} -> {}{}", plugin.name(), dependency, found_version(found));
+            bail!(ErrorKind::UnsatisfiedDependency(chain));
+        }
+    }
+
+    Ok(())
+}
+
+/// The plugins in `loaded` which would be left with an unsatisfied
+/// dependency if the plugin called `name` was unloaded.
+pub(crate) fn dependents<'a>(name: &str, loaded: &[&'a PluginHandle]) -> Vec<&'a PluginHandle> {
+    let remaining: Vec<&PluginHandle> = loaded
+        .iter()
+        .cloned()
+        .filter(|p| p.name() != name)
+        .collect();
+
+    remaining
+        .iter()
+        .cloned()
+        .filter(|p| check_loaded(p, loaded).is_ok() && check_loaded(p, &remaining).is_err())
+        .collect()
+}
+
+/// Work out which order the `pending` plugins should be started in, given
+/// the plugins which are already `loaded`.
+///
+/// Returns the indices of the plugins which can be started, in order, and
+/// the reason each of the others can't be.
+pub(crate) fn load_order(
+    loaded: &[&PluginHandle],
+    pending: &[&PluginHandle],
+) -> (Vec<usize>, Vec<(usize, Error)>) {
+    let mut resolver = Resolver {
+        loaded,
+        pending,
+        states: vec![State::Unvisited; pending.len()],
+        order: Vec::new(),
+    };
+
+    let mut failed = Vec::new();
+
+    for i in 0..pending.len() {
+        if let Err(reason) = resolver.visit(i) {
+            let chain = format!("{} -> {}", pending[i].name(), reason);
+            failed.push((i, ErrorKind::UnsatisfiedDependency(chain).into()));
+        }
+    }
+
+    (resolver.order, failed)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum State {
+    Unvisited,
+    /// We're in the middle of checking this plugin's dependencies, so coming
+    /// across it again means there's a cycle.
+    Visiting,
+    Done,
+    /// The rest of the chain of dependencies which couldn't be satisfied.
+    Failed(String),
+}
+
+/// A depth-first search through the dependency graph.
+struct Resolver<'a> {
+    loaded: &'a [&'a PluginHandle],
+    pending: &'a [&'a PluginHandle],
+    states: Vec<State>,
+    order: Vec<usize>,
+}
+
+impl<'a> Resolver<'a> {
+    fn visit(&mut self, i: usize) -> ::std::result::Result<(), String> {
+        match self.states[i] {
+            State::Done => return Ok(()),
+            State::Failed(ref reason) => return Err(reason.clone()),
+            State::Visiting | State::Unvisited => {}
+        }
+
+        self.states[i] = State::Visiting;
+        let outcome = self.visit_dependencies(i);
+
+        self.states[i] = match outcome {
+            Ok(_) => {
+                self.order.push(i);
+                State::Done
+            }
+            Err(ref reason) => State::Failed(reason.clone()),
+        };
+
+        outcome
+    }
+
+    fn visit_dependencies(&mut self, i: usize) -> ::std::result::Result<(), String> {
+        for dependency in self.pending[i].dependencies() {
+            if let Some(other) = self.loaded.iter().find(|p| p.name() == dependency.name) {
+                match dependency.is_satisfied_by(other) {
+                    Ok(true) => continue,
+                    Ok(false) => {
+                        let found = found_version(Some(other));
+                        return Err(format!("{}{}", dependency, found));
+                    }
+                    Err(e) => return Err(format!("{} ({})", dependency, e)),
+                }
+            }
+
+            let j = match self.pending.iter().position(|p| p.name() == dependency.name) {
+                Some(j) => j,
+                None => return Err(format!("{}{}", dependency, found_version(None))),
+            };
+
+            match dependency.is_satisfied_by(self.pending[j]) {
+                Ok(true) => {}
+                Ok(false) => {
+                    let found = found_version(Some(&self.pending[j]));
+                    return Err(format!("{}{}", dependency, found));
+                }
+                Err(e) => return Err(format!("{} ({})", dependency, e)),
+            }
+
+            if self.states[j] == State::Visiting {
+                return Err(format!("{} (a dependency cycle)", dependency.name));
+            }
+
+            self.visit(j)
+                .map_err(|rest| format!("{} -> {}", dependency.name, rest))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn found_version(found: Option<&&PluginHandle>) -> String {
+    match found {
+        Some(plugin) => format!(" (found version {})", plugin.version()),
+        None => String::from(" (not loaded)"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use abi::PluginVTable;
+    use context::PluginContext;
+    use plugins::{HookResult, Plugin, PluginManager};
+    use reqwest::{Method, Url};
+    use Request;
+
+    struct Fake {
+        name: &'static str,
+        version: &'static str,
+        dependencies: Vec<Dependency>,
+    }
+
+    impl Plugin for Fake {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn version(&self) -> &str {
+            self.version
+        }
+
+        fn dependencies(&self) -> Vec<Dependency> {
+            self.dependencies.clone()
+        }
+    }
+
+    fn fake(name: &'static str, version: &'static str, dependencies: Vec<Dependency>) -> Fake {
+        Fake {
+            name,
+            version,
+            dependencies,
+        }
+    }
+
+    fn plugin(
+        name: &'static str,
+        version: &'static str,
+        dependencies: Vec<Dependency>,
+    ) -> PluginHandle {
+        PluginHandle::new(PluginVTable::new(fake(name, version, dependencies)))
+    }
+
+    fn failures(failed: &[(usize, Error)]) -> Vec<(usize, String)> {
+        failed.iter().map(|&(i, ref e)| (i, e.to_string())).collect()
+    }
+
+    #[test]
+    fn dependencies_are_started_first() {
+        let app = plugin("app", "1.0.0", vec![Dependency::new("auth", "^1.2")]);
+        let auth = plugin("auth", "1.3.0", vec![Dependency::any("log")]);
+        let log = plugin("log", "0.1.0", Vec::new());
+
+        let (order, failed) = load_order(&[], &[&app, &auth, &log]);
+
+        assert_eq!(order, vec![2, 1, 0]);
+        assert!(failed.is_empty());
+    }
+
+    #[test]
+    fn already_loaded_plugins_satisfy_dependencies() {
+        let auth = plugin("auth", "1.3.0", Vec::new());
+        let app = plugin("app", "1.0.0", vec![Dependency::new("auth", ">=1.0")]);
+
+        let (order, failed) = load_order(&[&auth], &[&app]);
+
+        assert_eq!(order, vec![0]);
+        assert!(failed.is_empty());
+        assert!(check_loaded(&app, &[&auth]).is_ok());
+    }
+
+    #[test]
+    fn missing_dependencies_are_reported() {
+        let app = plugin("app", "1.0.0", vec![Dependency::any("auth")]);
+
+        let (order, failed) = load_order(&[], &[&app]);
+
+        assert!(order.is_empty());
+        assert_eq!(
+            failures(&failed),
+            vec![(0, String::from("Unsatisfied plugin dependency, app -> auth * (not loaded)"))]
+        );
+        assert!(check_loaded(&app, &[]).is_err());
+    }
+
+    #[test]
+    fn a_missing_dependency_fails_the_whole_chain() {
+        let app = plugin("app", "1.0.0", vec![Dependency::any("auth")]);
+        let auth = plugin("auth", "1.0.0", vec![Dependency::any("log")]);
+
+        let (order, failed) = load_order(&[], &[&app, &auth]);
+
+        assert!(order.is_empty());
+        let expected = vec![
+            (0, "Unsatisfied plugin dependency, app -> auth -> log * (not loaded)"),
+            (1, "Unsatisfied plugin dependency, auth -> log * (not loaded)"),
+        ];
+        let expected: Vec<_> = expected.into_iter().map(|(i, e)| (i, e.to_string())).collect();
+        assert_eq!(failures(&failed), expected);
+    }
+
+    #[test]
+    fn version_mismatches_are_reported() {
+        let app = plugin("app", "1.0.0", vec![Dependency::new("auth", "^2")]);
+        let auth = plugin("auth", "1.3.0", Vec::new());
+
+        let (order, failed) = load_order(&[], &[&app, &auth]);
+
+        assert_eq!(order, vec![1]);
+        let expected = "Unsatisfied plugin dependency, app -> auth ^2 (found version 1.3.0)";
+        assert_eq!(failures(&failed), vec![(0, String::from(expected))]);
+        assert!(check_loaded(&app, &[&auth]).is_err());
+    }
+
+    #[test]
+    fn plugins_without_a_semver_version_only_satisfy_any() {
+        let auth = plugin("auth", "unknown", Vec::new());
+        let picky = plugin("picky", "1.0.0", vec![Dependency::new("auth", ">=1.0")]);
+        let relaxed = plugin("relaxed", "1.0.0", vec![Dependency::any("auth")]);
+
+        assert!(check_loaded(&picky, &[&auth]).is_err());
+        assert!(check_loaded(&relaxed, &[&auth]).is_ok());
+    }
+
+    #[test]
+    fn invalid_version_requirements_are_reported() {
+        let app = plugin("app", "1.0.0", vec![Dependency::new("auth", "not a version")]);
+        let auth = plugin("auth", "1.0.0", Vec::new());
+
+        let (order, failed) = load_order(&[], &[&app, &auth]);
+
+        assert_eq!(order, vec![1]);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, 0);
+    }
+
+    #[test]
+    fn cycles_are_reported() {
+        let a = plugin("a", "1.0.0", vec![Dependency::any("b")]);
+        let b = plugin("b", "1.0.0", vec![Dependency::any("c")]);
+        let c = plugin("c", "1.0.0", vec![Dependency::any("a")]);
+        let unrelated = plugin("unrelated", "1.0.0", Vec::new());
+
+        let (order, failed) = load_order(&[], &[&a, &b, &c, &unrelated]);
+
+        assert_eq!(order, vec![3]);
+        let failed: Vec<usize> = failed.iter().map(|&(i, _)| i).collect();
+        assert_eq!(failed, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn a_plugin_which_depends_on_itself_is_a_cycle() {
+        let a = plugin("a", "1.0.0", vec![Dependency::any("a")]);
+
+        let (order, failed) = load_order(&[], &[&a]);
+
+        assert!(order.is_empty());
+        assert!(failed[0].1.to_string().contains("a dependency cycle"));
+    }
+
+    #[test]
+    fn dependents_are_the_plugins_which_would_break() {
+        let auth = plugin("auth", "1.0.0", Vec::new());
+        let app = plugin("app", "1.0.0", vec![Dependency::any("auth")]);
+        let log = plugin("log", "1.0.0", Vec::new());
+        let loaded = [&auth, &app, &log];
+
+        let names: Vec<&str> = dependents("auth", &loaded).iter().map(|p| p.name()).collect();
+        assert_eq!(names, vec!["app"]);
+        assert!(dependents("app", &loaded).is_empty());
+        assert!(dependents("log", &loaded).is_empty());
+    }
+
+    #[test]
+    fn plugins_with_dependents_cant_be_unloaded() {
+        let mut pm = PluginManager::new();
+        pm.register_static(Box::new(fake("auth", "1.0.0", Vec::new())))
+            .unwrap();
+        pm.register_static(Box::new(fake("app", "1.0.0", vec![Dependency::any("auth")])))
+            .unwrap();
+
+        let err = pm.unload_plugin("auth").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unsatisfied plugin dependency, auth is still needed by app"
+        );
+        assert_eq!(pm.plugins().count(), 2);
+
+        pm.unload_plugin("app").unwrap();
+        pm.unload_plugin("auth").unwrap();
+        assert_eq!(pm.plugins().count(), 0);
+    }
+
+    #[test]
+    fn plugins_with_dependents_cant_be_reloaded() {
+        let mut pm = PluginManager::new();
+        pm.register_static(Box::new(fake("auth", "1.0.0", Vec::new())))
+            .unwrap();
+        pm.register_static(Box::new(fake("app", "1.0.0", vec![Dependency::any("auth")])))
+            .unwrap();
+
+        let err = unsafe { pm.reload_plugin("auth").unwrap_err() };
+        assert_eq!(
+            err.to_string(),
+            "Unsatisfied plugin dependency, auth is still needed by app"
+        );
+        assert_eq!(pm.plugins().count(), 2);
+    }
+
+    /// A plugin which panics whenever a request is sent.
+    struct Crashy;
+
+    impl Plugin for Crashy {
+        fn name(&self) -> &str {
+            "crashy"
+        }
+
+        fn pre_send(&self, _ctx: &PluginContext, _request: &mut Request) -> HookResult {
+            panic!("pre_send")
+        }
+    }
+
+    #[test]
+    fn dependents_of_poisoned_plugins_are_unloaded_too() {
+        let mut pm = PluginManager::new();
+        pm.set_auto_unload(true);
+        pm.register_static(Box::new(Crashy)).unwrap();
+        let plugins = vec![
+            fake("middle", "1.0.0", vec![Dependency::any("crashy")]),
+            fake("top", "1.0.0", vec![Dependency::any("middle")]),
+            fake("unrelated", "1.0.0", Vec::new()),
+        ];
+        for plugin in plugins {
+            pm.register_static(Box::new(plugin)).unwrap();
+        }
+
+        let mut request = Request::new(Url::parse("http://localhost/").unwrap(), Method::Get);
+        assert!(pm.pre_send(&mut request).is_err());
+
+        let names: Vec<_> = pm.plugins().map(|p| p.name().to_string()).collect();
+        assert_eq!(names, vec!["unrelated"]);
+    }
+}
@@ -1,5 +1,8 @@
 //! Common error types used in this crate.
 
+use std::io;
+use native_tls;
+
 error_chain!{
     foreign_links {
         Reqwest(::reqwest::Error);
@@ -16,5 +19,160 @@ error_chain!{
                             String::from("Thread Panicked")
                         })
         }
+        Cancelled(reason: String) {
+            description("The operation was cancelled")
+            display("The operation was cancelled ({})", reason)
+        }
+        InvalidUrl(url: String) {
+            description("Invalid URL")
+            display("\"{}\" isn't a valid URL", url)
+        }
+        QuotaExceeded(environment: String, limit: &'static str) {
+            description("Quota exceeded")
+            display("Sending this request would exceed the {} quota for the \"{}\" environment",
+                    limit, environment)
+        }
+        HttpStatus(status: ::reqwest::StatusCode) {
+            description("The server responded with an error status code")
+            display("The server responded with {}", status)
+        }
+        Aborted(reason: String) {
+            description("A plugin aborted the request")
+            display("A plugin aborted the request ({})", reason)
+        }
+        PluginPanicked(plugin: String, hook: &'static str) {
+            description("A plugin panicked")
+            display("The \"{}\" plugin panicked in its {}() hook", plugin, hook)
+        }
+        IncompatiblePlugin(reason: String) {
+            description("The plugin isn't compatible with this version of the client")
+            display("Incompatible plugin, {}", reason)
+        }
+        UnsatisfiedDependency(chain: String) {
+            description("A plugin's dependencies couldn't be satisfied")
+            display("Unsatisfied plugin dependency, {}", chain)
+        }
+    }
+}
+
+/// A broad category for an error, letting C code decide how to react to an
+/// error without needing to parse its message.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// There is no error.
+    None = 0,
+    /// Something went wrong which doesn't fit into any other category.
+    Unknown = 1,
+    /// The connection couldn't be established or was dropped.
+    Network = 2,
+    /// The TLS handshake failed (e.g. because of an untrusted certificate).
+    Tls = 3,
+    /// The server took too long to respond.
+    Timeout = 4,
+    /// A URL couldn't be parsed.
+    InvalidUrl = 5,
+    /// Something panicked, usually a plugin.
+    Panic = 6,
+    /// The operation was cancelled.
+    Cancelled = 7,
+    /// Sending the request would exceed a quota.
+    QuotaExceeded = 8,
+    /// The server responded with a `4xx` or `5xx` status code.
+    HttpStatus = 9,
+    /// A plugin was built against a different version of the client, or the
+    /// plugins it depends on aren't loaded.
+    IncompatiblePlugin = 10,
+}
+
+impl ErrorCategory {
+    /// Figure out which category an error belongs to.
+    ///
+    /// Errors usually get wrapped with some context on their way out (e.g.
+    /// "Sending request failed."), so this looks through the chain of causes
+    /// for the first one with a more specific category than `Unknown`.
+    pub fn of(err: &Error) -> ErrorCategory {
+        let mut current = err;
+
+        loop {
+            let category = categorize_kind(current.kind());
+            if category != ErrorCategory::Unknown {
+                return category;
+            }
+
+            // Only the boxed cause is 'static, so it's the only part of the
+            // chain we can downcast
+            let cause = match current.1.next_error {
+                Some(ref cause) => cause,
+                None => return ErrorCategory::Unknown,
+            };
+
+            if let Some(inner) = cause.downcast_ref::<Error>() {
+                current = inner;
+            } else if let Some(inner) = cause.downcast_ref::<::reqwest::Error>() {
+                return categorize_reqwest(inner);
+            } else if let Some(inner) = cause.downcast_ref::<io::Error>() {
+                return categorize_io(inner);
+            } else {
+                return ErrorCategory::Unknown;
+            }
+        }
+    }
+}
+
+fn categorize_kind(kind: &ErrorKind) -> ErrorCategory {
+    match *kind {
+        ErrorKind::Panic(_) | ErrorKind::PluginPanicked(..) => ErrorCategory::Panic,
+        ErrorKind::Cancelled(_) | ErrorKind::Aborted(_) => ErrorCategory::Cancelled,
+        ErrorKind::QuotaExceeded(..) => ErrorCategory::QuotaExceeded,
+        ErrorKind::InvalidUrl(_) => ErrorCategory::InvalidUrl,
+        ErrorKind::HttpStatus(_) => ErrorCategory::HttpStatus,
+        ErrorKind::IncompatiblePlugin(_) | ErrorKind::UnsatisfiedDependency(_) => {
+            ErrorCategory::IncompatiblePlugin
+        }
+        ErrorKind::Reqwest(ref inner) => categorize_reqwest(inner),
+        _ => ErrorCategory::Unknown,
     }
 }
+
+fn categorize_reqwest(err: &::reqwest::Error) -> ErrorCategory {
+    if err.status().is_some() {
+        return ErrorCategory::HttpStatus;
+    }
+
+    let inner = match err.get_ref() {
+        Some(inner) => inner,
+        None => return ErrorCategory::Unknown,
+    };
+
+    if inner.downcast_ref::<::reqwest::UrlError>().is_some() {
+        ErrorCategory::InvalidUrl
+    } else if inner.downcast_ref::<native_tls::Error>().is_some() {
+        ErrorCategory::Tls
+    } else if let Some(io_err) = inner.downcast_ref::<io::Error>() {
+        categorize_io(io_err)
+    } else if err.is_http() {
+        ErrorCategory::Network
+    } else {
+        ErrorCategory::Unknown
+    }
+}
+
+fn categorize_io(err: &io::Error) -> ErrorCategory {
+    let is_tls = err.get_ref()
+        .map(|e| e.downcast_ref::<native_tls::Error>().is_some())
+        .unwrap_or(false);
+
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ErrorCategory::Timeout,
+        _ if is_tls => ErrorCategory::Tls,
+        _ => ErrorCategory::Network,
+    }
+}
+
+/// An error and each of its causes on a single line, for sending to another
+/// process or library as a plain message.
+pub(crate) fn describe(e: &Error) -> String {
+    let causes: Vec<String> = e.iter().map(|cause| cause.to_string()).collect();
+    causes.join(": ")
+}
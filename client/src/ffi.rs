@@ -2,19 +2,29 @@
 //! languages.
 
 use std::ffi::CStr;
+use std::panic::{self, AssertUnwindSafe};
 use std::ptr;
 use std::slice;
 use std::error::Error as StdError;
-use std::cell::RefCell;
-use libc::{c_char, c_int, size_t};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+use std::net::IpAddr;
+use std::ops::Deref;
+use cookie::Cookie;
+use libc::{c_char, c_double, c_int, c_uint, c_void, size_t};
 use reqwest::{Method, Url};
 
-use {send_request, PluginManager, Request, Response};
+use {send_request, send_request_metered, CancellationToken, HookResult, HttpClient,
+     LoadReport, PluginInfo, PluginManager, Quota, QuotaTracker, Rate, RedirectPolicy, Request,
+     RequestOptions, Response, Timing, TransportConfig, VersionPreference};
 use errors::*;
+use urls::parse_url;
 
 
 thread_local!{
     static LAST_ERROR: RefCell<Option<Box<StdError>>> = RefCell::new(None);
+    static LAST_ERROR_CATEGORY: Cell<ErrorCategory> = Cell::new(ErrorCategory::None);
 }
 
 /// Update the most recent error, clearing whatever may have been there before.
@@ -31,23 +41,113 @@ pub fn update_last_error<E: StdError + 'static>(err: E) {
         }
     }
 
+    let category = (&err as &Any)
+        .downcast_ref::<Error>()
+        .map(ErrorCategory::of)
+        .unwrap_or(ErrorCategory::Unknown);
+    LAST_ERROR_CATEGORY.with(|prev| prev.set(category));
+
     LAST_ERROR.with(|prev| {
         *prev.borrow_mut() = Some(Box::new(err));
     });
 }
 
+/// Run some code, catching any panics so they don't unwind into the caller
+/// (which is undefined behaviour when the caller is C).
+///
+/// If the code panics the panic is saved as an `ErrorKind::Panic` (so it can
+/// be read with [`last_error_message()`]) and `sentinel` is returned instead.
+///
+/// [`last_error_message()`]: fn.last_error_message.html
+pub(crate) fn catch_panic<T, F>(sentinel: T, func: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    match panic::catch_unwind(AssertUnwindSafe(func)) {
+        Ok(value) => value,
+        Err(payload) => {
+            update_last_error(Error::from_kind(ErrorKind::Panic(payload)));
+            sentinel
+        }
+    }
+}
+
 /// Retrieve the most recent error, clearing it in the process.
 pub fn take_last_error() -> Option<Box<StdError>> {
+    LAST_ERROR_CATEGORY.with(|prev| prev.set(ErrorCategory::None));
     LAST_ERROR.with(|prev| prev.borrow_mut().take())
 }
 
+/// Borrow a C string as a `&str`, updating the last error and returning `None`
+/// if it is null or not valid UTF-8.
+pub(crate) unsafe fn c_str_to_str<'a>(raw: *const c_char, name: &str) -> Option<&'a str> {
+    if raw.is_null() {
+        update_last_error(Error::from(format!("No {} provided", name)));
+        return None;
+    }
+
+    match CStr::from_ptr(raw).to_str() {
+        Ok(s) => Some(s),
+        Err(e) => {
+            let msg = format!("Unable to convert the {} to a UTF-8 string", name);
+            update_last_error(Error::with_chain(e, msg));
+            None
+        }
+    }
+}
+
+/// Copy some bytes into a caller-provided buffer, adding a trailing null so it
+/// can be used as a C string.
+///
+/// This returns the number of bytes written (not including the null), or
+/// `-1` if the buffer is null or too small.
+pub(crate) unsafe fn copy_to_buffer(data: &[u8], buffer: *mut c_char, length: size_t) -> c_int {
+    if buffer.is_null() {
+        update_last_error(Error::from("Null pointer passed in as the buffer"));
+        return -1;
+    }
+
+    let buffer = slice::from_raw_parts_mut(buffer as *mut u8, length as usize);
+
+    if data.len() >= buffer.len() {
+        let msg = format!(
+            "Buffer is an insufficient length, expected at least {} bytes but got {}",
+            data.len() + 1,
+            buffer.len()
+        );
+        update_last_error(Error::from(msg));
+        return -1;
+    }
+
+    ptr::copy_nonoverlapping(data.as_ptr(), buffer.as_mut_ptr(), data.len());
+    buffer[data.len()] = 0;
+
+    data.len() as c_int
+}
+
 /// Calculate the number of bytes in the last error's error message **not**
 /// including any trailing `null` characters.
 #[no_mangle]
 pub extern "C" fn last_error_length() -> c_int {
-    LAST_ERROR.with(|prev| match *prev.borrow() {
-        Some(ref err) => err.to_string().len() as c_int + 1,
-        None => 0,
+    catch_panic(-1, || {
+        LAST_ERROR.with(|prev| match *prev.borrow() {
+            Some(ref err) => err.to_string().len() as c_int + 1,
+            None => 0,
+        })
+    })
+}
+
+/// Get the category of the most recent error as an `ErrorCategory` code, or
+/// `0` (`ErrorCategory::None`) if there is no error.
+///
+/// Reading the message with [`last_error_message()`] clears the error, so
+/// make sure you check the code first.
+///
+/// [`last_error_message()`]: fn.last_error_message.html
+#[no_mangle]
+pub extern "C" fn last_error_code() -> c_int {
+    catch_panic(-1, || {
+        LAST_ERROR_CATEGORY.with(|prev| prev.get() as c_int)
     })
 }
 
@@ -64,41 +164,43 @@ pub extern "C" fn last_error_length() -> c_int {
 /// null pointer or a buffer of insufficient size.
 #[no_mangle]
 pub unsafe extern "C" fn last_error_message(buffer: *mut c_char, length: c_int) -> c_int {
-    if buffer.is_null() {
-        warn!("Null pointer passed into last_error_message() as the buffer");
-        return -1;
-    }
+    catch_panic(-1, || {
+        if buffer.is_null() {
+            warn!("Null pointer passed into last_error_message() as the buffer");
+            return -1;
+        }
 
-    let last_error = match take_last_error() {
-        Some(err) => err,
-        None => return 0,
-    };
+        let last_error = match take_last_error() {
+            Some(err) => err,
+            None => return 0,
+        };
 
-    let error_message = last_error.to_string();
+        let error_message = last_error.to_string();
 
-    let buffer = slice::from_raw_parts_mut(buffer as *mut u8, length as usize);
+        let buffer = slice::from_raw_parts_mut(buffer as *mut u8, length as usize);
 
-    if error_message.len() >= buffer.len() {
-        warn!("Buffer provided for writing the last error message is too small.");
-        warn!(
-            "Expected at least {} bytes but got {}",
-            error_message.len() + 1,
-            buffer.len()
-        );
-        return -1;
-    }
+        if error_message.len() >= buffer.len() {
+            warn!("Buffer provided for writing the last error message is too small.");
+            warn!(
+                "Expected at least {} bytes but got {}",
+                error_message.len() + 1,
+                buffer.len()
+            );
+            return -1;
+        }
 
-    ptr::copy_nonoverlapping(
-        error_message.as_ptr(),
-        buffer.as_mut_ptr(),
-        error_message.len(),
-    );
+        ptr::copy_nonoverlapping(
+            error_message.as_ptr(),
+            buffer.as_mut_ptr(),
+            error_message.len(),
+        );
 
-    // Add a trailing null so people using the string as a `char *` don't
-    // accidentally read into garbage.
-    buffer[error_message.len()] = 0;
+        // Add a trailing null so people using the string as a `char *` don't
+        // accidentally read into garbage.
+        buffer[error_message.len()] = 0;
 
-    error_message.len() as c_int
+        error_message.len() as c_int
+    })
 }
 
 /// Construct a new `Request` which will target the provided URL and fill out
@@ -116,189 +218,2396 @@ pub unsafe extern "C" fn last_error_message(buffer: *mut c_char, length: c_int)
 /// [`request_destroy()`]: fn.request_destroy.html
 #[no_mangle]
 pub unsafe extern "C" fn request_create(url: *const c_char) -> *mut Request {
-    if url.is_null() {
-        let err = Error::from("No URL provided");
-        update_last_error(err);
-        return ptr::null_mut();
-    }
+    catch_panic(ptr::null_mut(), || {
+        let url_as_str = match c_str_to_str(url, "URL") {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
 
-    let raw = CStr::from_ptr(url);
+        let parsed_url = match parse_url(url_as_str) {
+            Ok(u) => u,
+            Err(e) => {
+                update_last_error(e);
+                return ptr::null_mut();
+            }
+        };
 
-    let url_as_str = match raw.to_str() {
-        Ok(s) => s,
-        Err(e) => {
-            let err = Error::with_chain(e, "Unable to convert URL to a UTF-8 string");
-            update_last_error(err);
-            return ptr::null_mut();
-        }
-    };
+        let req = Request::new(parsed_url, Method::Get);
+        trace!("Created Request, {:?}", req);
+        Box::into_raw(Box::new(req))
+    })
+}
 
-    let parsed_url = match Url::parse(url_as_str) {
-        Ok(u) => u,
-        Err(e) => {
-            let err = Error::with_chain(e, "Unable to parse the URL");
-            update_last_error(err);
+/// Construct a new `Request` targeting a `Url` which was built with
+/// [`url_parse()`] and friends. The `Url` is copied, so you still need to
+/// destroy it afterwards.
+///
+/// [`url_parse()`]: ../urls/fn.url_parse.html
+#[no_mangle]
+pub unsafe extern "C" fn request_create_from_url(url: *const Url) -> *mut Request {
+    catch_panic(ptr::null_mut(), || {
+        if url.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_create_from_url()"));
             return ptr::null_mut();
         }
-    };
 
-    let req = Request::new(parsed_url, Method::Get);
-    trace!("Created Request, {:?}", req);
-    Box::into_raw(Box::new(req))
+        let req = Request::new((&*url).clone(), Method::Get);
+        trace!("Created Request, {:?}", req);
+        Box::into_raw(Box::new(req))
+    })
 }
 
 /// Destroy a `Request` once you are done with it.
 #[no_mangle]
 pub unsafe extern "C" fn request_destroy(req: *mut Request) {
-    if !req.is_null() {
-        let req = Box::from_raw(req);
-        trace!("Destroying Request, {:?}", req);
-        drop(req);
-    }
+    catch_panic((), || {
+        if !req.is_null() {
+            let req = Box::from_raw(req);
+            trace!("Destroying Request, {:?}", req);
+            drop(req);
+        }
+    })
 }
 
 /// Take a reference to a `Request` and execute it, getting back the server's
 /// response.
 ///
-/// If something goes wrong, this will return a null pointer. Don't forget to
-/// destroy the `Response` once you are done with it!
+/// If something goes wrong, this will return a null pointer. A `4xx` or `5xx`
+/// status still gives you a `Response` unless the request is in strict mode
+/// (see [`request_set_strict()`]). Don't forget to destroy the `Response` once
+/// you are done with it!
+///
+/// [`request_set_strict()`]: fn.request_set_strict.html
 #[no_mangle]
 pub unsafe extern "C" fn request_send(req: *const Request) -> *mut Response {
-    if req.is_null() {
-        update_last_error(Error::from("Received null pointer"));
-        return ptr::null_mut();
-    }
+    catch_panic(ptr::null_mut(), || {
+        if req.is_null() {
+            update_last_error(Error::from("Received null pointer"));
+            return ptr::null_mut();
+        }
 
-    let req = &*req;
+        let req = &*req;
 
-    let response = match send_request(req) {
-        Ok(r) => r,
-        Err(e) => {
-            update_last_error(Error::with_chain(e, "Sending request failed."));
-            return ptr::null_mut();
+        let response = match send_request(req) {
+            Ok(r) => r,
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Sending request failed."));
+                return ptr::null_mut();
+            }
+        };
+
+        debug!("Received Response");
+        trace!("{:?}", response);
+
+        Box::into_raw(Box::new(response))
+    })
+}
+
+/// Set the request's HTTP method (e.g. `"POST"`). Methods are case-sensitive,
+/// so standard methods should be upper-case.
+///
+/// Returns `0` on success or `-1` if the method is invalid.
+#[no_mangle]
+pub unsafe extern "C" fn request_set_method(req: *mut Request, method: *const c_char) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_set_method()"));
+            return -1;
         }
-    };
 
-    debug!("Received Response");
-    trace!("{:?}", response);
+        let method = match c_str_to_str(method, "method") {
+            Some(m) => m,
+            None => return -1,
+        };
 
-    Box::into_raw(Box::new(response))
+        match method.parse::<Method>() {
+            Ok(m) => {
+                (&mut *req).method = m;
+                0
+            }
+            Err(e) => {
+                let msg = format!("\"{}\" isn't a valid HTTP method", method);
+                update_last_error(Error::with_chain(e, msg));
+                -1
+            }
+        }
+    })
 }
 
-/// Destroy a `Response` once you are done with it.
+/// Set the request body, copying `length` bytes from `data`. Passing in a
+/// null pointer removes the body.
+///
+/// Returns `0` on success or `-1` on error.
 #[no_mangle]
-pub unsafe extern "C" fn response_destroy(res: *mut Response) {
-    if !res.is_null() {
-        drop(Box::from_raw(res));
-    }
+pub unsafe extern "C" fn request_set_body(
+    req: *mut Request,
+    data: *const u8,
+    length: size_t,
+) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_set_body()"));
+            return -1;
+        }
+
+        let req = &mut *req;
+
+        if data.is_null() {
+            req.body = None;
+        } else {
+            req.set_body(slice::from_raw_parts(data, length as usize));
+        }
+
+        0
+    })
 }
 
-/// Get the length of a `Response`'s body.
+/// Set a header, replacing any existing values for it.
+///
+/// Use this for things like `Content-Type`. Returns `0` on success or `-1` on
+/// error.
 #[no_mangle]
-pub unsafe extern "C" fn response_body_length(res: *const Response) -> size_t {
-    if res.is_null() {
-        update_last_error(Error::from("Null pointer passed to response_body_length()"));
-        return 0;
-    }
+pub unsafe extern "C" fn request_set_header(
+    req: *mut Request,
+    name: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_set_header()"));
+            return -1;
+        }
 
-    (&*res).body.len() as size_t
+        let (name, value) = match (
+            c_str_to_str(name, "header name"),
+            c_str_to_str(value, "header value"),
+        ) {
+            (Some(n), Some(v)) => (n, v),
+            _ => return -1,
+        };
+
+        (&mut *req).headers.set_raw(name.to_string(), value.to_string());
+        0
+    })
 }
 
-/// Copy the response body into a user-provided buffer, returning the number of
-/// bytes copied.
+/// Remove a header from the request. Removing a header which isn't set is not
+/// an error.
 ///
-/// If an error is encountered, this returns `-1`.
+/// Returns `0` on success or `-1` on error.
 #[no_mangle]
-pub unsafe extern "C" fn response_body(
-    res: *const Response,
-    buffer: *mut c_char,
-    length: size_t,
+pub unsafe extern "C" fn request_remove_header(req: *mut Request, name: *const c_char) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_remove_header()"));
+            return -1;
+        }
+
+        let name = match c_str_to_str(name, "header name") {
+            Some(n) => n,
+            None => return -1,
+        };
+
+        (&mut *req).headers.remove_raw(name);
+        0
+    })
+}
+
+/// Use HTTP Basic authentication, replacing any existing `Authorization`
+/// header.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn request_set_basic_auth(
+    req: *mut Request,
+    username: *const c_char,
+    password: *const c_char,
 ) -> c_int {
-    if res.is_null() || buffer.is_null() {
-        update_last_error(Error::from("Null pointer passed to response_body()"));
-        return -1;
-    }
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_set_basic_auth()"));
+            return -1;
+        }
 
-    let res = &*res;
-    let buffer: &mut [u8] = slice::from_raw_parts_mut(buffer as *mut u8, length as usize);
+        let (username, password) = match (
+            c_str_to_str(username, "username"),
+            c_str_to_str(password, "password"),
+        ) {
+            (Some(u), Some(p)) => (u, p),
+            _ => return -1,
+        };
 
-    if buffer.len() < res.body.len() {
-        update_last_error(Error::from("Buffer is an insufficient length"));
-        return -1;
-    }
+        (&mut *req).set_basic_auth(username, password);
+        0
+    })
+}
+
+/// Authenticate using a bearer token (e.g. an OAuth 2.0 access token),
+/// replacing any existing `Authorization` header.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn request_set_bearer_token(
+    req: *mut Request,
+    token: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_set_bearer_token()"));
+            return -1;
+        }
 
-    ptr::copy_nonoverlapping(res.body.as_ptr(), buffer.as_mut_ptr(), res.body.len());
+        let token = match c_str_to_str(token, "token") {
+            Some(t) => t,
+            None => return -1,
+        };
 
-    res.body.len() as c_int
+        (&mut *req).set_bearer_token(token);
+        0
+    })
 }
 
-/// Create a new `PluginManager`.
+/// Append a `key=value` pair to the request URL's query string,
+/// percent-encoding both as necessary. Existing parameters with the same key
+/// are kept.
+///
+/// Returns `0` on success or `-1` on error.
 #[no_mangle]
-pub extern "C" fn plugin_manager_new() -> *mut PluginManager {
-    Box::into_raw(Box::new(PluginManager::new()))
+pub unsafe extern "C" fn request_add_query_param(
+    req: *mut Request,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_add_query_param()"));
+            return -1;
+        }
+
+        let (key, value) = match (c_str_to_str(key, "key"), c_str_to_str(value, "value")) {
+            (Some(k), Some(v)) => (k, v),
+            _ => return -1,
+        };
+
+        (&mut *req)
+            .destination
+            .query_pairs_mut()
+            .append_pair(key, value);
+        0
+    })
 }
 
-/// Destroy a `PluginManager` once you are done with it.
+/// Set the fragment (the bit after the `#`) of the request URL. Passing a
+/// null pointer removes the fragment.
+///
+/// Returns `0` on success or `-1` on error.
 #[no_mangle]
-pub unsafe extern "C" fn plugin_manager_destroy(pm: *mut PluginManager) {
-    if !pm.is_null() {
-        let pm = Box::from_raw(pm);
-        drop(pm);
-    }
+pub unsafe extern "C" fn request_set_fragment(req: *mut Request, fragment: *const c_char) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_set_fragment()"));
+            return -1;
+        }
+
+        let fragment = if fragment.is_null() {
+            None
+        } else {
+            match c_str_to_str(fragment, "fragment") {
+                Some(f) => Some(f),
+                None => return -1,
+            }
+        };
+
+        (&mut *req).destination.set_fragment(fragment);
+        0
+    })
 }
 
+/// Write the request's (fully encoded) URL into a buffer as a null-terminated
+/// string, returning the number of bytes written or `-1` on error.
 #[no_mangle]
-pub unsafe extern "C" fn plugin_manager_load_plugin(
-    pm: *mut PluginManager,
-    filename: *const c_char,
+pub unsafe extern "C" fn request_url(
+    req: *const Request,
+    buffer: *mut c_char,
+    length: size_t,
 ) -> c_int {
-    let pm = &mut *pm;
-    let filename = CStr::from_ptr(filename);
-    let filename_as_str = match filename.to_str() {
-        Ok(s) => s,
-        Err(e) => {
-            update_last_error(Error::with_chain(
-                e,
-                "Unable to convert the plugin filename to UTF-8",
-            ));
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_url()"));
             return -1;
         }
-    };
 
-    debug!("Loading plugin, {:?}", filename_as_str);
+        copy_to_buffer((&*req).destination.as_str().as_bytes(), buffer, length)
+    })
+}
 
-    // TODO: proper error handling and catch_unwind
-    match pm.load_plugin(filename_as_str) {
-        Ok(_) => 0,
-        Err(e) => {
-            update_last_error(Error::with_chain(e, "Loading plugin failed"));
-            -1
+/// Attach a cookie to the request, replacing any existing cookie with the
+/// same name.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn request_add_cookie(
+    req: *mut Request,
+    name: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_add_cookie()"));
+            return -1;
         }
-    }
+
+        let (name, value) = match (
+            c_str_to_str(name, "cookie name"),
+            c_str_to_str(value, "cookie value"),
+        ) {
+            (Some(n), Some(v)) => (n, v),
+            _ => return -1,
+        };
+
+        (&mut *req).cookies.add(Cookie::new(name.to_string(), value.to_string()));
+        0
+    })
 }
 
-/// Unload all loaded plugins.
+/// Add a text field to the request's form, turning the body into an HTML
+/// form if it wasn't one already.
+///
+/// Returns `0` on success or `-1` on error.
 #[no_mangle]
-pub unsafe extern "C" fn plugin_manager_unload(pm: *mut PluginManager) {
-    let pm = &mut *pm;
-    pm.unload();
+pub unsafe extern "C" fn request_add_form_field(
+    req: *mut Request,
+    name: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_add_form_field()"));
+            return -1;
+        }
+
+        let (name, value) = match (
+            c_str_to_str(name, "field name"),
+            c_str_to_str(value, "field value"),
+        ) {
+            (Some(n), Some(v)) => (n, v),
+            _ => return -1,
+        };
+
+        (&mut *req).form().text(name, value);
+        0
+    })
 }
 
-/// Fire the `pre_send` plugin hooks.
+/// Upload a file as part of the request's form. This switches the body to
+/// `multipart/form-data`.
+///
+/// The file is read immediately, so it is safe to modify or delete it
+/// afterwards. Returns `0` on success or `-1` on error.
 #[no_mangle]
-pub unsafe extern "C" fn plugin_manager_pre_send(pm: *mut PluginManager, request: *mut Request) {
-    let pm = &mut *pm;
-    let request = &mut *request;
-    pm.pre_send(request);
+pub unsafe extern "C" fn request_add_file_part(
+    req: *mut Request,
+    name: *const c_char,
+    path: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_add_file_part()"));
+            return -1;
+        }
+
+        let (name, path) = match (
+            c_str_to_str(name, "field name"),
+            c_str_to_str(path, "file path"),
+        ) {
+            (Some(n), Some(p)) => (n, p),
+            _ => return -1,
+        };
+
+        match (&mut *req).multipart().file(name, path) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
 }
 
-/// Fire the `post_receive` plugin hooks.
+/// Create a new `HttpClient` which can be reused for many requests, returning
+/// a null pointer if it couldn't be initialized.
+///
+/// Make sure to destroy it with [`client_destroy()`] afterwards.
+///
+/// [`client_destroy()`]: fn.client_destroy.html
 #[no_mangle]
-pub unsafe extern "C" fn plugin_manager_post_receive(
+pub extern "C" fn client_new() -> *mut HttpClient {
+    catch_panic(ptr::null_mut(), || {
+        match HttpClient::new() {
+            Ok(client) => Box::into_raw(Box::new(client)),
+            Err(e) => {
+                update_last_error(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Destroy an `HttpClient` once you are done with it.
+#[no_mangle]
+pub unsafe extern "C" fn client_destroy(client: *mut HttpClient) {
+    catch_panic((), || {
+        if !client.is_null() {
+            drop(Box::from_raw(client));
+        }
+    })
+}
+
+/// Send a request using an existing `HttpClient`, reusing its connections.
+///
+/// Like [`request_send()`], this returns a null pointer if something goes
+/// wrong.
+///
+/// [`request_send()`]: fn.request_send.html
+#[no_mangle]
+pub unsafe extern "C" fn request_send_with(
+    client: *const HttpClient,
+    req: *const Request,
+) -> *mut Response {
+    catch_panic(ptr::null_mut(), || {
+        if client.is_null() || req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_send_with()"));
+            return ptr::null_mut();
+        }
+
+        match (&*client).send(&*req) {
+            Ok(r) => Box::into_raw(Box::new(r)),
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Sending request failed."));
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Send a request using an existing `HttpClient`, firing every plugin hook
+/// (including `on_redirect`, `on_retry` and `on_error`) along the way.
+///
+/// Returns a null pointer if something goes wrong.
+#[no_mangle]
+pub unsafe extern "C" fn request_send_with_plugins(
+    client: *const HttpClient,
+    req: *const Request,
     pm: *mut PluginManager,
-    response: *mut Response,
-) {
-    let pm = &mut *pm;
-    let response = &mut *response;
-    pm.post_receive(response);
+) -> *mut Response {
+    catch_panic(ptr::null_mut(), || {
+        if client.is_null() || req.is_null() || pm.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_send_with_plugins()"));
+            return ptr::null_mut();
+        }
+
+        match (&*client).send_with_plugins(&*req, &mut *pm) {
+            Ok(r) => Box::into_raw(Box::new(r)),
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Sending request failed."));
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Send a request which can be aborted by cancelling the provided
+/// `CancellationToken` (e.g. with [`cancel_token_cancel()`] from a UI
+/// thread).
+///
+/// If the request is cancelled this returns a null pointer and the last error
+/// will be an `ErrorKind::Cancelled`.
+///
+/// [`cancel_token_cancel()`]: ../cancellation/fn.cancel_token_cancel.html
+#[no_mangle]
+pub unsafe extern "C" fn request_send_cancellable(
+    client: *const HttpClient,
+    req: *const Request,
+    token: *const CancellationToken,
+) -> *mut Response {
+    catch_panic(ptr::null_mut(), || {
+        if client.is_null() || req.is_null() || token.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_send_cancellable()"));
+            return ptr::null_mut();
+        }
+
+        match (&*client).send_cancellable(&*req, &*token) {
+            Ok(r) => Box::into_raw(Box::new(r)),
+            Err(e) => {
+                update_last_error(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// A callback invoked when an asynchronous request completes.
+///
+/// On success `response` points to the `Response` (which the callback now
+/// owns and must destroy) and `error_code` is `0`. On failure `response` is
+/// null and `error_code` is `-1`. The callback is invoked on a background
+/// thread where the error has been stored as the last error, so you can use
+/// [`last_error_message()`] to find out what went wrong.
+///
+/// [`last_error_message()`]: fn.last_error_message.html
+pub type CompletionCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, response: *mut Response, error_code: c_int);
+
+/// The `user_data` pointer passed to a callback.
+///
+/// It's up to the caller to make sure whatever it points to can be used from
+/// another thread.
+pub(crate) struct UserData(pub *mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// Send a request on a background thread, invoking `callback` with the
+/// result when it completes.
+///
+/// The request is copied so it can be destroyed as soon as this function
+/// returns. Returns `0` if the request was queued or `-1` on error, in which
+/// case the callback will never be invoked.
+#[no_mangle]
+pub unsafe extern "C" fn request_send_async(
+    client: *const HttpClient,
+    req: *const Request,
+    callback: CompletionCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    catch_panic(-1, || {
+        if client.is_null() || req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_send_async()"));
+            return -1;
+        }
+
+        let user_data = UserData(user_data);
+
+        let outcome = (&*client).send_async((&*req).clone(), move |outcome| {
+            let user_data = user_data;
+
+            match outcome {
+                Ok(response) => callback(user_data.0, Box::into_raw(Box::new(response)), 0),
+                Err(e) => {
+                    update_last_error(Error::with_chain(e, "Sending request failed."));
+                    callback(user_data.0, ptr::null_mut(), -1);
+                }
+            }
+        });
+
+        match outcome {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// A `PluginManager` the caller has promised will outlive an asynchronous
+/// request.
+pub(crate) struct SharedPlugins(pub *const PluginManager);
+
+unsafe impl Send for SharedPlugins {}
+
+impl Deref for SharedPlugins {
+    type Target = PluginManager;
+
+    fn deref(&self) -> &PluginManager {
+        unsafe { &*self.0 }
+    }
+}
+
+/// Send a request on a background thread like [`request_send_async()`],
+/// firing every plugin hook along the way. The plugins' `pre_send_async()`
+/// and `post_receive_async()` hooks are used instead of their blocking
+/// versions.
+///
+/// The `PluginManager` must not be destroyed or modified (e.g. by loading
+/// or unloading plugins) until `callback` has been invoked.
+///
+/// [`request_send_async()`]: fn.request_send_async.html
+#[no_mangle]
+pub unsafe extern "C" fn request_send_async_with_plugins(
+    client: *const HttpClient,
+    req: *const Request,
+    pm: *const PluginManager,
+    callback: CompletionCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    catch_panic(-1, || {
+        if client.is_null() || req.is_null() || pm.is_null() {
+            let err = Error::from("Null pointer passed to request_send_async_with_plugins()");
+            update_last_error(err);
+            return -1;
+        }
+
+        let user_data = UserData(user_data);
+        let plugins = SharedPlugins(pm);
+
+        let outcome = (&*client).send_async_with_plugins((&*req).clone(), plugins, move |outcome| {
+            let user_data = user_data;
+
+            match outcome {
+                Ok(response) => callback(user_data.0, Box::into_raw(Box::new(response)), 0),
+                Err(e) => {
+                    update_last_error(Error::with_chain(e, "Sending request failed."));
+                    callback(user_data.0, ptr::null_mut(), -1);
+                }
+            }
+        });
+
+        match outcome {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// A callback which receives the response body piece by piece.
+///
+/// Return `0` to keep receiving the body, or anything else to stop.
+pub type ChunkCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, data: *const u8, length: size_t) -> c_int;
+
+/// Send a request, passing the response body to `on_chunk` as it arrives
+/// instead of storing it in the `Response`.
+///
+/// The returned `Response` contains the status code and headers, but its body
+/// will be empty. If something goes wrong (including `on_chunk` asking to
+/// stop) this returns a null pointer.
+#[no_mangle]
+pub unsafe extern "C" fn request_send_streaming(
+    req: *const Request,
+    on_chunk: ChunkCallback,
+    user_data: *mut c_void,
+) -> *mut Response {
+    catch_panic(ptr::null_mut(), || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_send_streaming()"));
+            return ptr::null_mut();
+        }
+
+        let outcome = HttpClient::new().and_then(|client| {
+            client.try_send_streaming(&*req, |chunk| {
+                if on_chunk(user_data, chunk.as_ptr(), chunk.len() as size_t) == 0 {
+                    Ok(())
+                } else {
+                    let reason = String::from("the chunk callback asked to stop");
+                    Err(ErrorKind::Cancelled(reason).into())
+                }
+            })
+        });
+
+        match outcome {
+            Ok(r) => Box::into_raw(Box::new(r)),
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Sending request failed."));
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// A callback used to report download progress. `total_bytes` is `0` if the
+/// size isn't known.
+///
+/// Return `0` to continue downloading, or anything else to stop.
+pub type ProgressCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, bytes_received: u64, total_bytes: u64) -> c_int;
+
+/// Download the response body straight to a file, resuming a partial
+/// download if the file already exists and the server supports it.
+///
+/// The `progress` callback is optional (it may be null). Returns `0` on
+/// success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn request_download_to_file(
+    req: *const Request,
+    path: *const c_char,
+    progress: Option<ProgressCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_download_to_file()"));
+            return -1;
+        }
+
+        let path = match c_str_to_str(path, "download path") {
+            Some(p) => p,
+            None => return -1,
+        };
+
+        let outcome = HttpClient::new().and_then(|client| {
+            client.download_to_file(&*req, path, |received, total| match progress {
+                Some(cb) if cb(user_data, received, total.unwrap_or(0)) != 0 => {
+                    let reason = String::from("the progress callback asked to stop");
+                    Err(ErrorKind::Cancelled(reason).into())
+                }
+                _ => Ok(()),
+            })
+        });
+
+        match outcome {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Downloading failed"));
+                -1
+            }
+        }
+    })
+}
+
+/// Set the client's default timeout, in milliseconds. A timeout of `0` means
+/// no timeout.
+///
+/// The timeout applies to connecting and to each read or write, not to the
+/// request as a whole.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_set_timeout_ms(client: *mut HttpClient, timeout: u64) -> c_int {
+    catch_panic(-1, || {
+        if client.is_null() {
+            update_last_error(Error::from("Null pointer passed to client_set_timeout_ms()"));
+            return -1;
+        }
+
+        let client = &mut *client;
+        let options = RequestOptions {
+            timeout: millis(timeout),
+            ..client.options()
+        };
+
+        match client.set_options(options) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Set how many times the client should retry requests which fail for a
+/// transient reason, and how long (in milliseconds) to wait before the first
+/// retry. The delay doubles after each attempt, up to five minutes.
+///
+/// Only requests with an idempotent method (e.g. `GET` or `PUT`) are retried
+/// unless [`client_set_retry_non_idempotent()`] is used.
+///
+/// [`client_set_retry_non_idempotent()`]: fn.client_set_retry_non_idempotent.html
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_set_retry_policy(
+    client: *mut HttpClient,
+    retries: c_uint,
+    backoff: u64,
+) -> c_int {
+    catch_panic(-1, || {
+        if client.is_null() {
+            update_last_error(Error::from("Null pointer passed to client_set_retry_policy()"));
+            return -1;
+        }
+
+        let client = &mut *client;
+        let options = RequestOptions {
+            retries: Some(retries),
+            backoff: Some(Duration::from_millis(backoff)),
+            ..client.options()
+        };
+
+        match client.set_options(options) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// When `retry` is non-zero, also retry requests whose method isn't
+/// idempotent (e.g. `POST`). The server may have already acted on the failed
+/// attempt, so only turn this on if sending the request twice is harmless.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_set_retry_non_idempotent(
+    client: *mut HttpClient,
+    retry: c_int,
+) -> c_int {
+    catch_panic(-1, || {
+        if client.is_null() {
+            let err = Error::from("Null pointer passed to client_set_retry_non_idempotent()");
+            update_last_error(err);
+            return -1;
+        }
+
+        let client = &mut *client;
+        let options = RequestOptions {
+            retry_non_idempotent: Some(retry != 0),
+            ..client.options()
+        };
+
+        match client.set_options(options) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Limit the client (and any requests sent with it) to `requests_per_second`
+/// on average, allowing bursts of up to `burst` requests. A rate of `0`
+/// removes the limit.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_set_rate_limit(
+    client: *const HttpClient,
+    requests_per_second: c_double,
+    burst: c_uint,
+) -> c_int {
+    catch_panic(-1, || {
+        if client.is_null() {
+            update_last_error(Error::from("Null pointer passed to client_set_rate_limit()"));
+            return -1;
+        }
+
+        let rate = if requests_per_second == 0.0 {
+            None
+        } else {
+            Some(Rate {
+                per_second: requests_per_second,
+                burst,
+            })
+        };
+
+        match (&*client).rate_limiter().set_rate(rate) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Limit how many requests the client can have in flight at once. A limit of
+/// `0` removes the limit.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_set_max_in_flight(client: *const HttpClient, max: c_uint) -> c_int {
+    catch_panic(-1, || {
+        if client.is_null() {
+            update_last_error(Error::from("Null pointer passed to client_set_max_in_flight()"));
+            return -1;
+        }
+
+        let max = if max == 0 { None } else { Some(max as usize) };
+
+        match (&*client).rate_limiter().set_max_in_flight(max) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Send every request through a HTTP proxy (e.g. `http://proxy:3128`).
+/// Passing a null pointer stops using a proxy.
+///
+/// SOCKS proxies aren't supported by the HTTP backend and are rejected.
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_set_proxy(client: *mut HttpClient, url: *const c_char) -> c_int {
+    catch_panic(-1, || {
+        let proxy = if url.is_null() {
+            None
+        } else {
+            match c_str_to_str(url, "proxy URL") {
+                Some(u) => Some(u.to_string()),
+                None => return -1,
+            }
+        };
+
+        update_transport(client, "client_set_proxy", |transport| {
+            transport.proxy = proxy;
+            Ok(())
+        })
+    })
+}
+
+/// Trust the certificates in a file, either a single DER-encoded certificate
+/// or a bundle of PEM-encoded certificates.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_add_root_certificate(
+    client: *mut HttpClient,
+    path: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        let path = match c_str_to_str(path, "certificate path") {
+            Some(p) => p,
+            None => return -1,
+        };
+
+        update_transport(client, "client_add_root_certificate", |transport| {
+            transport.add_root_certificates(path)
+        })
+    })
+}
+
+/// Identify the client using the certificate and private key in a PKCS#12
+/// archive.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_set_identity(
+    client: *mut HttpClient,
+    path: *const c_char,
+    password: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        let (path, password) = match (
+            c_str_to_str(path, "identity path"),
+            c_str_to_str(password, "password"),
+        ) {
+            (Some(p), Some(pw)) => (p, pw),
+            _ => return -1,
+        };
+
+        update_transport(client, "client_set_identity", |transport| {
+            transport.set_identity(path, password)
+        })
+    })
+}
+
+/// Accept server certificates which don't match the server's hostname when
+/// `accept` is non-zero. Only use this for testing!
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_set_accept_invalid_hostnames(
+    client: *mut HttpClient,
+    accept: c_int,
+) -> c_int {
+    catch_panic(-1, || {
+        update_transport(client, "client_set_accept_invalid_hostnames", |transport| {
+            transport.accept_invalid_hostnames = accept != 0;
+            Ok(())
+        })
+    })
+}
+
+/// Send requests for `host` on `port` to the IP address `ip`, instead of
+/// looking the host up in DNS (like curl's `--resolve`). Passing a null `ip`
+/// removes the override.
+///
+/// HTTPS requests still send `host` for SNI and check the server's
+/// certificate against it.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_resolve(
+    client: *mut HttpClient,
+    host: *const c_char,
+    ip: *const c_char,
+    port: u16,
+) -> c_int {
+    catch_panic(-1, || {
+        let host = match c_str_to_str(host, "host") {
+            Some(h) => h,
+            None => return -1,
+        };
+        let address = if ip.is_null() {
+            None
+        } else {
+            match c_str_to_str(ip, "IP address").map(|ip| ip.parse::<IpAddr>()) {
+                Some(Ok(address)) => Some(address),
+                Some(Err(_)) => {
+                    update_last_error(Error::from("Invalid IP address"));
+                    return -1;
+                }
+                None => return -1,
+            }
+        };
+
+        update_transport(client, "client_resolve", |transport| {
+            match address {
+                Some(address) => transport.resolve(host, port, address),
+                None => {
+                    transport.resolve.remove(&(host.to_lowercase(), port));
+                }
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Choose which version of HTTP the client uses, where `preference` is one of
+/// the `VersionPreference` values.
+///
+/// Returns `0` on success or `-1` on error. The HTTP backend doesn't support
+/// HTTP/2, so there's no value which asks for it.
+#[no_mangle]
+pub unsafe extern "C" fn client_set_http_version(
+    client: *mut HttpClient,
+    preference: c_int,
+) -> c_int {
+    catch_panic(-1, || {
+        let preference = match preference {
+            0 => VersionPreference::Default,
+            1 => VersionPreference::Http1Only,
+            other => {
+                let msg = format!("Unknown HTTP version preference, {}", other);
+                update_last_error(Error::from(msg));
+                return -1;
+            }
+        };
+
+        update_transport(client, "client_set_http_version", |transport| {
+            transport.http_version = preference;
+            Ok(())
+        })
+    })
+}
+
+unsafe fn update_transport<F>(client: *mut HttpClient, function: &str, update: F) -> c_int
+where
+    F: FnOnce(&mut TransportConfig) -> Result<()>,
+{
+    if client.is_null() {
+        update_last_error(Error::from(format!("Null pointer passed to {}()", function)));
+        return -1;
+    }
+
+    let client = &mut *client;
+    let mut transport = client.transport().clone();
+
+    match update(&mut transport).and_then(|_| client.set_transport(transport)) {
+        Ok(_) => 0,
+        Err(e) => {
+            update_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Override the client's timeout for this particular request, in
+/// milliseconds. A timeout of `0` means the client's timeout will be used.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn request_set_timeout_ms(req: *mut Request, timeout: u64) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_set_timeout_ms()"));
+            return -1;
+        }
+
+        (&mut *req).options.timeout = millis(timeout);
+        0
+    })
+}
+
+/// When `strict` is non-zero, treat `4xx` and `5xx` responses as errors
+/// instead of returning them.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn request_set_strict(req: *mut Request, strict: c_int) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_set_strict()"));
+            return -1;
+        }
+
+        (&mut *req).options.strict = Some(strict != 0);
+        0
+    })
+}
+
+/// Choose whether gzip and deflate response bodies are decompressed
+/// automatically (the default). Pass `0` to get the raw bytes instead.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn request_set_decompress(req: *mut Request, decompress: c_int) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_set_decompress()"));
+            return -1;
+        }
+
+        (&mut *req).decompress = decompress != 0;
+        0
+    })
+}
+
+/// Limit how many redirects will be followed for this request. A limit of
+/// `0` means redirects won't be followed at all, and a negative limit means
+/// the client's redirect policy will be used.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn request_set_max_redirects(
+    req: *mut Request,
+    max_redirects: c_int,
+) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_set_max_redirects()"));
+            return -1;
+        }
+
+        (&mut *req).redirect_policy = redirect_limit(max_redirects);
+        0
+    })
+}
+
+/// Limit how many redirects the client will follow for requests which don't
+/// set their own limit. A limit of `0` means redirects won't be followed,
+/// and a negative limit restores the default.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_set_max_redirects(
+    client: *mut HttpClient,
+    max_redirects: c_int,
+) -> c_int {
+    catch_panic(-1, || {
+        if client.is_null() {
+            update_last_error(Error::from("Null pointer passed to client_set_max_redirects()"));
+            return -1;
+        }
+
+        let policy = redirect_limit(max_redirects).unwrap_or_default();
+        (&mut *client).set_redirect_policy(policy);
+        0
+    })
+}
+
+fn redirect_limit(max_redirects: c_int) -> Option<RedirectPolicy> {
+    match max_redirects {
+        n if n < 0 => None,
+        0 => Some(RedirectPolicy::None),
+        n => Some(RedirectPolicy::Limited(n as usize)),
+    }
+}
+
+fn millis(ms: u64) -> Option<Duration> {
+    if ms == 0 {
+        None
+    } else {
+        Some(Duration::from_millis(ms))
+    }
+}
+
+/// Destroy a `Response` once you are done with it.
+#[no_mangle]
+pub unsafe extern "C" fn response_destroy(res: *mut Response) {
+    catch_panic((), || {
+        if !res.is_null() {
+            drop(Box::from_raw(res));
+        }
+    })
+}
+
+/// Get the length of a `Response`'s body.
+#[no_mangle]
+pub unsafe extern "C" fn response_body_length(res: *const Response) -> size_t {
+    catch_panic(0, || {
+        if res.is_null() {
+            update_last_error(Error::from("Null pointer passed to response_body_length()"));
+            return 0;
+        }
+
+        (&*res).body.len() as size_t
+    })
+}
+
+/// Get a pointer to the start of a `Response`'s body without copying it. Use
+/// [`response_body_length()`] to find out how many bytes it points to.
+///
+/// The pointer borrows from the `Response`, so it is only valid until the
+/// `Response` is destroyed. The body is not null-terminated. Returns a null
+/// pointer if passed a null pointer.
+///
+/// [`response_body_length()`]: fn.response_body_length.html
+#[no_mangle]
+pub unsafe extern "C" fn response_body_ptr(res: *const Response) -> *const u8 {
+    catch_panic(ptr::null(), || {
+        if res.is_null() {
+            update_last_error(Error::from("Null pointer passed to response_body_ptr()"));
+            return ptr::null();
+        }
+
+        (&*res).body.as_ptr()
+    })
+}
+
+/// Get the response's HTTP status code, or `-1` if passed a null pointer.
+#[no_mangle]
+pub unsafe extern "C" fn response_status(res: *const Response) -> c_int {
+    catch_panic(-1, || {
+        if res.is_null() {
+            update_last_error(Error::from("Null pointer passed to response_status()"));
+            return -1;
+        }
+
+        (&*res).status.as_u16() as c_int
+    })
+}
+
+/// Get the number of headers in the response, or `-1` if passed a null
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn response_header_count(res: *const Response) -> c_int {
+    catch_panic(-1, || {
+        if res.is_null() {
+            update_last_error(Error::from("Null pointer passed to response_header_count()"));
+            return -1;
+        }
+
+        (&*res).headers.len() as c_int
+    })
+}
+
+/// Copy the name and value of the `index`'th header into two caller-provided
+/// buffers as null-terminated strings.
+///
+/// Returns `0` on success or `-1` if the index is out of bounds or either
+/// buffer is too small.
+#[no_mangle]
+pub unsafe extern "C" fn response_header_get(
+    res: *const Response,
+    index: c_int,
+    name_buffer: *mut c_char,
+    name_length: size_t,
+    value_buffer: *mut c_char,
+    value_length: size_t,
+) -> c_int {
+    catch_panic(-1, || {
+        if res.is_null() {
+            update_last_error(Error::from("Null pointer passed to response_header_get()"));
+            return -1;
+        }
+
+        let header = match (&*res).headers.iter().nth(index as usize) {
+            Some(h) if index >= 0 => h,
+            _ => {
+                update_last_error(Error::from(format!("There is no header at index {}", index)));
+                return -1;
+            }
+        };
+
+        let value = header.value_string();
+
+        if copy_to_buffer(header.name().as_bytes(), name_buffer, name_length) < 0
+            || copy_to_buffer(value.as_bytes(), value_buffer, value_length) < 0
+        {
+            return -1;
+        }
+
+        0
+    })
+}
+
+/// Check whether the response has a `2xx` status code. Returns `1` if it
+/// does, `0` if it doesn't, or `-1` if passed a null pointer.
+#[no_mangle]
+pub unsafe extern "C" fn response_is_success(res: *const Response) -> c_int {
+    catch_panic(-1, || {
+        if res.is_null() {
+            update_last_error(Error::from("Null pointer passed to response_is_success()"));
+            return -1;
+        }
+
+        (&*res).is_success() as c_int
+    })
+}
+
+/// Get the number of redirects followed before getting this response, or `-1`
+/// if passed a null pointer.
+#[no_mangle]
+pub unsafe extern "C" fn response_redirect_count(res: *const Response) -> c_int {
+    catch_panic(-1, || {
+        if res.is_null() {
+            update_last_error(Error::from("Null pointer passed to response_redirect_count()"));
+            return -1;
+        }
+
+        (&*res).redirects.len() as c_int
+    })
+}
+
+/// Copy the URL of the `index`'th redirect into a caller-provided buffer as a
+/// null-terminated string.
+///
+/// Returns the number of bytes written, or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn response_redirect_url(
+    res: *const Response,
+    index: c_int,
+    buffer: *mut c_char,
+    length: size_t,
+) -> c_int {
+    catch_panic(-1, || {
+        if res.is_null() {
+            update_last_error(Error::from("Null pointer passed to response_redirect_url()"));
+            return -1;
+        }
+
+        match (&*res).redirects.get(index as usize) {
+            Some(url) if index >= 0 => copy_to_buffer(url.as_str().as_bytes(), buffer, length),
+            _ => {
+                update_last_error(Error::from(format!("There is no redirect at index {}", index)));
+                -1
+            }
+        }
+    })
+}
+
+/// Get the number of cookies set by the server, or `-1` if passed a null
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn response_cookie_count(res: *const Response) -> c_int {
+    catch_panic(-1, || {
+        if res.is_null() {
+            update_last_error(Error::from("Null pointer passed to response_cookie_count()"));
+            return -1;
+        }
+
+        (&*res).cookies.iter().count() as c_int
+    })
+}
+
+/// Copy the name and value of the `index`'th cookie into two caller-provided
+/// buffers as null-terminated strings.
+///
+/// Returns `0` on success or `-1` if the index is out of bounds or either
+/// buffer is too small.
+#[no_mangle]
+pub unsafe extern "C" fn response_cookie_get(
+    res: *const Response,
+    index: c_int,
+    name_buffer: *mut c_char,
+    name_length: size_t,
+    value_buffer: *mut c_char,
+    value_length: size_t,
+) -> c_int {
+    catch_panic(-1, || {
+        if res.is_null() {
+            update_last_error(Error::from("Null pointer passed to response_cookie_get()"));
+            return -1;
+        }
+
+        let cookie = match (&*res).cookies.iter().nth(index as usize) {
+            Some(c) if index >= 0 => c,
+            _ => {
+                update_last_error(Error::from(format!("There is no cookie at index {}", index)));
+                return -1;
+            }
+        };
+
+        if copy_to_buffer(cookie.name().as_bytes(), name_buffer, name_length) < 0
+            || copy_to_buffer(cookie.value().as_bytes(), value_buffer, value_length) < 0
+        {
+            return -1;
+        }
+
+        0
+    })
+}
+
+/// Copy how long each stage of the request took into `timing`. Stages whose
+/// duration isn't known are set to `-1`.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn response_timing(res: *const Response, timing: *mut Timing) -> c_int {
+    catch_panic(-1, || {
+        if res.is_null() || timing.is_null() {
+            update_last_error(Error::from("Null pointer passed to response_timing()"));
+            return -1;
+        }
+
+        *timing = (&*res).timing;
+        0
+    })
+}
+
+/// Copy the response body into a user-provided buffer, returning the number of
+/// bytes copied.
+///
+/// If an error is encountered, this returns `-1`.
+#[no_mangle]
+pub unsafe extern "C" fn response_body(
+    res: *const Response,
+    buffer: *mut c_char,
+    length: size_t,
+) -> c_int {
+    catch_panic(-1, || {
+        if res.is_null() || buffer.is_null() {
+            update_last_error(Error::from("Null pointer passed to response_body()"));
+            return -1;
+        }
+
+        let res = &*res;
+        let buffer: &mut [u8] = slice::from_raw_parts_mut(buffer as *mut u8, length as usize);
+
+        if buffer.len() < res.body.len() {
+            update_last_error(Error::from("Buffer is an insufficient length"));
+            return -1;
+        }
+
+        ptr::copy_nonoverlapping(res.body.as_ptr(), buffer.as_mut_ptr(), res.body.len());
+
+        res.body.len() as c_int
+    })
+}
+
+/// Create a new `PluginManager`.
+#[no_mangle]
+pub extern "C" fn plugin_manager_new() -> *mut PluginManager {
+    catch_panic(ptr::null_mut(), || {
+        Box::into_raw(Box::new(PluginManager::new()))
+    })
+}
+
+/// Destroy a `PluginManager` once you are done with it.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_destroy(pm: *mut PluginManager) {
+    catch_panic((), || {
+        if !pm.is_null() {
+            let pm = Box::from_raw(pm);
+            drop(pm);
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_load_plugin(
+    pm: *mut PluginManager,
+    filename: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() {
+            update_last_error(Error::from("Null pointer passed to plugin_manager_load_plugin()"));
+            return -1;
+        }
+
+        let filename = match c_str_to_str(filename, "plugin filename") {
+            Some(f) => f,
+            None => return -1,
+        };
+
+        debug!("Loading plugin, {:?}", filename);
+
+        match (&mut *pm).load_plugin(filename) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Loading plugin failed"));
+                -1
+            }
+        }
+    })
+}
+
+/// Load a plugin in its own `plugin-host` process (see
+/// `PluginManager::load_plugin_sandboxed()`), so it can't crash the client.
+/// The plugin still has the same permissions as the client.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_load_plugin_sandboxed(
+    pm: *mut PluginManager,
+    filename: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() {
+            let err = Error::from("Null pointer passed to plugin_manager_load_plugin_sandboxed()");
+            update_last_error(err);
+            return -1;
+        }
+
+        let filename = match c_str_to_str(filename, "filename") {
+            Some(f) => f,
+            None => return -1,
+        };
+
+        match (&mut *pm).load_plugin_sandboxed(filename) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Loading plugin failed"));
+                -1
+            }
+        }
+    })
+}
+
+/// Get the number of loaded plugins, or `-1` if passed a null pointer.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_count(pm: *const PluginManager) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() {
+            update_last_error(Error::from("Null pointer passed to plugin_manager_count()"));
+            return -1;
+        }
+
+        (&*pm).len() as c_int
+    })
+}
+
+/// Get information about the `index`'th loaded plugin (in load order).
+///
+/// The strings in `info` are borrowed from the plugin and are only valid
+/// until it is unloaded.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_info(
+    pm: *const PluginManager,
+    index: c_int,
+    info: *mut PluginInfo,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() || info.is_null() {
+            update_last_error(Error::from("Null pointer passed to plugin_manager_info()"));
+            return -1;
+        }
+
+        match (&*pm).plugins().nth(index as usize) {
+            Some(plugin) if index >= 0 => {
+                *info = plugin.info();
+                0
+            }
+            _ => {
+                update_last_error(Error::from(format!("There is no plugin at index {}", index)));
+                -1
+            }
+        }
+    })
+}
+
+/// Load each plugin's settings from a TOML file, with one table per plugin.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_load_config(
+    pm: *mut PluginManager,
+    path: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() {
+            update_last_error(Error::from("Null pointer passed to plugin_manager_load_config()"));
+            return -1;
+        }
+
+        let path = match c_str_to_str(path, "settings path") {
+            Some(p) => p,
+            None => return -1,
+        };
+
+        match (&mut *pm).load_config(path) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Change one of a plugin's settings. If the plugin is already loaded it
+/// is told about the change straight away, otherwise it gets the setting
+/// when it is loaded.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_configure(
+    pm: *mut PluginManager,
+    name: *const c_char,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() {
+            update_last_error(Error::from("Null pointer passed to plugin_manager_configure()"));
+            return -1;
+        }
+
+        let (name, key, value) = match (
+            c_str_to_str(name, "plugin name"),
+            c_str_to_str(key, "setting name"),
+            c_str_to_str(value, "setting value"),
+        ) {
+            (Some(n), Some(k), Some(v)) => (n, k, v),
+            _ => return -1,
+        };
+
+        match (&mut *pm).configure(name, key, value) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Load every plugin in a directory.
+///
+/// Returns the number of plugins loaded, or `-1` if the directory couldn't
+/// be read. Plugins which fail to load are skipped, and the last error is set
+/// to say why.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_load_dir(
+    pm: *mut PluginManager,
+    path: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() {
+            update_last_error(Error::from("Null pointer passed to plugin_manager_load_dir()"));
+            return -1;
+        }
+
+        let path = match c_str_to_str(path, "plugin directory") {
+            Some(p) => p,
+            None => return -1,
+        };
+
+        match (&mut *pm).load_dir(path) {
+            Ok(report) => report_loaded(report),
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Load every plugin in a directory like [`plugin_manager_load_dir()`], and
+/// keep an eye out for new ones with
+/// [`plugin_manager_check_watched_dirs()`].
+///
+/// [`plugin_manager_load_dir()`]: fn.plugin_manager_load_dir.html
+/// [`plugin_manager_check_watched_dirs()`]: fn.plugin_manager_check_watched_dirs.html
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_watch_dir(
+    pm: *mut PluginManager,
+    path: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() {
+            update_last_error(Error::from("Null pointer passed to plugin_manager_watch_dir()"));
+            return -1;
+        }
+
+        let path = match c_str_to_str(path, "plugin directory") {
+            Some(p) => p,
+            None => return -1,
+        };
+
+        match (&mut *pm).watch_dir(path) {
+            Ok(report) => report_loaded(report),
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Load any plugins added to a watched directory since it was last checked.
+///
+/// Returns the number of new plugins loaded, or `-1` if passed a null
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_check_watched_dirs(pm: *mut PluginManager) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() {
+            update_last_error(Error::from(
+                "Null pointer passed to plugin_manager_check_watched_dirs()",
+            ));
+            return -1;
+        }
+
+        report_loaded((&mut *pm).check_watched_dirs())
+    })
+}
+
+fn report_loaded(report: LoadReport) -> c_int {
+    let loaded = report.loaded.len() as c_int;
+
+    if let Some((path, e)) = report.failed.into_iter().last() {
+        let msg = format!("Unable to load {}", path.display());
+        update_last_error(Error::with_chain(e, msg));
+    }
+
+    loaded
+}
+
+/// Unload all loaded plugins.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_unload(pm: *mut PluginManager) {
+    catch_panic((), || {
+        if !pm.is_null() {
+            (&mut *pm).unload();
+        }
+    })
+}
+
+/// Unload a single plugin.
+///
+/// Returns `0` on success or `-1` if there was no plugin with that name or
+/// other plugins still depend on it.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_unload_plugin(
+    pm: *mut PluginManager,
+    name: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() {
+            update_last_error(Error::from("Null pointer passed to plugin_manager_unload_plugin()"));
+            return -1;
+        }
+
+        let name = match c_str_to_str(name, "plugin name") {
+            Some(n) => n,
+            None => return -1,
+        };
+
+        match (&mut *pm).unload_plugin(name) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Unload a plugin and load it again from the same file.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_reload_plugin(
+    pm: *mut PluginManager,
+    name: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() {
+            update_last_error(Error::from("Null pointer passed to plugin_manager_reload_plugin()"));
+            return -1;
+        }
+
+        let name = match c_str_to_str(name, "plugin name") {
+            Some(n) => n,
+            None => return -1,
+        };
+
+        match (&mut *pm).reload_plugin(name) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Fire the `pre_send` plugin hooks.
+///
+/// Returns `0` if the request should be sent, or `1` if a plugin answered
+/// the request itself. In that case `*response` is set to the plugin's
+/// response, which must be freed with `response_destroy()`.
+///
+/// Returns `-1` if a plugin aborted the request or panicked.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_pre_send(
+    pm: *mut PluginManager,
+    request: *mut Request,
+    response: *mut *mut Response,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() || request.is_null() {
+            update_last_error(Error::from("Null pointer passed to plugin_manager_pre_send()"));
+            return -1;
+        }
+
+        match (&mut *pm).pre_send(&mut *request) {
+            Ok(HookResult::Continue) => 0,
+            Ok(HookResult::Abort(reason)) => {
+                update_last_error(Error::from_kind(ErrorKind::Aborted(reason)));
+                -1
+            }
+            Ok(HookResult::Respond(r)) => {
+                if response.is_null() {
+                    update_last_error(Error::from(
+                        "A plugin answered the request but no response pointer was provided",
+                    ));
+                    return -1;
+                }
+
+                *response = Box::into_raw(Box::new(r));
+                1
+            }
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Fire the `post_receive` plugin hooks.
+///
+/// Returns `0` on success or `-1` if a plugin panicked.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_post_receive(
+    pm: *mut PluginManager,
+    response: *mut Response,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() || response.is_null() {
+            update_last_error(Error::from("Null pointer passed to plugin_manager_post_receive()"));
+            return -1;
+        }
+
+        match (&mut *pm).post_receive(&mut *response) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Fire the `post_receive` plugin hooks, skipping any plugins the request
+/// opted out of with [`request_skip_plugin()`].
+///
+/// Returns `0` on success or `-1` on error.
+///
+/// [`request_skip_plugin()`]: fn.request_skip_plugin.html
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_post_receive_for(
+    pm: *mut PluginManager,
+    request: *const Request,
+    response: *mut Response,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() || request.is_null() || response.is_null() {
+            update_last_error(Error::from(
+                "Null pointer passed to plugin_manager_post_receive_for()",
+            ));
+            return -1;
+        }
+
+        match (&mut *pm).post_receive_for(&*request, &mut *response) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Unload plugins as soon as they panic. By default their hooks are just
+/// skipped.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_set_auto_unload(pm: *mut PluginManager, enabled: c_int) {
+    catch_panic((), || {
+        if !pm.is_null() {
+            (&mut *pm).set_auto_unload(enabled != 0);
+        }
+    })
+}
+
+/// Run the `count` named plugins first (in the order given), followed by
+/// the rest in order of priority.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn plugin_manager_set_order(
+    pm: *mut PluginManager,
+    names: *const *const c_char,
+    count: size_t,
+) -> c_int {
+    catch_panic(-1, || {
+        if pm.is_null() || (names.is_null() && count > 0) {
+            update_last_error(Error::from("Null pointer passed to plugin_manager_set_order()"));
+            return -1;
+        }
+
+        let mut order = Vec::new();
+        for i in 0..count {
+            match c_str_to_str(*names.offset(i as isize), "plugin name") {
+                Some(name) => order.push(name),
+                None => return -1,
+            }
+        }
+
+        (&mut *pm).set_order(&order);
+        0
+    })
+}
+
+/// Don't run the named plugin's hooks for this request.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn request_skip_plugin(req: *mut Request, name: *const c_char) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_skip_plugin()"));
+            return -1;
+        }
+
+        let name = match c_str_to_str(name, "plugin name") {
+            Some(n) => n,
+            None => return -1,
+        };
+
+        (&mut *req).skip_plugin(name);
+        0
+    })
+}
+
+/// Create a new `QuotaTracker`, persisting usage to the provided file.
+///
+/// If `path` is null, usage will only be tracked in memory. A null pointer is
+/// returned if an existing quota file couldn't be loaded.
+#[no_mangle]
+pub unsafe extern "C" fn quota_tracker_new(path: *const c_char) -> *mut QuotaTracker {
+    catch_panic(ptr::null_mut(), || {
+        if path.is_null() {
+            return Box::into_raw(Box::new(QuotaTracker::new()));
+        }
+
+        let path = match c_str_to_str(path, "quota file") {
+            Some(p) => p,
+            None => return ptr::null_mut(),
+        };
+
+        match QuotaTracker::load(path) {
+            Ok(tracker) => Box::into_raw(Box::new(tracker)),
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Unable to load the quota tracker"));
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Destroy a `QuotaTracker` once you are done with it.
+#[no_mangle]
+pub unsafe extern "C" fn quota_tracker_destroy(tracker: *mut QuotaTracker) {
+    catch_panic((), || {
+        if !tracker.is_null() {
+            drop(Box::from_raw(tracker));
+        }
+    })
+}
+
+/// Set the quota for a particular environment. Passing in `0` for either
+/// limit means it won't be enforced.
+///
+/// Returns `0` on success or `-1` if an error occurred.
+#[no_mangle]
+pub unsafe extern "C" fn quota_tracker_set_quota(
+    tracker: *mut QuotaTracker,
+    environment: *const c_char,
+    max_requests_per_hour: c_uint,
+    max_upload_bytes_per_day: u64,
+) -> c_int {
+    catch_panic(-1, || {
+        if tracker.is_null() {
+            update_last_error(Error::from("Null pointer passed to quota_tracker_set_quota()"));
+            return -1;
+        }
+
+        let environment = match c_str_to_str(environment, "environment") {
+            Some(env) => env,
+            None => return -1,
+        };
+
+        let quota = Quota {
+            max_requests_per_hour: if max_requests_per_hour == 0 {
+                None
+            } else {
+                Some(max_requests_per_hour)
+            },
+            max_upload_bytes_per_day: if max_upload_bytes_per_day == 0 {
+                None
+            } else {
+                Some(max_upload_bytes_per_day)
+            },
+        };
+
+        (&mut *tracker).set_quota(environment, quota);
+        0
+    })
+}
+
+/// Send a request on behalf of an environment, refusing to send it if doing
+/// so would exceed the environment's quota.
+///
+/// The plugin manager is optional (it may be null), but if provided its
+/// plugins will be notified whenever a request is blocked. Like
+/// [`request_send()`], this returns a null pointer on failure.
+///
+/// [`request_send()`]: fn.request_send.html
+#[no_mangle]
+pub unsafe extern "C" fn request_send_metered(
+    tracker: *mut QuotaTracker,
+    environment: *const c_char,
+    pm: *mut PluginManager,
+    req: *const Request,
+) -> *mut Response {
+    catch_panic(ptr::null_mut(), || {
+        if tracker.is_null() || req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_send_metered()"));
+            return ptr::null_mut();
+        }
+
+        let environment = match c_str_to_str(environment, "environment") {
+            Some(env) => env,
+            None => return ptr::null_mut(),
+        };
+
+        let plugins = if pm.is_null() { None } else { Some(&mut *pm) };
+
+        match send_request_metered(&*req, environment, &mut *tracker, plugins) {
+            Ok(r) => Box::into_raw(Box::new(r)),
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Sending request failed."));
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+    use {Dependency, Plugin, PluginContext};
+
+    /// Accept a single connection on a background thread and hand it to
+    /// `handler`.
+    fn listen<F>(handler: F) -> SocketAddr
+    where
+        F: FnOnce(TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                handler(stream);
+            }
+        });
+
+        address
+    }
+
+    /// Answer a single request with a canned response.
+    fn respond_once(response: &'static str) -> SocketAddr {
+        listen(move |mut stream| {
+            let mut buffer = [0; 4096];
+            let _ = stream.read(&mut buffer);
+            let _ = stream.write_all(response.as_bytes());
+        })
+    }
+
+    fn request(url: &str) -> *mut Request {
+        let url = CString::new(url).unwrap();
+        let req = unsafe { request_create(url.as_ptr()) };
+        assert!(!req.is_null(), "Couldn't create a request for {}", url.to_string_lossy());
+        req
+    }
+
+    fn assert_category(expected: ErrorCategory) {
+        assert_eq!(last_error_code(), expected as c_int);
+        take_last_error();
+    }
+
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello";
+
+    #[test]
+    fn no_error() {
+        take_last_error();
+        assert_category(ErrorCategory::None);
+    }
+
+    #[test]
+    fn unknown() {
+        let req = request("http://localhost/");
+        let method = CString::new("").unwrap();
+
+        unsafe {
+            assert_eq!(request_set_method(req, method.as_ptr()), -1);
+            assert_category(ErrorCategory::Unknown);
+            request_destroy(req);
+        }
+    }
+
+    #[test]
+    fn network() {
+        // Nothing is listening on the port once the listener is dropped
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let req = request(&format!("http://{}/", address));
+
+        unsafe {
+            assert!(request_send(req).is_null());
+            assert_category(ErrorCategory::Network);
+            request_destroy(req);
+        }
+    }
+
+    #[test]
+    fn tls() {
+        let address = respond_once(OK);
+        let req = request(&format!("https://{}/", address));
+
+        unsafe {
+            assert!(request_send(req).is_null());
+            assert_category(ErrorCategory::Tls);
+            request_destroy(req);
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let address = listen(|stream| {
+            thread::sleep(Duration::from_secs(5));
+            drop(stream);
+        });
+        let req = request(&format!("http://{}/", address));
+        let client = client_new();
+
+        unsafe {
+            assert_eq!(client_set_timeout_ms(client, 100), 0);
+            assert!(request_send_with(client, req).is_null());
+            assert_category(ErrorCategory::Timeout);
+            client_destroy(client);
+            request_destroy(req);
+        }
+    }
+
+    #[test]
+    fn setters_reject_null_pointers() {
+        unsafe {
+            assert_eq!(request_set_timeout_ms(ptr::null_mut(), 100), -1);
+            assert_eq!(request_set_strict(ptr::null_mut(), 1), -1);
+            assert_eq!(request_set_decompress(ptr::null_mut(), 0), -1);
+            assert_eq!(request_set_max_redirects(ptr::null_mut(), 3), -1);
+            assert_eq!(client_set_max_redirects(ptr::null_mut(), 3), -1);
+        }
+        assert_eq!(
+            take_last_error().unwrap().to_string(),
+            "Null pointer passed to client_set_max_redirects()"
+        );
+
+        let req = request("http://localhost/");
+        let client = client_new();
+
+        unsafe {
+            assert_eq!(request_set_timeout_ms(req, 100), 0);
+            assert_eq!(request_set_strict(req, 1), 0);
+            assert_eq!(request_set_decompress(req, 0), 0);
+            assert_eq!(request_set_max_redirects(req, 3), 0);
+            assert_eq!(client_set_max_redirects(client, 3), 0);
+            client_destroy(client);
+            request_destroy(req);
+        }
+    }
+
+    #[test]
+    fn unsupported_http_versions_are_rejected() {
+        let client = client_new();
+
+        unsafe {
+            assert_eq!(client_set_http_version(client, 1), 0);
+            assert_eq!(client_set_http_version(client, 2), -1);
+            assert_eq!(
+                take_last_error().unwrap().to_string(),
+                "Unknown HTTP version preference, 2"
+            );
+            client_destroy(client);
+        }
+    }
+
+    #[test]
+    fn invalid_url() {
+        let url = CString::new("not a URL").unwrap();
+
+        unsafe {
+            assert!(request_create(url.as_ptr()).is_null());
+        }
+        assert_category(ErrorCategory::InvalidUrl);
+    }
+
+    struct Panicky;
+
+    impl Plugin for Panicky {
+        fn name(&self) -> &'static str {
+            "panicky"
+        }
+
+        fn pre_send(&self, _ctx: &PluginContext, _request: &mut Request) -> HookResult {
+            panic!("Oops")
+        }
+    }
+
+    #[test]
+    fn panic() {
+        let pm = plugin_manager_new();
+        let req = request("http://localhost/");
+
+        unsafe {
+            (&mut *pm).register_static(Box::new(Panicky)).unwrap();
+            assert_eq!(plugin_manager_pre_send(pm, req, ptr::null_mut()), -1);
+            assert_category(ErrorCategory::Panic);
+            request_destroy(req);
+            plugin_manager_destroy(pm);
+        }
+    }
+
+    unsafe extern "C" fn stop(_user_data: *mut c_void, _data: *const u8, _len: size_t) -> c_int {
+        1
+    }
+
+    #[test]
+    fn cancelled() {
+        let address = respond_once(OK);
+        let req = request(&format!("http://{}/", address));
+
+        unsafe {
+            assert!(request_send_streaming(req, stop, ptr::null_mut()).is_null());
+            assert_category(ErrorCategory::Cancelled);
+            request_destroy(req);
+        }
+    }
+
+    #[test]
+    fn quota_exceeded() {
+        let address = respond_once(OK);
+        let req = request(&format!("http://{}/", address));
+        let environment = CString::new("prod").unwrap();
+        let env = environment.as_ptr();
+        let tracker = unsafe { quota_tracker_new(ptr::null()) };
+
+        unsafe {
+            assert_eq!(quota_tracker_set_quota(tracker, env, 1, 0), 0);
+
+            let response = request_send_metered(tracker, env, ptr::null_mut(), req);
+            assert!(!response.is_null());
+            response_destroy(response);
+
+            let blocked = request_send_metered(tracker, env, ptr::null_mut(), req);
+            assert!(blocked.is_null());
+            assert_category(ErrorCategory::QuotaExceeded);
+
+            quota_tracker_destroy(tracker);
+            request_destroy(req);
+        }
+    }
+
+    #[test]
+    fn http_status() {
+        let address = respond_once(
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+        let req = request(&format!("http://{}/", address));
+
+        unsafe {
+            request_set_strict(req, 1);
+            assert!(request_send(req).is_null());
+            assert_category(ErrorCategory::HttpStatus);
+            request_destroy(req);
+        }
+    }
+
+    struct Needy;
+
+    impl Plugin for Needy {
+        fn name(&self) -> &'static str {
+            "needy"
+        }
+
+        fn dependencies(&self) -> Vec<Dependency> {
+            vec![Dependency::any("missing")]
+        }
+    }
+
+    #[test]
+    fn incompatible_plugin() {
+        let mut pm = PluginManager::new();
+        let err = pm.register_static(Box::new(Needy)).unwrap_err();
+
+        // The same way plugin_manager_load_plugin() reports it
+        update_last_error(Error::with_chain(err, "Loading plugin failed"));
+        assert_category(ErrorCategory::IncompatiblePlugin);
+    }
+
+    #[test]
+    fn categories_are_found_through_several_layers_of_context() {
+        let err = Error::from(ErrorKind::Cancelled(String::from("testing")))
+            .chain_err(|| "inner")
+            .chain_err(|| "outer");
+
+        assert_eq!(ErrorCategory::of(&err), ErrorCategory::Cancelled);
+    }
+
+    #[test]
+    fn plugin_manager_functions_reject_null_pointers() {
+        let pm = plugin_manager_new();
+        let req = request("http://localhost/");
+        let filename = CString::new("libmissing.so").unwrap();
+
+        unsafe {
+            assert_eq!(plugin_manager_load_plugin(ptr::null_mut(), filename.as_ptr()), -1);
+            assert_eq!(plugin_manager_load_plugin(pm, ptr::null()), -1);
+            assert_eq!(plugin_manager_pre_send(ptr::null_mut(), req, ptr::null_mut()), -1);
+            assert_eq!(plugin_manager_pre_send(pm, ptr::null_mut(), ptr::null_mut()), -1);
+            assert_eq!(plugin_manager_post_receive(ptr::null_mut(), ptr::null_mut()), -1);
+            assert_eq!(plugin_manager_post_receive(pm, ptr::null_mut()), -1);
+            assert_eq!(
+                take_last_error().unwrap().to_string(),
+                "Null pointer passed to plugin_manager_post_receive()"
+            );
+
+            plugin_manager_unload(ptr::null_mut());
+
+            request_destroy(req);
+            plugin_manager_destroy(pm);
+        }
+    }
 }
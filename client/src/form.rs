@@ -0,0 +1,255 @@
+//! HTML-style form bodies, either `application/x-www-form-urlencoded` or
+//! `multipart/form-data`.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use chrono::Utc;
+use url::form_urlencoded;
+
+use errors::*;
+use Request;
+
+
+/// A form which will be sent as the request body.
+///
+/// Forms are URL-encoded unless they contain a file or were explicitly
+/// created with [`Request::multipart()`].
+///
+/// [`Request::multipart()`]: struct.Request.html#method.multipart
+#[derive(Debug, Clone, PartialEq)]
+pub struct Form {
+    multipart: bool,
+    boundary: String,
+    fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    Text {
+        name: String,
+        value: String,
+    },
+    File {
+        name: String,
+        filename: String,
+        content_type: String,
+        data: Vec<u8>,
+    },
+}
+
+impl Form {
+    pub fn new() -> Form {
+        Form {
+            multipart: false,
+            boundary: new_boundary(),
+            fields: Vec::new(),
+        }
+    }
+
+    /// Will this form be sent as `multipart/form-data`?
+    pub fn is_multipart(&self) -> bool {
+        self.multipart || self.fields.iter().any(|f| match *f {
+            Field::File { .. } => true,
+            _ => false,
+        })
+    }
+
+    /// Encode the form, returning its `Content-Type` and body.
+    pub fn encode(&self) -> (String, Vec<u8>) {
+        if self.is_multipart() {
+            let content_type = format!("multipart/form-data; boundary={}", self.boundary);
+            (content_type, self.encode_multipart())
+        } else {
+            let content_type = String::from("application/x-www-form-urlencoded");
+            (content_type, self.encode_urlencoded())
+        }
+    }
+
+    fn encode_urlencoded(&self) -> Vec<u8> {
+        let mut serializer = form_urlencoded::Serializer::new(String::new());
+
+        for field in &self.fields {
+            if let Field::Text { ref name, ref value } = *field {
+                serializer.append_pair(name, value);
+            }
+        }
+
+        serializer.finish().into_bytes()
+    }
+
+    fn encode_multipart(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        for field in &self.fields {
+            body.extend(format!("--{}\r\n", self.boundary).into_bytes());
+
+            match *field {
+                Field::Text { ref name, ref value } => {
+                    body.extend(
+                        format!(
+                            "Content-Disposition: form-data; name=\"{}\"\r\n\r\n",
+                            escape_quotes(name)
+                        ).into_bytes(),
+                    );
+                    body.extend(value.as_bytes());
+                }
+                Field::File {
+                    ref name,
+                    ref filename,
+                    ref content_type,
+                    ref data,
+                } => {
+                    body.extend(
+                        format!(
+                            "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\
+                             Content-Type: {}\r\n\r\n",
+                            escape_quotes(name),
+                            escape_quotes(filename),
+                            content_type
+                        ).into_bytes(),
+                    );
+                    body.extend(data);
+                }
+            }
+
+            body.extend(b"\r\n");
+        }
+
+        body.extend(format!("--{}--\r\n", self.boundary).into_bytes());
+        body
+    }
+}
+
+impl Default for Form {
+    fn default() -> Form {
+        Form::new()
+    }
+}
+
+/// A helper for adding fields to a `Request`'s form, keeping the request's
+/// body and `Content-Type` up to date.
+#[derive(Debug)]
+pub struct FormBuilder<'a> {
+    req: &'a mut Request,
+}
+
+impl<'a> FormBuilder<'a> {
+    pub(crate) fn new(req: &'a mut Request) -> FormBuilder<'a> {
+        if req.form.is_none() {
+            req.form = Some(Form::new());
+        }
+
+        FormBuilder { req }
+    }
+
+    /// Add a text field.
+    pub fn text<N, V>(&mut self, name: N, value: V) -> &mut Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.push(Field::Text {
+            name: name.into(),
+            value: value.into(),
+        })
+    }
+
+    /// Add a file part, reading its contents from disk.
+    pub fn file<N, P>(&mut self, name: N, path: P) -> Result<&mut Self>
+    where
+        N: Into<String>,
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let mut data = Vec::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .chain_err(|| format!("Unable to read {}", path.display()))?;
+
+        let filename = path.file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let content_type = guess_content_type(path);
+
+        Ok(self.file_bytes(name, filename, content_type, data))
+    }
+
+    /// Add a file part from memory.
+    pub fn file_bytes<N, F, C>(
+        &mut self,
+        name: N,
+        filename: F,
+        content_type: C,
+        data: Vec<u8>,
+    ) -> &mut Self
+    where
+        N: Into<String>,
+        F: Into<String>,
+        C: Into<String>,
+    {
+        self.push(Field::File {
+            name: name.into(),
+            filename: filename.into(),
+            content_type: content_type.into(),
+            data,
+        })
+    }
+
+    pub(crate) fn set_multipart(&mut self) {
+        if let Some(ref mut form) = self.req.form {
+            form.multipart = true;
+        }
+
+        self.req.sync_form();
+    }
+
+    fn push(&mut self, field: Field) -> &mut Self {
+        if let Some(ref mut form) = self.req.form {
+            form.fields.push(field);
+        }
+
+        self.req.sync_form();
+        self
+    }
+}
+
+fn guess_content_type(path: &Path) -> &'static str {
+    let extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+
+    match extension.as_ref().map(|e| e.as_str()) {
+        Some("txt") => "text/plain",
+        Some("html") | Some("htm") => "text/html",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+fn escape_quotes(s: &str) -> String {
+    s.replace('"', "\\\"")
+}
+
+/// Generate a multipart boundary which is vanishingly unlikely to appear in
+/// the body.
+fn new_boundary() -> String {
+    static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let now = Utc::now();
+    let count = COUNTER.fetch_add(1, Ordering::SeqCst);
+
+    format!(
+        "------------------------{:x}{:08x}{:04x}",
+        now.timestamp(),
+        now.timestamp_subsec_nanos(),
+        count & 0xffff
+    )
+}
@@ -0,0 +1,74 @@
+//! A small in-memory record of recently sent requests, primarily so users can
+//! attach it to bug reports.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use chrono::Local;
+use reqwest::Url;
+
+use errors::*;
+use {Request, Response};
+
+
+/// The maximum number of requests to remember.
+const MAX_ENTRIES: usize = 50;
+
+lazy_static! {
+    static ref HISTORY: Mutex<VecDeque<HistoryEntry>> = Mutex::new(VecDeque::new());
+}
+
+/// A redacted summary of a request which was sent.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    pub timestamp: String,
+    pub method: String,
+    /// The destination URL, with any passwords and query values stripped
+    /// out.
+    pub url: String,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+/// Remember that a request was sent.
+pub(crate) fn record(req: &Request, outcome: &Result<Response>) {
+    let entry = HistoryEntry {
+        timestamp: Local::now().to_rfc3339(),
+        method: req.method.to_string(),
+        url: redact(&req.destination),
+        status: outcome.as_ref().ok().map(|r| r.status.as_u16()),
+        error: outcome.as_ref().err().map(|e| e.to_string()),
+    };
+
+    if let Ok(mut history) = HISTORY.lock() {
+        if history.len() >= MAX_ENTRIES {
+            history.pop_front();
+        }
+        history.push_back(entry);
+    }
+}
+
+/// Get a copy of the most recently sent requests, oldest first.
+pub fn recent() -> Vec<HistoryEntry> {
+    HISTORY
+        .lock()
+        .map(|h| h.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Strip anything sensitive out of a URL.
+pub fn redact(url: &Url) -> String {
+    let mut url = url.clone();
+
+    if url.password().is_some() {
+        let _ = url.set_password(Some("REDACTED"));
+    }
+
+    let keys: Vec<String> = url.query_pairs().map(|(k, _)| k.into_owned()).collect();
+    if !keys.is_empty() {
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(keys.iter().map(|k| (k, "REDACTED")));
+    }
+
+    url.into_string()
+}
@@ -1,51 +1,138 @@
 //! The business logic for a REST client.
 
+extern crate base64;
 extern crate chrono;
 extern crate cookie;
 #[macro_use]
 extern crate error_chain;
 extern crate fern;
+extern crate flate2;
+extern crate futures;
+#[macro_use]
+extern crate lazy_static;
 extern crate libc;
 extern crate libloading;
 #[macro_use]
 extern crate log;
+extern crate native_tls;
 extern crate reqwest;
+extern crate semver;
 extern crate env_logger;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate tar;
+extern crate threadpool;
+extern crate toml;
+extern crate url;
 
 mod plugins;
+mod abi;
+mod c_plugin;
+mod context;
+mod dependencies;
 pub mod errors;
 pub mod utils;
 pub mod ffi;
+mod client;
+mod options;
 mod request;
 mod response;
+mod quota;
+pub mod urls;
+pub mod openapi;
+mod cancellation;
+pub mod history;
+pub mod support;
+mod form;
+pub mod cookies;
+mod transport;
+mod tunnel;
+mod redirect;
+mod rate_limit;
+pub mod auth;
+pub mod validate;
+pub mod sse;
+pub mod template;
+pub mod session;
+pub mod recorder;
+pub mod mock;
+pub mod sandbox;
 
+pub use client::HttpClient;
+pub use options::RequestOptions;
 pub use request::Request;
-pub use response::Response;
-pub use plugins::{Plugin, PluginManager};
+pub use response::{Response, Timing};
+pub use plugins::{HookFuture, HookResult, LoadReport, Plugin, PluginConfig, PluginManager,
+                  PluginMetadata};
+pub use abi::{AbiVersion, Capabilities, PluginHandle, PluginInfo, PluginVTable, PollStatus,
+              RawFuture, RawStr, Waker, PLUGIN_API_VERSION};
+pub use c_plugin::{CPluginVTable, C_PLUGIN_API_VERSION};
+pub use context::{Logger, PluginContext, Scratch};
+pub use dependencies::Dependency;
+pub use quota::{Quota, QuotaTracker};
+pub use cancellation::CancellationToken;
+pub use form::{Form, FormBuilder};
+pub use transport::{ClientBuilder, ClientIdentity, TransportConfig, VersionPreference};
+pub use redirect::{RedirectPolicy, DEFAULT_MAX_REDIRECTS};
+pub use rate_limit::{Rate, RateLimiter};
+pub use validate::ValidationWarning;
+pub use template::{Environment, RequestTemplate};
+pub use session::Session;
+pub use recorder::Recorder;
+pub use mock::MockTransport;
 
-use reqwest::Client;
 use errors::*;
 
 
-/// Perform a single `GET` request.
+/// Send a single request.
+///
+/// This creates a brand new `HttpClient` every time, so if you are sending
+/// more than a handful of requests you should create your own `HttpClient`
+/// and reuse it.
 pub fn send_request(req: &Request) -> Result<Response> {
-    info!("Sending a GET request to {}", req.destination);
-    if log_enabled!(::log::LogLevel::Debug) {
-        debug!("Sending {} Headers", req.headers.len());
-        for header in req.headers.iter() {
-            debug!("\t{}: {}", header.name(), header.value_string());
-        }
-        for cookie in req.cookies.iter() {
-            debug!("\t{} = {}", cookie.name(), cookie.value());
+    HttpClient::new()?.send(req)
+}
+
+/// Send a single request, passing the response body to `on_chunk` as it
+/// arrives instead of keeping the whole thing in memory.
+pub fn send_request_streaming<F>(req: &Request, on_chunk: F) -> Result<Response>
+where
+    F: FnMut(&[u8]),
+{
+    HttpClient::new()?.send_streaming(req, on_chunk)
+}
+
+/// Send a request on behalf of a particular environment, making sure it won't
+/// exceed that environment's quotas.
+///
+/// If a quota would be exceeded the request is never sent. Instead any loaded
+/// plugins are notified via their `on_quota_exceeded()` hook and an
+/// `ErrorKind::QuotaExceeded` error is returned.
+///
+/// Only requests which were actually sent count towards the quota.
+pub fn send_request_metered(
+    req: &Request,
+    environment: &str,
+    quotas: &mut QuotaTracker,
+    plugins: Option<&mut PluginManager>,
+) -> Result<Response> {
+    if let Err(e) = quotas.check(environment, req) {
+        if let Some(pm) = plugins {
+            pm.quota_exceeded(environment, req);
         }
+        return Err(e);
     }
 
-    let client = Client::builder()
-        .build()
-        .chain_err(|| "The native TLS backend couldn't be initialized")?;
+    let response = send_request(req)?;
+
+    // The request has already gone out, so failing to save the usage
+    // shouldn't throw the response away
+    if let Err(e) = quotas.record(environment, req) {
+        warn!("Unable to update the quota usage for {:?}: {}", environment, e);
+    }
 
-    client
-        .execute(req.to_reqwest())
-        .chain_err(|| "The request failed")
-        .and_then(|r| Response::from_reqwest(r))
+    Ok(response)
 }
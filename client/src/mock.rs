@@ -0,0 +1,381 @@
+//! A fake transport which answers requests with canned responses, so the
+//! whole stack (including plugin hooks) can be tested without a network.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use libc::{c_char, c_int};
+use reqwest::{Method, StatusCode, Url};
+use reqwest::header::{ContentLength, Headers};
+
+use cookies;
+use errors::*;
+use ffi::{c_str_to_str, catch_panic, update_last_error};
+use urls::parse_url;
+use {HttpClient, Request, Response, Timing};
+
+
+/// A canned response for requests with a particular method and URL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expectation {
+    pub method: Method,
+    pub url: Url,
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// How many times this expectation has been used.
+    pub calls: usize,
+}
+
+impl Expectation {
+    pub fn new<B: Into<Vec<u8>>>(
+        method: Method,
+        url: Url,
+        status: StatusCode,
+        body: B,
+    ) -> Expectation {
+        Expectation {
+            method,
+            url,
+            status,
+            headers: Headers::new(),
+            body: body.into(),
+            calls: 0,
+        }
+    }
+
+    fn matches(&self, req: &Request) -> bool {
+        self.method == req.method
+            && without_fragment(&self.url) == without_fragment(&req.destination)
+    }
+
+    fn to_response(&self) -> Response {
+        let mut headers = self.headers.clone();
+        headers.set(ContentLength(self.body.len() as u64));
+
+        Response {
+            status: self.status,
+            cookies: cookies::from_headers(&headers),
+            headers,
+            body: self.body.clone(),
+            redirects: Vec::new(),
+            timing: Timing::default(),
+        }
+    }
+}
+
+/// Answers requests using a list of expectations instead of the network.
+///
+/// Clones share the same expectations, so a test can keep a copy around to
+/// check which requests were sent.
+#[derive(Debug, Default, Clone)]
+pub struct MockTransport {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    expectations: Vec<Expectation>,
+    received: Vec<Request>,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// Respond to every `method` request for `url` with `status` and `body`.
+    pub fn expect<B: Into<Vec<u8>>>(&self, method: Method, url: Url, status: StatusCode, body: B) {
+        self.add(Expectation::new(method, url, status, body));
+    }
+
+    pub fn add(&self, expectation: Expectation) {
+        if let Ok(mut state) = self.lock() {
+            state.expectations.push(expectation);
+        }
+    }
+
+    /// Find the first expectation matching the request and use it to create
+    /// a response.
+    pub(crate) fn respond(&self, req: &Request) -> Result<Response> {
+        let mut state = self.lock()?;
+        state.received.push(req.clone());
+
+        match state.expectations.iter_mut().find(|e| e.matches(req)) {
+            Some(expectation) => {
+                debug!("Mocking a {} response to {}", expectation.status, req.destination);
+                expectation.calls += 1;
+                Ok(expectation.to_response())
+            }
+            None => bail!("No mock response was set up for {} {}", req.method, req.destination),
+        }
+    }
+
+    /// Every request received so far, in order.
+    pub fn received(&self) -> Vec<Request> {
+        self.lock()
+            .map(|state| state.received.clone())
+            .unwrap_or_default()
+    }
+
+    /// Make sure every expectation was used at least once.
+    pub fn verify(&self) -> Result<()> {
+        let state = self.lock()?;
+
+        let unused: Vec<String> = state
+            .expectations
+            .iter()
+            .filter(|e| e.calls == 0)
+            .map(|e| format!("{} {}", e.method, e.url))
+            .collect();
+
+        if unused.is_empty() {
+            Ok(())
+        } else {
+            bail!("These requests were never sent: {}", unused.join(", "))
+        }
+    }
+
+    /// Forget all expectations and received requests.
+    pub fn reset(&self) {
+        if let Ok(mut state) = self.lock() {
+            state.expectations.clear();
+            state.received.clear();
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<State>> {
+        self.state
+            .lock()
+            .map_err(|_| Error::from("The mock transport's lock is poisoned"))
+    }
+}
+
+fn without_fragment(url: &Url) -> Url {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    url
+}
+
+/// Stop using the network and answer the client's requests using canned
+/// responses registered with [`mock_expect()`].
+///
+/// Returns `0` on success or `-1` on error.
+///
+/// [`mock_expect()`]: fn.mock_expect.html
+#[no_mangle]
+pub unsafe extern "C" fn client_enable_mock(client: *mut HttpClient) -> c_int {
+    catch_panic(-1, || {
+        if client.is_null() {
+            update_last_error(Error::from("Null pointer passed to client_enable_mock()"));
+            return -1;
+        }
+
+        let client = &mut *client;
+        if client.mock().is_none() {
+            client.set_mock(Some(MockTransport::new()));
+        }
+        0
+    })
+}
+
+/// Go back to sending requests over the network, forgetting any mock
+/// responses.
+#[no_mangle]
+pub unsafe extern "C" fn client_disable_mock(client: *mut HttpClient) {
+    catch_panic((), || {
+        if !client.is_null() {
+            (&mut *client).set_mock(None);
+        }
+    })
+}
+
+/// Respond to every `method` request for `url` with `status` and `body` (which
+/// may be null for an empty body). The mock must have been enabled with
+/// [`client_enable_mock()`].
+///
+/// Returns `0` on success or `-1` on error.
+///
+/// [`client_enable_mock()`]: fn.client_enable_mock.html
+#[no_mangle]
+pub unsafe extern "C" fn mock_expect(
+    client: *const HttpClient,
+    method: *const c_char,
+    url: *const c_char,
+    status: c_int,
+    body: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        let mock = match mock_of(client, "mock_expect") {
+            Some(m) => m,
+            None => return -1,
+        };
+
+        let (method, url) = match (c_str_to_str(method, "method"), c_str_to_str(url, "url")) {
+            (Some(m), Some(u)) => (m, u),
+            _ => return -1,
+        };
+        let body = if body.is_null() {
+            ""
+        } else {
+            match c_str_to_str(body, "body") {
+                Some(b) => b,
+                None => return -1,
+            }
+        };
+
+        let method = match method.parse::<Method>() {
+            Ok(m) => m,
+            Err(e) => {
+                let msg = format!("\"{}\" isn't a valid HTTP method", method);
+                update_last_error(Error::with_chain(e, msg));
+                return -1;
+            }
+        };
+        let url = match parse_url(url) {
+            Ok(u) => u,
+            Err(e) => {
+                update_last_error(e);
+                return -1;
+            }
+        };
+        if status < 100 || status > 999 {
+            update_last_error(Error::from(format!("Invalid status code, {}", status)));
+            return -1;
+        }
+
+        mock.expect(method, url, StatusCode::from(status as u16), body);
+        0
+    })
+}
+
+/// Check that every mock response was used at least once.
+///
+/// Returns `0` if they were or `-1` otherwise.
+#[no_mangle]
+pub unsafe extern "C" fn mock_verify(client: *const HttpClient) -> c_int {
+    catch_panic(-1, || {
+        let mock = match mock_of(client, "mock_verify") {
+            Some(m) => m,
+            None => return -1,
+        };
+
+        match mock.verify() {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Get the number of requests the mock has received, or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn mock_received_count(client: *const HttpClient) -> c_int {
+    catch_panic(-1, || match mock_of(client, "mock_received_count") {
+        Some(mock) => mock.received().len() as c_int,
+        None => -1,
+    })
+}
+
+unsafe fn mock_of<'a>(client: *const HttpClient, function: &str) -> Option<&'a MockTransport> {
+    if client.is_null() {
+        update_last_error(Error::from(format!("Null pointer passed to {}()", function)));
+        return None;
+    }
+
+    let mock = (&*client).mock();
+    if mock.is_none() {
+        let msg = format!("{}() was called before client_enable_mock()", function);
+        update_last_error(Error::from(msg));
+    }
+    mock
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use context::PluginContext;
+    use plugins::{Plugin, PluginManager};
+    use HookResult;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn requests_are_answered_by_the_matching_expectation() {
+        let mock = MockTransport::new();
+        mock.expect(Method::Get, url("http://example.com/a"), StatusCode::Ok, "a");
+        mock.expect(Method::Post, url("http://example.com/a"), StatusCode::Created, "");
+        mock.expect(Method::Get, url("http://example.com/b"), StatusCode::NotFound, "b");
+
+        let got = mock.respond(&Request::new(url("http://example.com/b"), Method::Get)).unwrap();
+        assert_eq!(got.status, StatusCode::NotFound);
+        assert_eq!(got.body, b"b");
+
+        let req = Request::new(url("http://example.com/a"), Method::Post);
+        assert_eq!(mock.respond(&req).unwrap().status, StatusCode::Created);
+
+        // Fragments never get sent to the server, so they're ignored
+        let req = Request::new(url("http://example.com/a#section"), Method::Get);
+        assert_eq!(mock.respond(&req).unwrap().body, b"a");
+
+        let req = Request::new(url("http://example.com/a"), Method::Delete);
+        assert!(mock.respond(&req).is_err());
+        assert_eq!(mock.received().len(), 4);
+    }
+
+    #[test]
+    fn verify_complains_about_unused_expectations() {
+        let mock = MockTransport::new();
+        mock.expect(Method::Get, url("http://example.com/used"), StatusCode::Ok, "");
+        mock.expect(Method::Get, url("http://example.com/unused"), StatusCode::Ok, "");
+
+        mock.respond(&Request::new(url("http://example.com/used"), Method::Get)).unwrap();
+
+        let err = mock.verify().unwrap_err();
+        assert!(err.to_string().contains("http://example.com/unused"), "{}", err);
+        assert!(!err.to_string().contains("http://example.com/used"), "{}", err);
+
+        mock.reset();
+        assert!(mock.verify().is_ok());
+        assert!(mock.received().is_empty());
+    }
+
+    /// Tags outgoing requests and shouts the response body.
+    struct Shouty;
+
+    impl Plugin for Shouty {
+        fn name(&self) -> &str {
+            "shouty"
+        }
+
+        fn pre_send(&self, _ctx: &PluginContext, request: &mut Request) -> HookResult {
+            request.headers.set_raw("X-Shouty", "yes");
+            HookResult::Continue
+        }
+
+        fn post_receive(&self, _ctx: &PluginContext, response: &mut Response) {
+            response.body = response.body.to_ascii_uppercase();
+        }
+    }
+
+    #[test]
+    fn plugins_run_against_mocked_responses() {
+        let mock = MockTransport::new();
+        mock.expect(Method::Get, url("http://example.com/"), StatusCode::Ok, "hello");
+        let mut client = HttpClient::new().unwrap();
+        client.set_mock(Some(mock.clone()));
+        let mut plugins = PluginManager::new();
+        plugins.register_static(Box::new(Shouty)).unwrap();
+
+        let req = Request::new(url("http://example.com/"), Method::Get);
+        let response = client.send_with_plugins(&req, &mut plugins).unwrap();
+
+        assert_eq!(response.body, b"HELLO");
+        let received = mock.received();
+        assert_eq!(received.len(), 1);
+        assert!(received[0].headers.get_raw("X-Shouty").is_some());
+        assert!(mock.verify().is_ok());
+    }
+}
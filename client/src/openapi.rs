@@ -0,0 +1,580 @@
+//! Turn an OpenAPI 3.0 document into requests.
+//!
+//! Rather than making C hosts construct every URL, header, and body by hand,
+//! they can load the API's OpenAPI document and ask for an operation by its
+//! `operationId`. We take care of path templating, parameter serialization,
+//! and content types, and can check the response against the schemas the
+//! document declares.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::ptr;
+use std::str;
+use cookie::Cookie;
+use libc::{c_char, c_int};
+use reqwest::Method;
+use serde_json::{self, Map, Value};
+use serde_yaml;
+
+use errors::*;
+use ffi::{c_str_to_str, catch_panic, update_last_error};
+use urls::parse_url;
+use {Request, Response};
+
+
+const METHODS: &[&str] = &[
+    "get", "put", "post", "delete", "options", "head", "patch", "trace"
+];
+
+/// A parsed OpenAPI 3.0 document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenApi {
+    document: Value,
+}
+
+/// An operation found in the document.
+#[derive(Debug, Clone, PartialEq)]
+struct Operation<'a> {
+    path: &'a str,
+    method: &'a str,
+    path_item: &'a Value,
+    definition: &'a Value,
+}
+
+impl OpenApi {
+    /// Parse an OpenAPI document written as either JSON or YAML.
+    pub fn parse(src: &str) -> Result<OpenApi> {
+        let document: Value = match serde_json::from_str(src) {
+            Ok(doc) => doc,
+            Err(_) => serde_yaml::from_str(src).chain_err(|| "Unable to parse the document")?,
+        };
+
+        match document.get("openapi").and_then(|v| v.as_str()) {
+            Some(version) if version.starts_with("3.") => {}
+            Some(version) => bail!("Unsupported OpenAPI version, {}", version),
+            None => bail!("The document doesn't look like an OpenAPI 3.0 document"),
+        }
+
+        Ok(OpenApi { document })
+    }
+
+    /// Load an OpenAPI document from disk.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<OpenApi> {
+        let mut src = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut src))
+            .chain_err(|| "Unable to read the OpenAPI document")?;
+
+        OpenApi::parse(&src)
+    }
+
+    /// Create a fully-formed `Request` for an operation.
+    ///
+    /// The `params` should be a JSON object mapping parameter names to their
+    /// values, while `body` (if provided) is serialized according to the
+    /// operation's declared request body content type.
+    pub fn create_request(
+        &self,
+        operation_id: &str,
+        params: &Map<String, Value>,
+        body: Option<&Value>,
+    ) -> Result<Request> {
+        let op = self.operation(operation_id)?;
+        let mut path = op.path.to_string();
+        let mut query = Vec::new();
+        let mut headers = Vec::new();
+        let mut cookies = Vec::new();
+
+        for param in self.parameters(&op) {
+            let name = param
+                .get("name")
+                .and_then(|n| n.as_str())
+                .ok_or("Parameters must have a name")?;
+            let location = param.get("in").and_then(|n| n.as_str()).unwrap_or("");
+            let required = location == "path"
+                || param.get("required").and_then(|r| r.as_bool()) == Some(true);
+
+            let value = match params.get(name) {
+                Some(v) => v,
+                None if required => bail!("The \"{}\" parameter is required", name),
+                None => continue,
+            };
+
+            match location {
+                "path" => {
+                    let encoded = percent_encode(&simple_style(value));
+                    path = path.replace(&format!("{{{}}}", name), &encoded);
+                }
+                "query" => query.extend(form_style(name, value)),
+                "header" => headers.push((name.to_string(), simple_style(value))),
+                "cookie" => cookies.push((name.to_string(), simple_style(value))),
+                other => bail!("Unknown parameter location, {:?}", other),
+            }
+        }
+
+        let mut url = parse_url(&format!("{}{}", self.server_url()?, path))?;
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+
+        let method = match op.method {
+            "get" => Method::Get,
+            "put" => Method::Put,
+            "post" => Method::Post,
+            "delete" => Method::Delete,
+            "options" => Method::Options,
+            "head" => Method::Head,
+            "patch" => Method::Patch,
+            _ => Method::Trace,
+        };
+
+        let mut req = Request::new(url, method);
+
+        for (name, value) in headers {
+            req.headers.set_raw(name, value);
+        }
+        for (name, value) in cookies {
+            req.cookies.add(Cookie::new(name, value));
+        }
+
+        if let Some(body) = body {
+            let content = self.resolve(&op.definition["requestBody"])["content"].as_object();
+            let content_type = match content {
+                Some(c) if c.contains_key("application/json") => "application/json".to_string(),
+                Some(c) => c.keys()
+                    .next()
+                    .cloned()
+                    .unwrap_or_else(|| String::from("application/json")),
+                None => bail!("The \"{}\" operation doesn't accept a body", operation_id),
+            };
+
+            req.body = Some(serialize_body(&content_type, body)?);
+            req.headers.set_raw("Content-Type", content_type);
+        }
+
+        Ok(req)
+    }
+
+    /// Check a `Response` against the schemas declared for an operation,
+    /// returning a list of everything which doesn't match.
+    pub fn validate_response(&self, operation_id: &str, res: &Response) -> Result<Vec<String>> {
+        let op = self.operation(operation_id)?;
+        let responses = &op.definition["responses"];
+
+        let code = res.status.as_u16();
+        let declared = [
+            code.to_string(),
+            format!("{}XX", code / 100),
+            String::from("default"),
+        ];
+        let expected = match declared.iter().filter_map(|c| responses.get(c)).next() {
+            Some(r) => self.resolve(r),
+            None => return Ok(vec![format!("Status code {} isn't declared", code)]),
+        };
+
+        let content = match expected.get("content").and_then(|c| c.as_object()) {
+            Some(c) => c,
+            None => return Ok(Vec::new()),
+        };
+
+        let content_type = res.headers
+            .get_raw("Content-Type")
+            .and_then(|raw| raw.one())
+            .and_then(|ct| str::from_utf8(ct).ok())
+            .map(|ct| ct.split(';').next().unwrap_or("").trim().to_string())
+            .unwrap_or_default();
+
+        let media = match content.get(&content_type).or_else(|| content.get("*/*")) {
+            Some(m) => m,
+            None => return Ok(vec![format!("Unexpected content type, {:?}", content_type)]),
+        };
+
+        let schema = match media.get("schema") {
+            Some(s) => s,
+            None => return Ok(Vec::new()),
+        };
+
+        if !content_type.ends_with("json") {
+            return Ok(Vec::new());
+        }
+
+        let body: Value = match serde_json::from_slice(&res.body) {
+            Ok(b) => b,
+            Err(e) => return Ok(vec![format!("The body isn't valid JSON, {}", e)]),
+        };
+
+        let mut violations = Vec::new();
+        self.validate(schema, &body, "", &mut violations);
+        Ok(violations)
+    }
+
+    fn operation(&self, operation_id: &str) -> Result<Operation> {
+        let paths = self.document
+            .get("paths")
+            .and_then(|p| p.as_object())
+            .ok_or("The document has no paths")?;
+
+        for (path, item) in paths {
+            let item = self.resolve(item);
+
+            for method in METHODS {
+                let definition = &item[*method];
+                if definition["operationId"].as_str() == Some(operation_id) {
+                    return Ok(Operation {
+                        path,
+                        method,
+                        path_item: item,
+                        definition,
+                    });
+                }
+            }
+        }
+
+        bail!("No operation with the ID \"{}\"", operation_id)
+    }
+
+    /// Get an operation's parameters, with the operation's own parameters
+    /// overriding those declared on the path.
+    fn parameters<'a>(&'a self, op: &Operation<'a>) -> Vec<&'a Value> {
+        let mut params: Vec<&Value> = Vec::new();
+
+        let declared = [&op.path_item["parameters"], &op.definition["parameters"]];
+        for list in declared.iter().filter_map(|p| p.as_array()) {
+            for param in list {
+                let param = self.resolve(param);
+                params.retain(|p| p["name"] != param["name"] || p["in"] != param["in"]);
+                params.push(param);
+            }
+        }
+
+        params
+    }
+
+    /// The server URL, with any server variables replaced by their defaults.
+    fn server_url(&self) -> Result<String> {
+        let server = &self.document["servers"][0];
+        let mut url = server["url"]
+            .as_str()
+            .ok_or("The document doesn't declare a server URL")?
+            .trim_right_matches('/')
+            .to_string();
+
+        if let Some(variables) = server["variables"].as_object() {
+            for (name, variable) in variables {
+                if let Some(default) = variable["default"].as_str() {
+                    url = url.replace(&format!("{{{}}}", name), default);
+                }
+            }
+        }
+
+        Ok(url)
+    }
+
+    /// Follow a local `$ref` (e.g. `#/components/schemas/User`), returning
+    /// the original value if it isn't a reference.
+    fn resolve<'a>(&'a self, value: &'a Value) -> &'a Value {
+        let mut value = value;
+
+        // Guard against reference cycles
+        for _ in 0..32 {
+            match value["$ref"].as_str() {
+                Some(reference) if reference.starts_with('#') => {
+                    match self.document.pointer(&reference[1..]) {
+                        Some(target) => value = target,
+                        None => return value,
+                    }
+                }
+                _ => return value,
+            }
+        }
+
+        value
+    }
+
+    /// Validate a value against (a useful subset of) JSON Schema.
+    fn validate(&self, schema: &Value, value: &Value, path: &str, violations: &mut Vec<String>) {
+        let schema = self.resolve(schema);
+        let location = if path.is_empty() { "/" } else { path };
+
+        if value.is_null() && schema["nullable"].as_bool() == Some(true) {
+            return;
+        }
+
+        if let Some(expected) = schema["type"].as_str() {
+            let matches = match expected {
+                "object" => value.is_object(),
+                "array" => value.is_array(),
+                "string" => value.is_string(),
+                "integer" => value.is_i64() || value.is_u64(),
+                "number" => value.is_number(),
+                "boolean" => value.is_boolean(),
+                _ => true,
+            };
+
+            if !matches {
+                violations.push(format!("{}: expected {}, found {}", location, expected, value));
+                return;
+            }
+        }
+
+        if let Some(allowed) = schema["enum"].as_array() {
+            if !allowed.contains(value) {
+                violations.push(format!("{}: {} isn't one of the allowed values", location, value));
+            }
+        }
+
+        if let Some(all) = schema["allOf"].as_array() {
+            for sub_schema in all {
+                self.validate(sub_schema, value, path, violations);
+            }
+        }
+
+        for key in &["oneOf", "anyOf"] {
+            if let Some(candidates) = schema[*key].as_array() {
+                let any_match = candidates.iter().any(|s| {
+                    let mut v = Vec::new();
+                    self.validate(s, value, path, &mut v);
+                    v.is_empty()
+                });
+                if !any_match {
+                    violations.push(format!("{}: doesn't match any of the {} schemas", location, key));
+                }
+            }
+        }
+
+        if let Some(object) = value.as_object() {
+            if let Some(required) = schema["required"].as_array() {
+                for name in required.iter().filter_map(|r| r.as_str()) {
+                    if !object.contains_key(name) {
+                        violations.push(format!("{}: missing the \"{}\" property", location, name));
+                    }
+                }
+            }
+
+            let properties = schema["properties"].as_object();
+            for (name, property) in object {
+                let property_path = format!("{}/{}", path, name);
+
+                match properties.and_then(|p| p.get(name)) {
+                    Some(property_schema) => {
+                        self.validate(property_schema, property, &property_path, violations)
+                    }
+                    None => match schema.get("additionalProperties") {
+                        Some(&Value::Bool(false)) => {
+                            violations.push(format!("{}: unexpected property", property_path))
+                        }
+                        Some(additional) if additional.is_object() => {
+                            self.validate(additional, property, &property_path, violations)
+                        }
+                        _ => {}
+                    },
+                }
+            }
+        }
+
+        if let Some(items) = value.as_array() {
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    self.validate(item_schema, item, &format!("{}/{}", path, i), violations);
+                }
+            }
+        }
+    }
+}
+
+/// Serialize a parameter using the `simple` style (comma-separated lists).
+fn simple_style(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) => s.clone(),
+        Value::Array(ref items) => items.iter().map(simple_style).collect::<Vec<_>>().join(","),
+        Value::Object(ref map) => map.iter()
+            .map(|(k, v)| format!("{},{}", k, simple_style(v)))
+            .collect::<Vec<_>>()
+            .join(","),
+        Value::Null => String::new(),
+        ref other => other.to_string(),
+    }
+}
+
+/// Serialize a query parameter using the `form` style with `explode`, the
+/// OpenAPI default.
+fn form_style(name: &str, value: &Value) -> Vec<(String, String)> {
+    match *value {
+        Value::Array(ref items) => items
+            .iter()
+            .map(|item| (name.to_string(), simple_style(item)))
+            .collect(),
+        Value::Object(ref map) => map.iter()
+            .map(|(k, v)| (k.clone(), simple_style(v)))
+            .collect(),
+        ref other => vec![(name.to_string(), simple_style(other))],
+    }
+}
+
+fn serialize_body(content_type: &str, body: &Value) -> Result<Vec<u8>> {
+    if content_type == "application/x-www-form-urlencoded" {
+        let fields = body.as_object()
+            .ok_or("Form bodies must be an object")?
+            .iter()
+            .flat_map(|(name, value)| form_style(name, value))
+            .map(|(k, v)| format!("{}={}", percent_encode(&k), percent_encode(&v)))
+            .collect::<Vec<_>>();
+
+        Ok(fields.join("&").into_bytes())
+    } else if content_type.ends_with("json") {
+        serde_json::to_vec(body).chain_err(|| "Unable to serialize the body")
+    } else {
+        match *body {
+            Value::String(ref s) => Ok(s.clone().into_bytes()),
+            _ => bail!("Only strings can be sent as {}", content_type),
+        }
+    }
+}
+
+/// Percent-encode everything except the RFC 3986 "unreserved" characters.
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+
+    for &b in s.as_bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    encoded
+}
+
+/// Load an OpenAPI document from disk, returning a null pointer if it can't
+/// be loaded.
+#[no_mangle]
+pub unsafe extern "C" fn openapi_load(path: *const c_char) -> *mut OpenApi {
+    catch_panic(ptr::null_mut(), || {
+        let path = match c_str_to_str(path, "OpenAPI document path") {
+            Some(p) => p,
+            None => return ptr::null_mut(),
+        };
+
+        match OpenApi::load(path) {
+            Ok(spec) => Box::into_raw(Box::new(spec)),
+            Err(e) => {
+                update_last_error(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Destroy an `OpenApi` document once you are done with it.
+#[no_mangle]
+pub unsafe extern "C" fn openapi_destroy(spec: *mut OpenApi) {
+    catch_panic((), || {
+        if !spec.is_null() {
+            drop(Box::from_raw(spec));
+        }
+    })
+}
+
+/// Create a `Request` for the operation with the provided `operationId`.
+///
+/// `params` is a JSON object containing the values for each parameter and may
+/// be null if the operation has no required parameters. `body` is the JSON
+/// request body, or null if there is none.
+///
+/// Returns a null pointer if the request couldn't be created. Don't forget to
+/// destroy the request with `request_destroy()` afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn operation_create(
+    spec: *const OpenApi,
+    operation_id: *const c_char,
+    params: *const c_char,
+    body: *const c_char,
+) -> *mut Request {
+    catch_panic(ptr::null_mut(), || {
+        if spec.is_null() {
+            update_last_error(Error::from("Null pointer passed to operation_create()"));
+            return ptr::null_mut();
+        }
+
+        let operation_id = match c_str_to_str(operation_id, "operation ID") {
+            Some(id) => id,
+            None => return ptr::null_mut(),
+        };
+
+        let params = if params.is_null() {
+            Map::new()
+        } else {
+            let parsed = c_str_to_str(params, "parameters")
+                .map(serde_json::from_str::<Map<String, Value>>);
+
+            match parsed {
+                Some(Ok(p)) => p,
+                Some(Err(e)) => {
+                    update_last_error(Error::with_chain(e, "The parameters must be a JSON object"));
+                    return ptr::null_mut();
+                }
+                None => return ptr::null_mut(),
+            }
+        };
+
+        let body: Option<Value> = if body.is_null() {
+            None
+        } else {
+            match c_str_to_str(body, "body").map(serde_json::from_str::<Value>) {
+                Some(Ok(b)) => Some(b),
+                Some(Err(e)) => {
+                    update_last_error(Error::with_chain(e, "The body must be valid JSON"));
+                    return ptr::null_mut();
+                }
+                None => return ptr::null_mut(),
+            }
+        };
+
+        match (&*spec).create_request(operation_id, &params, body.as_ref()) {
+            Ok(req) => Box::into_raw(Box::new(req)),
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Unable to create the request"));
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Validate a `Response` against the schemas declared for an operation.
+///
+/// Returns the number of problems found (`0` means the response is valid) or
+/// `-1` if validation couldn't be done. The problems are joined together and
+/// available as the last error message.
+#[no_mangle]
+pub unsafe extern "C" fn openapi_validate_response(
+    spec: *const OpenApi,
+    operation_id: *const c_char,
+    res: *const Response,
+) -> c_int {
+    catch_panic(-1, || {
+        if spec.is_null() || res.is_null() {
+            update_last_error(Error::from("Null pointer passed to openapi_validate_response()"));
+            return -1;
+        }
+
+        let operation_id = match c_str_to_str(operation_id, "operation ID") {
+            Some(id) => id,
+            None => return -1,
+        };
+
+        match (&*spec).validate_response(operation_id, &*res) {
+            Ok(ref violations) if violations.is_empty() => 0,
+            Ok(violations) => {
+                update_last_error(Error::from(violations.join("\n")));
+                violations.len() as c_int
+            }
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
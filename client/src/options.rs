@@ -0,0 +1,119 @@
+use std::time::Duration;
+use reqwest::Method;
+
+
+/// How long to wait before the first retry if no backoff is specified.
+pub const DEFAULT_BACKOFF_MS: u64 = 500;
+
+/// The longest we'll ever wait between retries.
+pub const MAX_BACKOFF_MS: u64 = 5 * 60 * 1000;
+
+/// Timeouts and retry behaviour used when sending a request.
+///
+/// Every field is optional so options set on a `Request` can override just
+/// the bits they care about, falling back to the `HttpClient`'s options for
+/// everything else.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct RequestOptions {
+    /// How long to wait on the server before giving up. The transport applies
+    /// this to connecting and to each read or write, not to the request as a
+    /// whole.
+    ///
+    /// There are no separate connect and read timeouts because the transport
+    /// only has a single timeout for both.
+    pub timeout: Option<Duration>,
+    /// How many times to retry a request which failed for a transient
+    /// reason (e.g. a dropped connection or a `5xx` status code).
+    pub retries: Option<u32>,
+    /// How long to wait before the first retry. The delay doubles for each
+    /// subsequent attempt, up to `MAX_BACKOFF_MS`.
+    pub backoff: Option<Duration>,
+    /// Also retry requests whose method isn't idempotent (e.g. `POST`).
+    /// They aren't retried by default because the server may have already
+    /// acted on the failed attempt.
+    pub retry_non_idempotent: Option<bool>,
+    /// Treat `4xx` and `5xx` responses as errors instead of returning them
+    /// like any other response.
+    pub strict: Option<bool>,
+}
+
+impl RequestOptions {
+    /// Fill in any unset options with those from `fallback`.
+    pub fn or(self, fallback: RequestOptions) -> RequestOptions {
+        RequestOptions {
+            timeout: self.timeout.or(fallback.timeout),
+            retries: self.retries.or(fallback.retries),
+            backoff: self.backoff.or(fallback.backoff),
+            retry_non_idempotent: self.retry_non_idempotent.or(fallback.retry_non_idempotent),
+            strict: self.strict.or(fallback.strict),
+        }
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(0)
+    }
+
+    /// How many times a request using `method` may be retried.
+    pub fn retries_for(&self, method: &Method) -> u32 {
+        if method.idempotent() || self.retry_non_idempotent.unwrap_or(false) {
+            self.retries()
+        } else {
+            0
+        }
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict.unwrap_or(false)
+    }
+
+    /// How long to wait before making retry number `attempt` (starting from
+    /// 0).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let base = self.backoff
+            .unwrap_or_else(|| Duration::from_millis(DEFAULT_BACKOFF_MS));
+        let max = Duration::from_millis(MAX_BACKOFF_MS);
+
+        base.checked_mul(1 << attempt.min(16))
+            .map(|delay| delay.min(max))
+            .unwrap_or(max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_backoff(ms: u64) -> RequestOptions {
+        RequestOptions {
+            backoff: Some(Duration::from_millis(ms)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_after_each_attempt() {
+        let options = with_backoff(100);
+        let delays: Vec<_> = (0..4).map(|attempt| options.backoff(attempt)).collect();
+
+        let should_be: Vec<_> = vec![100, 200, 400, 800]
+            .into_iter()
+            .map(Duration::from_millis)
+            .collect();
+        assert_eq!(delays, should_be);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let max = Duration::from_millis(MAX_BACKOFF_MS);
+
+        assert_eq!(with_backoff(100).backoff(100), max);
+        assert_eq!(with_backoff(MAX_BACKOFF_MS * 2).backoff(0), max);
+    }
+
+    #[test]
+    fn huge_backoffs_dont_overflow() {
+        let options = with_backoff(u64::max_value());
+
+        assert_eq!(options.backoff(16), Duration::from_millis(MAX_BACKOFF_MS));
+    }
+}
@@ -1,62 +1,433 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env::consts::DLL_EXTENSION;
 use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::fmt::{self, Formatter, Debug};
 use std::any::Any;
+use std::cmp::Reverse;
+use std::mem;
+use futures::{future, Future};
 use libloading::{Library, Symbol};
+use reqwest::Url;
+use toml::{self, Value};
 
+use abi::{AbiVersion, Capabilities, PluginHandle, PluginVTable};
+use c_plugin::{CPlugin, CPluginVTable};
+use context::PluginContext;
+use dependencies::{self, Dependency};
 use errors::*;
+use sandbox::{self, SandboxedPlugin};
+use template::flatten_toml;
+use validate::ValidationWarning;
 use {Request, Response};
 
 
 /// A plugin which allows you to add extra functionality to the REST client.
+///
+/// The host never sees the trait object itself. Instead [`declare_plugin!`]
+/// turns the plugin into a `PluginVTable`, whose layout doesn't depend on
+/// the compiler version.
+///
+/// [`declare_plugin!`]: macro.declare_plugin.html
 pub trait Plugin: Any + Send + Sync {
     /// Get a name describing the `Plugin`.
-    fn name(&self) -> &'static str;
+    fn name(&self) -> &str;
+    /// The plugin's version number. This should be a semver version, like
+    /// `"1.2.3"`.
+    fn version(&self) -> &str {
+        "unknown"
+    }
+    /// Who wrote the plugin.
+    fn author(&self) -> &str {
+        ""
+    }
+    /// A short, human readable explanation of what the plugin does.
+    fn description(&self) -> &str {
+        ""
+    }
+    /// What the plugin does, so the host can show it to the user.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::NONE
+    }
+    /// Plugins with a higher priority have their hooks run first. Plugins
+    /// with the same priority run in the order they were loaded.
+    fn priority(&self) -> i32 {
+        0
+    }
+    /// Other plugins which must be loaded before this one.
+    fn dependencies(&self) -> Vec<Dependency> {
+        Vec::new()
+    }
     /// A callback fired immediately after the plugin is loaded. Usually used
     /// for initialization.
-    fn on_plugin_load(&self) {}
+    ///
+    /// Every hook gets the `PluginContext` shared by all plugins loaded into
+    /// the same `PluginManager`.
+    fn on_plugin_load(&self, _ctx: &PluginContext) {}
     /// A callback fired immediately before the plugin is unloaded. Use this if
     /// you need to do any cleanup.
-    fn on_plugin_unload(&self) {}
-    /// Inspect (and possibly mutate) the request before it is sent.
-    fn pre_send(&self, _request: &mut Request) {}
+    fn on_plugin_unload(&self, _ctx: &PluginContext) {}
+    /// The plugin's settings, fired after `on_plugin_load()` if there are
+    /// any and again whenever they change.
+    fn on_configure(&self, _ctx: &PluginContext, _config: &PluginConfig) {}
+    /// Inspect (and possibly mutate) the request before it is sent. Plugins
+    /// can also stop the request from being sent, or answer it themselves.
+    fn pre_send(&self, _ctx: &PluginContext, _request: &mut Request) -> HookResult {
+        HookResult::Continue
+    }
     /// Inspect and/or mutate the received response before it is displayed to
     /// the user.
-    fn post_receive(&self, _response: &mut Response) {}
+    fn post_receive(&self, _ctx: &PluginContext, _response: &mut Response) {}
+    /// An asynchronous version of `pre_send()`, for plugins which need to do
+    /// IO of their own (e.g. refreshing an access token) without blocking.
+    /// The request is handed back when the future resolves.
+    ///
+    /// By default this just calls `pre_send()`.
+    fn pre_send_async<'a>(
+        &'a self,
+        ctx: &'a PluginContext,
+        request: Request,
+    ) -> HookFuture<'a, (Request, HookResult)> {
+        let mut request = request;
+        let result = self.pre_send(ctx, &mut request);
+        Box::new(future::ok((request, result)))
+    }
+    /// An asynchronous version of `post_receive()`.
+    ///
+    /// By default this just calls `post_receive()`.
+    fn post_receive_async<'a>(
+        &'a self,
+        ctx: &'a PluginContext,
+        response: Response,
+    ) -> HookFuture<'a, Response> {
+        let mut response = response;
+        self.post_receive(ctx, &mut response);
+        Box::new(future::ok(response))
+    }
+    /// Sending the request failed.
+    fn on_error(&self, _ctx: &PluginContext, _error: &Error, _request: &Request) {}
+    /// The server redirected us to `url`. The request about to be sent there
+    /// can be changed (e.g. to add credentials for the new host).
+    fn on_redirect(&self, _ctx: &PluginContext, _url: &Url, _request: &mut Request) {}
+    /// The request is about to be sent again because the last attempt failed.
+    /// `attempt` starts from 1 for the first retry.
+    fn on_retry(&self, _ctx: &PluginContext, _attempt: u32, _request: &mut Request) {}
+    /// A request was blocked because sending it would exceed the quota for
+    /// the environment it was sent on behalf of.
+    fn on_quota_exceeded(&self, _ctx: &PluginContext, _environment: &str, _request: &Request) {}
+    /// Do any extra checks on a request when it is validated.
+    fn validate(&self, _ctx: &PluginContext, _request: &Request) -> Vec<ValidationWarning> {
+        Vec::new()
+    }
 }
 
 
+/// Lets a plugin which is only known at runtime be wrapped up in a
+/// `PluginVTable`, like the ones passed to
+/// `PluginManager::register_static()`.
+impl Plugin for Box<Plugin> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+    fn version(&self) -> &str {
+        (**self).version()
+    }
+    fn author(&self) -> &str {
+        (**self).author()
+    }
+    fn description(&self) -> &str {
+        (**self).description()
+    }
+    fn capabilities(&self) -> Capabilities {
+        (**self).capabilities()
+    }
+    fn priority(&self) -> i32 {
+        (**self).priority()
+    }
+    fn dependencies(&self) -> Vec<Dependency> {
+        (**self).dependencies()
+    }
+    fn on_plugin_load(&self, ctx: &PluginContext) {
+        (**self).on_plugin_load(ctx)
+    }
+    fn on_plugin_unload(&self, ctx: &PluginContext) {
+        (**self).on_plugin_unload(ctx)
+    }
+    fn on_configure(&self, ctx: &PluginContext, config: &PluginConfig) {
+        (**self).on_configure(ctx, config)
+    }
+    fn pre_send(&self, ctx: &PluginContext, request: &mut Request) -> HookResult {
+        (**self).pre_send(ctx, request)
+    }
+    fn post_receive(&self, ctx: &PluginContext, response: &mut Response) {
+        (**self).post_receive(ctx, response)
+    }
+    fn pre_send_async<'a>(
+        &'a self,
+        ctx: &'a PluginContext,
+        request: Request,
+    ) -> HookFuture<'a, (Request, HookResult)> {
+        (**self).pre_send_async(ctx, request)
+    }
+    fn post_receive_async<'a>(
+        &'a self,
+        ctx: &'a PluginContext,
+        response: Response,
+    ) -> HookFuture<'a, Response> {
+        (**self).post_receive_async(ctx, response)
+    }
+    fn on_error(&self, ctx: &PluginContext, error: &Error, request: &Request) {
+        (**self).on_error(ctx, error, request)
+    }
+    fn on_redirect(&self, ctx: &PluginContext, url: &Url, request: &mut Request) {
+        (**self).on_redirect(ctx, url, request)
+    }
+    fn on_retry(&self, ctx: &PluginContext, attempt: u32, request: &mut Request) {
+        (**self).on_retry(ctx, attempt, request)
+    }
+    fn on_quota_exceeded(&self, ctx: &PluginContext, environment: &str, request: &Request) {
+        (**self).on_quota_exceeded(ctx, environment, request)
+    }
+    fn validate(&self, ctx: &PluginContext, request: &Request) -> Vec<ValidationWarning> {
+        (**self).validate(ctx, request)
+    }
+}
+
+/// What should happen to a request after a plugin's `pre_send()` hook.
+#[derive(Debug)]
+pub enum HookResult {
+    /// Carry on as normal.
+    Continue,
+    /// Don't send the request, for the given reason.
+    Abort(String),
+    /// Don't send the request, use this response instead.
+    Respond(Response),
+}
+
+/// The future returned by an asynchronous hook. It may borrow the plugin.
+pub type HookFuture<'a, T> = Box<Future<Item = T, Error = Error> + 'a>;
+
 /// Declare a plugin type and its constructor.
 ///
 /// # Notes
 ///
-/// This works by automatically generating an `extern "C"` function with a
-/// pre-defined signature and symbol name. Therefore you will only be able to
+/// This works by automatically generating `extern "C"` functions with
+/// pre-defined signatures and symbol names. Therefore you will only be able to
 /// declare one plugin per library.
 #[macro_export]
 macro_rules! declare_plugin {
     ($plugin_type:ty, $constructor:path) => {
         #[no_mangle]
-        pub extern "C" fn _plugin_create() -> *mut $crate::Plugin {
+        pub extern "C" fn __plugin_abi_version() -> $crate::AbiVersion {
+            $crate::AbiVersion::current()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn _plugin_create() -> $crate::PluginVTable {
             // make sure the constructor is the correct type.
             let constructor: fn() -> $plugin_type = $constructor;
 
-            let object = constructor();
-            let boxed: Box<$crate::Plugin> = Box::new(object);
-            Box::into_raw(boxed)
+            $crate::PluginVTable::new(constructor())
         }
     };
 }
 
 pub struct PluginManager {
-    plugins: Vec<Box<Plugin>>,
-    loaded_libraries: Vec<Library>,
+    plugins: Vec<LoadedPlugin>,
+    order: Vec<String>,
+    auto_unload: bool,
+    watched: Vec<WatchedDir>,
+    configs: HashMap<String, PluginConfig>,
+    sandbox_host: PathBuf,
+    context: PluginContext,
+}
+
+/// Settings for a single plugin.
+///
+/// These are normally loaded from the plugin's section of a TOML file (see
+/// [`PluginManager::load_config()`]), with nested tables flattened into
+/// dotted names.
+///
+/// [`PluginManager::load_config()`]: struct.PluginManager.html#method.load_config
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PluginConfig {
+    values: BTreeMap<String, String>,
+}
+
+impl PluginConfig {
+    pub fn new() -> PluginConfig {
+        PluginConfig::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|v| v.as_str())
+    }
+
+    pub fn set<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.values.insert(key.into(), value.into());
+    }
+
+    /// Iterate over every setting and its value.
+    pub fn values<'a>(&'a self) -> Box<Iterator<Item = (&'a str, &'a str)> + 'a> {
+        Box::new(self.values.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+    }
+}
+
+/// A snapshot of what a loaded plugin says about itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PluginMetadata {
+    pub name: String,
+    pub version: String,
+    pub author: String,
+    pub description: String,
+    pub capabilities: Capabilities,
+    pub priority: i32,
+    pub dependencies: Vec<Dependency>,
+    /// Has the plugin panicked?
+    pub poisoned: bool,
+    /// Where the plugin was loaded from. This is empty for built-in plugins.
+    pub path: PathBuf,
+}
+
+/// What happened when loading every plugin in a directory.
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    /// The plugins which were loaded successfully.
+    pub loaded: Vec<PathBuf>,
+    /// The plugins which couldn't be loaded, and why.
+    pub failed: Vec<(PathBuf, Error)>,
+}
+
+impl LoadReport {
+    /// Did every plugin load?
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// A directory which is checked for new plugins.
+#[derive(Debug)]
+struct WatchedDir {
+    path: PathBuf,
+    /// Every file we've already tried to load, so broken plugins aren't
+    /// retried each time the directory is checked.
+    seen: HashSet<PathBuf>,
+}
+
+/// A plugin and the library its code lives in.
+struct LoadedPlugin {
+    // Fields are dropped in declaration order, so the plugin is always
+    // destroyed before its library gets unloaded.
+    plugin: PluginHandle,
+    source: Source,
+    /// Empty for built-in plugins.
+    path: PathBuf,
+}
+
+/// Where a plugin's code lives.
+enum Source {
+    Library(Library),
+    /// The plugin runs in its own process.
+    Sandbox,
+    /// The plugin was compiled into the program.
+    Static,
+}
+
+impl LoadedPlugin {
+    unsafe fn open(path: &Path) -> Result<LoadedPlugin> {
+        type PluginCreate = unsafe extern "C" fn() -> PluginVTable;
+        type CPluginCreate = unsafe extern "C" fn() -> CPluginVTable;
+
+        let library = Library::new(path).chain_err(|| "Unable to load the plugin")?;
+
+        let vtable = if let Ok(constructor) = library.get::<CPluginCreate>(b"__plugin_create_c") {
+            debug!("{} is a C plugin", path.display());
+            PluginVTable::new(CPlugin::new(constructor())?)
+        } else {
+            check_abi_version(&library)?;
+
+            let constructor: Symbol<PluginCreate> = library
+                .get(b"_plugin_create")
+                .chain_err(|| "The `_plugin_create` symbol wasn't found.")?;
+            constructor()
+        };
+
+        // The vtable points into the library, so from here on the library
+        // must outlive the plugin
+        let plugin = PluginHandle::new(vtable);
+        debug!("Loaded plugin: {}", plugin.name());
+
+        Ok(LoadedPlugin {
+            plugin,
+            source: Source::Library(library),
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Load the plugin in a `plugin-host` process.
+    fn open_sandboxed(host: &Path, path: &Path) -> Result<LoadedPlugin> {
+        let plugin = PluginHandle::new(PluginVTable::new(SandboxedPlugin::spawn(host, path)?));
+        debug!("Loaded sandboxed plugin: {}", plugin.name());
+
+        Ok(LoadedPlugin {
+            plugin,
+            source: Source::Sandbox,
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn from_static(plugin: Box<Plugin>) -> LoadedPlugin {
+        let plugin = PluginHandle::new(PluginVTable::new(plugin));
+        debug!("Registered built-in plugin: {}", plugin.name());
+
+        LoadedPlugin {
+            plugin,
+            source: Source::Static,
+            path: PathBuf::new(),
+        }
+    }
+
+    fn is_sandboxed(&self) -> bool {
+        match self.source {
+            Source::Sandbox => true,
+            _ => false,
+        }
+    }
+
+    fn is_static(&self) -> bool {
+        match self.source {
+            Source::Static => true,
+            _ => false,
+        }
+    }
+
+    /// Fire the plugin's `on_plugin_unload()` hook then unload it.
+    fn close(self, ctx: &PluginContext) {
+        let LoadedPlugin { plugin, source, .. } = self;
+
+        trace!("Firing on_plugin_unload for {:?}", plugin.name());
+        if let Err(e) = plugin.on_plugin_unload(ctx) {
+            warn!("{}", e);
+        }
+
+        // Destroy the plugin object while its code is still mapped
+        drop(plugin);
+        drop(source);
+    }
 }
 
 impl PluginManager {
     pub fn new() -> PluginManager {
         PluginManager {
             plugins: Vec::new(),
-            loaded_libraries: Vec::new(),
+            order: Vec::new(),
+            auto_unload: false,
+            watched: Vec::new(),
+            configs: HashMap::new(),
+            sandbox_host: sandbox::default_host_executable(),
+            context: PluginContext::new(),
         }
     }
 
@@ -65,55 +436,644 @@ impl PluginManager {
     /// # Safety
     ///
     /// This function is `unsafe` because there are no guarantees that the
-    /// plugin loaded will be correct. In particular, all plugins must be
-    /// compiled against the *exact* same version of the library that will be
-    /// loading them!
+    /// plugin loaded will be correct. The plugin's hooks are called through a
+    /// `#[repr(C)]` vtable so its layout is stable, but `Request` and
+    /// `Response` are still passed by pointer, so plugins must be compiled
+    /// against the same version of this library as the host.
+    ///
+    /// Plugins built for a different version of the plugin API or with a
+    /// different compiler are rejected with an `ErrorKind::IncompatiblePlugin`
+    /// error.
     ///
-    /// Failure to ensure ABI compatibility will most probably result in UB
-    /// because the vtable we expect to get (from `Box<Plugin>`) and the vtable
-    /// we actually get may be completely different.
+    /// Plugins written in C export `__plugin_create_c()` instead (see
+    /// `CPluginVTable`), and don't need to match the host's compiler.
+    ///
+    /// Any plugins it depends on must already be loaded, otherwise an
+    /// `ErrorKind::UnsatisfiedDependency` error is returned.
     pub unsafe fn load_plugin<P: AsRef<OsStr>>(&mut self, filename: P) -> Result<()> {
-        type PluginCreate = unsafe fn() -> *mut Plugin;
+        let loaded = self.open(Path::new(filename.as_ref()), false)?;
+        self.add(loaded)
+    }
+
+    /// Load a plugin in its own `plugin-host` process, so it can't crash or
+    /// corrupt the client. Its hooks are called over a pipe, which makes
+    /// them slower than a normal plugin's.
+    ///
+    /// This is only isolation from crashes, not a security boundary. The
+    /// plugin runs with the same permissions as the client, so only load
+    /// plugins you trust. See the [`sandbox`] module for the details.
+    ///
+    /// [`sandbox`]: sandbox/index.html
+    pub fn load_plugin_sandboxed<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        // Nothing gets loaded into this process, so this is safe
+        let loaded = unsafe { self.open(path.as_ref(), true)? };
+        self.add(loaded)
+    }
 
-        let lib = Library::new(filename.as_ref()).chain_err(|| "Unable to load the plugin")?;
+    /// Add a plugin which was compiled into the program, so first-party
+    /// plugins don't need to be shipped as separate libraries. Built-in
+    /// plugins are treated exactly like the ones loaded from disk, except
+    /// they can't be reloaded.
+    ///
+    /// Unlike [`load_plugin()`] this is completely safe, and works on
+    /// platforms which can't load libraries at runtime.
+    ///
+    /// [`load_plugin()`]: #method.load_plugin
+    pub fn register_static(&mut self, plugin: Box<Plugin>) -> Result<()> {
+        self.add(LoadedPlugin::from_static(plugin))
+    }
 
-        // We need to keep the library around otherwise our plugin's vtable will
-        // point to garbage. We do this little dance to make sure the library
-        // doesn't end up getting moved.
-        self.loaded_libraries.push(lib);
+    /// Use a different `plugin-host` executable for sandboxed plugins. By
+    /// default it's expected to be next to the current executable.
+    pub fn set_sandbox_host<P: Into<PathBuf>>(&mut self, host: P) {
+        self.sandbox_host = host.into();
+    }
 
-        let lib = self.loaded_libraries.last().unwrap();
+    /// Load a plugin inside the `plugin-host`, which only ever has one. Its
+    /// dependencies were already checked against the client's plugins.
+    pub(crate) unsafe fn load_sandboxed_plugin(&mut self, path: &Path) -> Result<()> {
+        let loaded = self.open(path, false)?;
+        self.start(&loaded)?;
+        self.plugins.push(loaded);
+        Ok(())
+    }
 
-        let constructor: Symbol<PluginCreate> = lib.get(b"_plugin_create")
-            .chain_err(|| "The `_plugin_create` symbol wasn't found.")?;
-        let boxed_raw = constructor();
+    /// Start a single plugin, as long as its dependencies are loaded.
+    fn add(&mut self, loaded: LoadedPlugin) -> Result<()> {
+        let mut report = LoadReport::default();
+        self.install(vec![loaded], &mut report);
 
-        let plugin = Box::from_raw(boxed_raw);
-        debug!("Loaded plugin: {}", plugin.name());
-        plugin.on_plugin_load();
-        self.plugins.push(plugin);
+        match report.failed.pop() {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    unsafe fn open(&self, path: &Path, sandboxed: bool) -> Result<LoadedPlugin> {
+        if sandboxed {
+            LoadedPlugin::open_sandboxed(&self.sandbox_host, path)
+        } else {
+            LoadedPlugin::open(path)
+        }
+    }
+
+    /// Start newly opened plugins once the plugins they depend on have been
+    /// started, and add them to the manager.
+    fn install(&mut self, opened: Vec<LoadedPlugin>, report: &mut LoadReport) {
+        let (order, unsatisfied) = {
+            let loaded: Vec<_> = self.plugins().collect();
+            let pending: Vec<_> = opened.iter().map(|l| &l.plugin).collect();
+            dependencies::load_order(&loaded, &pending)
+        };
+
+        let mut opened: Vec<Option<LoadedPlugin>> = opened.into_iter().map(Some).collect();
+
+        for (i, e) in unsatisfied {
+            if let Some(plugin) = opened[i].take() {
+                warn!("Unable to load {:?}: {}", plugin.plugin.name(), e);
+                report.failed.push((plugin.path.clone(), e));
+            }
+        }
 
+        for i in order {
+            let plugin = opened[i].take().expect("Plugins are only started once");
+
+            // A dependency may have failed to start
+            let started = {
+                let loaded: Vec<_> = self.plugins().collect();
+                dependencies::check_loaded(&plugin.plugin, &loaded)
+            }.and_then(|_| self.start(&plugin));
+
+            match started {
+                Ok(_) => {
+                    report.loaded.push(plugin.path.clone());
+                    self.plugins.push(plugin);
+                }
+                Err(e) => {
+                    warn!("Unable to load {:?}: {}", plugin.plugin.name(), e);
+                    report.failed.push((plugin.path.clone(), e));
+                }
+            }
+        }
+    }
+
+    /// Fire a plugin's `on_plugin_load()` hook and pass it its settings.
+    fn start(&self, loaded: &LoadedPlugin) -> Result<()> {
+        trace!("Firing on_plugin_load for {:?}", loaded.plugin.name());
+        loaded.plugin.on_plugin_load(&self.context)?;
+
+        if let Some(config) = self.configs.get(loaded.plugin.name()) {
+            trace!("Firing on_configure for {:?}", loaded.plugin.name());
+            loaded.plugin.on_configure(&self.context, config)?;
+        }
 
         Ok(())
     }
 
+    /// Load the settings for each plugin from a TOML file, where each plugin
+    /// has its own table.
+    ///
+    /// ```toml
+    /// ["Header Injector"]
+    /// header = "X-Injected"
+    /// value = "true"
+    /// ```
+    ///
+    /// Plugins which are already loaded get their new settings immediately.
+    pub fn load_config<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        debug!("Loading plugin settings from {}", path.display());
+
+        let mut src = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut src))
+            .chain_err(|| format!("Unable to read {}", path.display()))?;
+
+        let document: Value =
+            toml::from_str(&src).chain_err(|| "Unable to parse the plugin settings")?;
+        let sections = match document {
+            Value::Table(sections) => sections,
+            _ => bail!("The plugin settings should be a table"),
+        };
+
+        let mut outcome = Ok(());
+
+        for (name, section) in sections {
+            if !section.is_table() {
+                bail!("The settings for \"{}\" should be a table", name);
+            }
+
+            let mut config = PluginConfig::new();
+            flatten_toml("", &section, &mut config.values)?;
+            keep_first_error(&mut outcome, self.set_config(name, config));
+        }
+
+        outcome
+    }
+
+    /// Change a single setting for the named plugin. If the plugin is
+    /// loaded, its `on_configure()` hook is fired with the updated settings.
+    pub fn configure(&mut self, name: &str, key: &str, value: &str) -> Result<()> {
+        let mut config = self.configs.get(name).cloned().unwrap_or_default();
+        config.set(key, value);
+        self.set_config(name.to_string(), config)
+    }
+
+    /// The settings which will be passed to the named plugin.
+    pub fn config(&self, name: &str) -> Option<&PluginConfig> {
+        self.configs.get(name)
+    }
+
+    fn set_config(&mut self, name: String, config: PluginConfig) -> Result<()> {
+        let result = match self.plugins.iter().find(|l| l.plugin.name() == name) {
+            Some(loaded) if !loaded.plugin.is_poisoned() => {
+                trace!("Firing on_configure for {:?}", name);
+                loaded.plugin.on_configure(&self.context, &config)
+            }
+            _ => Ok(()),
+        };
+
+        self.configs.insert(name, config);
+        self.unload_poisoned();
+        result
+    }
+
+    /// Load every plugin in a directory (`*.so` on Linux, `*.dylib` on macOS
+    /// and `*.dll` on Windows), in alphabetical order. Plugins which depend
+    /// on other plugins are started after their dependencies.
+    ///
+    /// A plugin failing to load doesn't stop the others from being loaded.
+    /// Check the returned `LoadReport` to see what happened to each file.
+    ///
+    /// # Safety
+    ///
+    /// See [`load_plugin()`].
+    ///
+    /// [`load_plugin()`]: #method.load_plugin
+    pub unsafe fn load_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<LoadReport> {
+        let candidates = plugin_files(path.as_ref())?;
+        Ok(self.load_all(candidates))
+    }
+
+    /// Load every plugin in a directory like [`load_dir()`], then remember
+    /// the directory so plugins added later can be picked up by
+    /// [`check_watched_dirs()`].
+    ///
+    /// [`load_dir()`]: #method.load_dir
+    /// [`check_watched_dirs()`]: #method.check_watched_dirs
+    pub unsafe fn watch_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<LoadReport> {
+        let path = path.as_ref();
+        let candidates = plugin_files(path)?;
+
+        self.watched.push(WatchedDir {
+            path: path.to_path_buf(),
+            seen: candidates.iter().cloned().collect(),
+        });
+
+        Ok(self.load_all(candidates))
+    }
+
+    /// Load any plugins which have been added to a watched directory since it
+    /// was last checked.
+    ///
+    /// # Safety
+    ///
+    /// See [`load_plugin()`].
+    ///
+    /// [`load_plugin()`]: #method.load_plugin
+    pub unsafe fn check_watched_dirs(&mut self) -> LoadReport {
+        let mut new_files = Vec::new();
+
+        for dir in &mut self.watched {
+            let candidates = match plugin_files(&dir.path) {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("{}", e);
+                    continue;
+                }
+            };
+
+            for candidate in candidates {
+                if dir.seen.insert(candidate.clone()) {
+                    debug!("Found a new plugin, {}", candidate.display());
+                    new_files.push(candidate);
+                }
+            }
+        }
+
+        self.load_all(new_files)
+    }
+
+    /// Open every candidate, then start them in an order where each plugin's
+    /// dependencies are started first.
+    unsafe fn load_all(&mut self, candidates: Vec<PathBuf>) -> LoadReport {
+        let mut report = LoadReport::default();
+        let mut opened = Vec::new();
+
+        for candidate in candidates {
+            match self.open(&candidate, false) {
+                Ok(loaded) => opened.push(loaded),
+                Err(e) => {
+                    warn!("Unable to load {}: {}", candidate.display(), e);
+                    report.failed.push((candidate, e));
+                }
+            }
+        }
+
+        self.install(opened, &mut report);
+        report
+    }
+
+    /// Unload a single plugin, firing its `on_plugin_unload()` hook first.
+    ///
+    /// A plugin can't be unloaded while other plugins depend on it, so they
+    /// have to be unloaded first.
+    pub fn unload_plugin(&mut self, name: &str) -> Result<()> {
+        let index = self.position(name)?;
+        self.check_no_dependents(name)?;
+
+        debug!("Unloading plugin: {}", name);
+
+        self.plugins.remove(index).close(&self.context);
+        Ok(())
+    }
+
+    /// Unload a plugin and load it again from the same file, picking up any
+    /// changes made since it was first loaded. The plugin keeps its place in
+    /// the load order, and sandboxed plugins stay sandboxed.
+    ///
+    /// If the new version can't be loaded, the old one stays unloaded.
+    /// Built-in plugins can't be reloaded, and like [`unload_plugin()`],
+    /// neither can plugins other plugins depend on.
+    ///
+    /// # Safety
+    ///
+    /// See [`load_plugin()`].
+    ///
+    /// [`load_plugin()`]: #method.load_plugin
+    /// [`unload_plugin()`]: #method.unload_plugin
+    pub unsafe fn reload_plugin(&mut self, name: &str) -> Result<()> {
+        let index = self.position(name)?;
+        self.check_no_dependents(name)?;
+        if self.plugins[index].is_static() {
+            bail!("\"{}\" is built in, so it can't be reloaded", name);
+        }
+
+        let old = self.plugins.remove(index);
+        let path = old.path.clone();
+        let sandboxed = old.is_sandboxed();
+        debug!("Reloading {:?} from {}", name, path.display());
+
+        // The old library must be closed first, otherwise the OS will just
+        // hand us back the copy that's already mapped
+        old.close(&self.context);
+
+        let loaded = self.open(&path, sandboxed)
+            .and_then(|loaded| {
+                let others: Vec<_> = self.plugins().collect();
+                dependencies::check_loaded(&loaded.plugin, &others)?;
+                self.start(&loaded)?;
+                Ok(loaded)
+            })
+            .chain_err(|| format!("Unable to reload {}", path.display()))?;
+        self.plugins.insert(index, loaded);
+        Ok(())
+    }
+
+    /// Make sure no other plugins would be left without a dependency if
+    /// `name` went away.
+    fn check_no_dependents(&self, name: &str) -> Result<()> {
+        let dependents: Vec<String> = {
+            let loaded: Vec<_> = self.plugins().collect();
+            dependencies::dependents(name, &loaded)
+                .iter()
+                .map(|p| p.name().to_string())
+                .collect()
+        };
+
+        if dependents.is_empty() {
+            Ok(())
+        } else {
+            let chain = format!("{} is still needed by {}", name, dependents.join(", "));
+            Err(ErrorKind::UnsatisfiedDependency(chain).into())
+        }
+    }
+
+    fn position(&self, name: &str) -> Result<usize> {
+        self.plugins
+            .iter()
+            .position(|loaded| loaded.plugin.name() == name)
+            .ok_or_else(|| Error::from(format!("There is no plugin called \"{}\"", name)))
+    }
+
+    /// Iterate over the loaded plugins.
+    pub fn plugins<'a>(&'a self) -> Box<Iterator<Item = &'a PluginHandle> + 'a> {
+        Box::new(self.plugins.iter().map(|loaded| &loaded.plugin))
+    }
+
+    /// Describe each loaded plugin, in the order they were loaded.
+    pub fn list(&self) -> Vec<PluginMetadata> {
+        self.plugins
+            .iter()
+            .map(|loaded| {
+                let plugin = &loaded.plugin;
+
+                PluginMetadata {
+                    name: plugin.name().to_string(),
+                    version: plugin.version().to_string(),
+                    author: plugin.author().to_string(),
+                    description: plugin.description().to_string(),
+                    capabilities: plugin.capabilities(),
+                    priority: plugin.priority(),
+                    dependencies: plugin.dependencies(),
+                    poisoned: plugin.is_poisoned(),
+                    path: loaded.path.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// The number of loaded plugins.
+    pub fn len(&self) -> usize {
+        self.plugins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    /// Run the named plugins first (in the order given), followed by the
+    /// rest in order of priority.
+    pub fn set_order(&mut self, names: &[&str]) {
+        self.order = names.iter().map(|name| name.to_string()).collect();
+    }
+
+    /// Unload plugins as soon as they panic instead of just skipping their
+    /// hooks.
+    pub fn set_auto_unload(&mut self, auto_unload: bool) {
+        self.auto_unload = auto_unload;
+    }
+
+    /// The state shared by every plugin's hooks.
+    pub fn context(&self) -> &PluginContext {
+        &self.context
+    }
+
+    pub fn context_mut(&mut self) -> &mut PluginContext {
+        &mut self.context
+    }
+
+    /// The plugins in the order their hooks should be run, leaving out any
+    /// which have panicked.
+    pub(crate) fn ordered(&self) -> Vec<&PluginHandle> {
+        let mut plugins: Vec<&PluginHandle> = self.plugins
+            .iter()
+            .map(|l| &l.plugin)
+            .filter(|p| !p.is_poisoned())
+            .collect();
+
+        plugins.sort_by_key(|p| {
+            let position = self.order.iter().position(|name| name == p.name());
+            (position.unwrap_or(self.order.len()), Reverse(p.priority()))
+        });
+
+        plugins
+    }
+
     /// Iterate over the plugins, running their `pre_send()` hook.
-    pub fn pre_send(&mut self, request: &mut Request) {
+    ///
+    /// As soon as a plugin aborts or answers the request, the remaining
+    /// plugins are skipped and its `HookResult` is returned.
+    ///
+    /// If a plugin panics the remaining plugins are still run, and the first
+    /// panic is returned as an `ErrorKind::PluginPanicked` error.
+    pub fn pre_send(&mut self, request: &mut Request) -> Result<HookResult> {
         debug!("Firing pre_send hooks");
+        let mut outcome = Ok(());
+        let mut result = HookResult::Continue;
+
+        for plugin in self.ordered() {
+            if request.skip_plugins.contains(plugin.name()) {
+                trace!("Skipping pre_send for {:?}", plugin.name());
+                continue;
+            }
 
-        for plugin in &mut self.plugins {
             trace!("Firing pre_send for {:?}", plugin.name());
-            plugin.pre_send(request);
+            match plugin.pre_send(&self.context, request) {
+                Ok(HookResult::Continue) => {}
+                Ok(other) => {
+                    info!("{:?} short-circuited the request", plugin.name());
+                    result = other;
+                    break;
+                }
+                Err(e) => keep_first_error(&mut outcome, Err(e)),
+            }
         }
+
+        self.unload_poisoned();
+
+        match result {
+            HookResult::Continue => outcome.map(|_| HookResult::Continue),
+            other => Ok(other),
+        }
+    }
+
+    /// Run each plugin's `pre_send_async()` hook in turn, stopping as soon as
+    /// one aborts or answers the request.
+    ///
+    /// Unlike [`pre_send()`], a plugin which fails or panics stops the chain,
+    /// because the request was handed to it and never given back. The
+    /// returned future borrows the manager, so plugins can't be unloaded
+    /// while their hooks are still running.
+    ///
+    /// [`pre_send()`]: #method.pre_send
+    pub fn pre_send_async<'a>(&'a self, request: Request) -> HookFuture<'a, (Request, HookResult)> {
+        debug!("Firing pre_send_async hooks");
+        let start: HookFuture<'a, _> = Box::new(future::ok((request, HookResult::Continue)));
+
+        self.ordered().into_iter().fold(start, |previous, plugin| -> HookFuture<'a, _> {
+            Box::new(previous.and_then(move |(request, result)| -> HookFuture<'a, _> {
+                match result {
+                    HookResult::Continue => {}
+                    other => return Box::new(future::ok((request, other))),
+                }
+
+                if request.skip_plugins.contains(plugin.name()) {
+                    trace!("Skipping pre_send_async for {:?}", plugin.name());
+                    Box::new(future::ok((request, HookResult::Continue)))
+                } else {
+                    trace!("Firing pre_send_async for {:?}", plugin.name());
+                    plugin.pre_send_async(&self.context, request)
+                }
+            }))
+        })
+    }
+
+    /// Run each plugin's `post_receive_async()` hook in turn, skipping the
+    /// plugins `request` opted out of.
+    ///
+    /// Failures are handled the same way as in [`pre_send_async()`].
+    ///
+    /// [`pre_send_async()`]: #method.pre_send_async
+    pub fn post_receive_async<'a>(
+        &'a self,
+        request: &Request,
+        response: Response,
+    ) -> HookFuture<'a, Response> {
+        debug!("Firing post_receive_async hooks");
+        let start: HookFuture<'a, _> = Box::new(future::ok(response));
+
+        self.ordered()
+            .into_iter()
+            .filter(|plugin| !request.skip_plugins.contains(plugin.name()))
+            .fold(start, |previous, plugin| -> HookFuture<'a, _> {
+                Box::new(previous.and_then(move |response| {
+                    trace!("Firing post_receive_async for {:?}", plugin.name());
+                    plugin.post_receive_async(&self.context, response)
+                }))
+            })
     }
 
     /// Iterate over the plugins, running their `post_receive()` hook.
-    pub fn post_receive(&mut self, response: &mut Response) {
+    ///
+    /// Panics are handled the same way as in [`pre_send()`].
+    ///
+    /// [`pre_send()`]: #method.pre_send
+    pub fn post_receive(&mut self, response: &mut Response) -> Result<()> {
+        debug!("Firing post_receive hooks");
+        let mut outcome = Ok(());
+
+        for plugin in self.ordered() {
+            trace!("Firing post_receive for {:?}", plugin.name());
+            keep_first_error(&mut outcome, plugin.post_receive(&self.context, response));
+        }
+
+        self.unload_poisoned();
+        outcome
+    }
+
+    /// Like [`post_receive()`], except plugins the request opted out of are
+    /// skipped.
+    ///
+    /// [`post_receive()`]: #method.post_receive
+    pub fn post_receive_for(&mut self, request: &Request, response: &mut Response) -> Result<()> {
         debug!("Firing post_receive hooks");
+        let mut outcome = Ok(());
+
+        for plugin in self.ordered() {
+            if request.skip_plugins.contains(plugin.name()) {
+                trace!("Skipping post_receive for {:?}", plugin.name());
+                continue;
+            }
 
-        for plugin in &mut self.plugins {
             trace!("Firing post_receive for {:?}", plugin.name());
-            plugin.post_receive(response);
+            keep_first_error(&mut outcome, plugin.post_receive(&self.context, response));
+        }
+
+        self.unload_poisoned();
+        outcome
+    }
+
+    /// Let the plugins know a request failed.
+    ///
+    /// Unlike the other hooks, this and the `redirect()` and `retry()` hooks
+    /// are fired while a request is in flight, so plugins which panic are
+    /// only logged and skipped.
+    pub fn error(&self, error: &Error, request: &Request) {
+        debug!("Firing on_error hooks");
+
+        for plugin in self.ordered() {
+            if !request.skip_plugins.contains(plugin.name()) {
+                trace!("Firing on_error for {:?}", plugin.name());
+                if let Err(e) = plugin.on_error(&self.context, error, request) {
+                    warn!("{}", e);
+                }
+            }
+        }
+    }
+
+    /// Let the plugins change a request before it is sent to the new `url`
+    /// after a redirect.
+    pub fn redirect(&self, url: &Url, request: &mut Request) {
+        debug!("Firing on_redirect hooks");
+
+        for plugin in self.ordered() {
+            if !request.skip_plugins.contains(plugin.name()) {
+                trace!("Firing on_redirect for {:?}", plugin.name());
+                if let Err(e) = plugin.on_redirect(&self.context, url, request) {
+                    warn!("{}", e);
+                }
+            }
+        }
+    }
+
+    /// Let the plugins change a request before it is retried.
+    pub fn retry(&self, attempt: u32, request: &mut Request) {
+        debug!("Firing on_retry hooks");
+
+        for plugin in self.ordered() {
+            if !request.skip_plugins.contains(plugin.name()) {
+                trace!("Firing on_retry for {:?}", plugin.name());
+                if let Err(e) = plugin.on_retry(&self.context, attempt, request) {
+                    warn!("{}", e);
+                }
+            }
+        }
+    }
+
+    /// Let the plugins know a request was blocked by an environment's quota.
+    pub fn quota_exceeded(&mut self, environment: &str, request: &Request) {
+        debug!("Firing on_quota_exceeded hooks");
+
+        for plugin in self.ordered() {
+            if request.skip_plugins.contains(plugin.name()) {
+                continue;
+            }
+
+            trace!("Firing on_quota_exceeded for {:?}", plugin.name());
+            if let Err(e) = plugin.on_quota_exceeded(&self.context, environment, request) {
+                warn!("{}", e);
+            }
         }
     }
 
@@ -122,20 +1082,112 @@ impl PluginManager {
     pub fn unload(&mut self) {
         debug!("Unloading plugins");
 
-        for plugin in self.plugins.drain(..) {
-            trace!("Firing on_plugin_unload for {:?}", plugin.name());
-            plugin.on_plugin_unload();
+        for loaded in self.plugins.drain(..) {
+            loaded.close(&self.context);
+        }
+    }
+
+    /// Get rid of any plugins which have panicked, if `auto_unload` is set.
+    /// Their `on_plugin_unload()` hook isn't fired because they may be in a
+    /// broken state.
+    ///
+    /// Plugins which depend on them (directly or not) are unloaded too, so
+    /// nothing is left running without its dependencies.
+    fn unload_poisoned(&mut self) {
+        if !self.auto_unload {
+            return;
+        }
+
+        let mut doomed: Vec<String> = self.plugins()
+            .filter(|p| p.is_poisoned())
+            .map(|p| p.name().to_string())
+            .collect();
+        if doomed.is_empty() {
+            return;
+        }
+
+        loop {
+            let orphans: Vec<String> = {
+                let remaining: Vec<_> = self.plugins()
+                    .filter(|p| !doomed.iter().any(|name| name == p.name()))
+                    .collect();
+
+                remaining
+                    .iter()
+                    .filter(|p| dependencies::check_loaded(p, &remaining).is_err())
+                    .map(|p| p.name().to_string())
+                    .collect()
+            };
+
+            if orphans.is_empty() {
+                break;
+            }
+            doomed.extend(orphans);
+        }
+
+        for loaded in mem::replace(&mut self.plugins, Vec::new()) {
+            if !doomed.iter().any(|name| name == loaded.plugin.name()) {
+                self.plugins.push(loaded);
+            } else if loaded.plugin.is_poisoned() {
+                warn!("Unloading {:?} because it panicked", loaded.plugin.name());
+            } else {
+                warn!(
+                    "Unloading {:?} because a plugin it depends on panicked",
+                    loaded.plugin.name()
+                );
+                loaded.close(&self.context);
+            }
         }
+    }
+}
+
+fn keep_first_error(outcome: &mut Result<()>, result: Result<()>) {
+    if let Err(e) = result {
+        warn!("{}", e);
+        if outcome.is_ok() {
+            *outcome = Err(e);
+        }
+    }
+}
 
-        for lib in self.loaded_libraries.drain(..) {
-            drop(lib);
+/// Find every file in a directory which looks like a plugin on this platform.
+fn plugin_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries =
+        fs::read_dir(dir).chain_err(|| format!("Unable to read the {} directory", dir.display()))?;
+    let mut files = Vec::new();
+
+    for entry in entries {
+        let path = entry
+            .chain_err(|| format!("Unable to read the {} directory", dir.display()))?
+            .path();
+
+        if path.is_file() && path.extension() == Some(OsStr::new(DLL_EXTENSION)) {
+            files.push(path);
         }
     }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Make sure we'll be able to talk to the plugin before calling into it.
+unsafe fn check_abi_version(lib: &Library) -> Result<()> {
+    type AbiVersionFn = unsafe extern "C" fn() -> AbiVersion;
+
+    let get_version: Symbol<AbiVersionFn> = match lib.get(b"__plugin_abi_version") {
+        Ok(f) => f,
+        Err(_) => {
+            let reason = String::from("it doesn't say which version of the plugin API it uses");
+            bail!(ErrorKind::IncompatiblePlugin(reason));
+        }
+    };
+
+    get_version().check_compatible()
 }
 
 impl Drop for PluginManager {
     fn drop(&mut self) {
-        if !self.plugins.is_empty() || !self.loaded_libraries.is_empty() {
+        if !self.plugins.is_empty() {
             self.unload();
         }
     }
@@ -143,7 +1195,7 @@ impl Drop for PluginManager {
 
 impl Debug for PluginManager {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        let plugins: Vec<_> = self.plugins.iter().map(|p| p.name()).collect();
+        let plugins: Vec<_> = self.plugins().map(|p| p.name()).collect();
 
         f.debug_struct("PluginManager")
             .field("plugins", &plugins)
@@ -0,0 +1,334 @@
+//! Per-environment quotas, for when the client is deployed somewhere with a
+//! metered data contract.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use chrono::Utc;
+use serde_json;
+
+use errors::*;
+use Request;
+
+
+const SECONDS_PER_HOUR: i64 = 60 * 60;
+const SECONDS_PER_DAY: i64 = 24 * SECONDS_PER_HOUR;
+
+/// The limits applied to a single environment. A `None` means that particular
+/// limit isn't enforced.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_requests_per_hour: Option<u32>,
+    pub max_upload_bytes_per_day: Option<u64>,
+}
+
+/// Usage history for an environment, stored as unix timestamps.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct Usage {
+    requests: Vec<i64>,
+    uploads: Vec<(i64, u64)>,
+}
+
+impl Usage {
+    /// Forget about anything which can no longer count towards a quota.
+    fn prune(&mut self, now: i64) {
+        self.requests.retain(|&t| now - t < SECONDS_PER_HOUR);
+        self.uploads.retain(|&(t, _)| now - t < SECONDS_PER_DAY);
+    }
+
+    fn uploaded_bytes(&self) -> u64 {
+        self.uploads.iter().map(|&(_, bytes)| bytes).sum()
+    }
+}
+
+/// Keeps track of how much each environment has been used and makes sure
+/// requests don't exceed their environment's `Quota`.
+///
+/// If the tracker was created with [`QuotaTracker::load()`] then usage is
+/// written back to disk every time a request is recorded, so quotas are
+/// respected across restarts.
+///
+/// [`QuotaTracker::load()`]: #method.load
+#[derive(Debug, Default)]
+pub struct QuotaTracker {
+    path: Option<PathBuf>,
+    state: State,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct State {
+    quotas: HashMap<String, Quota>,
+    usage: HashMap<String, Usage>,
+}
+
+impl QuotaTracker {
+    /// Create a tracker which only keeps usage in memory.
+    pub fn new() -> QuotaTracker {
+        QuotaTracker::default()
+    }
+
+    /// Load a tracker from disk, starting afresh if the file doesn't exist yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<QuotaTracker> {
+        let path = path.as_ref();
+
+        let state = if path.exists() {
+            let f = File::open(path).chain_err(|| "Unable to open the quota file")?;
+            serde_json::from_reader(f).chain_err(|| "Unable to parse the quota file")?
+        } else {
+            State::default()
+        };
+
+        Ok(QuotaTracker {
+            path: Some(path.to_path_buf()),
+            state,
+        })
+    }
+
+    /// Set the quota for an environment.
+    pub fn set_quota(&mut self, environment: &str, quota: Quota) {
+        debug!("Setting the quota for {:?} to {:?}", environment, quota);
+        self.state.quotas.insert(environment.to_string(), quota);
+    }
+
+    /// Get the quota for an environment, if one has been set.
+    pub fn quota(&self, environment: &str) -> Option<Quota> {
+        self.state.quotas.get(environment).cloned()
+    }
+
+    /// Get the quotas for every environment.
+    pub fn quotas(&self) -> &HashMap<String, Quota> {
+        &self.state.quotas
+    }
+
+    /// Check whether sending this request would exceed the environment's
+    /// quota.
+    pub fn check(&self, environment: &str, req: &Request) -> Result<()> {
+        let quota = match self.quota(environment) {
+            Some(q) => q,
+            None => return Ok(()),
+        };
+
+        let mut usage = self.state
+            .usage
+            .get(environment)
+            .cloned()
+            .unwrap_or_default();
+        usage.prune(Utc::now().timestamp());
+
+        if let Some(max) = quota.max_requests_per_hour {
+            if usage.requests.len() as u64 >= max as u64 {
+                let err = ErrorKind::QuotaExceeded(environment.to_string(), "requests per hour");
+                return Err(err.into());
+            }
+        }
+
+        if let Some(max) = quota.max_upload_bytes_per_day {
+            if usage.uploaded_bytes() + upload_size(req) > max {
+                let err =
+                    ErrorKind::QuotaExceeded(environment.to_string(), "upload bytes per day");
+                return Err(err.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record that a request was sent, saving the updated usage to disk if
+    /// necessary.
+    pub fn record(&mut self, environment: &str, req: &Request) -> Result<()> {
+        let now = Utc::now().timestamp();
+
+        {
+            let usage = self.state
+                .usage
+                .entry(environment.to_string())
+                .or_insert_with(Usage::default);
+            usage.prune(now);
+            usage.requests.push(now);
+
+            let bytes = upload_size(req);
+            if bytes > 0 {
+                usage.uploads.push((now, bytes));
+            }
+        }
+
+        self.save()
+    }
+
+    /// Write the usage to disk. It's written to a temporary file which then
+    /// replaces the old one, so crashing part way through can't leave a
+    /// truncated quota file behind.
+    fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+
+        {
+            let mut f = File::create(&temp).chain_err(|| "Unable to create the quota file")?;
+            serde_json::to_writer(&mut f, &self.state)
+                .chain_err(|| "Unable to save quota usage")?;
+            f.sync_all().chain_err(|| "Unable to save quota usage")?;
+        }
+
+        fs::rename(&temp, path).chain_err(|| "Unable to replace the quota file")
+    }
+}
+
+fn upload_size(req: &Request) -> u64 {
+    req.body.as_ref().map(|b| b.len() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::net::TcpListener;
+    use std::process;
+    use reqwest::Url;
+
+    fn request(body: usize) -> Request {
+        let mut req = Request::post(Url::parse("http://localhost/upload").unwrap());
+        if body > 0 {
+            req.body = Some(vec![0; body]);
+        }
+        req
+    }
+
+    fn limit_hit(result: Result<()>) -> &'static str {
+        match result.map_err(|e| e.0) {
+            Err(ErrorKind::QuotaExceeded(_, limit)) => limit,
+            other => panic!("Expected a QuotaExceeded error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn environments_without_a_quota_are_unlimited() {
+        let mut tracker = QuotaTracker::new();
+
+        for _ in 0..100 {
+            tracker.check("dev", &request(1024)).unwrap();
+            tracker.record("dev", &request(1024)).unwrap();
+        }
+    }
+
+    #[test]
+    fn requests_per_hour_allows_exactly_the_limit() {
+        let mut tracker = QuotaTracker::new();
+        tracker.set_quota(
+            "prod",
+            Quota {
+                max_requests_per_hour: Some(2),
+                max_upload_bytes_per_day: None,
+            },
+        );
+
+        for _ in 0..2 {
+            tracker.check("prod", &request(0)).unwrap();
+            tracker.record("prod", &request(0)).unwrap();
+        }
+
+        assert_eq!(limit_hit(tracker.check("prod", &request(0))), "requests per hour");
+        // Other environments aren't affected
+        tracker.check("staging", &request(0)).unwrap();
+    }
+
+    #[test]
+    fn a_zero_limit_blocks_everything() {
+        let mut tracker = QuotaTracker::new();
+        tracker.set_quota(
+            "prod",
+            Quota {
+                max_requests_per_hour: Some(0),
+                max_upload_bytes_per_day: None,
+            },
+        );
+
+        assert_eq!(limit_hit(tracker.check("prod", &request(0))), "requests per hour");
+    }
+
+    #[test]
+    fn uploads_can_use_the_whole_allowance_but_no_more() {
+        let mut tracker = QuotaTracker::new();
+        tracker.set_quota(
+            "prod",
+            Quota {
+                max_requests_per_hour: None,
+                max_upload_bytes_per_day: Some(10),
+            },
+        );
+
+        assert_eq!(limit_hit(tracker.check("prod", &request(11))), "upload bytes per day");
+        tracker.check("prod", &request(10)).unwrap();
+        tracker.record("prod", &request(10)).unwrap();
+
+        assert_eq!(limit_hit(tracker.check("prod", &request(1))), "upload bytes per day");
+        // Requests without a body don't use any of the allowance
+        tracker.check("prod", &request(0)).unwrap();
+    }
+
+    #[test]
+    fn old_usage_is_forgotten() {
+        let now = 1_000_000;
+        let mut usage = Usage {
+            requests: vec![now - SECONDS_PER_HOUR, now - SECONDS_PER_HOUR + 1],
+            uploads: vec![(now - SECONDS_PER_DAY, 5), (now - SECONDS_PER_DAY + 1, 7)],
+        };
+
+        usage.prune(now);
+
+        assert_eq!(usage.requests, vec![now - SECONDS_PER_HOUR + 1]);
+        assert_eq!(usage.uploaded_bytes(), 7);
+    }
+
+    #[test]
+    fn usage_survives_a_restart() {
+        let path = env::temp_dir().join(format!("quota-test-{}.json", process::id()));
+        let _ = fs::remove_file(&path);
+        let quota = Quota {
+            max_requests_per_hour: Some(1),
+            max_upload_bytes_per_day: None,
+        };
+
+        {
+            let mut tracker = QuotaTracker::load(&path).unwrap();
+            tracker.set_quota("prod", quota);
+            tracker.record("prod", &request(0)).unwrap();
+        }
+
+        let tracker = QuotaTracker::load(&path).unwrap();
+        assert_eq!(tracker.quota("prod"), Some(quota));
+        assert_eq!(limit_hit(tracker.check("prod", &request(0))), "requests per hour");
+
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        assert!(!PathBuf::from(temp).exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failed_requests_dont_count() {
+        // Nothing will be listening on this port once the listener is gone
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let url = Url::parse(&format!("http://{}/", address)).unwrap();
+
+        let mut tracker = QuotaTracker::new();
+        tracker.set_quota(
+            "prod",
+            Quota {
+                max_requests_per_hour: Some(1),
+                max_upload_bytes_per_day: None,
+            },
+        );
+
+        assert!(::send_request_metered(&Request::get(url), "prod", &mut tracker, None).is_err());
+        tracker.check("prod", &request(0)).unwrap();
+    }
+}
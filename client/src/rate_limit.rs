@@ -0,0 +1,304 @@
+//! Limiting how quickly (and how many) requests are sent, so batch jobs
+//! don't get us banned by the API they're talking to.
+
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use errors::*;
+use CancellationToken;
+
+
+/// How often a blocked request wakes up to check whether it was cancelled.
+const POLL_MS: u64 = 50;
+
+/// Allow `per_second` requests on average, with bursts of up to `burst`
+/// requests at a time.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// A token bucket combined with a cap on the number of requests in flight.
+///
+/// The limiter is shared between every clone of an `HttpClient`, and
+/// changing its settings takes effect immediately.
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+#[derive(Debug)]
+struct State {
+    rate: Option<Rate>,
+    tokens: f64,
+    last_refill: Instant,
+    max_in_flight: Option<usize>,
+    in_flight: usize,
+}
+
+impl RateLimiter {
+    /// Create a limiter which doesn't limit anything.
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            state: Mutex::new(State {
+                rate: None,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+                max_in_flight: None,
+                in_flight: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Limit how quickly requests can be sent, or remove the limit with
+    /// `None`.
+    pub fn set_rate(&self, rate: Option<Rate>) -> Result<()> {
+        if let Some(r) = rate {
+            if !(r.per_second > 0.0) {
+                bail!("The request rate must be positive, not {}", r.per_second);
+            }
+        }
+
+        let mut state = self.lock()?;
+        state.rate = rate;
+        // start with a full bucket
+        state.tokens = rate.map(|r| r.burst.max(1) as f64).unwrap_or(0.0);
+        state.last_refill = Instant::now();
+
+        self.released.notify_all();
+        Ok(())
+    }
+
+    /// Limit how many requests can be in flight at once, or remove the limit
+    /// with `None`.
+    pub fn set_max_in_flight(&self, max_in_flight: Option<usize>) -> Result<()> {
+        let mut state = self.lock()?;
+        state.max_in_flight = max_in_flight;
+
+        self.released.notify_all();
+        Ok(())
+    }
+
+    /// How many requests are currently in flight?
+    pub fn in_flight(&self) -> usize {
+        self.lock().map(|state| state.in_flight).unwrap_or(0)
+    }
+
+    /// Wait until we are allowed to send a request.
+    ///
+    /// The request counts as being in flight until the returned `Permit` is
+    /// dropped.
+    pub(crate) fn acquire(&self, token: Option<&CancellationToken>) -> Result<Permit> {
+        let mut state = self.lock()?;
+
+        loop {
+            if let Some(token) = token {
+                token.check()?;
+            }
+
+            state.refill(Instant::now());
+
+            let wait = match state.wait_time() {
+                Some(wait) => wait,
+                None => {
+                    state.take();
+                    return Ok(Permit { limiter: self });
+                }
+            };
+
+            trace!("Rate limited, waiting {:?}", wait);
+            let poll = Duration::from_millis(POLL_MS);
+            let timeout = if wait < poll { wait } else { poll };
+
+            state = self.released
+                .wait_timeout(state, timeout)
+                .map_err(|_| Error::from("The rate limiter's lock is poisoned"))?
+                .0;
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<State>> {
+        self.state
+            .lock()
+            .map_err(|_| Error::from("The rate limiter's lock is poisoned"))
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> RateLimiter {
+        RateLimiter::new()
+    }
+}
+
+impl State {
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now - self.last_refill;
+            let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+
+            self.tokens = (self.tokens + seconds * rate.per_second).min(rate.burst.max(1) as f64);
+        }
+
+        self.last_refill = now;
+    }
+
+    /// How long until we can send another request? `None` means right now.
+    fn wait_time(&self) -> Option<Duration> {
+        if let Some(max) = self.max_in_flight {
+            if self.in_flight >= max {
+                // We'll get woken up when a request finishes
+                return Some(Duration::from_millis(POLL_MS));
+            }
+        }
+
+        match self.rate {
+            Some(rate) if self.tokens < 1.0 => {
+                let seconds = (1.0 - self.tokens) / rate.per_second;
+                Some(Duration::from_millis((seconds * 1000.0).ceil() as u64))
+            }
+            _ => None,
+        }
+    }
+
+    fn take(&mut self) {
+        if self.rate.is_some() {
+            self.tokens -= 1.0;
+        }
+        self.in_flight += 1;
+    }
+}
+
+/// Permission to send a request. Dropping it marks the request as finished.
+#[derive(Debug)]
+pub(crate) struct Permit<'a> {
+    limiter: &'a RateLimiter,
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.limiter.state.lock() {
+            state.in_flight = state.in_flight.saturating_sub(1);
+        }
+
+        self.limiter.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn state(rate: Rate, tokens: f64, now: Instant) -> State {
+        State {
+            rate: Some(rate),
+            tokens,
+            last_refill: now,
+            max_in_flight: None,
+            in_flight: 0,
+        }
+    }
+
+    #[test]
+    fn tokens_trickle_back_in() {
+        let start = Instant::now();
+        let rate = Rate {
+            per_second: 0.5,
+            burst: 1,
+        };
+        let mut state = state(rate, 0.0, start);
+
+        assert_eq!(state.wait_time(), Some(Duration::from_secs(2)));
+
+        state.refill(start + Duration::from_secs(1));
+        assert_eq!(state.wait_time(), Some(Duration::from_secs(1)));
+
+        state.refill(start + Duration::from_secs(2));
+        assert_eq!(state.wait_time(), None);
+
+        // The bucket never holds more than the burst, so after a long pause
+        // there's only one request's worth of tokens
+        state.refill(start + Duration::from_secs(60));
+        state.take();
+        assert_eq!(state.wait_time(), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn a_full_bucket_allows_a_burst() {
+        let start = Instant::now();
+        let rate = Rate {
+            per_second: 1.0,
+            burst: 3,
+        };
+        let mut state = state(rate, 3.0, start);
+
+        for _ in 0..3 {
+            state.refill(start);
+            assert_eq!(state.wait_time(), None);
+            state.take();
+        }
+
+        assert_eq!(state.wait_time(), Some(Duration::from_secs(1)));
+        assert_eq!(state.in_flight, 3);
+
+        let limiter = RateLimiter::new();
+        limiter.set_rate(Some(Rate { per_second: 0.1, ..rate })).unwrap();
+        let started = Instant::now();
+
+        for _ in 0..3 {
+            drop(limiter.acquire(None).unwrap());
+        }
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn requests_wait_for_one_in_flight_to_finish() {
+        let limiter = Arc::new(RateLimiter::new());
+        limiter.set_max_in_flight(Some(1)).unwrap();
+        let permit = limiter.acquire(None).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let other = Arc::clone(&limiter);
+        let handle = thread::spawn(move || {
+            let _permit = other.acquire(None).unwrap();
+            tx.send(()).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        assert_eq!(limiter.in_flight(), 1);
+
+        drop(permit);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[test]
+    fn cancelling_wakes_up_a_blocked_request() {
+        let limiter = Arc::new(RateLimiter::new());
+        limiter.set_max_in_flight(Some(1)).unwrap();
+        let _permit = limiter.acquire(None).unwrap();
+        let token = CancellationToken::new();
+
+        let (tx, rx) = mpsc::channel();
+        let other = Arc::clone(&limiter);
+        let cancelled = token.clone();
+        thread::spawn(move || {
+            let result = other.acquire(Some(&cancelled)).map(|_| ());
+            tx.send(result).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        token.cancel();
+
+        let err = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap_err();
+        assert_eq!(ErrorCategory::of(&err), ErrorCategory::Cancelled);
+        assert_eq!(limiter.in_flight(), 1);
+    }
+}
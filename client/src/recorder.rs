@@ -0,0 +1,608 @@
+//! Recording traffic so it can be exported as an [HTTP Archive][har].
+//!
+//! Unlike the request history, which is deliberately redacted so it can be
+//! attached to bug reports, the recorder keeps everything (including
+//! credentials and bodies). Only turn it on while debugging.
+//!
+//! [har]: http://www.softwareishard.com/blog/har-12-spec/
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use base64;
+use chrono::{self, Local};
+use libc::{c_char, c_int};
+use reqwest::header::Headers;
+use serde_json;
+
+use errors::*;
+use ffi::{c_str_to_str, catch_panic, update_last_error};
+use {HttpClient, Request, Response};
+
+
+/// The transport only ever sends HTTP/1.1 requests.
+const REQUEST_HTTP_VERSION: &str = "HTTP/1.1";
+
+/// Records every request sent by the `HttpClient`s it is attached to.
+///
+/// Clones share the same set of recorded entries.
+#[derive(Debug, Default, Clone)]
+pub struct Recorder {
+    entries: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder::default()
+    }
+
+    /// The number of requests recorded so far.
+    pub fn len(&self) -> usize {
+        self.entries.lock().map(|e| e.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget everything recorded so far.
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+
+    pub(crate) fn record(&self, req: &Request, outcome: &Result<Response>) {
+        let entry = Entry::new(req, outcome);
+
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.push(entry);
+        }
+    }
+
+    /// Get everything recorded so far as a HAR document.
+    pub fn to_har(&self) -> Result<String> {
+        let entries = self.entries
+            .lock()
+            .map_err(|_| Error::from("The recorder's lock is poisoned"))?;
+
+        let har = Har {
+            log: Log {
+                version: "1.2",
+                creator: Creator {
+                    name: env!("CARGO_PKG_NAME"),
+                    version: env!("CARGO_PKG_VERSION"),
+                },
+                entries: &entries,
+            },
+        };
+
+        serde_json::to_string_pretty(&har).chain_err(|| "Unable to serialize the HAR document")
+    }
+
+    /// Write everything recorded so far to a HAR file, which can be imported
+    /// into most browsers' developer tools.
+    pub fn export_har<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        debug!("Exporting {} requests to {}", self.len(), path.display());
+
+        let har = self.to_har()?;
+        File::create(path)
+            .and_then(|mut f| f.write_all(har.as_bytes()))
+            .chain_err(|| format!("Unable to write to {}", path.display()))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Har<'a> {
+    log: Log<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct Log<'a> {
+    version: &'static str,
+    creator: Creator,
+    entries: &'a [Entry],
+}
+
+#[derive(Debug, Serialize)]
+struct Creator {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    time: f64,
+    request: HarRequest,
+    response: HarResponse,
+    cache: Empty,
+    timings: Timings,
+    /// Why the request failed, if it did. Custom fields must start with an
+    /// underscore.
+    #[serde(rename = "_error", skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    http_version: &'static str,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    query_string: Vec<NameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_data: Option<PostData>,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarResponse {
+    status: u16,
+    status_text: String,
+    http_version: &'static str,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct NameValue {
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PostData {
+    mime_type: String,
+    text: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: i64,
+    mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Empty {}
+
+/// How long each phase of the request took, in milliseconds. `-1` means the
+/// phase doesn't apply or wasn't measured.
+#[derive(Debug, Clone, Serialize)]
+struct Timings {
+    blocked: f64,
+    dns: f64,
+    connect: f64,
+    ssl: f64,
+    send: f64,
+    wait: f64,
+    receive: f64,
+}
+
+impl Entry {
+    fn new(req: &Request, outcome: &Result<Response>) -> Entry {
+        let response = match *outcome {
+            Ok(ref r) => Some(r),
+            Err(_) => None,
+        };
+        let total = response.map(|r| r.timing.total_ms.max(0.0)).unwrap_or(0.0);
+        let started = Local::now() - chrono::Duration::milliseconds(total as i64);
+        Entry {
+            started_date_time: started.to_rfc3339(),
+            time: total,
+            request: HarRequest::new(req),
+            response: match response {
+                Some(r) => HarResponse::new(r),
+                None => HarResponse::failed(),
+            },
+            cache: Empty {},
+            timings: match response {
+                Some(r) => Timings::new(r),
+                None => Timings::unknown(),
+            },
+            error: outcome.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+}
+
+impl HarRequest {
+    fn new(req: &Request) -> HarRequest {
+        let post_data = req.body.as_ref().map(|body| PostData {
+            mime_type: header(&req.headers, "Content-Type").unwrap_or_default(),
+            text: String::from_utf8_lossy(body).into_owned(),
+        });
+
+        HarRequest {
+            method: req.method.to_string(),
+            url: req.destination.to_string(),
+            http_version: REQUEST_HTTP_VERSION,
+            cookies: req.cookies
+                .iter()
+                .map(|c| NameValue {
+                    name: c.name().to_string(),
+                    value: c.value().to_string(),
+                })
+                .collect(),
+            headers: name_values(&req.headers),
+            query_string: req.destination
+                .query_pairs()
+                .map(|(name, value)| NameValue {
+                    name: name.into_owned(),
+                    value: value.into_owned(),
+                })
+                .collect(),
+            post_data,
+            headers_size: -1,
+            body_size: req.body.as_ref().map(|b| b.len() as i64).unwrap_or(0),
+        }
+    }
+}
+
+impl HarResponse {
+    fn new(res: &Response) -> HarResponse {
+        let (text, encoding) = match String::from_utf8(res.body.clone()) {
+            Ok(text) => (text, None),
+            Err(_) => (base64::encode(&res.body), Some("base64")),
+        };
+
+        HarResponse {
+            status: res.status.as_u16(),
+            status_text: res.status
+                .canonical_reason()
+                .unwrap_or_default()
+                .to_string(),
+            // The transport doesn't tell us which version the server replied
+            // with
+            http_version: "",
+            cookies: res.cookies
+                .iter()
+                .map(|c| NameValue {
+                    name: c.name().to_string(),
+                    value: c.value().to_string(),
+                })
+                .collect(),
+            headers: name_values(&res.headers),
+            content: Content {
+                size: res.body.len() as i64,
+                mime_type: header(&res.headers, "Content-Type").unwrap_or_default(),
+                text: Some(text),
+                encoding,
+            },
+            redirect_url: header(&res.headers, "Location").unwrap_or_default(),
+            headers_size: -1,
+            body_size: res.body.len() as i64,
+        }
+    }
+
+    /// Browsers record requests which never got a response with a status of
+    /// `0`.
+    fn failed() -> HarResponse {
+        HarResponse {
+            status: 0,
+            status_text: String::new(),
+            http_version: "",
+            cookies: Vec::new(),
+            headers: Vec::new(),
+            content: Content {
+                size: 0,
+                mime_type: String::new(),
+                text: None,
+                encoding: None,
+            },
+            redirect_url: String::new(),
+            headers_size: -1,
+            body_size: -1,
+        }
+    }
+}
+
+impl Timings {
+    fn new(res: &Response) -> Timings {
+        let timing = res.timing;
+        let wait = timing.first_byte_ms.max(0.0);
+
+        Timings {
+            blocked: -1.0,
+            // The transport doesn't tell us how long these took, so they're
+            // counted as part of the wait (see `Timing`)
+            dns: -1.0,
+            connect: -1.0,
+            ssl: -1.0,
+            send: 0.0,
+            wait,
+            receive: (timing.total_ms - wait).max(0.0),
+        }
+    }
+
+    fn unknown() -> Timings {
+        Timings {
+            blocked: -1.0,
+            dns: -1.0,
+            connect: -1.0,
+            ssl: -1.0,
+            send: 0.0,
+            wait: 0.0,
+            receive: 0.0,
+        }
+    }
+}
+
+fn name_values(headers: &Headers) -> Vec<NameValue> {
+    headers
+        .iter()
+        .map(|h| NameValue {
+            name: h.name().to_string(),
+            value: h.value_string(),
+        })
+        .collect()
+}
+
+fn header(headers: &Headers, name: &str) -> Option<String> {
+    headers
+        .get_raw(name)
+        .and_then(|raw| raw.one())
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+/// Create a new recorder. Attach it to a client with
+/// [`client_set_recorder()`].
+///
+/// [`client_set_recorder()`]: fn.client_set_recorder.html
+#[no_mangle]
+pub extern "C" fn recorder_new() -> *mut Recorder {
+    catch_panic(ptr::null_mut(), || Box::into_raw(Box::new(Recorder::new())))
+}
+
+/// Destroy a recorder. Clients it was attached to will keep recording into
+/// their own copy.
+#[no_mangle]
+pub unsafe extern "C" fn recorder_destroy(recorder: *mut Recorder) {
+    catch_panic((), || {
+        if !recorder.is_null() {
+            drop(Box::from_raw(recorder));
+        }
+    })
+}
+
+/// Forget everything recorded so far.
+#[no_mangle]
+pub unsafe extern "C" fn recorder_clear(recorder: *const Recorder) {
+    catch_panic((), || {
+        if !recorder.is_null() {
+            (&*recorder).clear();
+        }
+    })
+}
+
+/// Write everything recorded so far to a HAR file.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn recorder_export_har(
+    recorder: *const Recorder,
+    path: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if recorder.is_null() {
+            update_last_error(Error::from("Null pointer passed to recorder_export_har()"));
+            return -1;
+        }
+
+        let path = match c_str_to_str(path, "HAR path") {
+            Some(p) => p,
+            None => return -1,
+        };
+
+        match (&*recorder).export_har(path) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Record every request sent by the client. Passing a null `recorder` stops
+/// recording.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_set_recorder(
+    client: *mut HttpClient,
+    recorder: *const Recorder,
+) -> c_int {
+    catch_panic(-1, || {
+        if client.is_null() {
+            update_last_error(Error::from("Null pointer passed to client_set_recorder()"));
+            return -1;
+        }
+
+        let recorder = if recorder.is_null() {
+            None
+        } else {
+            Some((&*recorder).clone())
+        };
+
+        (&mut *client).set_recorder(recorder);
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::process;
+    use cookie::Cookie;
+    use reqwest::{Method, StatusCode, Url};
+    use serde_json::Value;
+    use mock::{Expectation, MockTransport};
+
+    fn client(recorder: &Recorder, mock: &MockTransport) -> HttpClient {
+        let mut client = HttpClient::new().unwrap();
+        client.set_recorder(Some(recorder.clone()));
+        client.set_mock(Some(mock.clone()));
+        client
+    }
+
+    fn entries(recorder: &Recorder) -> Vec<Value> {
+        let har: Value = serde_json::from_str(&recorder.to_har().unwrap()).unwrap();
+        assert_eq!(har["log"]["version"], "1.2");
+        assert_eq!(har["log"]["creator"]["name"], env!("CARGO_PKG_NAME"));
+
+        har["log"]["entries"].as_array().unwrap().clone()
+    }
+
+    /// Look up a value in one of the HAR's `[{name, value}]` lists.
+    fn lookup<'a>(list: &'a Value, name: &str) -> Option<&'a str> {
+        list.as_array()
+            .unwrap()
+            .iter()
+            .find(|nv| nv["name"].as_str().map_or(false, |n| n.eq_ignore_ascii_case(name)))
+            .and_then(|nv| nv["value"].as_str())
+    }
+
+    #[test]
+    fn sent_requests_are_recorded_faithfully() {
+        let url = Url::parse("http://example.com/search?q=rust&page=2").unwrap();
+        let mock = MockTransport::new();
+        let mut expectation =
+            Expectation::new(Method::Post, url.clone(), StatusCode::Created, "ok");
+        expectation.headers.set_raw("Content-Type", "text/plain");
+        expectation.headers.set_raw("Set-Cookie", "session=abc");
+        mock.add(expectation);
+
+        let recorder = Recorder::new();
+        let mut req = Request::post(url);
+        req.headers.set_raw("Content-Type", "application/json");
+        req.headers.set_raw("X-Trace", "1234");
+        req.cookies.add(Cookie::new("theme", "dark"));
+        req.set_body(r#"{"name":"Michael"}"#);
+
+        client(&recorder, &mock).send(&req).unwrap();
+
+        assert_eq!(recorder.len(), 1);
+        let entry = &entries(&recorder)[0];
+
+        let request = &entry["request"];
+        assert_eq!(request["method"], "POST");
+        assert_eq!(request["url"], "http://example.com/search?q=rust&page=2");
+        assert_eq!(request["httpVersion"], "HTTP/1.1");
+        assert_eq!(lookup(&request["headers"], "X-Trace"), Some("1234"));
+        assert_eq!(lookup(&request["cookies"], "theme"), Some("dark"));
+        assert_eq!(lookup(&request["queryString"], "q"), Some("rust"));
+        assert_eq!(lookup(&request["queryString"], "page"), Some("2"));
+        assert_eq!(request["postData"]["mimeType"], "application/json");
+        assert_eq!(request["postData"]["text"], r#"{"name":"Michael"}"#);
+        assert_eq!(request["bodySize"], 18);
+
+        let response = &entry["response"];
+        assert_eq!(response["status"], 201);
+        assert_eq!(response["statusText"], "Created");
+        assert_eq!(lookup(&response["headers"], "Content-Length"), Some("2"));
+        assert_eq!(lookup(&response["cookies"], "session"), Some("abc"));
+        assert_eq!(response["content"]["mimeType"], "text/plain");
+        assert_eq!(response["content"]["text"], "ok");
+        assert_eq!(response["content"]["size"], 2);
+        assert!(response["content"].get("encoding").is_none());
+        assert!(entry.get("_error").is_none());
+    }
+
+    #[test]
+    fn binary_bodies_are_base64_encoded() {
+        let url = Url::parse("http://example.com/image.png").unwrap();
+        let body = vec![0x89, b'P', b'N', b'G', 0xff, 0x00];
+        let mock = MockTransport::new();
+        mock.expect(Method::Get, url.clone(), StatusCode::Ok, body.clone());
+
+        let recorder = Recorder::new();
+        let got = client(&recorder, &mock).send(&Request::new(url, Method::Get)).unwrap();
+        assert_eq!(got.body, body);
+
+        let content = &entries(&recorder)[0]["response"]["content"];
+        assert_eq!(content["encoding"], "base64");
+        let text = content["text"].as_str().unwrap();
+        assert_eq!(base64::decode(text).unwrap(), body);
+    }
+
+    #[test]
+    fn failed_requests_are_recorded_with_the_error() {
+        let url = Url::parse("http://example.com/missing").unwrap();
+        let recorder = Recorder::new();
+
+        let err = client(&recorder, &MockTransport::new())
+            .send(&Request::new(url, Method::Delete))
+            .unwrap_err();
+
+        let entry = &entries(&recorder)[0];
+        assert_eq!(entry["request"]["method"], "DELETE");
+        assert!(entry["request"].get("postData").is_none());
+        assert_eq!(entry["response"]["status"], 0);
+        assert_eq!(entry["_error"], err.to_string());
+    }
+
+    #[test]
+    fn entries_are_kept_in_order_and_shared_between_clones() {
+        let mock = MockTransport::new();
+        let first = Url::parse("http://example.com/1").unwrap();
+        let second = Url::parse("http://example.com/2").unwrap();
+        mock.expect(Method::Get, first.clone(), StatusCode::Ok, "");
+        mock.expect(Method::Get, second.clone(), StatusCode::NotFound, "");
+
+        let recorder = Recorder::new();
+        let client = client(&recorder, &mock);
+        client.send(&Request::new(first, Method::Get)).unwrap();
+        client.send(&Request::new(second, Method::Get)).unwrap();
+
+        let urls: Vec<_> = entries(&recorder)
+            .iter()
+            .map(|e| e["request"]["url"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(urls, vec!["http://example.com/1", "http://example.com/2"]);
+
+        recorder.clear();
+        assert!(recorder.is_empty());
+        assert!(entries(&recorder).is_empty());
+    }
+
+    #[test]
+    fn export_har_writes_the_same_document() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mock = MockTransport::new();
+        mock.expect(Method::Get, url.clone(), StatusCode::Ok, "hello");
+        let recorder = Recorder::new();
+        client(&recorder, &mock).send(&Request::new(url, Method::Get)).unwrap();
+
+        let path = env::temp_dir().join(format!("recorder-test-{}.har", process::id()));
+        recorder.export_har(&path).unwrap();
+        let mut written = String::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut written))
+            .unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(written, recorder.to_har().unwrap());
+    }
+}
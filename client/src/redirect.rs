@@ -0,0 +1,130 @@
+//! Deciding which redirects to follow.
+
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+use cookie::CookieJar;
+use reqwest::{Method, StatusCode, Url};
+use reqwest::header::Location;
+
+use errors::*;
+use Request;
+
+
+/// The number of redirects followed when no policy is specified.
+pub const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// What to do when the server responds with a redirect.
+#[derive(Clone)]
+pub enum RedirectPolicy {
+    /// Never follow redirects, returning the `3xx` response as-is.
+    None,
+    /// Follow up to this many redirects before giving up with an error.
+    Limited(usize),
+    /// Ask a callback whether to follow the redirect to a URL, given the
+    /// chain of redirects followed so far. If the callback returns `false`
+    /// the `3xx` response is returned.
+    Custom(Arc<Fn(&Url, &[Url]) -> bool + Send + Sync>),
+}
+
+impl RedirectPolicy {
+    /// Create a policy which uses a callback to decide whether to follow
+    /// each redirect.
+    pub fn custom<F>(decide: F) -> RedirectPolicy
+    where
+        F: Fn(&Url, &[Url]) -> bool + Send + Sync + 'static,
+    {
+        RedirectPolicy::Custom(Arc::new(decide))
+    }
+
+    /// Should we follow a redirect to `next`?
+    pub(crate) fn should_follow(&self, next: &Url, previous: &[Url]) -> Result<bool> {
+        match *self {
+            RedirectPolicy::None => Ok(false),
+            RedirectPolicy::Limited(max) => {
+                if previous.len() >= max {
+                    bail!("Gave up after following {} redirects", max);
+                }
+                Ok(true)
+            }
+            RedirectPolicy::Custom(ref decide) => Ok(decide(next, previous)),
+        }
+    }
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> RedirectPolicy {
+        RedirectPolicy::Limited(DEFAULT_MAX_REDIRECTS)
+    }
+}
+
+impl Debug for RedirectPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            RedirectPolicy::None => write!(f, "None"),
+            RedirectPolicy::Limited(max) => write!(f, "Limited({})", max),
+            RedirectPolicy::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// Figure out where a response is redirecting us to, if anywhere.
+pub(crate) fn target(
+    current: &Url,
+    status: StatusCode,
+    location: Option<&Location>,
+) -> Option<Url> {
+    match status {
+        StatusCode::MovedPermanently
+        | StatusCode::Found
+        | StatusCode::SeeOther
+        | StatusCode::TemporaryRedirect
+        | StatusCode::PermanentRedirect => {}
+        _ => return None,
+    }
+
+    let location = match location {
+        Some(l) => &**l,
+        None => return None,
+    };
+
+    match current.join(location) {
+        Ok(url) => Some(url),
+        Err(e) => {
+            warn!("Ignoring a redirect to an invalid location, {:?} ({})", location, e);
+            None
+        }
+    }
+}
+
+/// Create the request sent when following a redirect.
+pub(crate) fn follow(req: &Request, status: StatusCode, next: Url) -> Request {
+    let mut redirected = req.clone();
+
+    // Browsers switch to GET for these, so servers expect it
+    let switch_to_get = match status {
+        StatusCode::SeeOther => req.method != Method::Head,
+        StatusCode::MovedPermanently | StatusCode::Found => req.method == Method::Post,
+        _ => false,
+    };
+
+    if switch_to_get {
+        redirected.method = Method::Get;
+        redirected.body = None;
+        redirected.form = None;
+        redirected.headers.remove_raw("Content-Type");
+        redirected.headers.remove_raw("Content-Length");
+    }
+
+    // Don't leak credentials to another server. Switching scheme or port
+    // counts, because that may be a different server on the same host.
+    if next.origin() != req.destination.origin() {
+        debug!("Redirected to another origin, dropping credentials and cookies");
+        redirected.headers.remove_raw("Authorization");
+        redirected.headers.remove_raw("Proxy-Authorization");
+        redirected.headers.remove_raw("Cookie");
+        redirected.cookies = CookieJar::new();
+    }
+
+    redirected.destination = next;
+    redirected
+}
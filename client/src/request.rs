@@ -1,7 +1,14 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
 use cookie::CookieJar;
 use reqwest::{self, Method, Url};
 use reqwest::header::{Cookie, Headers};
 
+use auth::{Authenticator, BasicAuth, BearerToken};
+use form::{Form, FormBuilder};
+use options::RequestOptions;
+use redirect::RedirectPolicy;
+
 
 /// A HTTP request.
 #[derive(Debug, Clone)]
@@ -11,6 +18,20 @@ pub struct Request {
     pub headers: Headers,
     pub cookies: CookieJar,
     pub body: Option<Vec<u8>>,
+    /// Options which override those of the `HttpClient` sending the request.
+    pub options: RequestOptions,
+    /// The form used to generate this request's body, if there is one.
+    pub form: Option<Form>,
+    /// How to handle redirects, overriding the `HttpClient`'s policy.
+    pub redirect_policy: Option<RedirectPolicy>,
+    /// Ask for a compressed response and decompress it when it arrives. Turn
+    /// this off to receive the body exactly as the server sent it.
+    pub decompress: bool,
+    /// Adds credentials to the request immediately before it is sent,
+    /// overriding the `HttpClient`'s authenticator.
+    pub authenticator: Option<Arc<Authenticator>>,
+    /// The names of plugins which shouldn't run for this request.
+    pub skip_plugins: BTreeSet<String>,
 }
 
 impl Request {
@@ -25,6 +46,118 @@ impl Request {
             headers,
             cookies,
             body,
+            options: RequestOptions::default(),
+            form: None,
+            redirect_policy: None,
+            decompress: true,
+            authenticator: None,
+            skip_plugins: BTreeSet::new(),
+        }
+    }
+
+    /// Create a `GET` request.
+    pub fn get(destination: Url) -> Request {
+        Request::new(destination, Method::Get)
+    }
+
+    /// Create a `POST` request.
+    pub fn post(destination: Url) -> Request {
+        Request::new(destination, Method::Post)
+    }
+
+    /// Create a `PUT` request.
+    pub fn put(destination: Url) -> Request {
+        Request::new(destination, Method::Put)
+    }
+
+    /// Create a `DELETE` request.
+    pub fn delete(destination: Url) -> Request {
+        Request::new(destination, Method::Delete)
+    }
+
+    /// Create a `PATCH` request.
+    pub fn patch(destination: Url) -> Request {
+        Request::new(destination, Method::Patch)
+    }
+
+    /// Create a `HEAD` request.
+    pub fn head(destination: Url) -> Request {
+        Request::new(destination, Method::Head)
+    }
+
+    /// Set the request body, replacing any previous body.
+    pub fn set_body<B: Into<Vec<u8>>>(&mut self, body: B) {
+        self.body = Some(body.into());
+        self.form = None;
+    }
+
+    /// Use HTTP Basic authentication.
+    pub fn set_basic_auth(&mut self, username: &str, password: &str) -> &mut Self {
+        let value = BasicAuth::new(username, password).header_value();
+        self.headers.set_raw("Authorization", value);
+        self
+    }
+
+    /// Authenticate using a bearer token (e.g. an OAuth 2.0 access token).
+    pub fn set_bearer_token(&mut self, token: &str) -> &mut Self {
+        let value = BearerToken(token.to_string()).header_value();
+        self.headers.set_raw("Authorization", value);
+        self
+    }
+
+    /// Use a custom authentication scheme.
+    pub fn set_authenticator<A: Authenticator + 'static>(&mut self, authenticator: A) -> &mut Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Don't run the named plugin's hooks for this request.
+    pub fn skip_plugin<S: Into<String>>(&mut self, name: S) -> &mut Self {
+        self.skip_plugins.insert(name.into());
+        self
+    }
+
+    /// Use a particular redirect policy for this request.
+    pub fn set_redirect_policy(&mut self, policy: RedirectPolicy) -> &mut Self {
+        self.redirect_policy = Some(policy);
+        self
+    }
+
+    /// Send the request body as a form, adding fields with the returned
+    /// `FormBuilder`.
+    ///
+    /// The form is URL-encoded unless a file is added, in which case it
+    /// switches to `multipart/form-data`.
+    pub fn form(&mut self) -> FormBuilder {
+        FormBuilder::new(self)
+    }
+
+    /// Send the request body as a `multipart/form-data` form.
+    pub fn multipart(&mut self) -> FormBuilder {
+        let mut builder = FormBuilder::new(self);
+        builder.set_multipart();
+        builder
+    }
+
+    /// Should the response to this request be decompressed?
+    pub(crate) fn wants_decompression(&self) -> bool {
+        self.decompress && self.method != Method::Head
+    }
+
+    /// Regenerate the body and `Content-Type` after the form was changed.
+    pub(crate) fn sync_form(&mut self) {
+        if let Some(ref form) = self.form {
+            let (content_type, body) = form.encode();
+            self.headers.set_raw("Content-Type", content_type);
+            self.body = Some(body);
+        }
+    }
+
+    /// Does this request's method usually carry a body?
+    pub fn method_allows_body(&self) -> bool {
+        match self.method {
+            Method::Get | Method::Head | Method::Options | Method::Trace | Method::Connect => false,
+            _ => true,
         }
     }
 
@@ -40,6 +173,14 @@ impl Request {
         }
         r.headers_mut().set(cookie_header);
 
+        if self.decompress && r.headers().get_raw("Accept-Encoding").is_none() {
+            r.headers_mut().set_raw("Accept-Encoding", "gzip, deflate");
+        }
+
+        if let Some(ref body) = self.body {
+            *r.body_mut() = Some(body.clone().into());
+        }
+
         r
     }
 }
@@ -1,33 +1,166 @@
-use std::io::Read;
-use reqwest::{self, StatusCode};
-use reqwest::header::Headers;
+use std::io::{self, Read};
+use std::str;
+use cookie::CookieJar;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use reqwest::{self, StatusCode, Url};
+use reqwest::header::{ContentLength, Headers};
 
+use cookies;
 use errors::*;
+use CancellationToken;
 
 
+/// How many bytes of the body to read at a time.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+
+/// How long each stage of a request took, in milliseconds.
+///
+/// A negative value means the time isn't known (e.g. because the response
+/// came from a plugin).
+///
+/// DNS lookups, connecting and the TLS handshake aren't timed separately.
+/// The HTTP backend does all three inside its connection pool without
+/// reporting back, so they're included in `first_byte_ms` (and take no time
+/// at all when a pooled connection is reused).
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct Timing {
+    /// From starting to send the request until the response headers arrived,
+    /// including any redirects.
+    pub first_byte_ms: f64,
+    /// From starting to send the request until the whole body was received.
+    pub total_ms: f64,
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing {
+            first_byte_ms: -1.0,
+            total_ms: -1.0,
+        }
+    }
+}
+
 /// Response received from the server.
 #[derive(Debug, Clone)]
 pub struct Response {
     pub headers: Headers,
     pub body: Vec<u8>,
     pub status: StatusCode,
+    /// Cookies set by the server.
+    pub cookies: CookieJar,
+    /// Every URL we were redirected to, in order. The last one is where the
+    /// response actually came from.
+    pub redirects: Vec<Url>,
+    /// How long the request took.
+    pub timing: Timing,
 }
 
 impl Response {
-    pub(crate) fn from_reqwest(original: reqwest::Response) -> Result<Response> {
-        let mut original = original.error_for_status()?;
-        let headers = original.headers().clone();
-        let status = original.status();
+    /// Did the server respond with a `2xx` status code?
+    pub fn is_success(&self) -> bool {
+        self.status.is_success()
+    }
 
+    /// Convert a `reqwest::Response`, reading the body in chunks so we can
+    /// stop early if the request is cancelled.
+    pub(crate) fn from_reqwest(
+        original: reqwest::Response,
+        token: Option<&CancellationToken>,
+        decompress: bool,
+    ) -> Result<Response> {
         let mut body = Vec::new();
-        original
-            .read_to_end(&mut body)
-            .chain_err(|| "Unable to read the response body")?;
+
+        let mut response = Response::stream_reqwest(original, token, decompress, |chunk| {
+            body.extend_from_slice(chunk);
+            Ok(())
+        })?;
+
+        response.body = body;
+        Ok(response)
+    }
+
+    /// Convert a `reqwest::Response`, passing each chunk of the body to a
+    /// callback instead of saving it.
+    ///
+    /// If `decompress` is set, gzip and deflate bodies are decompressed
+    /// before being passed to the callback.
+    pub(crate) fn stream_reqwest<F>(
+        original: reqwest::Response,
+        token: Option<&CancellationToken>,
+        decompress: bool,
+        mut on_chunk: F,
+    ) -> Result<Response>
+    where
+        F: FnMut(&[u8]) -> Result<()>,
+    {
+        let mut headers = original.headers().clone();
+        let cookies = cookies::from_headers(&headers);
+        let status = original.status();
+        let mut buffer = [0; CHUNK_SIZE];
+
+        let encoding = if decompress && has_body(status, &headers) {
+            content_encoding(&headers)
+        } else {
+            None
+        };
+
+        let (mut reader, decoded): (Box<Read>, bool) = match encoding.as_ref().map(|e| e.as_str()) {
+            Some("gzip") | Some("x-gzip") => (Box::new(GzDecoder::new(original)), true),
+            Some("deflate") => (Box::new(ZlibDecoder::new(original)), true),
+            Some("identity") | None => (Box::new(original), false),
+            Some(other) => {
+                warn!("Unable to decode a {:?} body, leaving it as-is", other);
+                (Box::new(original), false)
+            }
+        };
+
+        if decoded {
+            // The body we hand back no longer matches these
+            headers.remove_raw("Content-Encoding");
+            headers.remove_raw("Content-Length");
+        }
+
+        loop {
+            if let Some(token) = token {
+                token.check()?;
+            }
+
+            match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => on_chunk(&buffer[..n])?,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::with_chain(e, "Unable to read the response body")),
+            }
+        }
 
         Ok(Response {
             status,
-            body,
+            body: Vec::new(),
             headers,
+            cookies,
+            redirects: Vec::new(),
+            timing: Timing::default(),
         })
     }
 }
+
+/// Get the (lowercase) `Content-Encoding` of a response body, if it has one.
+fn content_encoding(headers: &Headers) -> Option<String> {
+    headers
+        .get_raw("Content-Encoding")
+        .and_then(|raw| raw.one())
+        .and_then(|value| str::from_utf8(value).ok())
+        .map(|value| value.trim().to_lowercase())
+}
+
+/// Some responses never have a body, so trying to decompress one would fail.
+fn has_body(status: StatusCode, headers: &Headers) -> bool {
+    let empty = headers
+        .get::<ContentLength>()
+        .map(|length| length.0 == 0)
+        .unwrap_or(false);
+
+    !empty && status != StatusCode::NoContent && status != StatusCode::NotModified
+}
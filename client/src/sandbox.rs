@@ -0,0 +1,836 @@
+//! Running a plugin in its own process.
+//!
+//! A sandboxed plugin is loaded by a separate `plugin-host` executable
+//! instead of the client itself. The host talks to it over the child's
+//! stdin and stdout, where each message is a big-endian `u32` length
+//! followed by that many bytes of JSON.
+//!
+//! This protects the client from a plugin which crashes or corrupts memory,
+//! but it is *not* a security sandbox. The child runs as the same user with
+//! the same permissions as the client, so it can still read and write files,
+//! open network connections, and so on. Only load plugins you trust.
+//!
+//! Inside the host the plugin is represented by a `SandboxedPlugin`, which
+//! implements the `Plugin` trait by forwarding each hook to the child. If
+//! the child crashes or sends something we don't understand, the proxy
+//! panics so the plugin is poisoned like any other plugin which panics.
+//!
+//! Every hook is forwarded, with the async hooks falling back to their
+//! blocking versions. The plugin host keeps the original stdout to itself
+//! and points the plugin's stdout at stderr, so a plugin which prints
+//! something can't corrupt the messages. A sandboxed plugin gets a
+//! `PluginContext` of its own instead of sharing the client's.
+
+use std::env;
+use std::env::consts::EXE_SUFFIX;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+use base64;
+use cookie::{Cookie, CookieJar};
+use libc::{self, c_int};
+use reqwest::{Method, StatusCode};
+use reqwest::header::Headers;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use reqwest::Url;
+
+use abi::{Capabilities, PluginHandle};
+use context::PluginContext;
+use dependencies::Dependency;
+use errors::*;
+use plugins::{HookResult, Plugin, PluginConfig, PluginMetadata};
+use urls::parse_url;
+use validate::ValidationWarning;
+use {PluginManager, Request, Response, Timing};
+
+
+/// The largest message we're willing to receive, so a misbehaving plugin
+/// can't make us allocate an arbitrary amount of memory.
+pub const MAX_MESSAGE_SIZE: u32 = 64 * 1024 * 1024;
+
+/// How long the plugin host gets to answer a message before we assume it has
+/// hung and kill it.
+pub const CALL_TIMEOUT_MS: u64 = 30 * 1000;
+
+/// Where to find the `plugin-host` executable if nothing else is specified.
+/// It normally lives alongside the program using this library.
+pub fn default_host_executable() -> PathBuf {
+    let name = format!("plugin-host{}", EXE_SUFFIX);
+
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(&name)))
+        .unwrap_or_else(|| PathBuf::from(name))
+}
+
+/// Messages sent from the client to the plugin host.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum HostMessage {
+    Load { path: PathBuf },
+    Configure { key: String, value: String },
+    PreSend { request: WireRequest },
+    PostReceive { response: WireResponse },
+    Error { message: String, request: WireRequest },
+    Redirect { url: String, request: WireRequest },
+    Retry { attempt: u32, request: WireRequest },
+    QuotaExceeded {
+        environment: String,
+        request: WireRequest,
+    },
+    Validate { request: WireRequest },
+    Shutdown,
+}
+
+/// Messages sent back by the plugin host.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PluginMessage {
+    Loaded { metadata: PluginMetadata },
+    PreSend {
+        request: WireRequest,
+        result: WireHookResult,
+    },
+    PostReceive { response: WireResponse },
+    /// The request after a hook which can change it.
+    Request { request: WireRequest },
+    Warnings { warnings: Vec<ValidationWarning> },
+    Done,
+    Error { message: String },
+}
+
+/// The parts of a `Request` a plugin can see and change.
+#[derive(Debug, Serialize, Deserialize)]
+struct WireRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    cookies: Vec<(String, String)>,
+    /// Base64 encoded.
+    body: Option<String>,
+}
+
+/// The parts of a `Response` a plugin can see and change.
+#[derive(Debug, Serialize, Deserialize)]
+struct WireResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    cookies: Vec<(String, String)>,
+    /// Base64 encoded.
+    body: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WireHookResult {
+    Continue,
+    Abort { reason: String },
+    Respond { response: WireResponse },
+}
+
+impl WireRequest {
+    fn new(req: &Request) -> WireRequest {
+        WireRequest {
+            method: req.method.to_string(),
+            url: req.destination.to_string(),
+            headers: header_pairs(&req.headers),
+            cookies: cookie_pairs(&req.cookies),
+            body: req.body.as_ref().map(base64::encode),
+        }
+    }
+
+    fn to_request(&self) -> Result<Request> {
+        let mut req = Request::new(parse_url(&self.url)?, Method::Get);
+        self.apply(&mut req)?;
+        Ok(req)
+    }
+
+    /// Copy the plugin's changes back into the request.
+    fn apply(&self, req: &mut Request) -> Result<()> {
+        req.method = self.method
+            .parse()
+            .chain_err(|| format!("\"{}\" isn't a valid HTTP method", self.method))?;
+        req.destination = parse_url(&self.url)?;
+        req.headers = headers_from_pairs(&self.headers);
+        req.cookies = jar_from_pairs(&self.cookies);
+        req.body = match self.body {
+            Some(ref body) => Some(base64::decode(body).chain_err(|| "Invalid request body")?),
+            None => None,
+        };
+
+        Ok(())
+    }
+}
+
+impl WireResponse {
+    fn new(res: &Response) -> WireResponse {
+        WireResponse {
+            status: res.status.as_u16(),
+            headers: header_pairs(&res.headers),
+            cookies: cookie_pairs(&res.cookies),
+            body: base64::encode(&res.body),
+        }
+    }
+
+    fn to_response(&self) -> Result<Response> {
+        let mut res = Response {
+            status: StatusCode::Ok,
+            headers: Headers::new(),
+            cookies: CookieJar::new(),
+            body: Vec::new(),
+            redirects: Vec::new(),
+            timing: Timing::default(),
+        };
+
+        self.apply(&mut res)?;
+        Ok(res)
+    }
+
+    /// Copy the plugin's changes back into the response.
+    fn apply(&self, res: &mut Response) -> Result<()> {
+        res.status = StatusCode::from(self.status);
+        res.headers = headers_from_pairs(&self.headers);
+        res.cookies = jar_from_pairs(&self.cookies);
+        res.body = base64::decode(&self.body).chain_err(|| "Invalid response body")?;
+
+        Ok(())
+    }
+}
+
+fn header_pairs(headers: &Headers) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|h| (h.name().to_string(), h.value_string()))
+        .collect()
+}
+
+fn headers_from_pairs(pairs: &[(String, String)]) -> Headers {
+    let mut headers = Headers::new();
+
+    for &(ref name, ref value) in pairs {
+        headers.append_raw(name.clone(), value.clone());
+    }
+
+    headers
+}
+
+fn cookie_pairs(jar: &CookieJar) -> Vec<(String, String)> {
+    jar.iter()
+        .map(|c| (c.name().to_string(), c.value().to_string()))
+        .collect()
+}
+
+fn jar_from_pairs(pairs: &[(String, String)]) -> CookieJar {
+    let mut jar = CookieJar::new();
+
+    for &(ref name, ref value) in pairs {
+        jar.add(Cookie::new(name.clone(), value.clone()));
+    }
+
+    jar
+}
+
+fn write_message<W: Write, T: Serialize>(writer: &mut W, msg: &T) -> Result<()> {
+    let body = serde_json::to_vec(msg).chain_err(|| "Unable to serialize the message")?;
+    let length = body.len() as u32;
+    let header = [
+        (length >> 24) as u8,
+        (length >> 16) as u8,
+        (length >> 8) as u8,
+        length as u8,
+    ];
+
+    writer
+        .write_all(&header)
+        .and_then(|_| writer.write_all(&body))
+        .and_then(|_| writer.flush())
+        .chain_err(|| "Unable to send the message")
+}
+
+/// Read the next message, returning `None` if the other end hung up.
+fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut header = [0; 4];
+    match reader.read_exact(&mut header) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(Error::with_chain(e, "Unable to read the message")),
+    }
+
+    let length = header
+        .iter()
+        .fold(0, |length, &byte| (length << 8) | u32::from(byte));
+    if length > MAX_MESSAGE_SIZE {
+        bail!("The message is too big ({} bytes)", length);
+    }
+
+    let mut body = vec![0; length as usize];
+    reader
+        .read_exact(&mut body)
+        .chain_err(|| "Unable to read the message")?;
+
+    serde_json::from_slice(&body)
+        .map(Some)
+        .chain_err(|| "Unable to parse the message")
+}
+
+/// The plugin host's side of a sandboxed plugin's process.
+///
+/// Messages are written and read on background threads so a plugin host
+/// which stops responding can't block the caller forever.
+struct Process {
+    child: Child,
+    requests: Sender<HostMessage>,
+    replies: Receiver<Result<Option<PluginMessage>>>,
+    timeout: Duration,
+}
+
+impl Process {
+    fn new(mut child: Child, timeout: Duration) -> Process {
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        Process {
+            child,
+            requests: send_requests(stdin),
+            replies: read_replies(stdout),
+            timeout,
+        }
+    }
+
+    /// Send a message and wait for the reply, killing the plugin host if it
+    /// takes too long.
+    fn call(&mut self, msg: HostMessage) -> Result<PluginMessage> {
+        if self.requests.send(msg).is_err() {
+            bail!("The plugin host is no longer accepting messages");
+        }
+
+        let reply = match self.replies.recv_timeout(self.timeout) {
+            Ok(reply) => reply?,
+            Err(RecvTimeoutError::Timeout) => {
+                self.kill();
+                bail!("The plugin host didn't respond within {:?}", self.timeout);
+            }
+            Err(RecvTimeoutError::Disconnected) => None,
+        };
+
+        match reply {
+            Some(PluginMessage::Error { message }) => Err(Error::from(message)),
+            Some(reply) => Ok(reply),
+            None => bail!("The plugin host exited unexpectedly"),
+        }
+    }
+
+    /// Ask the plugin host to unload the plugin and exit, killing it if that
+    /// doesn't work.
+    fn shutdown(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            match self.call(HostMessage::Shutdown) {
+                Ok(_) => {
+                    let _ = self.child.wait();
+                    return;
+                }
+                Err(e) => warn!("Unable to shut down the plugin host cleanly: {}", e),
+            }
+        }
+
+        self.kill();
+    }
+
+    fn kill(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+        }
+        let _ = self.child.wait();
+    }
+}
+
+/// Write each message to the plugin host's stdin until it hangs up or the
+/// `Process` is dropped.
+fn send_requests(stdin: ChildStdin) -> Sender<HostMessage> {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut stdin = stdin;
+
+        for msg in rx {
+            if let Err(e) = write_message(&mut stdin, &msg) {
+                debug!("Stopped writing to the plugin host: {}", e);
+                break;
+            }
+        }
+    });
+
+    tx
+}
+
+/// Read messages from the plugin host's stdout until it hangs up or sends
+/// something we can't read.
+fn read_replies(stdout: ChildStdout) -> Receiver<Result<Option<PluginMessage>>> {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut stdout = BufReader::new(stdout);
+
+        loop {
+            let reply = read_message(&mut stdout);
+            let finished = match reply {
+                Ok(Some(_)) => false,
+                _ => true,
+            };
+
+            if tx.send(reply).is_err() || finished {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+/// A plugin running in another process.
+pub(crate) struct SandboxedPlugin {
+    name: String,
+    version: String,
+    author: String,
+    description: String,
+    capabilities: Capabilities,
+    priority: i32,
+    dependencies: Vec<Dependency>,
+    process: Mutex<Process>,
+}
+
+impl SandboxedPlugin {
+    /// Start a `plugin-host` process and get it to load the plugin.
+    pub(crate) fn spawn(host: &Path, path: &Path) -> Result<SandboxedPlugin> {
+        debug!("Loading {} in a sandbox using {}", path.display(), host.display());
+
+        let child = Command::new(host)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .chain_err(|| format!("Unable to start {}", host.display()))?;
+        let mut process = Process::new(child, Duration::from_millis(CALL_TIMEOUT_MS));
+
+        let path = path.canonicalize()
+            .chain_err(|| format!("Unable to find {}", path.display()))?;
+        let metadata = match process.call(HostMessage::Load { path }) {
+            Ok(PluginMessage::Loaded { metadata }) => metadata,
+            Ok(other) => {
+                process.shutdown();
+                bail!("Expected the plugin's metadata but got {:?}", other);
+            }
+            Err(e) => {
+                process.shutdown();
+                return Err(e.chain_err(|| "The plugin host couldn't load the plugin"));
+            }
+        };
+
+        Ok(SandboxedPlugin {
+            name: metadata.name,
+            version: metadata.version,
+            author: metadata.author,
+            description: metadata.description,
+            capabilities: metadata.capabilities,
+            priority: metadata.priority,
+            dependencies: metadata.dependencies,
+            process: Mutex::new(process),
+        })
+    }
+
+    /// Forward a message to the plugin host, panicking if it doesn't respond
+    /// properly.
+    fn call(&self, msg: HostMessage) -> PluginMessage {
+        let mut process = self.process
+            .lock()
+            .expect("A previous call to the plugin host panicked");
+
+        match process.call(msg) {
+            Ok(reply) => reply,
+            Err(e) => panic!("The sandboxed {:?} plugin failed: {}", self.name, e),
+        }
+    }
+
+    /// Forward a message which the plugin host only needs to acknowledge.
+    fn notify(&self, msg: HostMessage) {
+        match self.call(msg) {
+            PluginMessage::Done => {}
+            other => panic!("Expected an acknowledgement but got {:?}", other),
+        }
+    }
+
+    /// Forward a message to a hook which may change the request.
+    fn update(&self, msg: HostMessage, request: &mut Request) {
+        let changed = match self.call(msg) {
+            PluginMessage::Request { request } => request,
+            other => panic!("Expected the updated request but got {:?}", other),
+        };
+
+        if let Err(e) = changed.apply(request) {
+            panic!("The sandboxed plugin sent back an invalid request: {}", e);
+        }
+    }
+}
+
+impl Plugin for SandboxedPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn version(&self) -> &str {
+        &self.version
+    }
+
+    fn author(&self) -> &str {
+        &self.author
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn dependencies(&self) -> Vec<Dependency> {
+        self.dependencies.clone()
+    }
+
+    fn on_plugin_unload(&self, _ctx: &PluginContext) {
+        if let Ok(mut process) = self.process.lock() {
+            process.shutdown();
+        }
+    }
+
+    fn on_configure(&self, _ctx: &PluginContext, config: &PluginConfig) {
+        for (key, value) in config.values() {
+            let msg = HostMessage::Configure {
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+
+            self.notify(msg);
+        }
+    }
+
+    fn pre_send(&self, _ctx: &PluginContext, request: &mut Request) -> HookResult {
+        let msg = HostMessage::PreSend {
+            request: WireRequest::new(request),
+        };
+
+        let (changed, result) = match self.call(msg) {
+            PluginMessage::PreSend { request, result } => (request, result),
+            other => panic!("Expected the updated request but got {:?}", other),
+        };
+
+        if let Err(e) = changed.apply(request) {
+            panic!("The sandboxed plugin sent back an invalid request: {}", e);
+        }
+
+        match result {
+            WireHookResult::Continue => HookResult::Continue,
+            WireHookResult::Abort { reason } => HookResult::Abort(reason),
+            WireHookResult::Respond { response } => match response.to_response() {
+                Ok(r) => HookResult::Respond(r),
+                Err(e) => panic!("The sandboxed plugin sent back an invalid response: {}", e),
+            },
+        }
+    }
+
+    fn post_receive(&self, _ctx: &PluginContext, response: &mut Response) {
+        let msg = HostMessage::PostReceive {
+            response: WireResponse::new(response),
+        };
+
+        let changed = match self.call(msg) {
+            PluginMessage::PostReceive { response } => response,
+            other => panic!("Expected the updated response but got {:?}", other),
+        };
+
+        if let Err(e) = changed.apply(response) {
+            panic!("The sandboxed plugin sent back an invalid response: {}", e);
+        }
+    }
+
+    fn on_error(&self, _ctx: &PluginContext, error: &Error, request: &Request) {
+        self.notify(HostMessage::Error {
+            message: describe(error),
+            request: WireRequest::new(request),
+        });
+    }
+
+    fn on_redirect(&self, _ctx: &PluginContext, url: &Url, request: &mut Request) {
+        let msg = HostMessage::Redirect {
+            url: url.to_string(),
+            request: WireRequest::new(request),
+        };
+
+        self.update(msg, request);
+    }
+
+    fn on_retry(&self, _ctx: &PluginContext, attempt: u32, request: &mut Request) {
+        let msg = HostMessage::Retry {
+            attempt,
+            request: WireRequest::new(request),
+        };
+
+        self.update(msg, request);
+    }
+
+    fn on_quota_exceeded(&self, _ctx: &PluginContext, environment: &str, request: &Request) {
+        self.notify(HostMessage::QuotaExceeded {
+            environment: environment.to_string(),
+            request: WireRequest::new(request),
+        });
+    }
+
+    fn validate(&self, _ctx: &PluginContext, request: &Request) -> Vec<ValidationWarning> {
+        let msg = HostMessage::Validate {
+            request: WireRequest::new(request),
+        };
+
+        match self.call(msg) {
+            PluginMessage::Warnings { warnings } => warnings,
+            other => panic!("Expected validation warnings but got {:?}", other),
+        }
+    }
+}
+
+impl Drop for SandboxedPlugin {
+    fn drop(&mut self) {
+        match self.process.lock() {
+            Ok(mut process) => process.shutdown(),
+            // We can't trust a plugin host which has already misbehaved
+            Err(poisoned) => poisoned.into_inner().kill(),
+        }
+    }
+}
+
+/// The entry point for the `plugin-host` executable. Loads a plugin and
+/// answers requests from the client on stdin/stdout until told to stop.
+pub fn serve() -> Result<()> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut output = private_stdout()?;
+
+    let mut pm = PluginManager::new();
+    let mut name = None;
+
+    while let Some(msg) = read_message(&mut input)? {
+        let shutting_down = match msg {
+            HostMessage::Shutdown => true,
+            _ => false,
+        };
+
+        let reply = handle(&mut pm, &mut name, msg).unwrap_or_else(|e| PluginMessage::Error {
+            message: describe(&e),
+        });
+        write_message(&mut output, &reply)?;
+
+        if shutting_down {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Take the process's stdout for talking to the client, and point the
+/// original at stderr so anything the plugin prints ends up there instead.
+fn private_stdout() -> Result<File> {
+    const STDOUT: c_int = 1;
+    const STDERR: c_int = 2;
+
+    io::stdout()
+        .flush()
+        .chain_err(|| "Unable to flush stdout")?;
+
+    unsafe {
+        let fd = libc::dup(STDOUT);
+        if fd < 0 {
+            let e = io::Error::last_os_error();
+            return Err(Error::with_chain(e, "Unable to duplicate stdout"));
+        }
+
+        if libc::dup2(STDERR, STDOUT) < 0 {
+            let e = io::Error::last_os_error();
+            return Err(Error::with_chain(e, "Unable to redirect stdout to stderr"));
+        }
+
+        Ok(file_from_fd(fd))
+    }
+}
+
+#[cfg(unix)]
+unsafe fn file_from_fd(fd: c_int) -> File {
+    use std::os::unix::io::FromRawFd;
+    File::from_raw_fd(fd)
+}
+
+#[cfg(windows)]
+unsafe fn file_from_fd(fd: c_int) -> File {
+    use std::os::windows::io::{FromRawHandle, RawHandle};
+    File::from_raw_handle(libc::get_osfhandle(fd) as RawHandle)
+}
+
+fn handle(
+    pm: &mut PluginManager,
+    name: &mut Option<String>,
+    msg: HostMessage,
+) -> Result<PluginMessage> {
+    if let HostMessage::Load { path } = msg {
+        unsafe {
+            pm.load_sandboxed_plugin(&path)?;
+        }
+        let metadata = pm.list()
+            .pop()
+            .ok_or_else(|| Error::from("The plugin wasn't loaded"))?;
+        *name = Some(metadata.name.clone());
+
+        return Ok(PluginMessage::Loaded { metadata });
+    }
+
+    let name = match *name {
+        Some(ref n) => n.as_str(),
+        None => bail!("No plugin has been loaded"),
+    };
+
+    match msg {
+        HostMessage::Load { .. } => unreachable!(),
+        HostMessage::Configure { key, value } => {
+            pm.configure(name, &key, &value)?;
+            Ok(PluginMessage::Done)
+        }
+        HostMessage::PreSend { request } => {
+            let mut req = request.to_request()?;
+            let result = match pm.pre_send(&mut req)? {
+                HookResult::Continue => WireHookResult::Continue,
+                HookResult::Abort(reason) => WireHookResult::Abort { reason },
+                HookResult::Respond(r) => WireHookResult::Respond {
+                    response: WireResponse::new(&r),
+                },
+            };
+
+            Ok(PluginMessage::PreSend {
+                request: WireRequest::new(&req),
+                result,
+            })
+        }
+        HostMessage::PostReceive { response } => {
+            let mut res = response.to_response()?;
+            pm.post_receive(&mut res)?;
+
+            Ok(PluginMessage::PostReceive {
+                response: WireResponse::new(&res),
+            })
+        }
+        HostMessage::Error { message, request } => {
+            let req = request.to_request()?;
+            let error = Error::from(message);
+            loaded(pm, name)?.on_error(pm.context(), &error, &req)?;
+            Ok(PluginMessage::Done)
+        }
+        HostMessage::Redirect { url, request } => {
+            let mut req = request.to_request()?;
+            let url = parse_url(&url)?;
+            loaded(pm, name)?.on_redirect(pm.context(), &url, &mut req)?;
+
+            Ok(PluginMessage::Request {
+                request: WireRequest::new(&req),
+            })
+        }
+        HostMessage::Retry { attempt, request } => {
+            let mut req = request.to_request()?;
+            loaded(pm, name)?.on_retry(pm.context(), attempt, &mut req)?;
+
+            Ok(PluginMessage::Request {
+                request: WireRequest::new(&req),
+            })
+        }
+        HostMessage::QuotaExceeded {
+            environment,
+            request,
+        } => {
+            let req = request.to_request()?;
+            loaded(pm, name)?.on_quota_exceeded(pm.context(), &environment, &req)?;
+            Ok(PluginMessage::Done)
+        }
+        HostMessage::Validate { request } => {
+            let req = request.to_request()?;
+            let warnings = loaded(pm, name)?.validate(pm.context(), &req)?;
+            Ok(PluginMessage::Warnings { warnings })
+        }
+        HostMessage::Shutdown => {
+            pm.unload();
+            Ok(PluginMessage::Done)
+        }
+    }
+}
+
+/// The plugin being hosted, as long as it hasn't panicked.
+///
+/// The `PluginManager` only logs plugins which panic in hooks like
+/// `on_error()`, so they're called directly to find out whether it worked.
+fn loaded<'a>(pm: &'a PluginManager, name: &str) -> Result<&'a PluginHandle> {
+    pm.ordered()
+        .into_iter()
+        .find(|plugin| plugin.name() == name)
+        .ok_or_else(|| Error::from("The plugin has panicked and can't be used any more"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn process(program: &str, args: &[&str]) -> Process {
+        let child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        Process::new(child, Duration::from_millis(200))
+    }
+
+    #[test]
+    fn hung_plugin_hosts_are_killed() {
+        let mut process = process("sleep", &["60"]);
+        let started = Instant::now();
+
+        let err = process.call(HostMessage::Shutdown).unwrap_err();
+
+        assert!(err.to_string().starts_with("The plugin host didn't respond"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(process.child.try_wait().unwrap().is_some());
+    }
+
+    #[test]
+    fn plugin_hosts_which_exit_are_detected() {
+        let mut process = process("true", &[]);
+
+        let err = process.call(HostMessage::Shutdown).unwrap_err();
+
+        assert_eq!(err.to_string(), "The plugin host exited unexpectedly");
+    }
+
+    #[test]
+    fn garbage_from_the_plugin_host_is_an_error() {
+        let mut process = process("echo", &["this isn't a message"]);
+
+        assert!(process.call(HostMessage::Shutdown).is_err());
+    }
+}
@@ -0,0 +1,440 @@
+//! A collection of saved requests, the way a REST client GUI would organise
+//! them.
+//!
+//! Sessions are saved to disk as JSON. Each request is stored as a
+//! `RequestTemplate`, so it can use `{{variable}}` placeholders which are
+//! filled in from the session's variables when the request is retrieved.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::ptr;
+use libc::{c_char, c_int, size_t};
+use serde_json;
+
+use errors::*;
+use ffi::{c_str_to_str, catch_panic, copy_to_buffer, update_last_error};
+use {Environment, Request, RequestTemplate};
+
+
+/// Credentials added to every request in a session.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "scheme", rename_all = "lowercase")]
+pub enum SessionAuth {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+
+/// Named requests plus the settings they share.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    #[serde(default)]
+    requests: BTreeMap<String, RequestTemplate>,
+    /// Headers added to every request which doesn't already set them.
+    #[serde(default)]
+    pub default_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub auth: Option<SessionAuth>,
+    /// Values for the placeholders in the saved requests.
+    #[serde(default)]
+    pub variables: Environment,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    /// Load a session previously written by [`save()`].
+    ///
+    /// [`save()`]: #method.save
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Session> {
+        let path = path.as_ref();
+        debug!("Loading the session from {}", path.display());
+
+        let f = File::open(path).chain_err(|| format!("Unable to open {}", path.display()))?;
+        serde_json::from_reader(f).chain_err(|| "Unable to parse the session")
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        debug!("Saving the session to {}", path.display());
+
+        let f = File::create(path).chain_err(|| format!("Unable to create {}", path.display()))?;
+        serde_json::to_writer_pretty(f, self).chain_err(|| "Unable to save the session")
+    }
+
+    /// Save a request under `name`, replacing any request which already has
+    /// that name.
+    pub fn add_request<S: Into<String>>(&mut self, name: S, req: &Request) -> Result<()> {
+        let template = RequestTemplate::from_request(req)?;
+        self.add_template(name, template);
+        Ok(())
+    }
+
+    pub fn add_template<S: Into<String>>(&mut self, name: S, template: RequestTemplate) {
+        self.requests.insert(name.into(), template);
+    }
+
+    pub fn remove_request(&mut self, name: &str) -> Option<RequestTemplate> {
+        self.requests.remove(name)
+    }
+
+    pub fn template(&self, name: &str) -> Option<&RequestTemplate> {
+        self.requests.get(name)
+    }
+
+    /// The names of every saved request, in alphabetical order.
+    pub fn request_names(&self) -> Vec<&str> {
+        self.requests.keys().map(|k| k.as_str()).collect()
+    }
+
+    /// Get a saved request, ready to be sent.
+    pub fn get_request(&self, name: &str) -> Result<Request> {
+        let template = self.template(name)
+            .ok_or_else(|| Error::from(format!("There is no request called \"{}\"", name)))?;
+        let mut req = template.render(&self.variables)?;
+
+        for (header, value) in &self.default_headers {
+            if req.headers.get_raw(header).is_none() {
+                req.headers.set_raw(header.clone(), value.clone());
+            }
+        }
+
+        match self.auth {
+            Some(SessionAuth::Basic {
+                ref username,
+                ref password,
+            }) => {
+                req.set_basic_auth(username, password);
+            }
+            Some(SessionAuth::Bearer { ref token }) => {
+                req.set_bearer_token(token);
+            }
+            None => {}
+        }
+
+        Ok(req)
+    }
+}
+
+/// Create an empty session.
+#[no_mangle]
+pub extern "C" fn session_new() -> *mut Session {
+    catch_panic(ptr::null_mut(), || Box::into_raw(Box::new(Session::new())))
+}
+
+/// Destroy a session once you are done with it.
+#[no_mangle]
+pub unsafe extern "C" fn session_destroy(session: *mut Session) {
+    catch_panic((), || {
+        if !session.is_null() {
+            drop(Box::from_raw(session));
+        }
+    })
+}
+
+/// Load a session from disk, returning a null pointer if it couldn't be
+/// loaded.
+#[no_mangle]
+pub unsafe extern "C" fn session_load(path: *const c_char) -> *mut Session {
+    catch_panic(ptr::null_mut(), || {
+        let path = match c_str_to_str(path, "session path") {
+            Some(p) => p,
+            None => return ptr::null_mut(),
+        };
+
+        match Session::load(path) {
+            Ok(session) => Box::into_raw(Box::new(session)),
+            Err(e) => {
+                update_last_error(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Save a session to disk.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn session_save(session: *const Session, path: *const c_char) -> c_int {
+    catch_panic(-1, || {
+        if session.is_null() {
+            update_last_error(Error::from("Null pointer passed to session_save()"));
+            return -1;
+        }
+
+        let path = match c_str_to_str(path, "session path") {
+            Some(p) => p,
+            None => return -1,
+        };
+
+        match (&*session).save(path) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Save a copy of the request under `name`. Only requests with a UTF-8 body
+/// can be saved.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn session_add_request(
+    session: *mut Session,
+    name: *const c_char,
+    req: *const Request,
+) -> c_int {
+    catch_panic(-1, || {
+        if session.is_null() || req.is_null() {
+            update_last_error(Error::from("Null pointer passed to session_add_request()"));
+            return -1;
+        }
+
+        let name = match c_str_to_str(name, "request name") {
+            Some(n) => n,
+            None => return -1,
+        };
+
+        match (&mut *session).add_request(name, &*req) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Remove a saved request.
+///
+/// Returns `0` on success or `-1` if there was no request with that name.
+#[no_mangle]
+pub unsafe extern "C" fn session_remove_request(
+    session: *mut Session,
+    name: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if session.is_null() {
+            update_last_error(Error::from("Null pointer passed to session_remove_request()"));
+            return -1;
+        }
+
+        let name = match c_str_to_str(name, "request name") {
+            Some(n) => n,
+            None => return -1,
+        };
+
+        match (&mut *session).remove_request(name) {
+            Some(_) => 0,
+            None => {
+                let msg = format!("There is no request called \"{}\"", name);
+                update_last_error(Error::from(msg));
+                -1
+            }
+        }
+    })
+}
+
+/// Get a new `Request` from the session, with its placeholders filled in and
+/// the session's default headers and credentials added.
+///
+/// Returns a null pointer on error.
+#[no_mangle]
+pub unsafe extern "C" fn session_get_request(
+    session: *const Session,
+    name: *const c_char,
+) -> *mut Request {
+    catch_panic(ptr::null_mut(), || {
+        if session.is_null() {
+            update_last_error(Error::from("Null pointer passed to session_get_request()"));
+            return ptr::null_mut();
+        }
+
+        let name = match c_str_to_str(name, "request name") {
+            Some(n) => n,
+            None => return ptr::null_mut(),
+        };
+
+        match (&*session).get_request(name) {
+            Ok(req) => Box::into_raw(Box::new(req)),
+            Err(e) => {
+                update_last_error(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Get the number of saved requests, or `-1` if passed a null pointer.
+#[no_mangle]
+pub unsafe extern "C" fn session_request_count(session: *const Session) -> c_int {
+    catch_panic(-1, || {
+        if session.is_null() {
+            update_last_error(Error::from("Null pointer passed to session_request_count()"));
+            return -1;
+        }
+
+        (&*session).requests.len() as c_int
+    })
+}
+
+/// Copy the name of the `index`'th saved request (in alphabetical order) into
+/// a caller-provided buffer.
+///
+/// Returns the number of bytes written, or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn session_request_name(
+    session: *const Session,
+    index: c_int,
+    buffer: *mut c_char,
+    length: size_t,
+) -> c_int {
+    catch_panic(-1, || {
+        if session.is_null() {
+            update_last_error(Error::from("Null pointer passed to session_request_name()"));
+            return -1;
+        }
+
+        let names = (&*session).request_names();
+        match names.get(index as usize) {
+            Some(name) if index >= 0 => copy_to_buffer(name.as_bytes(), buffer, length),
+            _ => {
+                update_last_error(Error::from(format!("There is no request at index {}", index)));
+                -1
+            }
+        }
+    })
+}
+
+/// Add a header to every request retrieved from the session, unless the
+/// request already sets it. Passing a null `value` removes the default.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn session_set_default_header(
+    session: *mut Session,
+    name: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if session.is_null() {
+            update_last_error(Error::from("Null pointer passed to session_set_default_header()"));
+            return -1;
+        }
+
+        let name = match c_str_to_str(name, "header name") {
+            Some(n) => n.to_string(),
+            None => return -1,
+        };
+
+        let defaults = &mut (&mut *session).default_headers;
+        if value.is_null() {
+            defaults.remove(&name);
+        } else {
+            match c_str_to_str(value, "header value") {
+                Some(v) => {
+                    defaults.insert(name, v.to_string());
+                }
+                None => return -1,
+            }
+        }
+
+        0
+    })
+}
+
+/// Use HTTP Basic authentication for every request in the session.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn session_set_basic_auth(
+    session: *mut Session,
+    username: *const c_char,
+    password: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if session.is_null() {
+            update_last_error(Error::from("Null pointer passed to session_set_basic_auth()"));
+            return -1;
+        }
+
+        let (username, password) = match (
+            c_str_to_str(username, "username"),
+            c_str_to_str(password, "password"),
+        ) {
+            (Some(u), Some(p)) => (u, p),
+            _ => return -1,
+        };
+
+        (&mut *session).auth = Some(SessionAuth::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        });
+        0
+    })
+}
+
+/// Use a bearer token for every request in the session. Passing a null
+/// `token` removes the session's credentials.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn session_set_bearer_token(
+    session: *mut Session,
+    token: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if session.is_null() {
+            update_last_error(Error::from("Null pointer passed to session_set_bearer_token()"));
+            return -1;
+        }
+
+        let auth = if token.is_null() {
+            None
+        } else {
+            match c_str_to_str(token, "token") {
+                Some(t) => Some(SessionAuth::Bearer {
+                    token: t.to_string(),
+                }),
+                None => return -1,
+            }
+        };
+
+        (&mut *session).auth = auth;
+        0
+    })
+}
+
+/// Set a variable used to fill in the placeholders in the session's requests.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn session_set_variable(
+    session: *mut Session,
+    name: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if session.is_null() {
+            update_last_error(Error::from("Null pointer passed to session_set_variable()"));
+            return -1;
+        }
+
+        let (name, value) = match (
+            c_str_to_str(name, "variable name"),
+            c_str_to_str(value, "variable value"),
+        ) {
+            (Some(n), Some(v)) => (n, v),
+            _ => return -1,
+        };
+
+        (&mut *session).variables.set(name, value);
+        0
+    })
+}
@@ -0,0 +1,449 @@
+//! Subscribing to a stream of [server-sent events][spec].
+//!
+//! [spec]: https://html.spec.whatwg.org/multipage/server-sent-events.html
+
+use std::ffi::CString;
+use std::thread;
+use std::time::Duration;
+use libc::{c_char, c_int, c_void};
+use reqwest::StatusCode;
+
+use client::status_of;
+use errors::*;
+use ffi::{catch_panic, update_last_error};
+use {HttpClient, Request};
+
+
+/// How long to wait before reconnecting if the server doesn't tell us.
+pub const DEFAULT_RECONNECT_MS: u64 = 3000;
+
+/// A single event received from the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// The most recent event ID sent by the server, if any.
+    pub id: Option<String>,
+    /// The event type, `"message"` unless the server says otherwise.
+    pub event: String,
+    pub data: String,
+}
+
+/// An incremental parser for the `text/event-stream` format.
+#[derive(Debug, Clone)]
+struct Parser {
+    buffer: Vec<u8>,
+    /// The last line ended with a CR, so a LF straight after it is part of
+    /// the same line ending.
+    after_cr: bool,
+    started: bool,
+    event: String,
+    data: String,
+    last_event_id: Option<String>,
+    reconnect: Duration,
+}
+
+impl Parser {
+    fn new() -> Parser {
+        Parser {
+            buffer: Vec::new(),
+            after_cr: false,
+            started: false,
+            event: String::new(),
+            data: String::new(),
+            last_event_id: None,
+            reconnect: Duration::from_millis(DEFAULT_RECONNECT_MS),
+        }
+    }
+
+    /// Feed another chunk of the response body into the parser, calling
+    /// `on_event` for every complete event.
+    ///
+    /// Lines may end with a LF, a CR, or a CRLF (which may be split across
+    /// chunks).
+    fn feed<F>(&mut self, chunk: &[u8], on_event: &mut F) -> Result<()>
+    where
+        F: FnMut(&Event) -> Result<()>,
+    {
+        for &byte in chunk {
+            let after_cr = self.after_cr;
+            self.after_cr = byte == b'\r';
+
+            match byte {
+                b'\n' if after_cr => {}
+                b'\n' | b'\r' => {
+                    let line = ::std::mem::replace(&mut self.buffer, Vec::new());
+                    self.line(&line, on_event)?;
+                }
+                _ => self.buffer.push(byte),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn line<F>(&mut self, line: &[u8], on_event: &mut F) -> Result<()>
+    where
+        F: FnMut(&Event) -> Result<()>,
+    {
+        let mut line = String::from_utf8_lossy(line).into_owned();
+        if !self.started {
+            self.started = true;
+            if line.starts_with('\u{feff}') {
+                line.remove(0);
+            }
+        }
+
+        if let Some(event) = self.process_line(&line) {
+            on_event(&event)?;
+        }
+
+        Ok(())
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<Event> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            // a comment, usually sent to keep the connection alive
+            return None;
+        }
+
+        let (field, value) = match line.find(':') {
+            Some(colon) => {
+                let value = &line[colon + 1..];
+                let value = if value.starts_with(' ') { &value[1..] } else { value };
+                (&line[..colon], value)
+            }
+            None => (line, ""),
+        };
+
+        match field {
+            "event" => self.event = value.to_string(),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => self.last_event_id = Some(value.to_string()),
+            "retry" => match value.parse() {
+                Ok(ms) => self.reconnect = Duration::from_millis(ms),
+                Err(_) => debug!("Ignoring an invalid retry time, {:?}", value),
+            },
+            _ => trace!("Ignoring an unknown field, {:?}", field),
+        }
+
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<Event> {
+        let event = ::std::mem::replace(&mut self.event, String::new());
+        let mut data = ::std::mem::replace(&mut self.data, String::new());
+
+        if data.is_empty() {
+            return None;
+        }
+        data.pop();
+
+        Some(Event {
+            id: self.last_event_id.clone(),
+            event: if event.is_empty() {
+                String::from("message")
+            } else {
+                event
+            },
+            data,
+        })
+    }
+
+    /// Throw away any partially received event after the connection drops.
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.after_cr = false;
+        self.started = false;
+        self.event.clear();
+        self.data.clear();
+    }
+}
+
+impl HttpClient {
+    /// Subscribe to a stream of server-sent events, calling `on_event` for
+    /// each event as it arrives.
+    ///
+    /// If the connection drops we automatically reconnect, sending the ID of
+    /// the last event we saw in a `Last-Event-ID` header so the server can
+    /// pick up where it left off. This only stops when the server responds
+    /// with `204 No Content` or an error status code, or `on_event` returns
+    /// an error (which is passed back to the caller).
+    pub fn subscribe<F>(&self, req: &Request, mut on_event: F) -> Result<()>
+    where
+        F: FnMut(&Event) -> Result<()>,
+    {
+        let mut parser = Parser::new();
+
+        loop {
+            let mut attempt = req.clone();
+            attempt.headers.set_raw("Accept", "text/event-stream");
+            attempt.headers.set_raw("Cache-Control", "no-cache");
+            attempt.options.strict = Some(true);
+            if let Some(ref id) = parser.last_event_id {
+                attempt.headers.set_raw("Last-Event-ID", id.clone());
+            }
+
+            let mut callback_failed = false;
+            let outcome = {
+                let parser = &mut parser;
+                let on_event = &mut on_event;
+                let callback_failed = &mut callback_failed;
+
+                self.try_send_streaming(&attempt, |chunk| {
+                    parser.feed(chunk, &mut |event| {
+                        on_event(event).map_err(|e| {
+                            *callback_failed = true;
+                            e
+                        })
+                    })
+                })
+            };
+
+            match outcome {
+                Ok(ref response) if response.status == StatusCode::NoContent => {
+                    debug!("The server asked us to stop listening for events");
+                    return Ok(());
+                }
+                Ok(_) => debug!("The event stream was closed"),
+                // the server doesn't want us to reconnect, or we were told to stop
+                Err(e) => if callback_failed || status_of(&e).is_some() {
+                    return Err(e);
+                } else {
+                    warn!("Lost the connection to the event stream, {}", e);
+                },
+            }
+
+            parser.reset();
+            debug!("Reconnecting to the event stream in {:?}", parser.reconnect);
+            thread::sleep(parser.reconnect);
+        }
+    }
+}
+
+/// A callback invoked for every server-sent event.
+///
+/// `id` is null if the server hasn't sent an event ID yet. Return `0` to
+/// keep listening, or anything else to stop.
+pub type EventCallback = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    id: *const c_char,
+    event: *const c_char,
+    data: *const c_char,
+) -> c_int;
+
+/// Subscribe to a stream of server-sent events, invoking `on_event` for each
+/// event. This blocks until the subscription ends, automatically
+/// reconnecting if the connection drops.
+///
+/// Returns `0` if the server or callback ended the subscription, or `-1` on
+/// error.
+#[no_mangle]
+pub unsafe extern "C" fn request_subscribe_sse(
+    req: *const Request,
+    on_event: EventCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    catch_panic(-1, || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_subscribe_sse()"));
+            return -1;
+        }
+
+        let mut stopped = false;
+        let outcome = HttpClient::new().and_then(|client| {
+            client.subscribe(&*req, |event| {
+                let id = event.id.as_ref().map(|id| to_c_string(id));
+                let name = to_c_string(&event.event);
+                let data = to_c_string(&event.data);
+                let id_ptr = id.as_ref().map(|id| id.as_ptr()).unwrap_or(::std::ptr::null());
+
+                if on_event(user_data, id_ptr, name.as_ptr(), data.as_ptr()) == 0 {
+                    Ok(())
+                } else {
+                    stopped = true;
+                    let reason = String::from("the event callback asked to stop");
+                    Err(ErrorKind::Cancelled(reason).into())
+                }
+            })
+        });
+
+        match outcome {
+            Ok(_) => 0,
+            Err(_) if stopped => 0,
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Listening for events failed"));
+                -1
+            }
+        }
+    })
+}
+
+/// C strings can't contain interior nulls, so strip them out.
+fn to_c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).expect("All nulls were removed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(parser: &mut Parser, chunks: &[&str]) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for chunk in chunks {
+            parser
+                .feed(chunk.as_bytes(), &mut |event: &Event| {
+                    events.push(event.clone());
+                    Ok(())
+                })
+                .unwrap();
+        }
+
+        events
+    }
+
+    fn message(data: &str) -> Event {
+        Event {
+            id: None,
+            event: String::from("message"),
+            data: data.to_string(),
+        }
+    }
+
+    #[test]
+    fn single_event() {
+        let got = parse(&mut Parser::new(), &["data: hello\n\n"]);
+
+        assert_eq!(got, vec![message("hello")]);
+    }
+
+    #[test]
+    fn multi_line_data_is_joined_with_newlines() {
+        let got = parse(&mut Parser::new(), &["data: first\ndata\ndata:  third\n\n"]);
+
+        assert_eq!(got, vec![message("first\n\n third")]);
+    }
+
+    #[test]
+    fn comments_are_ignored() {
+        let src = ": keep-alive\ndata: a\n:another comment\ndata: b\n\n:\n\n";
+        let got = parse(&mut Parser::new(), &[src]);
+
+        assert_eq!(got, vec![message("a\nb")]);
+    }
+
+    #[test]
+    fn events_without_data_are_not_dispatched() {
+        let got = parse(&mut Parser::new(), &["event: ping\n\n\n\ndata: x\n\n"]);
+
+        // the event type is reset by the empty line
+        assert_eq!(got, vec![message("x")]);
+    }
+
+    #[test]
+    fn incomplete_events_are_not_dispatched() {
+        let got = parse(&mut Parser::new(), &["data: a\n", "data: b"]);
+
+        assert!(got.is_empty());
+    }
+
+    #[test]
+    fn named_events() {
+        let got = parse(&mut Parser::new(), &["event: update\ndata: 1\n\ndata: 2\n\n"]);
+
+        assert_eq!(got.len(), 2);
+        assert_eq!(got[0].event, "update");
+        assert_eq!(got[1].event, "message");
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let got = parse(&mut Parser::new(), &["data: a\r\ndata: b\r\n\r\n"]);
+
+        assert_eq!(got, vec![message("a\nb")]);
+    }
+
+    #[test]
+    fn cr_line_endings() {
+        let got = parse(&mut Parser::new(), &["data: a\rdata: b\r\rdata: c\r\r"]);
+
+        assert_eq!(got, vec![message("a\nb"), message("c")]);
+    }
+
+    #[test]
+    fn crlf_split_across_chunks() {
+        let got = parse(&mut Parser::new(), &["data: a\r", "\ndata: b\r", "\n\r", "\n"]);
+
+        assert_eq!(got, vec![message("a\nb")]);
+    }
+
+    #[test]
+    fn lines_split_across_chunks() {
+        let got = parse(&mut Parser::new(), &["da", "ta: hel", "lo\n", "\n"]);
+
+        assert_eq!(got, vec![message("hello")]);
+    }
+
+    #[test]
+    fn leading_byte_order_mark_is_stripped() {
+        let got = parse(&mut Parser::new(), &["\u{feff}data: a\n\n\u{feff}data: b\n\n"]);
+
+        // only the first one is a byte order mark, the second is an unknown field
+        assert_eq!(got, vec![message("a")]);
+    }
+
+    #[test]
+    fn ids_persist_across_events() {
+        let src = "id: 1\ndata: a\n\ndata: b\n\nid\ndata: c\n\n";
+        let got = parse(&mut Parser::new(), &[src]);
+
+        let ids: Vec<_> = got.iter().map(|e| e.id.clone()).collect();
+        assert_eq!(
+            ids,
+            vec![Some(String::from("1")), Some(String::from("1")), Some(String::new())]
+        );
+    }
+
+    #[test]
+    fn ids_containing_null_are_ignored() {
+        let got = parse(&mut Parser::new(), &["id: 1\n\nid: 2\0\ndata: a\n\n"]);
+
+        assert_eq!(got[0].id, Some(String::from("1")));
+    }
+
+    #[test]
+    fn retry_updates_the_reconnect_time() {
+        let mut parser = Parser::new();
+        parse(&mut parser, &["retry: 1500\n\n"]);
+        assert_eq!(parser.reconnect, Duration::from_millis(1500));
+
+        parse(&mut parser, &["retry: soon\n\n"]);
+        assert_eq!(parser.reconnect, Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn reset_discards_the_partial_event_but_keeps_the_id() {
+        let mut parser = Parser::new();
+        parse(&mut parser, &["id: 7\n\ndata: stale\ndata: half"]);
+
+        parser.reset();
+        let got = parse(&mut parser, &["data: fresh\n\n"]);
+
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].data, "fresh");
+        assert_eq!(got[0].id, Some(String::from("7")));
+    }
+
+    #[test]
+    fn errors_from_the_callback_are_propagated() {
+        let mut parser = Parser::new();
+        let got = parser.feed(b"data: a\n\n", &mut |_: &Event| Err("Stop".into()));
+
+        assert!(got.is_err());
+    }
+}
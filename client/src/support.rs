@@ -0,0 +1,186 @@
+//! Exporting a support bundle users can attach to bug reports.
+//!
+//! A bundle is a tarball containing the recent (redacted) request history,
+//! the tail of the log file, the loaded plugins, a snapshot of the
+//! configuration, and some information about the environment the client is
+//! running in.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use chrono::Utc;
+use libc::{c_char, c_int};
+use serde::Serialize;
+use serde_json;
+use tar::{Builder, Header};
+
+use errors::*;
+use ffi::{c_str_to_str, catch_panic, update_last_error};
+use history;
+use utils::LOG_FILE;
+use plugins::PluginMetadata;
+use {PluginManager, Quota, QuotaTracker};
+
+
+/// How many lines from the end of the log file to include.
+pub const LOG_LINES: usize = 500;
+
+/// Builder for a support bundle.
+#[derive(Debug, Default)]
+pub struct SupportBundle<'a> {
+    plugins: Option<&'a PluginManager>,
+    quotas: Option<&'a QuotaTracker>,
+}
+
+#[derive(Debug, Serialize)]
+struct Fingerprint {
+    client_version: &'static str,
+    http_backend: &'static str,
+    tls_backend: &'static str,
+    os: &'static str,
+    arch: &'static str,
+    family: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct ConfigSnapshot {
+    log_file: &'static str,
+    quotas: Option<HashMap<String, Quota>>,
+}
+
+impl<'a> SupportBundle<'a> {
+    pub fn new() -> SupportBundle<'a> {
+        SupportBundle::default()
+    }
+
+    /// Include the list of loaded plugins.
+    pub fn plugins(&mut self, pm: &'a PluginManager) -> &mut Self {
+        self.plugins = Some(pm);
+        self
+    }
+
+    /// Include the quota configuration.
+    pub fn quotas(&mut self, quotas: &'a QuotaTracker) -> &mut Self {
+        self.quotas = Some(quotas);
+        self
+    }
+
+    /// Write the bundle to disk as a tarball.
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        info!("Exporting a support bundle to {}", path.display());
+
+        let f = File::create(path).chain_err(|| "Unable to create the support bundle")?;
+        let mut builder = Builder::new(f);
+
+        append_json(&mut builder, "history.json", &history::recent())?;
+        append_json(&mut builder, "plugins.json", &self.plugin_summaries())?;
+        append_json(&mut builder, "config.json", &self.config())?;
+        append_json(&mut builder, "environment.json", &fingerprint())?;
+        append(&mut builder, "rest_client.log", log_tail(LOG_FILE, LOG_LINES).as_bytes())?;
+
+        builder
+            .into_inner()
+            .chain_err(|| "Unable to finish writing the support bundle")?;
+
+        Ok(())
+    }
+
+    fn plugin_summaries(&self) -> Vec<PluginMetadata> {
+        self.plugins.map(|pm| pm.list()).unwrap_or_default()
+    }
+
+    fn config(&self) -> ConfigSnapshot {
+        ConfigSnapshot {
+            log_file: LOG_FILE,
+            quotas: self.quotas.map(|q| q.quotas().clone()),
+        }
+    }
+}
+
+fn fingerprint() -> Fingerprint {
+    Fingerprint {
+        client_version: env!("CARGO_PKG_VERSION"),
+        http_backend: "reqwest 0.8",
+        tls_backend: if cfg!(target_os = "macos") || cfg!(target_os = "ios") {
+            "Security.framework"
+        } else if cfg!(windows) {
+            "SChannel"
+        } else {
+            "OpenSSL"
+        },
+        os: env::consts::OS,
+        arch: env::consts::ARCH,
+        family: env::consts::FAMILY,
+    }
+}
+
+/// Read the last few lines of the log file, if there is one.
+fn log_tail<P: AsRef<Path>>(path: P, lines: usize) -> String {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return String::new(),
+    };
+
+    let all: Vec<String> = BufReader::new(f).lines().filter_map(|l| l.ok()).collect();
+    let start = all.len().saturating_sub(lines);
+
+    all[start..].join("\n")
+}
+
+fn append_json<W, T>(builder: &mut Builder<W>, name: &str, value: &T) -> Result<()>
+where
+    W: ::std::io::Write,
+    T: Serialize,
+{
+    let data = serde_json::to_vec_pretty(value)
+        .chain_err(|| format!("Unable to serialize {}", name))?;
+    append(builder, name, &data)
+}
+
+fn append<W: ::std::io::Write>(builder: &mut Builder<W>, name: &str, data: &[u8]) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header
+        .set_path(name)
+        .chain_err(|| format!("Invalid file name, {}", name))?;
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_cksum();
+
+    builder
+        .append(&header, data)
+        .chain_err(|| format!("Unable to add {} to the support bundle", name))
+}
+
+/// Export a support bundle containing everything needed to diagnose a problem
+/// with the client, including the plugins loaded by a `PluginManager`.
+///
+/// The plugin manager may be null. Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn client_export_support_bundle(
+    pm: *const PluginManager,
+    path: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        let path = match c_str_to_str(path, "support bundle path") {
+            Some(p) => p,
+            None => return -1,
+        };
+
+        let mut bundle = SupportBundle::new();
+        if !pm.is_null() {
+            bundle.plugins(&*pm);
+        }
+
+        match bundle.write_to(path) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
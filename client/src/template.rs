@@ -0,0 +1,609 @@
+//! Saved requests with `{{variable}}` placeholders.
+//!
+//! A `RequestTemplate` is filled in using the variables from an
+//! `Environment`, so the same set of requests can be sent against (for
+//! example) a staging and a production server, much like a Postman
+//! collection. Put a backslash in front of a placeholder (`\{{name}}`) to
+//! keep it as-is.
+//!
+//! Environments are stored as TOML. Nested tables are flattened, so
+//!
+//! ```toml
+//! token = "abc123"
+//!
+//! [server]
+//! host = "staging.example.com"
+//! port = 8080
+//! ```
+//!
+//! defines the variables `token`, `server.host`, and `server.port`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::ptr;
+use libc::{c_char, c_int};
+use reqwest::Method;
+use toml::{self, Value};
+
+use errors::*;
+use ffi::{c_str_to_str, catch_panic, update_last_error};
+use urls::parse_url;
+use Request;
+
+
+/// A set of variables which can be substituted into a `RequestTemplate`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Environment {
+    variables: BTreeMap<String, String>,
+}
+
+impl Environment {
+    pub fn new() -> Environment {
+        Environment::default()
+    }
+
+    /// Parse an environment written in TOML.
+    pub fn from_toml(src: &str) -> Result<Environment> {
+        let table: Value = toml::from_str(src).chain_err(|| "Unable to parse the environment")?;
+
+        let mut env = Environment::new();
+        flatten_toml("", &table, &mut env.variables)?;
+        Ok(env)
+    }
+
+    /// Load an environment from a TOML file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Environment> {
+        let path = path.as_ref();
+        debug!("Loading the environment from {}", path.display());
+
+        let mut src = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut src))
+            .chain_err(|| format!("Unable to read {}", path.display()))?;
+
+        Environment::from_toml(&src)
+    }
+
+    pub fn set<K: Into<String>, V: Into<String>>(&mut self, name: K, value: V) {
+        self.variables.insert(name.into(), value.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.variables.get(name).map(|v| v.as_str())
+    }
+
+    /// Iterate over every variable and its value.
+    pub fn variables<'a>(&'a self) -> Box<Iterator<Item = (&'a str, &'a str)> + 'a> {
+        Box::new(self.variables.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+    }
+
+}
+
+/// Turn a TOML document into a flat list of strings, where nested tables
+/// become dotted names (e.g. `auth.username`).
+pub(crate) fn flatten_toml(
+    prefix: &str,
+    value: &Value,
+    variables: &mut BTreeMap<String, String>,
+) -> Result<()> {
+    let rendered = match *value {
+        Value::Table(ref table) => {
+            for (key, value) in table {
+                let name = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_toml(&name, value, variables)?;
+            }
+            return Ok(());
+        }
+        Value::String(ref s) => s.clone(),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Datetime(ref d) => d.to_string(),
+        Value::Array(_) => bail!("Arrays can't be used as variables (\"{}\")", prefix),
+    };
+
+    variables.insert(prefix.to_string(), rendered);
+    Ok(())
+}
+
+/// A request with `{{variable}}` placeholders in its URL, headers, and body.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestTemplate {
+    pub method: String,
+    pub url: String,
+    // TOML needs plain values to come before tables like the headers
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl RequestTemplate {
+    pub fn new<M: Into<String>, U: Into<String>>(method: M, url: U) -> RequestTemplate {
+        RequestTemplate {
+            method: method.into(),
+            url: url.into(),
+            body: None,
+            headers: BTreeMap::new(),
+        }
+    }
+
+    /// Save an existing request as a template.
+    ///
+    /// Only requests with a UTF-8 body can be saved. Anything in the request
+    /// which looks like a `{{placeholder}}` is escaped, so rendering the
+    /// template gives back the original request.
+    pub fn from_request(req: &Request) -> Result<RequestTemplate> {
+        let url = escape(req.destination.as_str());
+        let mut template = RequestTemplate::new(req.method.to_string(), url);
+
+        for header in req.headers.iter() {
+            template
+                .headers
+                .insert(escape(header.name()), escape(&header.value_string()));
+        }
+
+        let cookies: Vec<String> = req.cookies
+            .iter()
+            .map(|c| format!("{}={}", c.name(), c.value()))
+            .collect();
+        if !cookies.is_empty() {
+            template
+                .headers
+                .insert(String::from("Cookie"), escape(&cookies.join("; ")));
+        }
+
+        if let Some(ref body) = req.body {
+            let body = String::from_utf8(body.clone())
+                .chain_err(|| "Only requests with a text body can be saved")?;
+            template.body = Some(escape(&body));
+        }
+
+        Ok(template)
+    }
+
+    /// Parse a template written in TOML.
+    pub fn from_toml(src: &str) -> Result<RequestTemplate> {
+        toml::from_str(src).chain_err(|| "Unable to parse the request template")
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).chain_err(|| "Unable to serialize the request template")
+    }
+
+    /// The names of every variable used by this template.
+    pub fn variables(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        let fields = Some(&self.url)
+            .into_iter()
+            .chain(self.headers.iter().flat_map(|(k, v)| vec![k, v]))
+            .chain(self.body.as_ref());
+
+        for field in fields {
+            for name in placeholders(field) {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+
+        names
+    }
+
+    /// Fill in the template's placeholders, turning it into a `Request`.
+    pub fn render(&self, env: &Environment) -> Result<Request> {
+        let method = self.method
+            .parse::<Method>()
+            .chain_err(|| format!("\"{}\" isn't a valid HTTP method", self.method))?;
+        let url = parse_url(&substitute(&self.url, env)?)?;
+
+        let mut req = Request::new(url, method);
+
+        for (name, value) in &self.headers {
+            req.headers
+                .set_raw(substitute(name, env)?, substitute(value, env)?);
+        }
+
+        if let Some(ref body) = self.body {
+            req.set_body(substitute(body, env)?);
+        }
+
+        Ok(req)
+    }
+}
+
+/// Replace every `{{name}}` in the text with the variable's value. An
+/// escaped placeholder (`\{{name}}`) becomes a literal `{{name}}`.
+pub fn substitute(text: &str, env: &Environment) -> Result<String> {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{{") {
+        if is_escaped(rest, start) {
+            rendered.push_str(&rest[..start - 1]);
+            rendered.push_str("{{");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => bail!("Unterminated placeholder in {:?}", text),
+        };
+
+        let name = rest[start + 2..end].trim();
+        let value = env.get(name)
+            .ok_or_else(|| Error::from(format!("The \"{}\" variable isn't defined", name)))?;
+
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(value);
+        rest = &rest[end + 2..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+fn placeholders(text: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{{") {
+        if is_escaped(rest, start) {
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        match rest[start..].find("}}") {
+            Some(end) => {
+                names.push(rest[start + 2..start + end].trim().to_string());
+                rest = &rest[start + end + 2..];
+            }
+            None => break,
+        }
+    }
+
+    names
+}
+
+/// Escape anything which looks like a placeholder so `substitute()` leaves it
+/// alone.
+fn escape(text: &str) -> String {
+    text.replace("{{", "\\{{")
+}
+
+/// Is the `{{` at `start` preceded by a backslash?
+fn is_escaped(text: &str, start: usize) -> bool {
+    text[..start].ends_with('\\')
+}
+
+/// Create an empty environment.
+#[no_mangle]
+pub extern "C" fn environment_new() -> *mut Environment {
+    catch_panic(ptr::null_mut(), || {
+        Box::into_raw(Box::new(Environment::new()))
+    })
+}
+
+/// Load an environment from a TOML file, returning a null pointer if it
+/// couldn't be loaded.
+#[no_mangle]
+pub unsafe extern "C" fn environment_load(path: *const c_char) -> *mut Environment {
+    catch_panic(ptr::null_mut(), || {
+        let path = match c_str_to_str(path, "environment path") {
+            Some(p) => p,
+            None => return ptr::null_mut(),
+        };
+
+        match Environment::load(path) {
+            Ok(env) => Box::into_raw(Box::new(env)),
+            Err(e) => {
+                update_last_error(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Destroy an environment once you are done with it.
+#[no_mangle]
+pub unsafe extern "C" fn environment_destroy(env: *mut Environment) {
+    catch_panic((), || {
+        if !env.is_null() {
+            drop(Box::from_raw(env));
+        }
+    })
+}
+
+/// Set a variable, replacing any previous value.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn environment_set(
+    env: *mut Environment,
+    name: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if env.is_null() {
+            update_last_error(Error::from("Null pointer passed to environment_set()"));
+            return -1;
+        }
+
+        let (name, value) = match (
+            c_str_to_str(name, "variable name"),
+            c_str_to_str(value, "variable value"),
+        ) {
+            (Some(n), Some(v)) => (n, v),
+            _ => return -1,
+        };
+
+        (&mut *env).set(name, value);
+        0
+    })
+}
+
+/// Create a new request template, where `url` may contain placeholders.
+#[no_mangle]
+pub unsafe extern "C" fn template_new(
+    method: *const c_char,
+    url: *const c_char,
+) -> *mut RequestTemplate {
+    catch_panic(ptr::null_mut(), || {
+        let (method, url) = match (
+            c_str_to_str(method, "method"),
+            c_str_to_str(url, "url"),
+        ) {
+            (Some(m), Some(u)) => (m, u),
+            _ => return ptr::null_mut(),
+        };
+
+        Box::into_raw(Box::new(RequestTemplate::new(method, url)))
+    })
+}
+
+/// Load a request template from a TOML file, returning a null pointer if it
+/// couldn't be loaded.
+#[no_mangle]
+pub unsafe extern "C" fn template_load(path: *const c_char) -> *mut RequestTemplate {
+    catch_panic(ptr::null_mut(), || {
+        let path = match c_str_to_str(path, "template path") {
+            Some(p) => p,
+            None => return ptr::null_mut(),
+        };
+
+        let mut src = String::new();
+        let outcome = File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut src))
+            .chain_err(|| format!("Unable to read {}", path))
+            .and_then(|_| RequestTemplate::from_toml(&src));
+
+        match outcome {
+            Ok(template) => Box::into_raw(Box::new(template)),
+            Err(e) => {
+                update_last_error(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Destroy a request template once you are done with it.
+#[no_mangle]
+pub unsafe extern "C" fn template_destroy(template: *mut RequestTemplate) {
+    catch_panic((), || {
+        if !template.is_null() {
+            drop(Box::from_raw(template));
+        }
+    })
+}
+
+/// Add a header to the template. Both the name and value may contain
+/// placeholders.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn template_add_header(
+    template: *mut RequestTemplate,
+    name: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if template.is_null() {
+            update_last_error(Error::from("Null pointer passed to template_add_header()"));
+            return -1;
+        }
+
+        let (name, value) = match (
+            c_str_to_str(name, "header name"),
+            c_str_to_str(value, "header value"),
+        ) {
+            (Some(n), Some(v)) => (n, v),
+            _ => return -1,
+        };
+
+        (&mut *template)
+            .headers
+            .insert(name.to_string(), value.to_string());
+        0
+    })
+}
+
+/// Set the template's body, which may contain placeholders. Passing in a
+/// null pointer removes the body.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn template_set_body(
+    template: *mut RequestTemplate,
+    body: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if template.is_null() {
+            update_last_error(Error::from("Null pointer passed to template_set_body()"));
+            return -1;
+        }
+
+        let body = if body.is_null() {
+            None
+        } else {
+            match c_str_to_str(body, "body") {
+                Some(b) => Some(b.to_string()),
+                None => return -1,
+            }
+        };
+
+        (&mut *template).body = body;
+        0
+    })
+}
+
+/// Fill in the template's placeholders using the variables in `env`, creating
+/// a new `Request`.
+///
+/// Returns a null pointer if a variable isn't defined or the result isn't a
+/// valid request.
+#[no_mangle]
+pub unsafe extern "C" fn template_render(
+    template: *const RequestTemplate,
+    env: *const Environment,
+) -> *mut Request {
+    catch_panic(ptr::null_mut(), || {
+        if template.is_null() || env.is_null() {
+            update_last_error(Error::from("Null pointer passed to template_render()"));
+            return ptr::null_mut();
+        }
+
+        match (&*template).render(&*env) {
+            Ok(req) => Box::into_raw(Box::new(req)),
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Unable to render the request template"));
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Url;
+
+    fn env() -> Environment {
+        let mut env = Environment::new();
+        env.set("host", "example.com");
+        env.set("token", "abc123");
+        env
+    }
+
+    #[test]
+    fn placeholders_are_replaced() {
+        let got = substitute("https://{{host}}/api?t={{ token }}", &env()).unwrap();
+        assert_eq!(got, "https://example.com/api?t=abc123");
+    }
+
+    #[test]
+    fn text_without_placeholders_is_unchanged() {
+        let got = substitute("{ \"json\": true }", &env()).unwrap();
+        assert_eq!(got, "{ \"json\": true }");
+    }
+
+    #[test]
+    fn escaped_placeholders_are_kept() {
+        let got = substitute("\\{{host}} is {{host}}", &env()).unwrap();
+        assert_eq!(got, "{{host}} is example.com");
+
+        // The variable doesn't need to exist
+        let got = substitute("\\{{missing}}", &env()).unwrap();
+        assert_eq!(got, "{{missing}}");
+    }
+
+    #[test]
+    fn unterminated_placeholders_are_an_error() {
+        let err = substitute("https://{{host/", &env()).unwrap_err();
+        assert_eq!(err.to_string(), "Unterminated placeholder in \"https://{{host/\"");
+    }
+
+    #[test]
+    fn undefined_variables_are_an_error() {
+        let err = substitute("{{missing}}", &env()).unwrap_err();
+        assert_eq!(err.to_string(), "The \"missing\" variable isn't defined");
+    }
+
+    #[test]
+    fn variables_are_listed_once_and_skip_escaped_placeholders() {
+        let mut template = RequestTemplate::new("POST", "https://{{host}}/{{path}}");
+        template
+            .headers
+            .insert(String::from("Authorization"), String::from("Bearer {{token}}"));
+        template.body = Some(String::from("{{host}} \\{{literal}} {{unterminated"));
+
+        assert_eq!(template.variables(), vec!["host", "path", "token"]);
+    }
+
+    #[test]
+    fn render_fills_in_the_url_headers_and_body() {
+        let mut template = RequestTemplate::new("PUT", "https://{{host}}/items");
+        template
+            .headers
+            .insert(String::from("Authorization"), String::from("Bearer {{token}}"));
+        template.body = Some(String::from("{\"token\": \"{{token}}\"}"));
+
+        let req = template.render(&env()).unwrap();
+
+        assert_eq!(req.method, Method::Put);
+        assert_eq!(req.destination.as_str(), "https://example.com/items");
+        let auth = req.headers.get_raw("Authorization").and_then(|raw| raw.one());
+        assert_eq!(auth, Some(&b"Bearer abc123"[..]));
+        assert_eq!(req.body, Some(b"{\"token\": \"abc123\"}".to_vec()));
+    }
+
+    #[test]
+    fn nested_tables_are_flattened() {
+        let src = "token = \"abc\"\n[server]\nhost = \"example.com\"\nport = 8080\n";
+        let env = Environment::from_toml(src).unwrap();
+
+        assert_eq!(env.get("token"), Some("abc"));
+        assert_eq!(env.get("server.host"), Some("example.com"));
+        assert_eq!(env.get("server.port"), Some("8080"));
+    }
+
+    #[test]
+    fn arrays_cant_be_variables() {
+        assert!(Environment::from_toml("hosts = [\"a\", \"b\"]").is_err());
+    }
+
+    #[test]
+    fn saved_requests_render_back_to_the_original() {
+        let url = Url::parse("https://example.com/search?q={{query}}").unwrap();
+        let mut req = Request::new(url, Method::Post);
+        req.headers.set_raw("X-Template", "{{not a placeholder}}");
+        req.set_body("{\"text\": \"{{a}} \\{{b}} {{{c}}}\"}");
+
+        let template = RequestTemplate::from_request(&req).unwrap();
+        assert!(template.variables().is_empty());
+        let got = template.render(&Environment::new()).unwrap();
+
+        assert_eq!(got.destination, req.destination);
+        assert_eq!(got.headers.get_raw("X-Template"), req.headers.get_raw("X-Template"));
+        assert_eq!(got.body, req.body);
+    }
+
+    #[test]
+    fn templates_round_trip_through_toml() {
+        let mut template = RequestTemplate::new("GET", "https://{{host}}/");
+        template
+            .headers
+            .insert(String::from("Accept"), String::from("application/json"));
+        template.body = Some(String::from("{{token}}"));
+
+        let src = template.to_toml().unwrap();
+        assert_eq!(RequestTemplate::from_toml(&src).unwrap(), template);
+    }
+}
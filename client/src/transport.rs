@@ -0,0 +1,345 @@
+//! Proxy and TLS settings for the underlying HTTP transport.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use base64;
+use reqwest::{self, Certificate, Identity, Proxy, Url};
+
+use errors::*;
+use rate_limit::Rate;
+use redirect::RedirectPolicy;
+use tunnel::{Pins, Tunnel};
+use {HttpClient, Request, RequestOptions};
+
+
+/// How the client connects to servers.
+///
+/// Certificates are kept as raw bytes so the transport can be rebuilt
+/// whenever the client's timeouts change.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TransportConfig {
+    /// Send every request through this proxy (e.g. `http://proxy:3128`).
+    ///
+    /// Only HTTP and HTTPS proxies work. The HTTP backend can't talk to SOCKS
+    /// proxies, so they're rejected when the client is built.
+    pub proxy: Option<String>,
+    /// Extra DER-encoded certificates to trust, on top of the system's root
+    /// certificates.
+    pub root_certificates: Vec<Vec<u8>>,
+    /// A certificate used to identify the client to the server.
+    pub identity: Option<ClientIdentity>,
+    /// Don't check that the server's certificate matches its hostname.
+    ///
+    /// The TLS backend can't skip validating the certificate chain entirely,
+    /// so to talk to a server with a self-signed certificate you also need to
+    /// add it as a root certificate.
+    pub accept_invalid_hostnames: bool,
+    /// Which version of HTTP to use.
+    pub http_version: VersionPreference,
+    /// Connect to these addresses instead of looking the host and port up in
+    /// DNS, like curl's `--resolve`.
+    pub resolve: HashMap<(String, u16), IpAddr>,
+}
+
+/// Which version of HTTP the client should try to use.
+///
+/// The HTTP backend only speaks HTTP/1.x, so there's no way to ask for HTTP/2
+/// yet.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub enum VersionPreference {
+    /// Let the transport decide.
+    Default = 0,
+    /// Only ever speak HTTP/1.1.
+    Http1Only = 1,
+}
+
+impl Default for VersionPreference {
+    fn default() -> VersionPreference {
+        VersionPreference::Default
+    }
+}
+
+/// A PKCS#12 archive containing the client's certificate and private key.
+#[derive(Clone, PartialEq)]
+pub struct ClientIdentity {
+    pub pkcs12: Vec<u8>,
+    pub password: String,
+}
+
+impl ::std::fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("ClientIdentity")
+            .field("pkcs12", &format!("{} bytes", self.pkcs12.len()))
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+impl TransportConfig {
+    /// Trust the certificates in a file. This may be a single DER-encoded
+    /// certificate or a bundle of PEM-encoded certificates.
+    pub fn add_root_certificates<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let contents = read_file(path)?;
+
+        let certificates = if contents.starts_with(b"-----BEGIN") {
+            parse_pem_bundle(&contents)
+                .chain_err(|| format!("Invalid certificate bundle, {}", path.display()))?
+        } else {
+            vec![contents]
+        };
+
+        debug!("Trusting {} certificates from {}", certificates.len(), path.display());
+        self.root_certificates.extend(certificates);
+        Ok(())
+    }
+
+    /// Identify the client using a PKCS#12 archive.
+    pub fn set_identity<P: AsRef<Path>>(&mut self, path: P, password: &str) -> Result<()> {
+        self.identity = Some(ClientIdentity {
+            pkcs12: read_file(path.as_ref())?,
+            password: password.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Always connect to `address` when talking to `host` on `port`.
+    ///
+    /// HTTPS requests are still checked against the hostname (and send it
+    /// for SNI), so a staging server can be reached using production's
+    /// certificate. They go through a proxy on the loopback interface, so
+    /// they bypass any other proxy the client uses.
+    pub fn resolve<H: Into<String>>(&mut self, host: H, port: u16, address: IpAddr) {
+        self.resolve.insert((host.into().to_lowercase(), port), address);
+    }
+
+    /// Where requests to this URL should go, if it has been pinned to an
+    /// address.
+    pub(crate) fn pinned_address(&self, url: &Url) -> Option<SocketAddr> {
+        pinned_address(&self.resolve, url)
+    }
+
+    /// Point a plain HTTP request at its pinned address (if it has one),
+    /// making sure the server still sees the original `Host`.
+    ///
+    /// The TLS backend uses the URL we connect to for SNI and for checking
+    /// the server's certificate, so HTTPS requests are left alone and sent
+    /// through the tunnel set up by `apply()` instead.
+    pub(crate) fn pin<'a>(&self, req: &'a Request) -> Result<Cow<'a, Request>> {
+        let address = match self.pinned_address(&req.destination) {
+            Some(ref address) if req.destination.scheme() == "http" => *address,
+            _ => return Ok(Cow::Borrowed(req)),
+        };
+
+        debug!("Sending the request for {} to {}", req.destination, address);
+        let mut pinned = req.clone();
+
+        if pinned.headers.get_raw("Host").is_none() {
+            let host = req.destination.host_str().unwrap_or_default();
+            let host = match req.destination.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            };
+            pinned.headers.set_raw("Host", host);
+        }
+
+        pinned
+            .destination
+            .set_ip_host(address.ip())
+            .map_err(|_| Error::from("Unable to send the request to a pinned address"))?;
+
+        Ok(Cow::Owned(pinned))
+    }
+
+    pub(crate) fn apply(&self, builder: &mut reqwest::ClientBuilder) -> Result<()> {
+        // This has to come before any other proxy so it gets the first say
+        if !self.resolve.is_empty() {
+            let tunnel = Tunnel::start(self.resolve.clone())?;
+            let pins = self.resolve.clone();
+
+            builder.proxy(Proxy::custom(move |url| {
+                if url.scheme() == "https" && pinned_address(&pins, url).is_some() {
+                    Some(tunnel.url())
+                } else {
+                    None
+                }
+            }));
+        }
+
+        if let Some(ref proxy) = self.proxy {
+            builder.proxy(parse_proxy(proxy)?);
+        }
+
+        for der in &self.root_certificates {
+            let cert = Certificate::from_der(der).chain_err(|| "Invalid root certificate")?;
+            builder.add_root_certificate(cert);
+        }
+
+        if let Some(ref identity) = self.identity {
+            let id = Identity::from_pkcs12_der(&identity.pkcs12, &identity.password)
+                .chain_err(|| "Invalid client certificate")?;
+            builder.identity(id);
+        }
+
+        if self.accept_invalid_hostnames {
+            warn!("Hostname verification is disabled");
+            builder.danger_disable_hostname_verification();
+        }
+
+        Ok(())
+    }
+}
+
+/// Builder for an `HttpClient` which needs more than the default settings.
+#[derive(Debug, Default, Clone)]
+pub struct ClientBuilder {
+    options: RequestOptions,
+    transport: TransportConfig,
+    redirect_policy: RedirectPolicy,
+    rate: Option<Rate>,
+    max_in_flight: Option<usize>,
+}
+
+impl ClientBuilder {
+    pub fn new() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// Set the default timeouts and retry policy.
+    pub fn options(&mut self, options: RequestOptions) -> &mut Self {
+        self.options = options;
+        self
+    }
+
+    /// Decide how redirects are followed.
+    pub fn redirect_policy(&mut self, policy: RedirectPolicy) -> &mut Self {
+        self.redirect_policy = policy;
+        self
+    }
+
+    /// Don't send requests faster than `per_second`, allowing bursts of up to
+    /// `burst` requests.
+    pub fn rate_limit(&mut self, per_second: f64, burst: u32) -> &mut Self {
+        self.rate = Some(Rate { per_second, burst });
+        self
+    }
+
+    /// Limit how many requests can be in flight at once.
+    pub fn max_in_flight(&mut self, max_in_flight: usize) -> &mut Self {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    /// Choose which version of HTTP to use.
+    pub fn http_version(&mut self, preference: VersionPreference) -> &mut Self {
+        self.transport.http_version = preference;
+        self
+    }
+
+    /// Send requests through a HTTP proxy.
+    pub fn proxy<S: Into<String>>(&mut self, url: S) -> &mut Self {
+        self.transport.proxy = Some(url.into());
+        self
+    }
+
+    /// Trust the certificates in a DER file or PEM bundle.
+    pub fn add_root_certificate<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self> {
+        self.transport.add_root_certificates(path)?;
+        Ok(self)
+    }
+
+    /// Identify the client with the certificate in a PKCS#12 archive.
+    pub fn identity<P: AsRef<Path>>(&mut self, path: P, password: &str) -> Result<&mut Self> {
+        self.transport.set_identity(path, password)?;
+        Ok(self)
+    }
+
+    /// Accept certificates which don't match the server's hostname.
+    ///
+    /// This is only meant for testing, never turn it on in production!
+    pub fn accept_invalid_hostnames(&mut self, accept: bool) -> &mut Self {
+        self.transport.accept_invalid_hostnames = accept;
+        self
+    }
+
+    /// Connect to `address` whenever we talk to `host` on `port`, instead of
+    /// looking it up in DNS.
+    pub fn resolve<H: Into<String>>(&mut self, host: H, port: u16, address: IpAddr) -> &mut Self {
+        self.transport.resolve(host, port, address);
+        self
+    }
+
+    pub fn build(&self) -> Result<HttpClient> {
+        let mut client = HttpClient::with_transport(self.options, self.transport.clone())?;
+        client.set_redirect_policy(self.redirect_policy.clone());
+        client.rate_limiter().set_rate(self.rate)?;
+        client.rate_limiter().set_max_in_flight(self.max_in_flight)?;
+        Ok(client)
+    }
+}
+
+fn pinned_address(pins: &Pins, url: &Url) -> Option<SocketAddr> {
+    let host = url.host_str()?.to_lowercase();
+    let port = url.port_or_known_default()?;
+
+    pins.get(&(host, port)).map(|&ip| SocketAddr::new(ip, port))
+}
+
+fn parse_proxy(proxy: &str) -> Result<Proxy> {
+    let url = Url::parse(proxy).chain_err(|| format!("Invalid proxy URL, {:?}", proxy))?;
+
+    match url.scheme() {
+        "http" | "https" => Proxy::all(url).chain_err(|| "Unable to configure the proxy"),
+        // The HTTP backend has no SOCKS connector, so these get a clearer
+        // error than "unknown scheme"
+        "socks4" | "socks4a" | "socks5" | "socks5h" => {
+            bail!("SOCKS proxies aren't supported by the HTTP backend")
+        }
+        other => bail!("Unknown proxy scheme, {:?}", other),
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .chain_err(|| format!("Unable to read {}", path.display()))?;
+    Ok(contents)
+}
+
+/// Decode every `CERTIFICATE` block in a PEM file.
+fn parse_pem_bundle(contents: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let text = String::from_utf8_lossy(contents);
+    let mut certificates = Vec::new();
+    let mut current: Option<String> = None;
+
+    for line in text.lines().map(|l| l.trim()) {
+        if line == "-----BEGIN CERTIFICATE-----" {
+            current = Some(String::new());
+        } else if line == "-----END CERTIFICATE-----" {
+            let encoded = match current.take() {
+                Some(e) => e,
+                None => bail!("Found the end of a certificate without a beginning"),
+            };
+            let der = base64::decode(&encoded)
+                .chain_err(|| "Certificates should be base64 encoded")?;
+            certificates.push(der);
+        } else if let Some(ref mut encoded) = current {
+            encoded.push_str(line);
+        }
+    }
+
+    if current.is_some() {
+        bail!("The last certificate is incomplete");
+    }
+    if certificates.is_empty() {
+        bail!("No certificates found");
+    }
+
+    Ok(certificates)
+}
@@ -0,0 +1,223 @@
+//! A tiny `CONNECT` proxy used to send HTTPS requests to pinned addresses.
+//!
+//! The HTTP backend doesn't let us choose which address it connects to, but
+//! it will tunnel HTTPS requests through a proxy. Pinned hosts are sent
+//! through a proxy running on the loopback interface which connects to the
+//! pinned address instead of looking the host up, while the TLS handshake
+//! inside the tunnel still uses the real hostname for SNI and for checking
+//! the server's certificate.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use reqwest::Url;
+
+use errors::*;
+
+
+/// Maps a host and port to the address it should connect to.
+pub(crate) type Pins = HashMap<(String, u16), IpAddr>;
+
+/// A proxy which only accepts `CONNECT` requests for pinned hosts. It stops
+/// accepting connections when dropped.
+#[derive(Debug)]
+pub(crate) struct Tunnel {
+    address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Tunnel {
+    pub(crate) fn start(pins: Pins) -> Result<Tunnel> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .chain_err(|| "Unable to start the proxy for pinned addresses")?;
+        let address = listener
+            .local_addr()
+            .chain_err(|| "Unable to start the proxy for pinned addresses")?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let flag = Arc::clone(&stopped);
+        thread::Builder::new()
+            .name(String::from("pinned-address-proxy"))
+            .spawn(move || accept(&listener, &flag, &pins))
+            .chain_err(|| "Unable to start the proxy for pinned addresses")?;
+
+        debug!("Tunnelling HTTPS requests for pinned hosts through {}", address);
+        Ok(Tunnel { address, stopped })
+    }
+
+    /// The URL to give the HTTP backend as a proxy.
+    pub(crate) fn url(&self) -> Url {
+        Url::parse(&format!("http://{}/", self.address)).expect("Always a valid URL")
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake the listener up so it notices
+        let _ = TcpStream::connect(self.address);
+    }
+}
+
+fn accept(listener: &TcpListener, stopped: &AtomicBool, pins: &Pins) {
+    for stream in listener.incoming() {
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+
+        match stream {
+            Ok(stream) => {
+                let pins = pins.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &pins) {
+                        debug!("A tunnel to a pinned address failed, {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Unable to accept a connection to the pinned address proxy, {}", e),
+        }
+    }
+}
+
+fn serve(mut client: TcpStream, pins: &Pins) -> io::Result<()> {
+    let mut reader = BufReader::new(client.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let target = parse_connect(&request_line)
+        .and_then(|(host, port)| pins.get(&(host, port)).map(|&ip| SocketAddr::new(ip, port)));
+    let target = match target {
+        Some(t) => t,
+        None => {
+            debug!("Refusing to tunnel {:?}", request_line.trim());
+            return client.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n");
+        }
+    };
+
+    let server = match TcpStream::connect(target) {
+        Ok(s) => s,
+        Err(e) => {
+            client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")?;
+            return Err(e);
+        }
+    };
+    client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+
+    let mut upstream = server.try_clone()?;
+    let upload = thread::spawn(move || {
+        // Keep reading through the BufReader in case it buffered anything
+        // sent straight after the headers
+        let _ = io::copy(&mut reader, &mut upstream);
+        let _ = upstream.shutdown(Shutdown::Write);
+    });
+
+    let mut downstream = server;
+    let _ = io::copy(&mut downstream, &mut client);
+    let _ = client.shutdown(Shutdown::Write);
+    let _ = upload.join();
+
+    Ok(())
+}
+
+/// Get the host and port out of a `CONNECT host:port HTTP/1.1` line.
+fn parse_connect(line: &str) -> Option<(String, u16)> {
+    let mut words = line.split_whitespace();
+    if words.next() != Some("CONNECT") {
+        return None;
+    }
+
+    let target = words.next()?;
+    let colon = target.rfind(':')?;
+    let host = target[..colon].trim_matches(|c| c == '[' || c == ']');
+    let port = target[colon + 1..].parse().ok()?;
+
+    Some((host.to_lowercase(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// Echo everything sent over a single connection.
+    fn echo_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut reader = stream.try_clone().unwrap();
+                let _ = io::copy(&mut reader, &mut stream);
+            }
+        });
+
+        port
+    }
+
+    fn connect(tunnel: &Tunnel, target: &str) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(tunnel.address).unwrap();
+        let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target);
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = Vec::new();
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+
+        (stream, String::from_utf8(response).unwrap())
+    }
+
+    #[test]
+    fn pinned_hosts_are_tunnelled_to_their_address() {
+        let port = echo_server();
+        let mut pins = Pins::new();
+        pins.insert((String::from("staging.example.com"), port), "127.0.0.1".parse().unwrap());
+        let tunnel = Tunnel::start(pins).unwrap();
+
+        let (mut stream, response) = connect(&tunnel, &format!("Staging.Example.com:{}", port));
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        stream.write_all(b"ping").unwrap();
+        let mut echoed = [0; 4];
+        stream.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[test]
+    fn other_hosts_are_refused() {
+        let tunnel = Tunnel::start(Pins::new()).unwrap();
+
+        let (_, response) = connect(&tunnel, "example.com:443");
+
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+    }
+
+    #[test]
+    fn parse_connect_lines() {
+        let inputs = vec![
+            ("CONNECT example.com:443 HTTP/1.1\r\n", Some(("example.com", 443))),
+            ("CONNECT [::1]:8443 HTTP/1.1\r\n", Some(("::1", 8443))),
+            ("GET http://example.com/ HTTP/1.1\r\n", None),
+            ("CONNECT example.com HTTP/1.1\r\n", None),
+            ("", None),
+        ];
+
+        for (line, should_be) in inputs {
+            let got = parse_connect(line);
+            let should_be = should_be.map(|(host, port)| (host.to_string(), port));
+            assert_eq!(got, should_be, "{:?}", line);
+        }
+    }
+}
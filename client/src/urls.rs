@@ -0,0 +1,200 @@
+//! Building and inspecting URLs from C.
+//!
+//! Letting C callers `sprintf()` their own URLs means every caller needs to
+//! get percent-encoding, relative paths, internationalized domain names, and
+//! IPv6 literals right. Instead we hand out an opaque `Url` and let the `url`
+//! crate do the hard work.
+
+use std::ptr;
+use libc::{c_char, c_int, size_t};
+use reqwest::Url;
+
+use errors::*;
+use ffi::{c_str_to_str, catch_panic, copy_to_buffer, update_last_error};
+
+
+/// Parse a string into a `Url`, making sure it is something we can actually
+/// send a request to.
+pub fn parse_url(raw: &str) -> Result<Url> {
+    let url = Url::parse(raw).chain_err(|| ErrorKind::InvalidUrl(raw.to_string()))?;
+
+    if url.cannot_be_a_base() {
+        bail!(ErrorKind::InvalidUrl(raw.to_string()));
+    }
+
+    Ok(url)
+}
+
+/// Parse a URL.
+///
+/// If the string passed in isn't a valid URL this will return a null pointer.
+/// Make sure you destroy the URL with [`url_destroy()`] once you are done with
+/// it.
+///
+/// [`url_destroy()`]: fn.url_destroy.html
+#[no_mangle]
+pub unsafe extern "C" fn url_parse(raw: *const c_char) -> *mut Url {
+    catch_panic(ptr::null_mut(), || {
+        let raw = match c_str_to_str(raw, "URL") {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
+
+        match parse_url(raw) {
+            Ok(url) => Box::into_raw(Box::new(url)),
+            Err(e) => {
+                update_last_error(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Resolve a (possibly relative) URL against a base URL, creating a new
+/// `Url`.
+///
+/// This returns a null pointer if the URL couldn't be resolved.
+#[no_mangle]
+pub unsafe extern "C" fn url_join(base: *const Url, relative: *const c_char) -> *mut Url {
+    catch_panic(ptr::null_mut(), || {
+        if base.is_null() {
+            update_last_error(Error::from("Null pointer passed to url_join()"));
+            return ptr::null_mut();
+        }
+
+        let relative = match c_str_to_str(relative, "relative URL") {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
+
+        match (&*base).join(relative) {
+            Ok(url) => Box::into_raw(Box::new(url)),
+            Err(e) => {
+                update_last_error(Error::with_chain(e, "Unable to resolve the relative URL"));
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Destroy a `Url` once you are done with it.
+#[no_mangle]
+pub unsafe extern "C" fn url_destroy(url: *mut Url) {
+    catch_panic((), || {
+        if !url.is_null() {
+            drop(Box::from_raw(url));
+        }
+    })
+}
+
+/// Replace the URL's path. Any characters which aren't allowed in a path will
+/// be percent-encoded.
+///
+/// Returns `0` on success or `-1` if an error occurred.
+#[no_mangle]
+pub unsafe extern "C" fn url_set_path(url: *mut Url, path: *const c_char) -> c_int {
+    catch_panic(-1, || {
+        if url.is_null() {
+            update_last_error(Error::from("Null pointer passed to url_set_path()"));
+            return -1;
+        }
+
+        let path = match c_str_to_str(path, "path") {
+            Some(p) => p,
+            None => return -1,
+        };
+
+        (&mut *url).set_path(path);
+        0
+    })
+}
+
+/// Set a query parameter, replacing any existing parameters with the same
+/// key. Both the key and value will be percent-encoded.
+///
+/// Returns `0` on success or `-1` if an error occurred.
+#[no_mangle]
+pub unsafe extern "C" fn url_set_query_pair(
+    url: *mut Url,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    catch_panic(-1, || {
+        if url.is_null() {
+            update_last_error(Error::from("Null pointer passed to url_set_query_pair()"));
+            return -1;
+        }
+
+        let (key, value) = match (c_str_to_str(key, "key"), c_str_to_str(value, "value")) {
+            (Some(k), Some(v)) => (k, v),
+            _ => return -1,
+        };
+
+        set_query_pair(&mut *url, key, value);
+        0
+    })
+}
+
+pub(crate) fn set_query_pair(url: &mut Url, key: &str, value: &str) {
+    let others: Vec<(String, String)> = url.query_pairs()
+        .filter(|&(ref k, _)| k != key)
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(others)
+        .append_pair(key, value);
+}
+
+/// Write the URL's host into a buffer as a null-terminated string, returning
+/// the number of bytes written (not including the null).
+///
+/// Domain names are written in their ASCII (punycode) form and IPv6 addresses
+/// are wrapped in square brackets. If the URL has no host, this returns `0`.
+/// `-1` is returned if there are any errors, for example when the buffer is
+/// too small.
+#[no_mangle]
+pub unsafe extern "C" fn url_host(url: *const Url, buffer: *mut c_char, length: size_t) -> c_int {
+    catch_panic(-1, || {
+        if url.is_null() {
+            update_last_error(Error::from("Null pointer passed to url_host()"));
+            return -1;
+        }
+
+        let host = (&*url).host_str().unwrap_or("");
+        copy_to_buffer(host.as_bytes(), buffer, length)
+    })
+}
+
+/// Get the number of bytes needed to hold the URL as a null-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn url_length(url: *const Url) -> c_int {
+    catch_panic(-1, || {
+        if url.is_null() {
+            update_last_error(Error::from("Null pointer passed to url_length()"));
+            return -1;
+        }
+
+        (&*url).as_str().len() as c_int + 1
+    })
+}
+
+/// Write the full URL into a buffer as a null-terminated string, returning the
+/// number of bytes written (not including the null) or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn url_to_string(
+    url: *const Url,
+    buffer: *mut c_char,
+    length: size_t,
+) -> c_int {
+    catch_panic(-1, || {
+        if url.is_null() {
+            update_last_error(Error::from("Null pointer passed to url_to_string()"));
+            return -1;
+        }
+
+        copy_to_buffer((&*url).as_str().as_bytes(), buffer, length)
+    })
+}
@@ -1,22 +1,143 @@
 //! Extra utility functions.
 
-use std::sync::{Once, ONCE_INIT};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Once, RwLock, ONCE_INIT};
 use fern;
-use log::LogLevelFilter;
+use libc::{c_char, c_int, c_void};
+use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord};
 use chrono::Local;
 
 use errors::*;
+use ffi::{c_str_to_str, catch_panic, update_last_error};
 
 
-/// Initialize the global logger and log to `rest_client.log`.
+/// The file logs are written to.
+pub const LOG_FILE: &str = "rest_client.log";
+
+/// How verbose the logs should be.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub enum LogLevel {
+    Off = 0x00,
+    Error = 0x01,
+    Warn = 0x02,
+    Info = 0x04,
+    Debug = 0x08,
+    Trace = 0x10,
+}
+
+impl LogLevel {
+    fn from_c_int(level: c_int) -> Option<LogLevel> {
+        match level {
+            0x00 => Some(LogLevel::Off),
+            0x01 => Some(LogLevel::Error),
+            0x02 => Some(LogLevel::Warn),
+            0x04 => Some(LogLevel::Info),
+            0x08 => Some(LogLevel::Debug),
+            0x10 => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+
+    fn filter(&self) -> LogLevelFilter {
+        match *self {
+            LogLevel::Off => LogLevelFilter::Off,
+            LogLevel::Error => LogLevelFilter::Error,
+            LogLevel::Warn => LogLevelFilter::Warn,
+            LogLevel::Info => LogLevelFilter::Info,
+            LogLevel::Debug => LogLevelFilter::Debug,
+            LogLevel::Trace => LogLevelFilter::Trace,
+        }
+    }
+}
+
+impl From<log::LogLevel> for LogLevel {
+    fn from(level: log::LogLevel) -> LogLevel {
+        match level {
+            log::LogLevel::Error => LogLevel::Error,
+            log::LogLevel::Warn => LogLevel::Warn,
+            log::LogLevel::Info => LogLevel::Info,
+            log::LogLevel::Debug => LogLevel::Debug,
+            log::LogLevel::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+/// A callback which receives every log message.
+pub type LogCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, level: LogLevel, message: *const c_char);
+
+/// Where log messages end up.
 ///
-/// Note that this is an idempotent function, so you can call it as many
-/// times as you want and logging will only be initialized the first time.
-#[no_mangle]
-pub extern "C" fn initialize_logging() {
+/// The global logger can only be installed once, so instead of rebuilding
+/// it we swap out its destinations.
+struct Sinks {
+    level: LogLevelFilter,
+    file: Option<File>,
+    callback: Option<(LogCallback, *mut c_void)>,
+}
+
+// The caller promised the callback and its user data can be used from any
+// thread when they registered it.
+unsafe impl Send for Sinks {}
+unsafe impl Sync for Sinks {}
+
+lazy_static! {
+    static ref SINKS: RwLock<Sinks> = RwLock::new(Sinks {
+        level: LogLevelFilter::Off,
+        file: None,
+        callback: None,
+    });
+}
+
+/// Writes the formatted message to the log file.
+struct FileSink;
+
+impl Log for FileSink {
+    fn enabled(&self, _metadata: &LogMetadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if let Ok(sinks) = SINKS.read() {
+            if let Some(mut f) = sinks.file.as_ref() {
+                let _ = writeln!(f, "{}", record.args());
+            }
+        }
+    }
+}
+
+/// Passes the unformatted message to the user's callback.
+struct CallbackSink;
+
+impl Log for CallbackSink {
+    fn enabled(&self, _metadata: &LogMetadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &LogRecord) {
+        // Don't hold the lock while calling the callback, it may want to log
+        // something itself or call set_log_callback()
+        let callback = SINKS.read().ok().and_then(|sinks| sinks.callback);
+
+        if let Some((callback, user_data)) = callback {
+            let message = record.args().to_string().replace('\0', "");
+            let message = CString::new(message).expect("All nulls were removed");
+
+            unsafe {
+                callback(user_data, record.level().into(), message.as_ptr());
+            }
+        }
+    }
+}
+
+/// Make sure the global logger is installed.
+fn install_logger() {
     static INITIALIZE: Once = ONCE_INIT;
     INITIALIZE.call_once(|| {
-        fern::Dispatch::new()
+        let to_file = fern::Dispatch::new()
             .format(|out, message, record| {
                 let loc = record.location();
 
@@ -30,13 +151,131 @@ pub extern "C" fn initialize_logging() {
                     if cfg!(windows) { "\r" } else { "" }
                 ))
             })
-            .level(LogLevelFilter::Debug)
-            .chain(fern::log_file("rest_client.log").unwrap())
+            .chain(Box::new(FileSink) as Box<Log>);
+
+        fern::Dispatch::new()
+            .level(LogLevelFilter::Trace)
+            .filter(|metadata| {
+                SINKS
+                    .read()
+                    .map(|sinks| metadata.level() <= sinks.level)
+                    .unwrap_or(false)
+            })
+            .chain(to_file)
+            .chain(Box::new(CallbackSink) as Box<Log>)
             .apply()
             .unwrap();
     });
 }
 
+/// Log at the specified level, appending to the file at `path` (or not
+/// logging to a file at all if `path` is `None`).
+///
+/// This can be called as many times as you want, each call replacing the
+/// previous settings.
+pub fn configure_logging(level: LogLevel, path: Option<&str>) -> Result<()> {
+    let file = match path {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .chain_err(|| format!("Unable to open the log file, {}", path))?,
+        ),
+        None => None,
+    };
+
+    install_logger();
+
+    let mut sinks = SINKS
+        .write()
+        .map_err(|_| Error::from("The logger's lock is poisoned"))?;
+    sinks.level = level.filter();
+    sinks.file = file;
+    Ok(())
+}
+
+/// Initialize the global logger and log to `rest_client.log`.
+///
+/// Note that this is an idempotent function, so you can call it as many
+/// times as you want and logging will only be initialized the first time.
+#[no_mangle]
+pub extern "C" fn initialize_logging() {
+    catch_panic((), || {
+        static INITIALIZE: Once = ONCE_INIT;
+        INITIALIZE.call_once(|| {
+            configure_logging(LogLevel::Debug, Some(LOG_FILE)).unwrap();
+        });
+    })
+}
+
+/// Initialize (or reconfigure) the global logger, logging everything at
+/// `level` or above to the file at `path`. If `path` is null, nothing is
+/// written to disk, which is useful when logs are sent to a callback (see
+/// [`set_log_callback()`]).
+///
+/// Returns `0` on success or `-1` on error.
+///
+/// [`set_log_callback()`]: fn.set_log_callback.html
+#[no_mangle]
+pub unsafe extern "C" fn initialize_logging_ex(level: c_int, path: *const c_char) -> c_int {
+    catch_panic(-1, || {
+        let level = match LogLevel::from_c_int(level) {
+            Some(l) => l,
+            None => {
+                update_last_error(Error::from(format!("Unknown log level, {}", level)));
+                return -1;
+            }
+        };
+
+        let path = if path.is_null() {
+            None
+        } else {
+            match c_str_to_str(path, "log file path") {
+                Some(p) => Some(p),
+                None => return -1,
+            }
+        };
+
+        match configure_logging(level, path) {
+            Ok(_) => 0,
+            Err(e) => {
+                update_last_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// Send every log message to `callback` so the application can route them
+/// into its own logging framework. Passing a null `callback` stops sending
+/// messages to the previous one.
+///
+/// The callback may be invoked from any thread. Messages are only passed on
+/// if the logger has been initialized with a level other than `Off`.
+///
+/// Returns `0` on success or `-1` on error.
+#[no_mangle]
+pub unsafe extern "C" fn set_log_callback(
+    callback: Option<LogCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    catch_panic(-1, || {
+        install_logger();
+
+        match SINKS.write() {
+            Ok(mut sinks) => {
+                sinks.callback = callback.map(|cb| (cb, user_data));
+                0
+            }
+            Err(_) => {
+                update_last_error(Error::from("The logger's lock is poisoned"));
+                -1
+            }
+        }
+    })
+}
+
 /// Log an error and each successive error which caused it.
 pub fn backtrace(e: &Error) {
     error!("Error: {}", e);
@@ -0,0 +1,418 @@
+//! Sanity checks for a request before it is sent.
+//!
+//! None of these problems stop a request from being sent (servers are often
+//! more forgiving than the spec), but they're usually a sign that the user
+//! made a mistake.
+
+use std::ptr;
+use libc::{c_char, c_int, size_t};
+use reqwest::header::ContentLength;
+
+use errors::*;
+use ffi::{catch_panic, copy_to_buffer, update_last_error};
+use {PluginManager, Request};
+
+
+/// Something which looks wrong with a request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValidationWarning {
+    /// A short machine-readable name for the problem (e.g. `"body-on-get"`).
+    pub code: String,
+    /// A human-readable description of the problem.
+    pub message: String,
+}
+
+impl ValidationWarning {
+    pub fn new<C, M>(code: C, message: M) -> ValidationWarning
+    where
+        C: Into<String>,
+        M: Into<String>,
+    {
+        ValidationWarning {
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl Request {
+    /// Check the request for common mistakes, like sending a body with a
+    /// `GET` request or headers which contradict each other.
+    pub fn validate(&self) -> Vec<ValidationWarning> {
+        let mut warnings = Vec::new();
+
+        match self.destination.scheme() {
+            "http" | "https" => {}
+            other => warnings.push(ValidationWarning::new(
+                "unsupported-scheme",
+                format!("\"{}\" URLs can't be sent over HTTP", other),
+            )),
+        }
+
+        match self.destination.host_str() {
+            None => warnings.push(ValidationWarning::new("missing-host", "The URL has no host")),
+            Some(host) => {
+                if let Some(explicit) = self.header("Host") {
+                    if !explicit.eq_ignore_ascii_case(host)
+                        && !explicit.to_lowercase().starts_with(&format!("{}:", host))
+                    {
+                        let msg = format!(
+                            "The Host header ({}) doesn't match the URL ({})",
+                            explicit, host
+                        );
+                        warnings.push(ValidationWarning::new("conflicting-host", msg));
+                    }
+                }
+            }
+        }
+
+        if self.body.is_some() && !self.method_allows_body() {
+            warnings.push(ValidationWarning::new(
+                "body-on-get",
+                format!("A {} request usually shouldn't have a body", self.method),
+            ));
+        }
+
+        if let Some(ref body) = self.body {
+            if let Some(&ContentLength(length)) = self.headers.get::<ContentLength>() {
+                if length != body.len() as u64 {
+                    warnings.push(ValidationWarning::new(
+                        "wrong-content-length",
+                        format!(
+                            "The Content-Length header says {} bytes but the body is {} bytes",
+                            length,
+                            body.len()
+                        ),
+                    ));
+                }
+            }
+
+            if !body.is_empty() && self.header("Content-Type").is_none() {
+                warnings.push(ValidationWarning::new(
+                    "missing-content-type",
+                    "The request has a body but no Content-Type",
+                ));
+            }
+        }
+
+        if self.header("Content-Length").is_some() && self.header("Transfer-Encoding").is_some() {
+            warnings.push(ValidationWarning::new(
+                "conflicting-length",
+                "Content-Length and Transfer-Encoding shouldn't be used together",
+            ));
+        }
+
+        if self.header("Authorization").is_some() && self.authenticator.is_some() {
+            warnings.push(ValidationWarning::new(
+                "conflicting-authorization",
+                "The Authorization header will be overwritten by the authenticator",
+            ));
+        }
+
+        if self.header("Cookie").is_some() && self.cookies.iter().next().is_some() {
+            warnings.push(ValidationWarning::new(
+                "conflicting-cookies",
+                "The Cookie header will be overwritten by the request's cookies",
+            ));
+        }
+
+        warnings
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .get_raw(name)
+            .and_then(|raw| raw.one())
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+    }
+}
+
+impl PluginManager {
+    /// Validate a request, including any extra checks done by plugins.
+    pub fn validate(&self, request: &Request) -> Vec<ValidationWarning> {
+        let mut warnings = request.validate();
+
+        for plugin in self.ordered() {
+            if request.skip_plugins.contains(plugin.name()) {
+                continue;
+            }
+
+            trace!("Firing validate for {:?}", plugin.name());
+            match plugin.validate(self.context(), request) {
+                Ok(extra) => warnings.extend(extra),
+                Err(e) => warn!("{}", e),
+            }
+        }
+
+        warnings
+    }
+}
+
+/// Check a request for common mistakes. If a `PluginManager` is provided
+/// (it may be null) the plugins get to do their own checks too.
+///
+/// Returns a list of warnings which must be destroyed with
+/// [`validation_destroy()`], or a null pointer on error.
+///
+/// [`validation_destroy()`]: fn.validation_destroy.html
+#[no_mangle]
+pub unsafe extern "C" fn request_validate(
+    req: *const Request,
+    pm: *const PluginManager,
+) -> *mut Vec<ValidationWarning> {
+    catch_panic(ptr::null_mut(), || {
+        if req.is_null() {
+            update_last_error(Error::from("Null pointer passed to request_validate()"));
+            return ptr::null_mut();
+        }
+
+        let warnings = if pm.is_null() {
+            (&*req).validate()
+        } else {
+            (&*pm).validate(&*req)
+        };
+
+        for warning in &warnings {
+            debug!("Validation warning ({}): {}", warning.code, warning.message);
+        }
+
+        Box::into_raw(Box::new(warnings))
+    })
+}
+
+/// Destroy the warnings returned by [`request_validate()`].
+///
+/// [`request_validate()`]: fn.request_validate.html
+#[no_mangle]
+pub unsafe extern "C" fn validation_destroy(warnings: *mut Vec<ValidationWarning>) {
+    catch_panic((), || {
+        if !warnings.is_null() {
+            drop(Box::from_raw(warnings));
+        }
+    })
+}
+
+/// Get the number of validation warnings, or `-1` if passed a null pointer.
+#[no_mangle]
+pub unsafe extern "C" fn validation_count(warnings: *const Vec<ValidationWarning>) -> c_int {
+    catch_panic(-1, || {
+        if warnings.is_null() {
+            update_last_error(Error::from("Null pointer passed to validation_count()"));
+            return -1;
+        }
+
+        (&*warnings).len() as c_int
+    })
+}
+
+/// Copy the code and message of the `index`'th warning into two
+/// caller-provided buffers as null-terminated strings.
+///
+/// Returns `0` on success or `-1` if the index is out of bounds or either
+/// buffer is too small.
+#[no_mangle]
+pub unsafe extern "C" fn validation_get(
+    warnings: *const Vec<ValidationWarning>,
+    index: c_int,
+    code_buffer: *mut c_char,
+    code_length: size_t,
+    message_buffer: *mut c_char,
+    message_length: size_t,
+) -> c_int {
+    catch_panic(-1, || {
+        if warnings.is_null() {
+            update_last_error(Error::from("Null pointer passed to validation_get()"));
+            return -1;
+        }
+
+        let warning = match (&*warnings).get(index as usize) {
+            Some(w) if index >= 0 => w,
+            _ => {
+                update_last_error(Error::from(format!("There is no warning at index {}", index)));
+                return -1;
+            }
+        };
+
+        if copy_to_buffer(warning.code.as_bytes(), code_buffer, code_length) < 0
+            || copy_to_buffer(warning.message.as_bytes(), message_buffer, message_length) < 0
+        {
+            return -1;
+        }
+
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use cookie::Cookie;
+    use reqwest::{Method, Url};
+    use auth::BasicAuth;
+    use context::PluginContext;
+    use plugins::Plugin;
+
+    fn get(url: &str) -> Request {
+        Request::new(Url::parse(url).unwrap(), Method::Get)
+    }
+
+    fn codes(warnings: &[ValidationWarning]) -> Vec<&str> {
+        warnings.iter().map(|w| w.code.as_str()).collect()
+    }
+
+    #[test]
+    fn a_normal_request_has_no_warnings() {
+        let mut req = Request::post(Url::parse("https://example.com/users").unwrap());
+        req.headers.set_raw("Content-Type", "application/json");
+        req.headers.set_raw("Host", "EXAMPLE.com:443");
+        req.set_body("{}");
+
+        assert!(req.validate().is_empty());
+    }
+
+    #[test]
+    fn non_http_urls() {
+        let warnings = get("ftp://example.com/file.txt").validate();
+        assert_eq!(codes(&warnings), vec!["unsupported-scheme"]);
+
+        let warnings = get("mailto:someone@example.com").validate();
+        assert_eq!(codes(&warnings), vec!["unsupported-scheme", "missing-host"]);
+    }
+
+    #[test]
+    fn host_header_must_match_the_url() {
+        let mut req = get("http://example.com/");
+        req.headers.set_raw("Host", "example.org");
+
+        let warnings = req.validate();
+        assert_eq!(codes(&warnings), vec!["conflicting-host"]);
+        assert_eq!(
+            warnings[0].message,
+            "The Host header (example.org) doesn't match the URL (example.com)"
+        );
+    }
+
+    #[test]
+    fn bodies_on_requests_which_shouldnt_have_them() {
+        for method in &[Method::Get, Method::Head, Method::Options] {
+            let mut req = get("http://example.com/");
+            req.method = method.clone();
+            req.set_body("");
+
+            assert_eq!(codes(&req.validate()), vec!["body-on-get"], "{}", method);
+        }
+
+        let mut req = Request::delete(Url::parse("http://example.com/").unwrap());
+        req.set_body("");
+        assert!(req.validate().is_empty());
+    }
+
+    #[test]
+    fn content_length_must_match_the_body() {
+        let mut req = Request::put(Url::parse("http://example.com/").unwrap());
+        req.headers.set_raw("Content-Type", "text/plain");
+        req.headers.set(ContentLength(3));
+        req.set_body("Hello");
+
+        let warnings = req.validate();
+        assert_eq!(codes(&warnings), vec!["wrong-content-length"]);
+        assert_eq!(
+            warnings[0].message,
+            "The Content-Length header says 3 bytes but the body is 5 bytes"
+        );
+    }
+
+    #[test]
+    fn non_empty_bodies_need_a_content_type() {
+        let mut req = Request::post(Url::parse("http://example.com/").unwrap());
+        req.set_body("Hello");
+        assert_eq!(codes(&req.validate()), vec!["missing-content-type"]);
+
+        req.set_body("");
+        assert!(req.validate().is_empty());
+    }
+
+    #[test]
+    fn content_length_and_transfer_encoding() {
+        let mut req = get("http://example.com/");
+        req.headers.set_raw("Content-Length", "0");
+        req.headers.set_raw("Transfer-Encoding", "chunked");
+
+        assert_eq!(codes(&req.validate()), vec!["conflicting-length"]);
+    }
+
+    #[test]
+    fn authorization_header_and_authenticator() {
+        let mut req = get("http://example.com/");
+        req.headers.set_raw("Authorization", "Bearer abc");
+        assert!(req.validate().is_empty());
+
+        req.set_authenticator(BasicAuth::new("user", "pass"));
+        assert_eq!(codes(&req.validate()), vec!["conflicting-authorization"]);
+    }
+
+    #[test]
+    fn cookie_header_and_cookies() {
+        let mut req = get("http://example.com/");
+        req.headers.set_raw("Cookie", "session=1");
+        assert!(req.validate().is_empty());
+
+        req.cookies.add(Cookie::new("session", "2"));
+        assert_eq!(codes(&req.validate()), vec!["conflicting-cookies"]);
+    }
+
+    struct Picky;
+
+    impl Plugin for Picky {
+        fn name(&self) -> &str {
+            "picky"
+        }
+
+        fn validate(&self, _ctx: &PluginContext, request: &Request) -> Vec<ValidationWarning> {
+            if request.destination.scheme() == "http" {
+                vec![ValidationWarning::new("insecure", "Use HTTPS")]
+            } else {
+                Vec::new()
+            }
+        }
+    }
+
+    #[test]
+    fn plugins_can_add_their_own_warnings() {
+        let mut pm = PluginManager::new();
+        pm.register_static(Box::new(Picky)).unwrap();
+
+        let mut req = get("http://example.com/");
+        req.set_body("");
+        assert_eq!(codes(&pm.validate(&req)), vec!["body-on-get", "insecure"]);
+
+        req.skip_plugin("picky");
+        assert_eq!(codes(&pm.validate(&req)), vec!["body-on-get"]);
+    }
+
+    #[test]
+    fn warnings_through_the_c_api() {
+        let mut req = get("http://example.com/");
+        req.set_body("");
+
+        unsafe {
+            let warnings = request_validate(&req, ptr::null());
+            assert!(!warnings.is_null());
+            assert_eq!(validation_count(warnings), 1);
+
+            let mut code = [0 as c_char; 32];
+            let mut message = [0 as c_char; 64];
+            let got = validation_get(warnings, 0, code.as_mut_ptr(), 32, message.as_mut_ptr(), 64);
+            assert_eq!(got, 0);
+            assert_eq!(CStr::from_ptr(code.as_ptr()).to_str().unwrap(), "body-on-get");
+
+            let got = validation_get(warnings, 1, code.as_mut_ptr(), 32, message.as_mut_ptr(), 64);
+            assert_eq!(got, -1);
+            let got = validation_get(warnings, 0, code.as_mut_ptr(), 4, message.as_mut_ptr(), 64);
+            assert_eq!(got, -1);
+
+            validation_destroy(warnings);
+        }
+    }
+}
@@ -15,8 +15,8 @@ void MainWindow::onClick() {
 
     Request req("http://httpbin.orgsdasd/get");
     std::cout << "Sending Request" << std::endl;
-    pm.pre_send(req);
-    Response res = req.send();
+    std::unique_ptr<Response> answer = pm.pre_send(req);
+    Response res = answer ? std::move(*answer) : req.send();
     pm.post_receive(res);
     std::cout << "Received Response" << std::endl;
 
@@ -44,8 +44,18 @@ PluginManager::PluginManager() { raw = ffi::plugin_manager_new(); }
 
 PluginManager::~PluginManager() { ffi::plugin_manager_destroy(raw); }
 
-void PluginManager::pre_send(Request &req) {
-  ffi::plugin_manager_pre_send(raw, req.raw);
+// Returns the response if one of the plugins answered the request itself.
+std::unique_ptr<Response> PluginManager::pre_send(Request &req) {
+  ffi::Response *answer = nullptr;
+  int ret = ffi::plugin_manager_pre_send(raw, req.raw, &answer);
+
+  if (ret < 0) {
+    throw WrapperException::last_error();
+  } else if (ret == 1) {
+    return std::unique_ptr<Response>(new Response(answer));
+  }
+
+  return nullptr;
 }
 
 void PluginManager::unload() { ffi::plugin_manager_unload(raw); }
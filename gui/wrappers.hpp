@@ -2,6 +2,7 @@
 
 #include "client.hpp"
 #include <exception>
+#include <memory>
 #include <string>
 #include <vector>
 
@@ -62,7 +63,7 @@ public:
   ~PluginManager();
   void unload();
   void load_plugin(const std::string& filename);
-  void pre_send(Request &req);
+  std::unique_ptr<Response> pre_send(Request &req);
   void post_receive(Response &res);
 
   PluginManager(const PluginManager&) = delete;
@@ -5,32 +5,81 @@ extern crate env_logger;
 extern crate client;
 
 use std::str;
-use client::{Request, Response, Plugin};
+use std::sync::RwLock;
+use client::{Capabilities, HookResult, Request, Response, Plugin, PluginConfig, PluginContext};
 
 
-#[derive(Debug, Default)]
-pub struct Injector;
+/// The header added when no other one is configured.
+const DEFAULT_HEADER: (&str, &str) = ("some-dodgy-header", "true");
+
+#[derive(Debug)]
+pub struct Injector {
+    /// The name and value of the header to inject.
+    header: RwLock<(String, String)>,
+}
+
+impl Injector {
+    fn header(&self) -> (String, String) {
+        self.header.read().unwrap().clone()
+    }
+}
+
+impl Default for Injector {
+    fn default() -> Injector {
+        let (name, value) = DEFAULT_HEADER;
+
+        Injector {
+            header: RwLock::new((name.to_string(), value.to_string())),
+        }
+    }
+}
 
 impl Plugin for Injector {
     fn name(&self) -> &'static str  {
         "Header Injector"
     }
 
-    fn on_plugin_load(&self) {
+    fn version(&self) -> &'static str {
+        env!("CARGO_PKG_VERSION")
+    }
+
+    fn author(&self) -> &'static str {
+        env!("CARGO_PKG_AUTHORS")
+    }
+
+    fn description(&self) -> &'static str {
+        env!("CARGO_PKG_DESCRIPTION")
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::MODIFIES_REQUESTS | Capabilities::MODIFIES_RESPONSES
+    }
+
+    fn on_plugin_load(&self, ctx: &PluginContext) {
         env_logger::init().ok();
-        info!("Injector loaded");
+        ctx.logger().info("Injector loaded");
     }
 
-    fn on_plugin_unload(&self) {
-        info!("Injector unloaded");
+    fn on_plugin_unload(&self, ctx: &PluginContext) {
+        ctx.logger().info("Injector unloaded");
+    }
+
+    fn on_configure(&self, _ctx: &PluginContext, config: &PluginConfig) {
+        let name = config.get("header").unwrap_or(DEFAULT_HEADER.0);
+        let value = config.get("value").unwrap_or(DEFAULT_HEADER.1);
+        info!("Injecting \"{}: {}\"", name, value);
+
+        *self.header.write().unwrap() = (name.to_string(), value.to_string());
     }
 
-    fn pre_send(&self, req: &mut Request) {
-        req.headers.set_raw("some-dodgy-header", "true");
+    fn pre_send(&self, _ctx: &PluginContext, req: &mut Request) -> HookResult {
+        let (name, value) = self.header();
+        req.headers.set_raw(name, value);
         debug!("Injected header into Request, {:?}", req);
+        HookResult::Continue
     }
 
-    fn post_receive(&self, res: &mut Response) {
+    fn post_receive(&self, _ctx: &PluginContext, res: &mut Response) {
         debug!("Received Response");
         debug!("Headers: {:?}", res.headers);
         if res.body.len() < 100 && log_enabled!(::log::LogLevel::Debug) {
@@ -38,7 +87,7 @@ impl Plugin for Injector {
                 debug!("Body: {:?}", body);
             }
         }
-        res.headers.remove_raw("some-dodgy-header");
+        res.headers.remove_raw(&self.header().0);
     }
 }
 